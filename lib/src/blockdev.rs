@@ -1,7 +1,10 @@
 use crate::task::Task;
 use crate::utils::run_in_host_mountns;
 use anyhow::{anyhow, Context, Result};
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std::fs::Dir;
+use cap_std_ext::cap_std;
+use cap_std_ext::dirext::CapStdExtDirExt;
 use fn_error_context::context;
 use nix::errno::Errno;
 use once_cell::sync::Lazy;
@@ -11,6 +14,18 @@ use std::collections::HashMap;
 use std::fs::File;
 use std::os::unix::io::AsRawFd;
 use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// How long we wait for udev to create expected device nodes and symlinks, by default.
+pub(crate) const DEFAULT_UDEV_SETTLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to sleep between `udevadm settle` invocations while waiting.
+const UDEV_SETTLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The number of times we waited again for udev to create device nodes, for metrics.
+pub(crate) static UDEV_SETTLE_RETRIES: AtomicU64 = AtomicU64::new(0);
+/// The number of failed attempts to reread a partition table, for metrics.
+pub(crate) static PARTITION_REREAD_RETRIES: AtomicU64 = AtomicU64::new(0);
 
 #[derive(Debug, Deserialize)]
 struct DevicesOutput {
@@ -24,7 +39,10 @@ pub(crate) struct Device {
     pub(crate) serial: Option<String>,
     pub(crate) model: Option<String>,
     pub(crate) label: Option<String>,
+    pub(crate) partlabel: Option<String>,
     pub(crate) fstype: Option<String>,
+    pub(crate) uuid: Option<String>,
+    pub(crate) parttype: Option<String>,
     pub(crate) children: Option<Vec<Device>>,
 }
 
@@ -50,7 +68,11 @@ pub(crate) fn wipefs(dev: &Utf8Path) -> Result<()> {
 
 fn list_impl(dev: Option<&Utf8Path>) -> Result<Vec<Device>> {
     let o = Command::new("lsblk")
-        .args(["-J", "-o", "NAME,SERIAL,MODEL,LABEL,FSTYPE"])
+        .args([
+            "-J",
+            "-o",
+            "NAME,SERIAL,MODEL,LABEL,PARTLABEL,FSTYPE,UUID,PARTTYPE",
+        ])
         .args(dev)
         .output()?;
     if !o.status.success() {
@@ -60,6 +82,94 @@ fn list_impl(dev: Option<&Utf8Path>) -> Result<Vec<Device>> {
     Ok(devs.blockdevices)
 }
 
+#[derive(Debug, Deserialize)]
+struct SfdiskOutput {
+    partitiontable: PartitionTable,
+}
+
+/// A partition table, as reported by `sfdisk --json`.
+#[derive(Debug, Deserialize)]
+pub(crate) struct PartitionTable {
+    /// The type of partition table, e.g. `gpt`
+    pub(crate) label: String,
+    /// The first sector usable for partitions (GPT only)
+    #[serde(rename = "first-lba")]
+    pub(crate) first_lba: Option<u64>,
+    /// The last sector usable for partitions (GPT only)
+    #[serde(rename = "last-lba")]
+    pub(crate) last_lba: Option<u64>,
+    pub(crate) partitions: Vec<Partition>,
+}
+
+/// A partition, as reported by `sfdisk --json`.
+#[derive(Debug, Deserialize)]
+pub(crate) struct Partition {
+    pub(crate) node: String,
+    /// The first sector of the partition
+    pub(crate) start: u64,
+    /// The size of the partition, in sectors
+    pub(crate) size: u64,
+    /// The partition type GUID
+    #[serde(rename = "type")]
+    pub(crate) parttype: String,
+    /// The partition UUID
+    pub(crate) uuid: Option<String>,
+    /// The partition name
+    pub(crate) name: Option<String>,
+}
+
+impl Partition {
+    /// The partition number, from the device node name.
+    pub(crate) fn number(&self) -> Result<u32> {
+        let digits = self.node.len()
+            - self
+                .node
+                .chars()
+                .rev()
+                .take_while(|c| c.is_ascii_digit())
+                .count();
+        self.node[digits..]
+            .parse()
+            .with_context(|| format!("Parsing partition number of {}", self.node))
+    }
+}
+
+fn parse_sfdisk(stdout: &[u8]) -> Result<PartitionTable> {
+    let o: SfdiskOutput = serde_json::from_slice(stdout).context("Parsing sfdisk output")?;
+    Ok(o.partitiontable)
+}
+
+/// Read the partition table of the provided device.
+#[context("Reading partition table of {dev}")]
+pub(crate) fn partition_table(dev: &Utf8Path) -> Result<PartitionTable> {
+    let o = Command::new("sfdisk")
+        .args(["--json", dev.as_str()])
+        .output()?;
+    if !o.status.success() {
+        anyhow::bail!("sfdisk failed: {:?}", o.status);
+    }
+    parse_sfdisk(&o.stdout)
+}
+
+/// The logical sector size of the provided block device, in bytes.
+#[allow(unsafe_code)]
+pub(crate) fn sector_size(file: &File) -> Result<u64> {
+    let mut size: std::os::raw::c_int = 0;
+    // SAFETY: The ioctl writes a single int
+    unsafe { ioctl::blksszget(file.as_raw_fd(), &mut size) }.context("Querying sector size")?;
+    Ok(u64::try_from(size)?)
+}
+
+/// The size of the provided block device, in bytes.
+#[allow(unsafe_code)]
+pub(crate) fn device_size(file: &File) -> Result<u64> {
+    let mut size: u64 = 0;
+    // SAFETY: The ioctl writes a single u64
+    let r = unsafe { nix::libc::ioctl(file.as_raw_fd(), ioctl::BLKGETSIZE64, &mut size) };
+    nix::errno::Errno::result(r).context("Querying device size")?;
+    Ok(size)
+}
+
 #[context("Listing device {dev}")]
 pub(crate) fn list_dev(dev: &Utf8Path) -> Result<Device> {
     let devices = list_impl(Some(dev))?;
@@ -88,6 +198,75 @@ pub(crate) fn udev_settle() -> Result<()> {
     Ok(())
 }
 
+/// Repeatedly invoke `settle` until `present` returns true for every path, or
+/// `timeout` elapses, returning the number of retries.  This is split out from the
+/// udev specifics for testing.
+fn settle_until(
+    mut settle: impl FnMut() -> Result<()>,
+    mut present: impl FnMut(&Utf8Path) -> bool,
+    paths: &[Utf8PathBuf],
+    timeout: Duration,
+    interval: Duration,
+) -> Result<u64> {
+    let start = Instant::now();
+    let mut retries = 0;
+    loop {
+        settle()?;
+        let missing = paths
+            .iter()
+            .filter(|p| !present(p))
+            .map(|p| p.as_str())
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            return Ok(retries);
+        }
+        if start.elapsed() >= timeout {
+            anyhow::bail!(
+                "Timed out after {}s waiting for udev to create: {}",
+                timeout.as_secs(),
+                missing.join(", ")
+            );
+        }
+        tracing::debug!("Waiting for udev: missing {}", missing.join(", "));
+        std::thread::sleep(interval);
+        retries += 1;
+    }
+}
+
+/// Run `udevadm settle` until all of the provided device nodes exist, or `timeout` elapses.
+#[context("Waiting for devices")]
+pub(crate) fn udev_settle_and_verify(devices: &[Utf8PathBuf], timeout: Duration) -> Result<()> {
+    let retries = settle_until(
+        udev_settle,
+        |p| p.exists(),
+        devices,
+        timeout,
+        UDEV_SETTLE_INTERVAL,
+    )?;
+    UDEV_SETTLE_RETRIES.fetch_add(retries, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Run `udevadm settle` until the `/dev/disk/by-uuid` symlinks for all of the
+/// provided filesystem UUIDs exist, or `timeout` elapses.  These are created by the
+/// host's udev, so we check for them in the host mount namespace.
+#[context("Waiting for filesystem UUIDs")]
+pub(crate) fn udev_settle_for_uuids(uuids: &[&str], timeout: Duration) -> Result<()> {
+    let paths = uuids
+        .iter()
+        .map(|u| Utf8PathBuf::from(format!("/dev/disk/by-uuid/{u}")))
+        .collect::<Vec<_>>();
+    let retries = settle_until(
+        udev_settle,
+        |p| crate::utils::host_path_exists(p.as_str()),
+        &paths,
+        timeout,
+        UDEV_SETTLE_INTERVAL,
+    )?;
+    UDEV_SETTLE_RETRIES.fetch_add(retries, Ordering::Relaxed);
+    Ok(())
+}
+
 #[allow(unsafe_code)]
 pub(crate) fn reread_partition_table(file: &mut File, retry: bool) -> Result<()> {
     let fd = file.as_raw_fd();
@@ -106,7 +285,10 @@ pub(crate) fn reread_partition_table(file: &mut File, retry: bool) -> Result<()>
                 return Err(err).context("couldn't reread partition table: device is in use")
             }
             Err(err) if retries == 0 => return Err(err).context("couldn't reread partition table"),
-            Err(_) => std::thread::sleep(std::time::Duration::from_millis(100)),
+            Err(_) => {
+                PARTITION_REREAD_RETRIES.fetch_add(1, Ordering::Relaxed);
+                std::thread::sleep(std::time::Duration::from_millis(100))
+            }
         }
     }
     Ok(())
@@ -152,9 +334,21 @@ pub(crate) fn find_parent_devices(device: &str) -> Result<Vec<String>> {
         .arg("NAME,TYPE")
         .arg(device);
     let output = cmd_output(&mut cmd)?;
+    parse_parent_devices(device, &output)
+}
+
+/// Parse the inverse `lsblk --pairs` output for the provided device into its parent
+/// devices.  A multipath device is resolved to itself, rather than to its paths.
+fn parse_parent_devices(device: &str, output: &str) -> Result<Vec<String>> {
+    let mut lines = output.lines();
+    // The first line is the device itself
+    if let Some(line) = lines.next() {
+        if split_lsblk_line(line).get("TYPE").map(String::as_str) == Some("mpath") {
+            return Ok(Vec::new());
+        }
+    }
     let mut parents = Vec::new();
-    // skip first line, which is the device itself
-    for line in output.lines().skip(1) {
+    for line in lines {
         let dev = split_lsblk_line(line);
         let name = dev
             .get("NAME")
@@ -163,7 +357,10 @@ pub(crate) fn find_parent_devices(device: &str) -> Result<Vec<String>> {
             .get("TYPE")
             .with_context(|| format!("device in hierarchy of {device} missing TYPE"))?;
         if kind == "disk" {
-            parents.push(name.clone());
+            // A logical volume on multiple partitions of a disk lists the disk repeatedly
+            if !parents.contains(name) {
+                parents.push(name.clone());
+            }
         } else if kind == "mpath" {
             parents.push(name.clone());
             // we don't need to know what disks back the multipath
@@ -173,14 +370,46 @@ pub(crate) fn find_parent_devices(device: &str) -> Result<Vec<String>> {
     Ok(parents)
 }
 
+/// The prefix of the device-mapper UUID of a multipath device; partitions on it have
+/// e.g. `part1-mpath-` instead.
+const MULTIPATH_DM_UUID_PREFIX: &str = "mpath-";
+
+/// Whether the block device with the provided kernel name (e.g. `dm-0`) is a multipath
+/// device, via the provided `/sys`.
+fn is_multipath_in(sysfs: &Dir, name: &str) -> Result<bool> {
+    let path = format!("class/block/{name}/dm/uuid");
+    let uuid = if let Some(mut f) = sysfs.open_optional(&path)? {
+        let mut buf = String::new();
+        std::io::Read::read_to_string(&mut f, &mut buf)
+            .with_context(|| format!("Reading {path}"))?;
+        buf
+    } else {
+        return Ok(false);
+    };
+    Ok(uuid.trim().starts_with(MULTIPATH_DM_UUID_PREFIX))
+}
+
+/// Whether the provided block device is a multipath device.
+#[context("Checking whether {device} is multipath")]
+pub(crate) fn is_multipath(device: &str) -> Result<bool> {
+    let device = Utf8Path::new(device).canonicalize_utf8()?;
+    let name = device
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid device {device}"))?;
+    let sysfs = Dir::open_ambient_dir("/sys", cap_std::ambient_authority())?;
+    is_multipath_in(&sysfs, name)
+}
+
 // create unsafe ioctl wrappers
 #[allow(clippy::missing_safety_doc)]
 mod ioctl {
     use libc::c_int;
-    use nix::{ioctl_none, ioctl_read, ioctl_read_bad, libc, request_code_none};
+    use nix::{ioctl_none, ioctl_read_bad, libc, request_code_none};
     ioctl_none!(blkrrpart, 0x12, 95);
     ioctl_read_bad!(blksszget, request_code_none!(0x12, 104), c_int);
-    ioctl_read!(blkgetsize64, 0x12, 114, libc::size_t);
+    /// The size of a block device in bytes
+    pub(super) const BLKGETSIZE64: libc::Ioctl =
+        nix::request_code_read!(0x12, 114, std::mem::size_of::<usize>()) as libc::Ioctl;
 }
 
 /// Parse a string into mibibytes
@@ -224,3 +453,167 @@ fn test_parse_size_mib() {
         assert_eq!(parse_size_mib(&s).unwrap(), v as u64, "Parsing {s}");
     }
 }
+
+#[test]
+fn test_settle_until() {
+    let paths = ["/dev/vda3", "/dev/vda4"].map(Utf8PathBuf::from);
+    let mut settles = 0;
+    // The second device only shows up after the third settle
+    let mut checks = 0;
+    let retries = settle_until(
+        || {
+            settles += 1;
+            Ok(())
+        },
+        |p| {
+            checks += 1;
+            p.as_str() != "/dev/vda4" || checks > 4
+        },
+        &paths,
+        Duration::from_secs(60),
+        Duration::ZERO,
+    )
+    .unwrap();
+    assert_eq!(settles, 3);
+    assert_eq!(retries, 2);
+
+    // A device that never appears times out with a useful error
+    let e = settle_until(
+        || Ok(()),
+        |p| p.as_str() != "/dev/vda4",
+        &paths,
+        Duration::ZERO,
+        Duration::ZERO,
+    )
+    .unwrap_err();
+    assert!(e.to_string().contains("Timed out after 0s"));
+    assert!(e.to_string().contains("/dev/vda4"));
+    assert!(!e.to_string().contains("/dev/vda3"));
+
+    // Errors from settle itself are propagated
+    settle_until(
+        || anyhow::bail!("udevadm failed"),
+        |_| true,
+        &paths,
+        Duration::from_secs(60),
+        Duration::ZERO,
+    )
+    .unwrap_err();
+}
+
+#[test]
+fn test_parse_sfdisk() {
+    let stdout = br#"{
+   "partitiontable": {
+      "label": "gpt",
+      "id": "1F5B3C8E-6A0D-4D1B-9A2C-3E4F5A6B7C8D",
+      "device": "/dev/nvme0n1",
+      "unit": "sectors",
+      "first-lba": 34,
+      "last-lba": 41943006,
+      "sectorsize": 512,
+      "partitions": [
+         {
+            "node": "/dev/nvme0n1p1",
+            "start": 2048,
+            "size": 2048,
+            "type": "21686148-6449-6E6F-744E-656564454649",
+            "uuid": "6B1F2C3D-4E5F-4A6B-8C7D-9E0F1A2B3C4D",
+            "name": "BIOS-BOOT"
+         },{
+            "node": "/dev/nvme0n1p12",
+            "start": 4096,
+            "size": 1048576,
+            "type": "0FC63DAF-8483-4772-8E79-3D69D8477DE4"
+         }
+      ]
+   }
+}"#;
+    let table = parse_sfdisk(stdout).unwrap();
+    assert_eq!(table.label, "gpt");
+    assert_eq!(table.first_lba, Some(34));
+    assert_eq!(table.last_lba, Some(41943006));
+    assert_eq!(table.partitions[1].start, 4096);
+    let numbers = table
+        .partitions
+        .iter()
+        .map(|p| p.number().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(numbers, [1, 12]);
+    assert_eq!(table.partitions[0].name.as_deref(), Some("BIOS-BOOT"));
+    assert!(table.partitions[1].uuid.is_none());
+    assert!(parse_sfdisk(b"{}").is_err());
+}
+
+#[test]
+fn test_parse_parent_devices() {
+    // A partition on a multipath device, with two paths
+    let output = r#"NAME="/dev/mapper/mpatha3" TYPE="part"
+NAME="/dev/mapper/mpatha" TYPE="mpath"
+NAME="/dev/sda" TYPE="disk"
+NAME="/dev/sdb" TYPE="disk"
+"#;
+    assert_eq!(
+        parse_parent_devices("/dev/mapper/mpatha3", output).unwrap(),
+        ["/dev/mapper/mpatha"]
+    );
+    // The multipath device itself is the backing device, not its paths
+    let output = r#"NAME="/dev/mapper/mpatha" TYPE="mpath"
+NAME="/dev/sda" TYPE="disk"
+NAME="/dev/sdb" TYPE="disk"
+"#;
+    assert!(parse_parent_devices("/dev/mapper/mpatha", output)
+        .unwrap()
+        .is_empty());
+    // A logical volume spanning two disks is ambiguous
+    let output = r#"NAME="/dev/mapper/vg-root" TYPE="lvm"
+NAME="/dev/vda2" TYPE="part"
+NAME="/dev/vda" TYPE="disk"
+NAME="/dev/vdb" TYPE="disk"
+"#;
+    assert_eq!(
+        parse_parent_devices("/dev/mapper/vg-root", output).unwrap(),
+        ["/dev/vda", "/dev/vdb"]
+    );
+    // A logical volume on two partitions of the same disk
+    let output = r#"NAME="/dev/mapper/vg-root" TYPE="lvm"
+NAME="/dev/vda3" TYPE="part"
+NAME="/dev/vda" TYPE="disk"
+NAME="/dev/vda4" TYPE="part"
+NAME="/dev/vda" TYPE="disk"
+"#;
+    assert_eq!(
+        parse_parent_devices("/dev/mapper/vg-root", output).unwrap(),
+        ["/dev/vda"]
+    );
+    let output = "NAME=\"/dev/vda\" TYPE=\"disk\"\n";
+    assert!(parse_parent_devices("/dev/vda", output).unwrap().is_empty());
+    let output = "NAME=\"/dev/vda1\" TYPE=\"part\"\nNAME=\"/dev/vda\"\n";
+    assert!(parse_parent_devices("/dev/vda1", output).is_err());
+}
+
+#[test]
+fn test_is_multipath() {
+    let sysfs = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    for (name, uuid) in [
+        ("dm-0", "mpath-3600508b400105e210000900000490000"),
+        ("dm-1", "part3-mpath-3600508b400105e210000900000490000"),
+        (
+            "dm-2",
+            "LVM-Qx1yZ2Wk9pVbVbVbVbVbVbVbVbVbVbVbVbVbVbVbVbVbVbVbVbVbVbVbVbVbVbVb",
+        ),
+    ] {
+        sysfs
+            .create_dir_all(format!("class/block/{name}/dm"))
+            .unwrap();
+        sysfs
+            .write(format!("class/block/{name}/dm/uuid"), format!("{uuid}\n"))
+            .unwrap();
+    }
+    sysfs.create_dir_all("class/block/sda").unwrap();
+    assert!(is_multipath_in(&sysfs, "dm-0").unwrap());
+    // A partition on a multipath device
+    assert!(!is_multipath_in(&sysfs, "dm-1").unwrap());
+    assert!(!is_multipath_in(&sysfs, "dm-2").unwrap());
+    assert!(!is_multipath_in(&sysfs, "sda").unwrap());
+}
@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+use std::io::Read;
 use std::os::unix::prelude::PermissionsExt;
 
 use anyhow::{Context, Result};
@@ -6,17 +8,116 @@ use cap_std::fs::Dir;
 use cap_std::fs::Permissions;
 use cap_std_ext::cap_std;
 use cap_std_ext::prelude::*;
+use cap_std_ext::rustix::fs::MetadataExt;
 use fn_error_context::context;
+use serde::{Deserialize, Serialize};
 
+use crate::install::EspMountpoint;
 use crate::task::Task;
 
 /// This variable is referenced by our GRUB fragment
 pub(crate) const IGNITION_VARIABLE: &str = "$ignition_firstboot";
+/// This variable is set by our GRUB fragment on the first boot only
+pub(crate) const FIRSTBOOT_KARGS_VARIABLE: &str = "$bootc_firstboot_kargs_once";
+/// The GRUB environment block key holding the first boot kernel arguments
+const GRUBENV_FIRSTBOOT_KARGS: &str = "bootc_firstboot_kargs";
+/// The GRUB environment block is always exactly this size
+const GRUBENV_SIZE: usize = 1024;
+/// The custom configuration fragment sourced by our GRUB config
+const GRUB_CUSTOM_CFG: &str = "custom.cfg";
+/// GRUB commands which read another configuration file
+const GRUB_INCLUDE_COMMANDS: &[&str] = &[
+    "source",
+    ".",
+    "configfile",
+    "extract_entries_source",
+    "extract_entries_configfile",
+    "extract_legacy_entries_source",
+    "extract_legacy_entries_configfile",
+    "legacy_source",
+    "legacy_configfile",
+    "syslinux_source",
+    "syslinux_configfile",
+];
+/// The directory holding boot loader spec entries, relative to /boot
+const BLS_ENTRIES: &str = "loader/entries";
+/// The extlinux configuration generated by ostree for U-Boot, relative to /boot
+const EXTLINUX_CONF: &str = "extlinux/extlinux.conf";
+/// The file name of the rescue boot entry
+const RESCUE_ENTRY: &str = "bootc-rescue.conf";
+/// The maximum number of boot attempts with `--boot-counting`
+const BOOT_COUNT_MAX: u32 = 99;
+/// Enabling this unit makes a boot without failed units reach `boot-complete.target`,
+/// which blesses the boot entry
+const BOOT_CHECK_UNIT: &str = "systemd-boot-check-no-failures.service";
 const GRUB_BOOT_UUID_FILE: &str = "bootuuid.cfg";
 const STATIC_GRUB_CFG: &str = include_str!("grub.cfg");
 const STATIC_GRUB_CFG_EFI: &str = include_str!("grub-efi.cfg");
 /// The name of the mountpoint for efi (as a subdirectory of /boot, or at the toplevel)
 pub(crate) const EFI_DIR: &str = "efi";
+/// The state file written by bootupd, relative to the root
+pub(crate) const BOOTUPD_STATE: &str = "boot/bootupd-state.json";
+/// Where bootupd looks for the ESP, relative to the root; the first mountpoint is used
+const BOOTUPD_ESP_MOUNTS: &[EspMountpoint] = &[EspMountpoint::BootEfi, EspMountpoint::Efi];
+
+/// The subset of the bootupd state file we care about.
+#[derive(Debug, Deserialize)]
+struct BootupdState {
+    installed: BTreeMap<String, BootupdInstalledContent>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BootupdInstalledContent {
+    meta: BootupdContentMetadata,
+}
+
+#[derive(Debug, Deserialize)]
+struct BootupdContentMetadata {
+    version: String,
+}
+
+/// A bootloader component installed by bootupd (e.g. BIOS or EFI)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct BootloaderComponent {
+    pub(crate) name: String,
+    pub(crate) version: String,
+}
+
+/// The bootloader components we expect bootupd to install on this architecture.
+const fn expected_bootupd_components() -> &'static [&'static str] {
+    if cfg!(target_arch = "x86_64") {
+        &["BIOS", "EFI"]
+    } else if cfg!(target_arch = "aarch64") {
+        &["EFI"]
+    } else {
+        &[]
+    }
+}
+
+/// Parse the bootupd state file and verify that all of `expected` were installed.
+fn parse_bootupd_state(r: impl Read, expected: &[&str]) -> Result<Vec<BootloaderComponent>> {
+    let state: BootupdState = serde_json::from_reader(r).context("Parsing bootupd state")?;
+    let components = state
+        .installed
+        .into_iter()
+        .map(|(name, v)| BootloaderComponent {
+            name,
+            version: v.meta.version,
+        })
+        .collect::<Vec<_>>();
+    let missing = expected
+        .iter()
+        .filter(|&&e| !components.iter().any(|c| c.name == e))
+        .copied()
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        anyhow::bail!(
+            "bootupd did not install all bootloader components; missing: {}",
+            missing.join(", ")
+        );
+    }
+    Ok(components)
+}
 
 fn install_grub2_efi(efidir: &Dir, uuid: &str) -> Result<()> {
     let mut vendordir = None;
@@ -49,24 +150,663 @@ fn install_grub2_efi(efidir: &Dir, uuid: &str) -> Result<()> {
     Ok(())
 }
 
+/// Generate a GRUB environment block with the provided variables.
+fn grubenv_contents<'a>(vars: impl IntoIterator<Item = (&'a str, &'a str)>) -> Result<String> {
+    let mut r = String::from("# GRUB Environment Block\n");
+    for (k, v) in vars {
+        if v.contains(['\n', '\\']) {
+            anyhow::bail!("Invalid character in GRUB environment value: {v}");
+        }
+        r.push_str(&format!("{k}={v}\n"));
+    }
+    if r.len() > GRUBENV_SIZE {
+        anyhow::bail!("GRUB environment block exceeds {GRUBENV_SIZE} bytes");
+    }
+    // The remainder is padded with `#`
+    r.extend(std::iter::repeat('#').take(GRUBENV_SIZE - r.len()));
+    Ok(r)
+}
+
+/// Write kernel arguments which will be used only on the first boot into the GRUB
+/// environment block; our GRUB config clears them once consumed.
+#[context("Writing first boot kernel arguments")]
+pub(crate) fn write_firstboot_kargs(rootfs: &Utf8Path, kargs: &[String]) -> Result<()> {
+    let grub2 = rootfs.join("boot/grub2");
+    if !grub2.exists() {
+        anyhow::bail!("First boot kernel arguments are only supported with GRUB");
+    }
+    let grub2 = Dir::open_ambient_dir(grub2, cap_std::ambient_authority())?;
+    let kargs = kargs.join(" ");
+    let contents = grubenv_contents([(GRUBENV_FIRSTBOOT_KARGS, kargs.as_str())])?;
+    grub2
+        .atomic_write_with_perms("grubenv", contents, Permissions::from_mode(0o644))
+        .context("Writing grubenv")?;
+    Ok(())
+}
+
+/// Verify that any files included by a GRUB config fragment are inside the GRUB config directory.
+fn validate_grub_fragment(contents: &str) -> Result<()> {
+    for line in contents.lines() {
+        let line = line.trim_start();
+        if line.starts_with('#') {
+            continue;
+        }
+        let mut tokens = line
+            .split(|c: char| c.is_ascii_whitespace() || c == ';')
+            .filter(|t| !t.is_empty());
+        while let Some(token) = tokens.next() {
+            if !GRUB_INCLUDE_COMMANDS.contains(&token) {
+                continue;
+            }
+            let path = tokens
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("Missing path for {token}"))?
+                .trim_matches(|c| c == '"' || c == '\'');
+            let relpath = [
+                "$prefix/",
+                "${prefix}/",
+                "$config_directory/",
+                "${config_directory}/",
+            ]
+            .iter()
+            .find_map(|p| path.strip_prefix(p));
+            let valid = relpath
+                .map(|p| !p.is_empty() && !p.split('/').any(|c| c == ".."))
+                .unwrap_or(false);
+            if !valid {
+                anyhow::bail!(
+                    "Invalid include of {path}: must be relative to $prefix or $config_directory"
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read and validate a GRUB config fragment.
+#[context("Reading GRUB config fragment {path}")]
+pub(crate) fn read_grub_fragment(path: &Utf8Path) -> Result<String> {
+    if !path.is_file() {
+        anyhow::bail!("Not a regular file");
+    }
+    let contents = std::fs::read_to_string(path)?;
+    validate_grub_fragment(&contents)?;
+    Ok(contents)
+}
+
+/// Install a custom fragment which will be sourced by our GRUB config.
+#[context("Installing GRUB config fragment")]
+pub(crate) fn install_grub_fragment(rootfs: &Utf8Path, contents: &str) -> Result<()> {
+    let grub2 = rootfs.join("boot/grub2");
+    if !grub2.exists() {
+        anyhow::bail!("A GRUB config fragment is only supported with GRUB");
+    }
+    let grub2 = Dir::open_ambient_dir(grub2, cap_std::ambient_authority())?;
+    grub2
+        .atomic_write_with_perms(GRUB_CUSTOM_CFG, contents, Permissions::from_mode(0o644))
+        .with_context(|| format!("Writing {GRUB_CUSTOM_CFG}"))?;
+    Ok(())
+}
+
+/// Check that a kernel argument can be added to the `options` of a boot entry: it
+/// must be a single argument, where spaces are only allowed within double quotes.
+pub(crate) fn validate_karg(karg: &str) -> Result<()> {
+    if karg.is_empty() {
+        anyhow::bail!("Invalid empty kernel argument");
+    }
+    if karg.chars().any(|c| c.is_control()) {
+        anyhow::bail!("Invalid control character in kernel argument {karg:?}");
+    }
+    let mut quoted = false;
+    for c in karg.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            ' ' if !quoted => anyhow::bail!("Invalid kernel argument {karg:?}: contains a space"),
+            _ => {}
+        }
+    }
+    if quoted {
+        anyhow::bail!("Invalid kernel argument {karg:?}: unterminated quote");
+    }
+    Ok(())
+}
+
+/// Generate a rescue boot entry from the primary entry; it uses the same kernel and
+/// initramfs, but has additional kernel arguments.  The entry is sorted after the
+/// primary one so that it is not the default.
+fn rescue_entry_contents(primary: &str, kargs: &[String]) -> Result<String> {
+    let kargs = kargs.join(" ");
+    let mut found_options = false;
+    let mut r = String::new();
+    for line in primary.lines() {
+        let (k, v) = line.split_once(' ').unwrap_or((line, ""));
+        match k {
+            "title" => r.push_str(&format!("title {v} (rescue)")),
+            "version" => r.push_str("version 0"),
+            "options" => {
+                found_options = true;
+                r.push_str(&format!("options {v} {kargs}"))
+            }
+            _ => r.push_str(line),
+        }
+        r.push('\n');
+    }
+    if !found_options {
+        anyhow::bail!("No options found in boot entry");
+    }
+    Ok(r)
+}
+
+/// Write a rescue boot entry duplicating the (single) primary entry.  Note that ostree
+/// does not know about this entry, so it will not be preserved across updates.
+#[context("Writing rescue boot entry")]
+pub(crate) fn write_rescue_entry(rootfs: &Utf8Path, kargs: &[String]) -> Result<()> {
+    let entries = Dir::open_ambient_dir(
+        rootfs.join("boot").join(BLS_ENTRIES),
+        cap_std::ambient_authority(),
+    )?;
+    let mut primary = None;
+    for e in entries.entries()? {
+        let e = e?;
+        let name = e.file_name();
+        let name = name
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Invalid non-UTF8 filename: {name:?}"))?;
+        if !name.ends_with(".conf") {
+            continue;
+        }
+        if let Some(prev) = primary.replace(name.to_string()) {
+            anyhow::bail!("Found multiple boot entries: {prev} {name}");
+        }
+    }
+    let primary = primary.ok_or_else(|| anyhow::anyhow!("No boot entry found"))?;
+    let contents = entries.read_to_string(&primary)?;
+    let rescue = rescue_entry_contents(&contents, kargs)?;
+    entries
+        .atomic_write_with_perms(RESCUE_ENTRY, rescue, Permissions::from_mode(0o644))
+        .with_context(|| format!("Writing {RESCUE_ENTRY}"))?;
+    println!("Created rescue boot entry from {primary}");
+    Ok(())
+}
+
+/// The subset of a boot loader specification entry used to verify the boot chain.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct BootEntry {
+    version: Option<String>,
+    linux: Option<String>,
+    initrd: Vec<String>,
+    options: Option<String>,
+}
+
+impl BootEntry {
+    fn parse(contents: &str) -> Self {
+        let mut r = Self::default();
+        for line in contents.lines() {
+            let (k, v) = line.trim().split_once(' ').unwrap_or((line, ""));
+            let v = v.trim().to_string();
+            match k {
+                "version" => r.version = Some(v),
+                "linux" => r.linux = Some(v),
+                "initrd" => r.initrd.push(v),
+                "options" => r.options = Some(v),
+                _ => {}
+            }
+        }
+        r
+    }
+
+    /// The value of the last `root=` kernel argument, if any.
+    fn root(&self) -> Option<&str> {
+        self.options
+            .as_deref()?
+            .split_ascii_whitespace()
+            .filter_map(|k| k.strip_prefix("root="))
+            .next_back()
+    }
+}
+
+/// Whether the provided `root=` kernel argument value names an existing block device.
+pub(crate) fn root_device_exists(spec: &str) -> bool {
+    Utf8Path::new(&crate::mount::source_device_path(spec)).exists()
+}
+
+/// Find the default boot entry in the provided `/boot`, which is the one with the
+/// highest version, returning its file name.
+fn find_default_entry(bootfs: &Dir) -> Result<(String, BootEntry)> {
+    let entries = bootfs
+        .open_dir(BLS_ENTRIES)
+        .with_context(|| format!("Opening {BLS_ENTRIES}"))?;
+    let mut default: Option<(String, BootEntry)> = None;
+    for e in entries.entries()? {
+        let e = e?;
+        let name = e.file_name();
+        let name = name
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Invalid non-UTF8 filename: {name:?}"))?;
+        if !name.ends_with(".conf") {
+            continue;
+        }
+        let entry = BootEntry::parse(&entries.read_to_string(name)?);
+        let version_key = |e: &BootEntry| {
+            let v = e.version.as_deref().unwrap_or_default();
+            (v.parse::<u64>().ok(), v.to_string())
+        };
+        let replace = default
+            .as_ref()
+            .map_or(true, |(_, prev)| version_key(&entry) > version_key(prev));
+        if replace {
+            default = Some((name.to_string(), entry));
+        }
+    }
+    default.ok_or_else(|| anyhow::anyhow!("No boot entries found"))
+}
+
+/// Find a path referenced by a boot entry in the provided `/boot`, returning it
+/// relative to `/boot`.  Paths are relative to the root of the boot filesystem; with
+/// ostree's `sysroot.bootprefix` they're prefixed with /boot.
+fn find_boot_path<'p>(bootfs: &Dir, path: &'p str) -> Result<Option<&'p str>> {
+    let path = path.trim_start_matches('/');
+    if bootfs.try_exists(path)? {
+        return Ok(Some(path));
+    }
+    match path.strip_prefix("boot/") {
+        Some(p) if bootfs.try_exists(p)? => Ok(Some(p)),
+        _ => Ok(None),
+    }
+}
+
+/// Verify that the default boot entry in the provided `/boot` references an existing
+/// kernel and initramfs, and has a `root=` kernel argument for which `resolve_root`
+/// returns true.  The default entry is the one with the highest version.
+#[context("Verifying boot chain")]
+pub(crate) fn verify_boot_chain(bootfs: &Dir, resolve_root: impl Fn(&str) -> bool) -> Result<()> {
+    let (name, entry) = find_default_entry(bootfs)?;
+    let exists = |path: &str| -> Result<bool> { Ok(find_boot_path(bootfs, path)?.is_some()) };
+    let linux = entry
+        .linux
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("No kernel in boot entry {name}"))?;
+    if !exists(linux)? {
+        anyhow::bail!("Kernel {linux} referenced by boot entry {name} not found");
+    }
+    if entry.initrd.is_empty() {
+        anyhow::bail!("No initramfs in boot entry {name}");
+    }
+    for initrd in entry.initrd.iter() {
+        if !exists(initrd)? {
+            anyhow::bail!("Initramfs {initrd} referenced by boot entry {name} not found");
+        }
+    }
+    let root = entry
+        .root()
+        .ok_or_else(|| anyhow::anyhow!("No root= kernel argument in boot entry {name}"))?;
+    if !resolve_root(root) {
+        anyhow::bail!("Root device {root} referenced by boot entry {name} not found");
+    }
+    Ok(())
+}
+
+/// The prefix of paths in boot entries for the provided kernel path: `/boot` with
+/// ostree's `sysroot.bootprefix`, as then `/boot` is not a separate filesystem.
+fn boot_path_prefix(bootfs: &Dir, kernel: &str) -> Result<&'static str> {
+    let kernel = kernel.trim_start_matches('/');
+    match find_boot_path(bootfs, kernel)? {
+        Some(p) if p != kernel => Ok("/boot"),
+        _ => Ok(""),
+    }
+}
+
+/// Set the devicetree of a boot entry, replacing any previous one.
+fn set_entry_devicetree(contents: &str, devicetree: &str) -> String {
+    let mut r = contents
+        .lines()
+        .filter(|l| l.trim().split_once(' ').map(|(k, _)| k) != Some("devicetree"))
+        .map(|l| format!("{l}\n"))
+        .collect::<String>();
+    r.push_str(&format!("devicetree {devicetree}\n"));
+    r
+}
+
+/// Set the devicetree of every label in an extlinux configuration, replacing any
+/// previous one.  `fdt` takes precedence over `fdtdir` in U-Boot.
+fn set_extlinux_devicetree(contents: &str, devicetree: &str) -> String {
+    let mut r = String::new();
+    for line in contents.lines() {
+        let trimmed = line.trim_start();
+        let key = trimmed.split_ascii_whitespace().next().unwrap_or_default();
+        if matches!(key, "fdt" | "devicetree") {
+            continue;
+        }
+        r.push_str(line);
+        r.push('\n');
+        if matches!(key, "kernel" | "linux") {
+            let indent = &line[..line.len() - trimmed.len()];
+            r.push_str(&format!("{indent}fdt {devicetree}\n"));
+        }
+    }
+    r
+}
+
+/// Reference the provided devicetree blob (relative to `/boot`) in the boot entries,
+/// and the extlinux configuration if any.  Like the rescue entry, this is not preserved
+/// across updates.
+#[context("Setting devicetree in boot entries")]
+pub(crate) fn set_devicetree(bootfs: &Dir, dtb: &Utf8Path) -> Result<()> {
+    let entries = bootfs
+        .open_dir(BLS_ENTRIES)
+        .with_context(|| format!("Opening {BLS_ENTRIES}"))?;
+    let mut found = false;
+    for e in entries.entries()? {
+        let e = e?;
+        let name = e.file_name();
+        let name = name
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Invalid non-UTF8 filename: {name:?}"))?;
+        if !name.ends_with(".conf") {
+            continue;
+        }
+        let contents = entries.read_to_string(name)?;
+        let entry = BootEntry::parse(&contents);
+        let kernel = entry
+            .linux
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("No kernel in boot entry {name}"))?;
+        let devicetree = format!("{}/{dtb}", boot_path_prefix(bootfs, kernel)?);
+        entries
+            .atomic_write_with_perms(
+                name,
+                set_entry_devicetree(&contents, &devicetree),
+                Permissions::from_mode(0o644),
+            )
+            .with_context(|| format!("Writing {name}"))?;
+        found = true;
+    }
+    if !found {
+        anyhow::bail!("No boot entries found");
+    }
+    if let Some(contents) = bootfs.open_optional(EXTLINUX_CONF)? {
+        let mut buf = String::new();
+        std::io::BufReader::new(contents).read_to_string(&mut buf)?;
+        let (_, entry) = find_default_entry(bootfs)?;
+        // SAFETY: Checked above
+        let prefix = boot_path_prefix(bootfs, entry.linux.as_deref().unwrap())?;
+        let devicetree = format!("{prefix}/{dtb}");
+        bootfs
+            .atomic_write_with_perms(
+                EXTLINUX_CONF,
+                set_extlinux_devicetree(&buf, &devicetree),
+                Permissions::from_mode(0o644),
+            )
+            .with_context(|| format!("Writing {EXTLINUX_CONF}"))?;
+    }
+    Ok(())
+}
+
+/// Set the kernel and initramfs of a boot entry, or of every label in an extlinux
+/// configuration.  The first initramfs is the kernel's own, and is replaced; any
+/// further ones (e.g. added with `ostree admin deploy --overlay-initrd`) are kept.
+fn set_entry_kernel(contents: &str, kernel: &str, initrd: &str) -> String {
+    let mut r = String::new();
+    // The indentation of the kernel whose initramfs is yet to be replaced
+    let mut pending: Option<&str> = None;
+    for line in contents.lines() {
+        let trimmed = line.trim_start();
+        let indent = &line[..line.len() - trimmed.len()];
+        let key = trimmed.split_ascii_whitespace().next().unwrap_or_default();
+        if matches!(key, "kernel" | "linux" | "label") {
+            if let Some(indent) = pending.take() {
+                r.push_str(&format!("{indent}initrd {initrd}\n"));
+            }
+        }
+        match key {
+            "initrd" if pending.is_some() => {
+                pending = None;
+                r.push_str(&format!("{indent}initrd {initrd}\n"));
+            }
+            "kernel" | "linux" => {
+                pending = Some(indent);
+                r.push_str(&format!("{indent}{key} {kernel}\n"));
+            }
+            _ => {
+                r.push_str(line);
+                r.push('\n');
+            }
+        }
+    }
+    if let Some(indent) = pending {
+        r.push_str(&format!("{indent}initrd {initrd}\n"));
+    }
+    r
+}
+
+/// Boot the provided kernel and initramfs (relative to `/boot`) from the boot entries,
+/// and the extlinux configuration if any.  Like the rescue entry, this is not preserved
+/// across updates.
+#[context("Setting kernel in boot entries")]
+pub(crate) fn set_kernel(bootfs: &Dir, kernel: &Utf8Path, initrd: &Utf8Path) -> Result<()> {
+    let (_, entry) = find_default_entry(bootfs)?;
+    let linux = entry
+        .linux
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("No kernel in default boot entry"))?;
+    let prefix = boot_path_prefix(bootfs, linux)?;
+    let kernel = format!("{prefix}/{kernel}");
+    let initrd = format!("{prefix}/{initrd}");
+    let entries = bootfs
+        .open_dir(BLS_ENTRIES)
+        .with_context(|| format!("Opening {BLS_ENTRIES}"))?;
+    for e in entries.entries()? {
+        let e = e?;
+        let name = e.file_name();
+        let name = name
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Invalid non-UTF8 filename: {name:?}"))?;
+        if !name.ends_with(".conf") {
+            continue;
+        }
+        let contents = entries.read_to_string(name)?;
+        entries
+            .atomic_write_with_perms(
+                name,
+                set_entry_kernel(&contents, &kernel, &initrd),
+                Permissions::from_mode(0o644),
+            )
+            .with_context(|| format!("Writing {name}"))?;
+    }
+    if let Some(contents) = bootfs.open_optional(EXTLINUX_CONF)? {
+        let mut buf = String::new();
+        std::io::BufReader::new(contents).read_to_string(&mut buf)?;
+        bootfs
+            .atomic_write_with_perms(
+                EXTLINUX_CONF,
+                set_entry_kernel(&buf, &kernel, &initrd),
+                Permissions::from_mode(0o644),
+            )
+            .with_context(|| format!("Writing {EXTLINUX_CONF}"))?;
+    }
+    Ok(())
+}
+
+/// Check the number of boot attempts for `--boot-counting`.
+pub(crate) fn validate_boot_count(tries: u32) -> Result<()> {
+    if !(1..=BOOT_COUNT_MAX).contains(&tries) {
+        anyhow::bail!("Invalid boot count {tries}: must be between 1 and {BOOT_COUNT_MAX}");
+    }
+    Ok(())
+}
+
+/// The name of the boot entry with the provided name and a boot counter of `tries`
+/// attempts, replacing any previous counter.  See the Automatic Boot Assessment
+/// specification for the `+LEFT[-DONE]` suffix.
+fn counted_entry_name(name: &str, tries: u32) -> Result<String> {
+    let stem = name
+        .strip_suffix(".conf")
+        .ok_or_else(|| anyhow::anyhow!("Invalid boot entry name {name}"))?;
+    let is_counter = |c: &str| {
+        !c.is_empty()
+            && c.split('-').count() <= 2
+            && c.split('-')
+                .all(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+    };
+    let stem = match stem.rsplit_once('+') {
+        Some((s, c)) if is_counter(c) => s,
+        _ => stem,
+    };
+    Ok(format!("{stem}+{tries}.conf"))
+}
+
+/// Add a boot counter of `tries` attempts to the default boot entry in the provided
+/// `/boot`, returning its new name; if the deployment does not reach
+/// `boot-complete.target` within that many boots, a bootloader implementing boot
+/// counting (such as systemd-boot) falls back to another entry.  Like the rescue
+/// entry, this is not preserved across updates.
+#[context("Enabling boot counting")]
+pub(crate) fn set_boot_counting(bootfs: &Dir, tries: u32) -> Result<String> {
+    validate_boot_count(tries)?;
+    if bootfs.try_exists("grub2")? {
+        anyhow::bail!("Boot counting requires systemd-boot; GRUB ignores boot counters");
+    }
+    let (name, _) = find_default_entry(bootfs)?;
+    let counted = counted_entry_name(&name, tries)?;
+    let entries = bootfs
+        .open_dir(BLS_ENTRIES)
+        .with_context(|| format!("Opening {BLS_ENTRIES}"))?;
+    entries
+        .rename(&name, &entries, &counted)
+        .with_context(|| format!("Renaming {name} to {counted}"))?;
+    Ok(counted)
+}
+
+/// Enable the unit which marks a boot as successful in the provided deployment,
+/// returning the link relative to the deployment.  systemd-bless-boot, which then
+/// resets the boot counter, is enabled by its generator when booting a counted entry.
+#[context("Enabling boot assessment")]
+pub(crate) fn enable_boot_assessment(deployment: &Dir) -> Result<String> {
+    let unit = format!("usr/lib/systemd/system/{BOOT_CHECK_UNIT}");
+    if !deployment.try_exists(&unit)? {
+        anyhow::bail!("The image lacks /{unit}, which boot counting requires");
+    }
+    let dir = "etc/systemd/system/boot-complete.target.requires";
+    deployment.create_dir_all(dir)?;
+    let link = format!("{dir}/{BOOT_CHECK_UNIT}");
+    if deployment.symlink_metadata_optional(&link)?.is_none() {
+        deployment
+            .symlink(format!("../../../../{unit}"), &link)
+            .with_context(|| format!("Creating {link}"))?;
+    }
+    Ok(link)
+}
+
+/// What the default boot entry boots, as needed to load it for kexec.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct BootTarget {
+    /// The kernel, relative to `/boot`
+    pub(crate) kernel: String,
+    /// The initramfs, relative to `/boot`
+    pub(crate) initrd: Option<String>,
+    /// The kernel arguments
+    pub(crate) options: String,
+}
+
+/// Find what the default boot entry in the provided `/boot` boots.  Only a single
+/// initramfs is supported.
+#[context("Finding default boot entry")]
+pub(crate) fn default_boot_target(bootfs: &Dir) -> Result<BootTarget> {
+    let (name, entry) = find_default_entry(bootfs)?;
+    let find = |path: &str| -> Result<String> {
+        let found = find_boot_path(bootfs, path)?
+            .ok_or_else(|| anyhow::anyhow!("{path} referenced by boot entry {name} not found"))?;
+        Ok(found.to_string())
+    };
+    let kernel = entry
+        .linux
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("No kernel in boot entry {name}"))?;
+    let initrd = match entry.initrd.as_slice() {
+        [] => None,
+        [initrd] => Some(find(initrd)?),
+        _ => anyhow::bail!("Multiple initramfs images in boot entry {name}"),
+    };
+    Ok(BootTarget {
+        kernel: find(kernel)?,
+        initrd,
+        options: entry.options.unwrap_or_default(),
+    })
+}
+
+/// Compute the arguments for `bootupctl` to install the bootloader to the root
+/// filesystem at `rootfs`, with the ESP mounted at `esp`.  bootupd has no option for
+/// the location of the ESP, instead using the first of [`BOOTUPD_ESP_MOUNTS`] which
+/// is a mountpoint according to `is_mountpoint`; so verify that's the intended one.
+fn bootupd_install_args(
+    rootfs: &Utf8Path,
+    esp: EspMountpoint,
+    is_mountpoint: impl Fn(EspMountpoint) -> Result<bool>,
+) -> Result<Vec<String>> {
+    for &candidate in BOOTUPD_ESP_MOUNTS {
+        if candidate == esp {
+            break;
+        }
+        if is_mountpoint(candidate)? {
+            anyhow::bail!(
+                "Found a filesystem mounted at {}, which bootupd would use as the ESP instead of {}",
+                candidate.path(),
+                esp.path()
+            );
+        }
+    }
+    if !is_mountpoint(esp)? {
+        tracing::debug!("No ESP mounted at {}", esp.path());
+    }
+    let args = ["backend", "install", "--src-root", "/", rootfs.as_str()];
+    Ok(args.into_iter().map(ToOwned::to_owned).collect())
+}
+
 #[context("Installing bootloader")]
 pub(crate) fn install_via_bootupd(
     device: &Utf8Path,
     rootfs: &Utf8Path,
+    esp: EspMountpoint,
     boot_uuid: &str,
-) -> Result<()> {
-    Task::new_and_run(
-        "Running bootupctl to install bootloader",
-        "bootupctl",
-        ["backend", "install", "--src-root", "/", rootfs.as_str()],
-    )?;
+) -> Result<Vec<BootloaderComponent>> {
+    let rootfs_fd = Dir::open_ambient_dir(rootfs, cap_std::ambient_authority())?;
+    let is_mountpoint = |mnt: EspMountpoint| -> Result<bool> {
+        let path = Utf8Path::new(mnt.relpath());
+        // SAFETY: The ESP mountpoints have a parent
+        let parent = path.parent().unwrap();
+        let parent = if parent.as_str().is_empty() {
+            rootfs_fd.dir_metadata()?
+        } else if let Some(m) = rootfs_fd.symlink_metadata_optional(parent)? {
+            m
+        } else {
+            return Ok(false);
+        };
+        Ok(rootfs_fd
+            .symlink_metadata_optional(path)?
+            .map_or(false, |m| m.is_dir() && m.dev() != parent.dev()))
+    };
+    let args = bootupd_install_args(rootfs, esp, is_mountpoint)?;
+    let output = Task::new("Running bootupctl to install bootloader", "bootupctl")
+        .args(args)
+        .read()?;
+    tracing::debug!("bootupctl: {output}");
+    let components = {
+        let f = std::fs::File::open(rootfs.join(BOOTUPD_STATE))
+            .with_context(|| format!("Opening {BOOTUPD_STATE}"))?;
+        parse_bootupd_state(std::io::BufReader::new(f), expected_bootupd_components())?
+    };
+    for c in components.iter() {
+        println!("Installed bootloader component {}: {}", c.name, c.version);
+    }
 
     let grub2_uuid_contents = format!("set BOOT_UUID=\"{boot_uuid}\"\n");
 
     let bootfs = &rootfs.join("boot");
 
     {
-        let efidir = Dir::open_ambient_dir(bootfs.join("efi"), cap_std::ambient_authority())?;
+        let efidir = rootfs_fd
+            .open_dir(esp.relpath())
+            .with_context(|| format!("Opening {}", esp.path()))?;
         install_grub2_efi(&efidir, &grub2_uuid_contents)?;
     }
 
@@ -103,5 +843,501 @@ pub(crate) fn install_via_bootupd(
         ])
         .run()?;
 
-    Ok(())
+    Ok(components)
+}
+
+#[test]
+fn test_bootupd_install_args() {
+    let rootfs = Utf8Path::new("/run/target");
+    let expected = ["backend", "install", "--src-root", "/", "/run/target"];
+    for esp in [EspMountpoint::BootEfi, EspMountpoint::Efi] {
+        let args = bootupd_install_args(rootfs, esp, |m| Ok(m == esp)).unwrap();
+        assert_eq!(args, expected);
+        // Nothing mounted yet; bootupd will fail to find an ESP if one is required
+        let args = bootupd_install_args(rootfs, esp, |_| Ok(false)).unwrap();
+        assert_eq!(args, expected);
+    }
+    // bootupd checks /boot/efi first
+    let args = bootupd_install_args(rootfs, EspMountpoint::BootEfi, |_| Ok(true)).unwrap();
+    assert_eq!(args, expected);
+    let e = bootupd_install_args(rootfs, EspMountpoint::Efi, |_| Ok(true)).unwrap_err();
+    assert!(e.to_string().contains("mounted at /boot/efi"), "{e}");
+    assert!(bootupd_install_args(rootfs, EspMountpoint::Efi, |_| anyhow::bail!("oops")).is_err());
+}
+
+#[test]
+fn test_parse_bootupd_state() {
+    let state = r#"{
+  "installed": {
+    "BIOS": {
+      "meta": { "timestamp": "2022-10-28T09:17:24Z", "version": "grub2-tools-1:2.06-58.fc37.x86_64" },
+      "filetree": null,
+      "adopted-from": null
+    },
+    "EFI": {
+      "meta": { "timestamp": "2022-10-28T09:17:24Z", "version": "grub2-efi-x64-1:2.06-58.fc37.x86_64,shim-x64-15.6-2.x86_64" },
+      "filetree": { "timestamp": "2022-10-28T09:17:24Z", "children": {} },
+      "adopted-from": null
+    }
+  },
+  "pending": null
+}"#;
+    let components = parse_bootupd_state(state.as_bytes(), &["BIOS", "EFI"]).unwrap();
+    assert_eq!(
+        components,
+        [
+            BootloaderComponent {
+                name: "BIOS".into(),
+                version: "grub2-tools-1:2.06-58.fc37.x86_64".into()
+            },
+            BootloaderComponent {
+                name: "EFI".into(),
+                version: "grub2-efi-x64-1:2.06-58.fc37.x86_64,shim-x64-15.6-2.x86_64".into()
+            }
+        ]
+    );
+    let e = parse_bootupd_state(state.as_bytes(), &["BIOS", "EFI", "PReP"]).unwrap_err();
+    assert!(e.to_string().contains("missing: PReP"));
+    assert!(parse_bootupd_state(&b"{}"[..], &[]).is_err());
+}
+
+#[test]
+fn test_grubenv_contents() {
+    let kargs = ["systemd.unit=provision.target", "rd.debug"].join(" ");
+    let env = grubenv_contents([(GRUBENV_FIRSTBOOT_KARGS, kargs.as_str())]).unwrap();
+    assert_eq!(env.len(), GRUBENV_SIZE);
+    let expected_prefix = "# GRUB Environment Block\n\
+        bootc_firstboot_kargs=systemd.unit=provision.target rd.debug\n#";
+    assert!(env.starts_with(expected_prefix));
+    assert!(env[expected_prefix.len()..].chars().all(|c| c == '#'));
+    // The persistent kernel arguments only reference the one-shot variable
+    assert!(FIRSTBOOT_KARGS_VARIABLE.starts_with('$'));
+    assert!(STATIC_GRUB_CFG.contains(&FIRSTBOOT_KARGS_VARIABLE[1..]));
+    assert!(STATIC_GRUB_CFG.contains(GRUBENV_FIRSTBOOT_KARGS));
+
+    assert!(grubenv_contents([("k", "a\nb")]).is_err());
+    let long = "x".repeat(GRUBENV_SIZE);
+    assert!(grubenv_contents([("k", long.as_str())]).is_err());
+}
+
+#[test]
+fn test_rescue_entry_contents() {
+    let primary = "title Fedora Linux 37.20221127.3.0 (CoreOS) (ostree:0)
+version 1
+options root=UUID=4d8e7a5b rw boot=UUID=0e2d7a53 ostree=/ostree/boot.1/default/5e0b/0
+linux /ostree/default-5e0b/vmlinuz-6.0.9-300.fc37.x86_64
+initrd /ostree/default-5e0b/initramfs-6.0.9-300.fc37.x86_64.img
+";
+    let kargs = ["systemd.unit=rescue.target", "rd.debug"].map(String::from);
+    assert_eq!(
+        rescue_entry_contents(primary, &kargs).unwrap(),
+        "title Fedora Linux 37.20221127.3.0 (CoreOS) (ostree:0) (rescue)
+version 0
+options root=UUID=4d8e7a5b rw boot=UUID=0e2d7a53 ostree=/ostree/boot.1/default/5e0b/0 systemd.unit=rescue.target rd.debug
+linux /ostree/default-5e0b/vmlinuz-6.0.9-300.fc37.x86_64
+initrd /ostree/default-5e0b/initramfs-6.0.9-300.fc37.x86_64.img
+"
+    );
+    assert!(rescue_entry_contents("title foo\nlinux /vmlinuz\n", &kargs).is_err());
+}
+
+#[test]
+fn test_validate_karg() {
+    for valid in ["single", "nomodeset", "dyndbg=\"file drm* +p\"", "a=\"\""] {
+        validate_karg(valid).unwrap();
+    }
+    for invalid in ["", "single nomodeset", "a=\"b", "a=b\ninitrd /evil", "\t"] {
+        assert!(validate_karg(invalid).is_err(), "{invalid:?}");
+    }
+}
+
+#[test]
+fn test_write_rescue_entry() {
+    let td = tempfile::tempdir().unwrap();
+    let rootfs = Utf8Path::from_path(td.path()).unwrap();
+    let entries = rootfs.join("boot").join(BLS_ENTRIES);
+    std::fs::create_dir_all(&entries).unwrap();
+    let primary = "title Fedora Linux 37 (ostree:0)
+version 1
+options root=UUID=4d8e7a5b rw ostree=/ostree/boot.1/default/5e0b/0
+linux /ostree/default-5e0b/vmlinuz
+";
+    std::fs::write(entries.join("ostree-1-default.conf"), primary).unwrap();
+    let kargs = ["single", "nomodeset"].map(String::from);
+    write_rescue_entry(rootfs, &kargs).unwrap();
+    let options = |name: &str| {
+        let contents = std::fs::read_to_string(entries.join(name)).unwrap();
+        let line = contents
+            .lines()
+            .find(|l| l.starts_with("options "))
+            .unwrap();
+        line.to_string()
+    };
+    // Only the rescue entry has the additional arguments
+    assert_eq!(
+        options("ostree-1-default.conf"),
+        "options root=UUID=4d8e7a5b rw ostree=/ostree/boot.1/default/5e0b/0"
+    );
+    assert_eq!(
+        options(RESCUE_ENTRY),
+        "options root=UUID=4d8e7a5b rw ostree=/ostree/boot.1/default/5e0b/0 single nomodeset"
+    );
+}
+
+#[test]
+fn test_grub_fragment() {
+    let valid = [
+        "",
+        "menuentry 'Firmware setup' { fwsetup }\n",
+        "# source /etc/grub.cfg\nset theme=$prefix/themes/foo/theme.txt\n",
+        "source $prefix/extra.cfg\n",
+        "if [ -f ${config_directory}/a.cfg ]; then source \"${config_directory}/a.cfg\"; fi\n",
+        ". $prefix/themes/foo/vars.cfg",
+    ];
+    for v in valid {
+        validate_grub_fragment(v).unwrap();
+    }
+    let invalid = [
+        "source /etc/grub.cfg",
+        "configfile (hd0,gpt2)/grub.cfg",
+        "set x=1; source $root/grub.cfg",
+        "source $prefix/../../etc/grub.cfg",
+        "source $prefix/",
+        "  . /boot/foo.cfg",
+        "source",
+    ];
+    for v in invalid {
+        assert!(validate_grub_fragment(v).is_err(), "{v}");
+    }
+
+    let td = tempfile::tempdir().unwrap();
+    let td = Utf8Path::from_path(td.path()).unwrap();
+    let fragment = td.join("fragment.cfg");
+    let contents = "menuentry 'Firmware setup' { fwsetup }\n";
+    std::fs::write(&fragment, contents).unwrap();
+    assert_eq!(read_grub_fragment(&fragment).unwrap(), contents);
+    assert!(read_grub_fragment(td).is_err());
+    assert!(read_grub_fragment(&td.join("nonexistent")).is_err());
+    std::fs::write(&fragment, "source /etc/grub.cfg\n").unwrap();
+    assert!(read_grub_fragment(&fragment).is_err());
+
+    let rootfs = td.join("rootfs");
+    assert!(install_grub_fragment(&rootfs, contents).is_err());
+    std::fs::create_dir_all(rootfs.join("boot/grub2")).unwrap();
+    install_grub_fragment(&rootfs, contents).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(rootfs.join("boot/grub2/custom.cfg")).unwrap(),
+        contents
+    );
+}
+
+#[test]
+fn test_verify_boot_chain() {
+    let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    let resolve = |root: &str| root == "UUID=4d8e7a5b";
+    // No entries directory
+    assert!(verify_boot_chain(&td, resolve).is_err());
+    td.create_dir_all(BLS_ENTRIES).unwrap();
+    assert!(verify_boot_chain(&td, resolve).is_err());
+
+    let entry = |version: u32, root: &str| {
+        format!(
+            "title Fedora Linux 37 (ostree:0)
+version {version}
+options root={root} rw ostree=/ostree/boot.1/default/5e0b/0
+linux /ostree/default-5e0b/vmlinuz-6.0.9-300.fc37.x86_64
+initrd /ostree/default-5e0b/initramfs-6.0.9-300.fc37.x86_64.img
+"
+        )
+    };
+    let entries = td.open_dir(BLS_ENTRIES).unwrap();
+    entries
+        .write("ostree-1-default.conf", entry(1, "UUID=4d8e7a5b"))
+        .unwrap();
+    // The kernel and initramfs are missing
+    assert!(verify_boot_chain(&td, resolve).is_err());
+    td.create_dir_all("ostree/default-5e0b").unwrap();
+    td.write("ostree/default-5e0b/vmlinuz-6.0.9-300.fc37.x86_64", "")
+        .unwrap();
+    assert!(verify_boot_chain(&td, resolve).is_err());
+    td.write(
+        "ostree/default-5e0b/initramfs-6.0.9-300.fc37.x86_64.img",
+        "",
+    )
+    .unwrap();
+    verify_boot_chain(&td, resolve).unwrap();
+    // A lower versioned entry (e.g. the rescue entry) doesn't matter
+    entries
+        .write(RESCUE_ENTRY, entry(0, "UUID=missing"))
+        .unwrap();
+    verify_boot_chain(&td, resolve).unwrap();
+    // But the default entry's root must be resolvable
+    entries
+        .write("ostree-2-default.conf", entry(2, "UUID=missing"))
+        .unwrap();
+    assert!(verify_boot_chain(&td, resolve).is_err());
+    entries
+        .write(
+            "ostree-2-default.conf",
+            entry(2, "UUID=4d8e7a5b").replace("options root=UUID=4d8e7a5b ", "options "),
+        )
+        .unwrap();
+    assert!(verify_boot_chain(&td, resolve).is_err());
+    entries
+        .write("ostree-2-default.conf", entry(2, "UUID=4d8e7a5b"))
+        .unwrap();
+    verify_boot_chain(&td, resolve).unwrap();
+
+    // Paths with ostree's /boot prefix
+    let prefixed = entry(3, "UUID=4d8e7a5b").replace(" /ostree/default", " /boot/ostree/default");
+    entries.write("ostree-3-default.conf", prefixed).unwrap();
+    verify_boot_chain(&td, resolve).unwrap();
+}
+
+#[test]
+fn test_default_boot_target() {
+    let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    td.create_dir_all(BLS_ENTRIES).unwrap();
+    td.create_dir_all("ostree/default-5e0b").unwrap();
+    let kernel = "ostree/default-5e0b/vmlinuz-6.0.9-300.fc37.x86_64";
+    let initrd = "ostree/default-5e0b/initramfs-6.0.9-300.fc37.x86_64.img";
+    td.write(kernel, "").unwrap();
+    let entries = td.open_dir(BLS_ENTRIES).unwrap();
+    let options = "root=UUID=4d8e7a5b rw ostree=/ostree/boot.1/default/5e0b/0 console=ttyS0";
+    let entry =
+        format!("version 1\noptions {options}\nlinux /boot/{kernel}\ninitrd /boot/{initrd}\n");
+    entries.write("ostree-1-default.conf", &entry).unwrap();
+    // The initramfs is missing
+    assert!(default_boot_target(&td).is_err());
+    td.write(initrd, "").unwrap();
+    assert_eq!(
+        default_boot_target(&td).unwrap(),
+        BootTarget {
+            kernel: kernel.into(),
+            initrd: Some(initrd.into()),
+            options: options.into(),
+        }
+    );
+    // The rescue entry is not the default
+    entries
+        .write(RESCUE_ENTRY, "version 0\nlinux /missing\n")
+        .unwrap();
+    assert_eq!(default_boot_target(&td).unwrap().kernel, kernel);
+
+    let entry = format!("version 2\nlinux /{kernel}\ninitrd /{initrd}\ninitrd /{initrd}\n");
+    entries.write("ostree-2-default.conf", &entry).unwrap();
+    assert!(default_boot_target(&td).is_err());
+    let entry = format!("version 2\nlinux /{kernel}\n");
+    entries.write("ostree-2-default.conf", &entry).unwrap();
+    assert_eq!(
+        default_boot_target(&td).unwrap(),
+        BootTarget {
+            kernel: kernel.into(),
+            initrd: None,
+            options: String::new(),
+        }
+    );
+}
+
+#[test]
+fn test_set_devicetree() {
+    let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    let dtb = Utf8Path::new("bootc-dtb/rk3399-rockpro64.dtb");
+    assert!(set_devicetree(&td, dtb).is_err());
+    td.create_dir_all(BLS_ENTRIES).unwrap();
+    assert!(set_devicetree(&td, dtb).is_err());
+    td.create_dir_all("ostree/default-5e0b").unwrap();
+    td.write("ostree/default-5e0b/vmlinuz-6.2.9-300.fc38.aarch64", "")
+        .unwrap();
+    let entries = td.open_dir(BLS_ENTRIES).unwrap();
+    let entry = "title Fedora Linux 38 (ostree:0)
+version 1
+options root=UUID=4d8e7a5b rw ostree=/ostree/boot.1/default/5e0b/0
+linux /ostree/default-5e0b/vmlinuz-6.2.9-300.fc38.aarch64
+devicetree /ostree/default-5e0b/dtb/old.dtb
+";
+    entries.write("ostree-1-default.conf", entry).unwrap();
+    let extlinux = "# Generated by ostree
+
+label Fedora Linux 38 (ostree:0)
+\tkernel /ostree/default-5e0b/vmlinuz-6.2.9-300.fc38.aarch64
+\tappend root=UUID=4d8e7a5b rw ostree=/ostree/boot.1/default/5e0b/0
+\tfdtdir /ostree/default-5e0b/dtb
+";
+    td.create_dir_all("extlinux").unwrap();
+    td.write(EXTLINUX_CONF, extlinux).unwrap();
+    set_devicetree(&td, dtb).unwrap();
+    assert_eq!(
+        entries.read_to_string("ostree-1-default.conf").unwrap(),
+        "title Fedora Linux 38 (ostree:0)
+version 1
+options root=UUID=4d8e7a5b rw ostree=/ostree/boot.1/default/5e0b/0
+linux /ostree/default-5e0b/vmlinuz-6.2.9-300.fc38.aarch64
+devicetree /bootc-dtb/rk3399-rockpro64.dtb
+"
+    );
+    assert_eq!(
+        td.read_to_string(EXTLINUX_CONF).unwrap(),
+        "# Generated by ostree
+
+label Fedora Linux 38 (ostree:0)
+\tkernel /ostree/default-5e0b/vmlinuz-6.2.9-300.fc38.aarch64
+\tfdt /bootc-dtb/rk3399-rockpro64.dtb
+\tappend root=UUID=4d8e7a5b rw ostree=/ostree/boot.1/default/5e0b/0
+\tfdtdir /ostree/default-5e0b/dtb
+"
+    );
+    // Idempotent
+    set_devicetree(&td, dtb).unwrap();
+    assert_eq!(
+        td.read_to_string(EXTLINUX_CONF)
+            .unwrap()
+            .matches("fdt ")
+            .count(),
+        1
+    );
+
+    // With ostree's /boot prefix
+    let prefixed = entry.replace(" /ostree/default", " /boot/ostree/default");
+    entries.write("ostree-1-default.conf", prefixed).unwrap();
+    td.remove_file(EXTLINUX_CONF).unwrap();
+    set_devicetree(&td, dtb).unwrap();
+    let contents = entries.read_to_string("ostree-1-default.conf").unwrap();
+    assert!(contents.ends_with("\ndevicetree /boot/bootc-dtb/rk3399-rockpro64.dtb\n"));
+    assert!(!td.try_exists(EXTLINUX_CONF).unwrap());
+}
+
+#[test]
+fn test_set_kernel() {
+    let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    let kernel = Utf8Path::new("bootc-kernel/6.2.9-300.rt14.fc38.x86_64/vmlinuz");
+    let initrd = Utf8Path::new("bootc-kernel/6.2.9-300.rt14.fc38.x86_64/initramfs.img");
+    assert!(set_kernel(&td, kernel, initrd).is_err());
+    td.create_dir_all(BLS_ENTRIES).unwrap();
+    td.create_dir_all("ostree/default-5e0b").unwrap();
+    td.write("ostree/default-5e0b/vmlinuz-6.2.9-300.fc38.x86_64", "")
+        .unwrap();
+    let entries = td.open_dir(BLS_ENTRIES).unwrap();
+    let entry = "title Fedora Linux 38 (ostree:0)
+version 1
+options root=UUID=4d8e7a5b rw ostree=/ostree/boot.1/default/5e0b/0
+linux /ostree/default-5e0b/vmlinuz-6.2.9-300.fc38.x86_64
+initrd /ostree/default-5e0b/initramfs-6.2.9-300.fc38.x86_64.img
+initrd /ostree/default-5e0b/extra.img
+";
+    entries.write("ostree-1-default.conf", entry).unwrap();
+    let extlinux = "# Generated by ostree
+
+label Fedora Linux 38 (ostree:0)
+\tkernel /ostree/default-5e0b/vmlinuz-6.2.9-300.fc38.x86_64
+\tappend root=UUID=4d8e7a5b rw ostree=/ostree/boot.1/default/5e0b/0
+\tinitrd /ostree/default-5e0b/initramfs-6.2.9-300.fc38.x86_64.img
+";
+    td.create_dir_all("extlinux").unwrap();
+    td.write(EXTLINUX_CONF, extlinux).unwrap();
+    set_kernel(&td, kernel, initrd).unwrap();
+    assert_eq!(
+        entries.read_to_string("ostree-1-default.conf").unwrap(),
+        "title Fedora Linux 38 (ostree:0)
+version 1
+options root=UUID=4d8e7a5b rw ostree=/ostree/boot.1/default/5e0b/0
+linux /bootc-kernel/6.2.9-300.rt14.fc38.x86_64/vmlinuz
+initrd /bootc-kernel/6.2.9-300.rt14.fc38.x86_64/initramfs.img
+initrd /ostree/default-5e0b/extra.img
+"
+    );
+    assert_eq!(
+        td.read_to_string(EXTLINUX_CONF).unwrap(),
+        "# Generated by ostree
+
+label Fedora Linux 38 (ostree:0)
+\tkernel /bootc-kernel/6.2.9-300.rt14.fc38.x86_64/vmlinuz
+\tappend root=UUID=4d8e7a5b rw ostree=/ostree/boot.1/default/5e0b/0
+\tinitrd /bootc-kernel/6.2.9-300.rt14.fc38.x86_64/initramfs.img
+"
+    );
+    // An entry without an initramfs gets one
+    assert_eq!(
+        set_entry_kernel("linux /a\noptions rw\n", "/k", "/i"),
+        "linux /k\noptions rw\ninitrd /i\n"
+    );
+
+    // With ostree's /boot prefix
+    let prefixed = entry.replace(" /ostree/default", " /boot/ostree/default");
+    entries.write("ostree-1-default.conf", prefixed).unwrap();
+    td.remove_file(EXTLINUX_CONF).unwrap();
+    set_kernel(&td, kernel, initrd).unwrap();
+    let contents = entries.read_to_string("ostree-1-default.conf").unwrap();
+    assert!(contents.contains("\nlinux /boot/bootc-kernel/6.2.9-300.rt14.fc38.x86_64/vmlinuz\n"));
+    assert!(!td.try_exists(EXTLINUX_CONF).unwrap());
+}
+
+#[test]
+fn test_boot_counting() {
+    for valid in [1, 3, BOOT_COUNT_MAX] {
+        validate_boot_count(valid).unwrap();
+    }
+    for invalid in [0, BOOT_COUNT_MAX + 1] {
+        assert!(validate_boot_count(invalid).is_err());
+    }
+    for (name, expected) in [
+        ("ostree-1-default.conf", "ostree-1-default+3.conf"),
+        ("ostree-1-default+1.conf", "ostree-1-default+3.conf"),
+        ("ostree-1-default+0-2.conf", "ostree-1-default+3.conf"),
+        ("c++.conf", "c+++3.conf"),
+        ("a+b.conf", "a+b+3.conf"),
+    ] {
+        assert_eq!(counted_entry_name(name, 3).unwrap(), expected);
+    }
+    assert!(counted_entry_name("ostree-1-default", 3).is_err());
+
+    let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    assert!(set_boot_counting(&td, 3).is_err());
+    td.create_dir_all(BLS_ENTRIES).unwrap();
+    let entries = td.open_dir(BLS_ENTRIES).unwrap();
+    let entry = "title Fedora Linux 38 (ostree:0)\nversion 1\noptions root=UUID=4d8e7a5b rw\n";
+    entries.write("ostree-1-default.conf", entry).unwrap();
+    entries
+        .write(
+            RESCUE_ENTRY,
+            rescue_entry_contents(entry, &["single".into()]).unwrap(),
+        )
+        .unwrap();
+    assert!(set_boot_counting(&td, 0).is_err());
+    assert_eq!(
+        set_boot_counting(&td, 3).unwrap(),
+        "ostree-1-default+3.conf"
+    );
+    assert_eq!(
+        entries.read_to_string("ostree-1-default+3.conf").unwrap(),
+        entry
+    );
+    assert!(!entries.try_exists("ostree-1-default.conf").unwrap());
+    // The rescue entry is not counted
+    assert!(entries.try_exists(RESCUE_ENTRY).unwrap());
+    td.create_dir("grub2").unwrap();
+    let e = set_boot_counting(&td, 3).unwrap_err();
+    assert!(
+        format!("{e:#}").contains("GRUB ignores boot counters"),
+        "{e:#}"
+    );
+
+    let deployment = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    assert!(enable_boot_assessment(&deployment).is_err());
+    deployment.create_dir_all("usr/lib/systemd/system").unwrap();
+    deployment
+        .write(format!("usr/lib/systemd/system/{BOOT_CHECK_UNIT}"), "")
+        .unwrap();
+    let link = enable_boot_assessment(&deployment).unwrap();
+    assert_eq!(
+        link,
+        "etc/systemd/system/boot-complete.target.requires/systemd-boot-check-no-failures.service"
+    );
+    assert_eq!(
+        deployment.read_link(&link).unwrap().to_str().unwrap(),
+        "../../../../usr/lib/systemd/system/systemd-boot-check-no-failures.service"
+    );
+    assert!(deployment.metadata(&link).unwrap().is_file());
+    // Idempotent
+    enable_boot_assessment(&deployment).unwrap();
 }
@@ -89,6 +89,11 @@ pub(crate) enum TestingOpts {
         image: String,
         blockdev: Utf8PathBuf,
     },
+    /// e2e test of install-to-filesystem from a read-only additional image store
+    TestInstallFilesystemReadonlyStorage {
+        image: String,
+        blockdev: Utf8PathBuf,
+    },
 }
 
 /// Deploy and upgrade via bootable container images.
@@ -109,6 +114,13 @@ pub(crate) enum Opt {
     /// Install to the target filesystem.
     #[cfg(feature = "install")]
     InstallToFilesystem(crate::install::InstallToFilesystemOpts),
+    /// Export the running image for reuse by multiple installations.
+    #[cfg(feature = "install")]
+    InstallExportSource(crate::install::InstallExportSourceOpts),
+    /// Print the filesystems, block setups and partition layout supported by this build
+    /// of the installer, as JSON.
+    #[cfg(feature = "install")]
+    InstallListCapabilities,
     /// Internal integration testing helpers.
     #[clap(hide(true), subcommand)]
     #[cfg(feature = "internal-testing-api")]
@@ -345,9 +357,24 @@ where
         Opt::Upgrade(opts) => upgrade(opts).await,
         Opt::Switch(opts) => switch(opts).await,
         #[cfg(feature = "install")]
-        Opt::Install(opts) => crate::install::install(opts).await,
+        Opt::Install(opts) => {
+            let format = opts.config_opts.format;
+            let summary =
+                crate::install::run_reporting(format, crate::install::install(opts)).await?;
+            // Only now has the result been written
+            crate::install::run_post_install(summary.post_install)
+        }
+        #[cfg(feature = "install")]
+        Opt::InstallToFilesystem(opts) => {
+            let format = opts.config_opts.format;
+            let install = crate::install::install_to_filesystem(opts);
+            crate::install::run_reporting(format, install).await?;
+            Ok(())
+        }
+        #[cfg(feature = "install")]
+        Opt::InstallExportSource(opts) => crate::install::install_export_source(opts).await,
         #[cfg(feature = "install")]
-        Opt::InstallToFilesystem(opts) => crate::install::install_to_filesystem(opts).await,
+        Opt::InstallListCapabilities => crate::install::install_list_capabilities(),
         Opt::Status(opts) => super::status::status(opts).await,
         #[cfg(feature = "internal-testing-api")]
         Opt::InternalTests(opts) => crate::privtests::run(opts).await,
@@ -3,10 +3,11 @@
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use fn_error_context::context;
 
 const PATH: &str = "/run/.containerenv";
+const CGROUP_PATH: &str = "/proc/self/cgroup";
 
 #[derive(Debug, Default)]
 pub(crate) struct ContainerExecutionInfo {
@@ -17,12 +18,7 @@ pub(crate) struct ContainerExecutionInfo {
     pub(crate) imageid: String,
 }
 
-/// Load and parse the `/run/.containerenv` file.
-#[context("Parsing {PATH}")]
-pub(crate) fn get_container_execution_info() -> Result<ContainerExecutionInfo> {
-    let f = File::open(PATH)
-        .with_context(|| format!("Opening {PATH}"))
-        .map(BufReader::new)?;
+fn parse_containerenv(f: impl BufRead) -> Result<ContainerExecutionInfo> {
     let mut r = ContainerExecutionInfo::default();
     for line in f.lines() {
         let line = line?;
@@ -45,3 +41,126 @@ pub(crate) fn get_container_execution_info() -> Result<ContainerExecutionInfo> {
     }
     Ok(r)
 }
+
+/// Find the ID of the container we're running in from the contents of `/proc/self/cgroup`,
+/// for either cgroup v1 or v2.  The ID is part of the path, e.g. `libpod-<id>.scope`
+/// with the systemd cgroup manager or `libpod_parent/libpod-<id>` with cgroupfs.
+fn container_id_from_cgroup(cgroup: &str) -> Option<&str> {
+    cgroup
+        .lines()
+        // The format is hierarchy-ID:controller-list:cgroup-path
+        .filter_map(|line| line.splitn(3, ':').nth(2))
+        .flat_map(|path| path.split('/'))
+        .filter_map(|component| {
+            let id = component.strip_suffix(".scope").unwrap_or(component);
+            let id = id.rsplit_once('-').map_or(id, |(_, id)| id);
+            (id.len() == 64 && id.chars().all(|c| c.is_ascii_hexdigit())).then_some(id)
+        })
+        .next()
+}
+
+/// Fill in the image and image ID if missing, which is the case with some podman versions
+/// and configurations, by looking up the image of our container via `lookup`.  The
+/// container ID is taken from the containerenv, falling back to our cgroup.
+fn fill_image(
+    info: &mut ContainerExecutionInfo,
+    cgroup: &str,
+    lookup: impl FnOnce(&str) -> Result<Option<(String, String)>>,
+) -> Result<()> {
+    if !(info.image.is_empty() || info.imageid.is_empty()) {
+        return Ok(());
+    }
+    let id = if info.id.is_empty() {
+        container_id_from_cgroup(cgroup)
+            .ok_or_else(|| {
+                anyhow!("{PATH} lacks the image, and the container ID could not be found; use --source-imgref")
+            })?
+            .to_string()
+    } else {
+        info.id.clone()
+    };
+    let (imageid, image) = lookup(&id)
+        .and_then(|r| r.ok_or_else(|| anyhow!("Container not found")))
+        .with_context(|| {
+            format!("Finding the image for container {id}; use --source-imgref to specify it")
+        })?;
+    if info.imageid.is_empty() {
+        info.imageid = imageid;
+    }
+    if info.image.is_empty() {
+        info.image = image;
+    }
+    Ok(())
+}
+
+/// Load and parse the `/run/.containerenv` file.  If provided, `source_imgref` is
+/// used as the image; otherwise, if the file lacks it, the image is found via podman.
+#[context("Parsing {PATH}")]
+pub(crate) fn get_container_execution_info(
+    source_imgref: Option<&str>,
+) -> Result<ContainerExecutionInfo> {
+    let f = File::open(PATH)
+        .with_context(|| format!("Opening {PATH}"))
+        .map(BufReader::new)?;
+    let mut r = parse_containerenv(f)?;
+    if let Some(imgref) = source_imgref {
+        r.imageid = crate::podman::image_id(imgref)?;
+        r.image = imgref.to_string();
+        return Ok(r);
+    }
+    if r.image.is_empty() || r.imageid.is_empty() {
+        let cgroup = std::fs::read_to_string(CGROUP_PATH)
+            .with_context(|| format!("Reading {CGROUP_PATH}"))?;
+        fill_image(&mut r, &cgroup, crate::podman::container_image)?;
+    }
+    Ok(r)
+}
+
+#[test]
+fn test_containerenv_fallback() {
+    let id = "5d6b2ef7a1a4c24a1d9d3f2a3dc0e0e5cdb76cb5bb69c7c7d1ac63eb4b5f1e53";
+    let imageid = "a1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e5f60718293a4b5c6d7e8f90";
+    let image = "quay.io/example/os:latest";
+    let lookup = |want: &str| -> Result<Option<(String, String)>> {
+        Ok((want == id).then(|| (imageid.to_string(), image.to_string())))
+    };
+    let fail = |_: &str| -> Result<Option<(String, String)>> { panic!("Unexpected lookup") };
+    let cgroup_v2 = format!("0::/machine.slice/libpod-{id}.scope/container\n");
+    let cgroup_v1 = format!(
+        "12:pids:/libpod_parent/libpod-{id}\n11:memory:/libpod_parent/libpod-{id}\n1:name=systemd:/libpod_parent/libpod-{id}\n"
+    );
+
+    // podman 4.x, with everything
+    let podman4 = format!(
+        "engine=\"podman-4.4.1\"\nname=\"bootc\"\nid=\"{id}\"\nimage=\"{image}\"\nimageid=\"{imageid}\"\nrootless=0\n"
+    );
+    let mut info = parse_containerenv(podman4.as_bytes()).unwrap();
+    assert_eq!(info.engine, "podman-4.4.1");
+    fill_image(&mut info, "", fail).unwrap();
+    assert_eq!(info.image, image);
+    assert_eq!(info.imageid, imageid);
+
+    // podman 3.x, lacking the image
+    let podman3 = format!("engine=\"podman-3.4.4\"\nname=\"bootc\"\nid=\"{id}\"\nrootless=0\n");
+    let mut info = parse_containerenv(podman3.as_bytes()).unwrap();
+    fill_image(&mut info, "", lookup).unwrap();
+    assert_eq!(info.image, image);
+    assert_eq!(info.imageid, imageid);
+
+    // podman 3.x, also lacking the container ID, with cgroup v1 and v2
+    let podman3 = "engine=\"podman-3.0.1\"\nrootless=1\n";
+    for cgroup in [cgroup_v1.as_str(), cgroup_v2.as_str()] {
+        let mut info = parse_containerenv(podman3.as_bytes()).unwrap();
+        fill_image(&mut info, cgroup, lookup).unwrap();
+        assert_eq!(info.image, image);
+    }
+
+    // With a private cgroup namespace there's no ID
+    let mut info = parse_containerenv(podman3.as_bytes()).unwrap();
+    let e = fill_image(&mut info, "0::/\n", fail).unwrap_err();
+    assert!(e.to_string().contains("--source-imgref"));
+    // The container isn't known to podman
+    let mut info = parse_containerenv(podman3.as_bytes()).unwrap();
+    let e = fill_image(&mut info, &cgroup_v2, |_| Ok(None)).unwrap_err();
+    assert!(e.to_string().contains("--source-imgref"));
+}
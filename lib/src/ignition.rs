@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use anyhow::{bail, ensure, Context, Error, Result};
+use anyhow::{bail, Context, Error, Result};
 use camino::Utf8Path;
 use fn_error_context::context;
 use openssl::hash::{Hasher, MessageDigest};
@@ -49,40 +49,56 @@ enum IgnitionHasher {
     Sha512(sha::Sha512),
 }
 
+/// Decode a digest value which is expected to be `len` bytes long, either from
+/// hex or base64 encoding.
+fn decode_digest(kind: &str, value: &str, len: usize) -> Result<Vec<u8>> {
+    if value.is_empty() {
+        bail!("empty {} digest", kind);
+    }
+    let is_hex = value.chars().all(|c| c.is_ascii_hexdigit());
+    if is_hex && value.len() == len * 2 {
+        return hex::decode(value).context("decoding hex digest");
+    }
+    // Otherwise, try base64; note that an unpadded base64 value may consist of only hex characters.
+    match openssl::base64::decode_block(value) {
+        Ok(digest) if digest.len() == len => Ok(digest),
+        _ if is_hex => bail!(
+            "wrong {} digest length: expected {} hex characters, found {}",
+            kind,
+            len * 2,
+            value.len()
+        ),
+        Ok(digest) => bail!(
+            "wrong {} digest length: expected {} bytes, found {} (decoded as base64)",
+            kind,
+            len,
+            digest.len()
+        ),
+        Err(_) => bail!("{} digest is neither valid hex nor base64", kind),
+    }
+}
+
 impl FromStr for IgnitionHash {
     type Err = Error;
 
     /// Try to parse an hash-digest argument.
     ///
     /// This expects an input value following the `ignition.config.verification.hash`
-    /// spec, i.e. `<type>-<value>` format.
+    /// spec, i.e. `<type>-<value>` format.  The value may be hex (the canonical form)
+    /// or base64 encoded.
     fn from_str(input: &str) -> Result<Self, Self::Err> {
-        let parts: Vec<_> = input.splitn(2, '-').collect();
-        if parts.len() != 2 {
-            bail!("failed to detect hash-type and digest in '{}'", input);
-        }
-        let (hash_kind, hex_digest) = (parts[0], parts[1]);
+        let (hash_kind, digest) = input.split_once('-').ok_or_else(|| {
+            anyhow::anyhow!(
+                "missing '-' separator between hash type and digest in '{}'",
+                input
+            )
+        })?;
 
         let hash = match hash_kind {
-            "sha256" => {
-                let digest = hex::decode(hex_digest).context("decoding hex digest")?;
-                ensure!(
-                    digest.len().saturating_mul(8) == 256,
-                    "wrong digest length ({})",
-                    digest.len().saturating_mul(8)
-                );
-                IgnitionHash::Sha256(digest)
-            }
-            "sha512" => {
-                let digest = hex::decode(hex_digest).context("decoding hex digest")?;
-                ensure!(
-                    digest.len().saturating_mul(8) == 512,
-                    "wrong digest length ({})",
-                    digest.len().saturating_mul(8)
-                );
-                IgnitionHash::Sha512(digest)
-            }
-            x => bail!("unknown hash type '{}'", x),
+            "sha256" => IgnitionHash::Sha256(decode_digest(hash_kind, digest, 256 / 8)?),
+            "sha512" => IgnitionHash::Sha512(decode_digest(hash_kind, digest, 512 / 8)?),
+            "" => bail!("missing hash type in '{}'", input),
+            x => bail!("unknown hash type '{}' (expected sha256 or sha512)", x),
         };
 
         Ok(hash)
@@ -303,6 +319,49 @@ mod tests {
         IgnitionHash::from_str(null_digest).unwrap();
     }
 
+    #[test]
+    fn test_ignition_hash_parse_errors() {
+        let sha256_hex = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let truncated = format!("sha256-{}", &sha256_hex[..62]);
+        let cases = [
+            ("sha512", "missing '-' separator"),
+            ("-abcd", "missing hash type"),
+            (
+                "md5-d41d8cd98f00b204e9800998ecf8427e",
+                "unknown hash type 'md5'",
+            ),
+            ("sha256-", "empty sha256 digest"),
+            ("sha512-00", "expected 128 hex characters, found 2"),
+            (truncated.as_str(), "expected 64 hex characters, found 62"),
+            ("sha256-!!!!", "neither valid hex nor base64"),
+            // A valid base64 encoding of a sha256 digest, but for sha512
+            (
+                "sha512-ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=",
+                "expected 64 bytes, found 32",
+            ),
+        ];
+        for (input, expected) in cases {
+            let e = IgnitionHash::from_str(input).unwrap_err().to_string();
+            assert!(e.contains(expected), "input {input}: {e}");
+        }
+    }
+
+    #[test]
+    fn test_ignition_hash_base64() {
+        let hex = IgnitionHash::from_str(
+            "sha256-ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+        )
+        .unwrap();
+        let b64 =
+            IgnitionHash::from_str("sha256-ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=").unwrap();
+        assert_eq!(hex, b64);
+        let hex = IgnitionHash::from_str("sha512-ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f").unwrap();
+        let b64 = IgnitionHash::from_str("sha512-3a81oZNherrMQXNJriBBMRLm+k6JqX6iCp7u5ktV05ohkpkqJ0/BqDa6PCOj/uu9RU1EI2Q86A4qmslPpUyknw==").unwrap();
+        assert_eq!(hex, b64);
+        // We always display as hex
+        assert!(b64.to_string().starts_with("sha512-ddaf35a1"));
+    }
+
     #[test]
     fn test_ignition_hash_validate() {
         let input = vec![b'a', b'b', b'c'];
@@ -317,5 +376,12 @@ mod tests {
             let mut rd = std::io::Cursor::new(&input);
             assert!(hasher.validate(&mut rd).is_ok() == *valid);
         }
+        // Mismatched content
+        for (_, hash_arg) in hash_args.iter().filter(|v| v.0) {
+            let hasher = IgnitionHash::from_str(hash_arg).unwrap();
+            let e = hasher.validate(&mut &b"abd"[..]).unwrap_err();
+            assert!(e.to_string().contains("hash mismatch"));
+            hasher.validate(&mut &b""[..]).unwrap_err();
+        }
     }
 }
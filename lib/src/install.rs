@@ -6,10 +6,45 @@
 
 // This sub-module is the "basic" installer that handles creating basic block device
 // and filesystem setup.
+mod adopt;
+mod aleph;
 mod baseline;
+mod copy;
+mod deploycheck;
+mod devicetree;
+mod diagnostics;
+mod dns;
+mod entropy;
+mod existing;
+mod fetchstats;
+mod freespace;
+mod fsfeatures;
+mod journal;
+mod kernel;
+mod lock;
+mod lvm;
+mod machineid;
+mod metrics;
+mod ops;
+mod postinstall;
+mod remotekey;
+mod repart;
+mod report;
+mod sbom;
+mod sebool;
+mod sigpolicy;
+mod source;
+mod sshkeys;
+mod summary;
+mod swap;
+mod syncesp;
+mod targetdeploy;
 
+use std::collections::BTreeMap;
 use std::io::BufWriter;
+use std::io::Read;
 use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
 use std::str::FromStr;
 use std::sync::Arc;
 
@@ -27,10 +62,16 @@ use ostree::gio;
 use ostree_ext::container as ostree_container;
 use ostree_ext::container::SignatureSource;
 use ostree_ext::ostree;
-use ostree_ext::prelude::Cast;
+use ostree_ext::prelude::{CancellableExt, Cast};
 use serde::{Deserialize, Serialize};
+use serde_with::{DeserializeFromStr, SerializeDisplay};
 
 use self::baseline::InstallBlockDeviceOpts;
+use self::diagnostics::Diagnostics;
+use self::ops::InstallOps;
+pub(crate) use self::postinstall::run as run_post_install;
+pub(crate) use self::report::run_reporting;
+use self::sshkeys::SshHostKeySource;
 use crate::lsm::lsm_label;
 use crate::task::Task;
 use crate::utils::run_in_host_mountns;
@@ -46,6 +87,8 @@ const LOST_AND_FOUND: &str = "lost+found";
 
 /// Kernel argument used to specify we want the rootfs mounted read-write by default
 const RW_KARG: &str = "rw";
+/// Kernel argument used to have the initramfs assemble multipath devices
+const MULTIPATH_KARG: &str = "rd.multipath=default";
 
 #[derive(clap::Args, Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct InstallTargetOpts {
@@ -60,6 +103,12 @@ pub(crate) struct InstallTargetOpts {
     #[clap(long)]
     pub(crate) target_imgref: Option<String>,
 
+    /// Fetch subsequent updates from this stream, i.e. the source image with its tag
+    /// replaced by NAME (e.g. `stable` or `testing`).
+    #[clap(long, value_name = "NAME", conflicts_with = "target-imgref")]
+    #[serde(default)]
+    pub(crate) target_stream: Option<String>,
+
     /// Explicitly opt-out of requiring any form of signature verification.
     #[clap(long)]
     #[serde(default)]
@@ -68,6 +117,82 @@ pub(crate) struct InstallTargetOpts {
     /// Enable verification via an ostree remote
     #[clap(long)]
     pub(crate) target_ostree_remote: Option<String>,
+
+    /// Install this GPG public key into the deployment, and configure the
+    /// `--target-ostree-remote` to verify updates with it.  The image must configure
+    /// the remote in `/etc/ostree/remotes.d`.
+    #[clap(long, value_name = "PATH", requires = "target-ostree-remote")]
+    #[serde(default)]
+    pub(crate) ostree_remote_gpg_key: Option<Utf8PathBuf>,
+
+    /// Pin the target image to this manifest digest (e.g. `sha256:<hex>`).
+    ///
+    /// The system will not follow updates to the image tag until the target is changed
+    /// via e.g. `bootc switch`.  Combines with `--target-imgref`.
+    #[clap(long, value_name = "DIGEST")]
+    pub(crate) target_imgref_digest: Option<String>,
+
+    /// Skip verifying that the `--target-imgref` image exists in the registry and
+    /// matches this architecture; e.g. for offline installations.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) skip_target_check: bool,
+}
+
+/// Verify that the provided string is a manifest digest of the form `<algorithm>:<hex>`.
+fn validate_digest(digest: &str) -> Result<()> {
+    let (algo, value) = digest
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Invalid digest {digest}: expected <algorithm>:<hex>"))?;
+    let expected_len = match algo {
+        "sha256" => 64,
+        "sha512" => 128,
+        o => anyhow::bail!("Unsupported digest algorithm {o}"),
+    };
+    if value.len() != expected_len
+        || !value
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+    {
+        anyhow::bail!("Invalid {algo} digest value: {value}");
+    }
+    Ok(())
+}
+
+/// Compute the image reference the installed system will use for updates.
+fn target_imgref_from_opts(
+    opts: &InstallTargetOpts,
+    source_imageref: &ostree_container::ImageReference,
+) -> Result<ostree_container::OstreeImageReference> {
+    let sigverify = if opts.target_no_signature_verification {
+        SignatureSource::ContainerPolicyAllowInsecure
+    } else if let Some(remote) = opts.target_ostree_remote.as_deref() {
+        SignatureSource::OstreeRemote(remote.to_string())
+    } else {
+        SignatureSource::ContainerPolicy
+    };
+    let name = match (opts.target_imgref.as_deref(), opts.target_stream.as_deref()) {
+        (Some(_), Some(_)) => {
+            anyhow::bail!("--target-imgref and --target-stream cannot be used together")
+        }
+        (Some(imgref), None) => Some(imgref.to_string()),
+        (None, Some(stream)) => Some(
+            crate::utils::tagged_pullspec(&source_imageref.name, stream)
+                .with_context(|| format!("Deriving the image for stream {stream}"))?,
+        ),
+        (None, None) => None,
+    };
+    let mut imgref = if let Some(name) = name {
+        let transport = ostree_container::Transport::try_from(opts.target_transport.as_str())?;
+        ostree_container::ImageReference { transport, name }
+    } else {
+        source_imageref.clone()
+    };
+    if let Some(digest) = opts.target_imgref_digest.as_deref() {
+        validate_digest(digest)?;
+        imgref.name = crate::utils::digested_pullspec(&imgref.name, digest);
+    }
+    Ok(ostree_container::OstreeImageReference { sigverify, imgref })
 }
 
 #[derive(clap::Args, Debug, Clone, Serialize, Deserialize)]
@@ -80,7 +205,7 @@ pub(crate) struct InstallConfigOpts {
     ///
     /// Verify that the Ignition config matches the specified digest,
     /// formatted as <type>-<hexvalue>.  <type> can be sha256 or sha512.
-    #[clap(long, value_name = "digest", value_parser)]
+    #[clap(long, value_name = "digest", value_parser, requires = "ignition-file")]
     pub(crate) ignition_hash: Option<crate::ignition::IgnitionHash>,
 
     /// Disable SELinux in the target (installed) system.
@@ -91,794 +216,6850 @@ pub(crate) struct InstallConfigOpts {
     #[serde(default)]
     pub(crate) disable_selinux: bool,
 
+    /// Keep SELinux enabled in the target system when installing from a system with
+    /// SELinux disabled, instead of `--disable-selinux`.
+    ///
+    /// The target is installed without labels, and with `/.autorelabel`, so it is
+    /// relabeled on the first boot; this makes the first boot slow.  The image must
+    /// enable SELinux.
+    #[clap(long, conflicts_with = "disable-selinux")]
+    #[serde(default)]
+    pub(crate) selinux_firstboot_relabel: bool,
+
+    /// The SELinux mode of the target system, written to `/etc/selinux/config`; this does
+    /// not affect the labeling during installation.
+    #[clap(
+        long,
+        value_enum,
+        value_name = "MODE",
+        conflicts_with = "disable-selinux"
+    )]
+    #[serde(default)]
+    pub(crate) selinux_enforcing: Option<SelinuxMode>,
+
+    /// Persistently set an SELinux boolean in the target system, e.g.
+    /// `httpd_can_network_connect=on`.
+    ///
+    /// May be specified multiple times.  The booleans must exist in the loaded policy,
+    /// and the image's SELinux policy store must be in `/etc`; the policy of the target
+    /// is rebuilt with `semodule`.
+    #[clap(long, value_name = "NAME=on|off", conflicts_with = "disable-selinux")]
+    #[serde(default)]
+    pub(crate) selinux_boolean: Vec<sebool::SelinuxBoolean>,
+
     // Only occupy at most this much space (if no units are provided, GB is assumed).
     // Using this option reserves space for partitions created dynamically on the
     // next boot, or by subsequent tools.
     //    pub(crate) size: Option<String>,
     #[clap(long)]
-    /// Add a kernel argument
+    /// Add a kernel argument.
+    ///
+    /// An argument which is already present is not repeated.  It is an error if an
+    /// argument with the same key (the part before the first `=`) but another value is
+    /// present, except for keys which may be given several times such as `console` and
+    /// `rd.luks.uuid`; see `--karg-replace` to override such a value.
     karg: Option<Vec<String>>,
-}
 
-/// Perform an installation to a block device.
-#[derive(Debug, Clone, clap::Parser, Serialize, Deserialize)]
-pub(crate) struct InstallOpts {
-    #[clap(flatten)]
-    #[serde(flatten)]
-    pub(crate) block_opts: InstallBlockDeviceOpts,
+    /// Replace any kernel arguments with the key of KEY=VALUE (e.g. one provided by
+    /// the installer or `--inherit-kargs`) by KEY=VALUE, or add it if there are none.
+    /// Applied after `--karg`; may be specified multiple times.
+    #[clap(long, value_name = "KEY=VALUE")]
+    #[serde(default)]
+    pub(crate) karg_replace: Vec<String>,
 
-    #[clap(flatten)]
-    #[serde(flatten)]
-    pub(crate) target_opts: InstallTargetOpts,
+    /// Add KEY=VALUE as a kernel argument only if there is no argument with its key
+    /// yet, e.g. to provide a default `console`.  Applied after `--karg-replace`; may be
+    /// specified multiple times.
+    #[clap(long, value_name = "KEY=VALUE")]
+    #[serde(default)]
+    pub(crate) karg_append_if_missing: Vec<String>,
 
-    #[clap(flatten)]
-    #[serde(flatten)]
-    pub(crate) config_opts: InstallConfigOpts,
-}
+    /// Carry the kernel arguments of the running system (from `/proc/cmdline`) into the
+    /// installation, except for those describing its root filesystem, boot and
+    /// installation media, such as `root=`, `ostree=` and `rd.live.*`.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) inherit_kargs: bool,
 
-/// Options for installing to a filesystem
-#[derive(Debug, Clone, clap::Args)]
-pub(crate) struct InstallTargetFilesystemOpts {
-    /// Path to the mounted root filesystem.
-    ///
-    /// By default, the filesystem UUID will be discovered and used for mounting.
-    /// To override this, use `--root-mount-spec`.
-    pub(crate) root_path: Utf8PathBuf,
+    /// Do not add this kernel argument via `--inherit-kargs` or `--karg`; matches either
+    /// the full argument or its key.  May be specified multiple times.
+    #[clap(long, value_name = "KARG")]
+    #[serde(default)]
+    pub(crate) karg_delete: Vec<String>,
 
-    /// Source device specification for the root filesystem.  For example, UUID=2e9f4241-229b-4202-8429-62d2302382e1
+    /// Add a kernel argument which is only used for the first boot.
+    ///
+    /// This is implemented via the GRUB environment block, and is not supported
+    /// with other bootloaders.
     #[clap(long)]
-    pub(crate) root_mount_spec: Option<String>,
+    #[serde(default)]
+    pub(crate) firstboot_karg: Vec<String>,
 
-    /// Comma-separated mount options for the root filesystem.  For example: rw,prjquota
-    #[clap(long)]
-    pub(crate) root_options: Option<String>,
+    /// Add a kernel argument only when installing an image for the provided
+    /// architecture, e.g. `aarch64=console=ttyAMA0`; may be specified multiple times.
+    /// Both kernel (`aarch64`) and container (`arm64`) architecture names are accepted.
+    #[clap(long, value_parser, value_name = "ARCH=KARG")]
+    #[serde(default)]
+    pub(crate) karg_arch: Vec<ArchKarg>,
 
-    /// Mount specification for the /boot filesystem.
+    /// Execute a shell command inside the newly created deployment before finalizing.
     ///
-    /// At the current time, a separate /boot is required.  This restriction will be lifted in
-    /// future versions.  If not specified, the filesystem UUID will be used.
-    #[clap(long)]
-    pub(crate) boot_mount_spec: Option<String>,
+    /// May be specified multiple times; commands are run in order via `/bin/sh -c`, and
+    /// installation is aborted if any exits unsuccessfully.  `systemd-nspawn` is used if
+    /// available, otherwise `chroot`.  Commands run without networking, `/var` is not
+    /// mounted, and no services from the target are running.  `BOOTC_TARGET_DEPLOYMENT`
+    /// is set to `/`, the root of the deployment as seen by the command.
+    #[clap(long, value_name = "CMD")]
+    #[serde(default)]
+    pub(crate) run_in_target: Vec<String>,
 
-    /// Automatically wipe existing data on the filesystems.
+    /// Write installation results in the `KEY=value` format consumed by Anaconda.
+    #[clap(long, value_name = "PATH")]
+    pub(crate) write_anaconda_results: Option<Utf8PathBuf>,
+
+    /// Leave the physical root filesystem mutable, for development and debugging.
+    ///
+    /// This skips setting the immutable bit on the root and configures ostree to not
+    /// mount `/sysroot` read-only.  Do not use this in production; it makes it easy to
+    /// accidentally accumulate state outside of deployments.
     #[clap(long)]
-    pub(crate) wipe: bool,
-}
+    #[serde(default)]
+    pub(crate) dev_mutable: bool,
 
-/// Perform an installation to a mounted filesystem.
-#[derive(Debug, Clone, clap::Parser)]
-pub(crate) struct InstallToFilesystemOpts {
-    #[clap(flatten)]
-    pub(crate) filesystem_opts: InstallTargetFilesystemOpts,
+    /// On success, print shell-quoted `BOOTC_KEY=value` lines describing the installation.
+    ///
+    /// All other output is redirected to standard error, so the result can be used
+    /// via e.g. `eval "$(bootc install --print-env ...)"`.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) print_env: bool,
 
-    #[clap(flatten)]
-    pub(crate) target_opts: InstallTargetOpts,
+    /// The format of the result.  With `json`, all other output is redirected to
+    /// standard error, and a JSON object is written to standard output: on success the
+    /// installation summary (including any warnings), and on failure a description of
+    /// the error (including the failed phase and command, if any).
+    #[clap(long, value_enum, default_value_t, conflicts_with = "print-env")]
+    #[serde(default)]
+    pub(crate) format: report::OutputFormat,
 
-    #[clap(flatten)]
-    pub(crate) config_opts: InstallConfigOpts,
-}
+    /// The ostree physical root layout to create.
+    ///
+    /// modern: Only create the directories required for ostree
+    /// legacy: Also create the traditional toplevel directories, for compatibility with older tooling
+    #[clap(long, value_enum, default_value_t)]
+    #[serde(default)]
+    pub(crate) sysroot_layout: SysrootLayout,
 
-// Shared read-only global state
-pub(crate) struct State {
-    /// Image reference we'll pull from (today always containers-storage: type)
-    source_imageref: ostree_container::ImageReference,
-    /// The digest to use for pulls
-    source_digest: String,
-    /// Force SELinux off in target system
-    override_disable_selinux: bool,
-    config_opts: InstallConfigOpts,
-    target_opts: InstallTargetOpts,
-}
+    /// Create an additional "rescue" boot entry for the deployment.
+    ///
+    /// This uses the same kernel and initramfs as the default entry, with the
+    /// kernel arguments from `--rescue-karg` appended.  The entry is not
+    /// managed by ostree, and will not be preserved across updates.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) rescue_entry: bool,
 
-/// Path to initially deployed version information
-const BOOTC_ALEPH_PATH: &str = ".bootc-aleph.json";
+    /// Kernel argument to add to the rescue boot entry (but not the default entry), e.g.
+    /// `single` or `nomodeset`; may be specified multiple times.  Defaults to
+    /// `systemd.unit=rescue.target`.
+    #[clap(long, visible_alias = "recovery-karg", requires = "rescue-entry")]
+    #[serde(default, alias = "recovery_karg")]
+    pub(crate) rescue_karg: Vec<String>,
 
-/// The "aleph" version information is injected into /root/.bootc-aleph.json
-/// and contains the image ID that was initially used to install.  This can
-/// be used to trace things like the specific version of `mkfs.ext4` or
-/// kernel version that was used.
-#[derive(Debug, Serialize)]
-struct InstallAleph {
-    /// Digested pull spec for installed image
-    image: String,
-    kernel: String,
-}
+    /// Enable automatic boot assessment: the default boot entry gets a boot counter of
+    /// N attempts, and a boot without failed units is marked as successful.  If the
+    /// deployment fails to boot N times, the bootloader falls back to another entry.
+    /// This requires systemd-boot, so it can only be used with an existing or external
+    /// bootloader; the counter is not set on the entries written by later updates.
+    #[clap(long, value_name = "N")]
+    #[serde(default)]
+    pub(crate) boot_counting: Option<u32>,
 
-/// A mount specification is a subset of a line in `/etc/fstab`.
-///
-/// There are 3 (ASCII) whitespace separated values:
-///
-/// SOURCE TARGET [OPTIONS]
-///
-/// Examples:
-///   - /dev/vda3 /boot ext4 ro
-///   - /dev/nvme0n1p4 /
-///   - /dev/sda2 /var/mnt xfs
-#[derive(Debug, Clone)]
-pub(crate) struct MountSpec {
-    pub(crate) source: String,
-    pub(crate) target: String,
-    pub(crate) fstype: String,
-    pub(crate) options: Option<String>,
-}
+    /// Install a devicetree blob and reference it in the boot entries (and the extlinux
+    /// configuration for U-Boot, if any).  This is a path relative to the kernel's
+    /// `/usr/lib/modules/$kver/dtb` directory in the image, e.g.
+    /// `rockchip/rk3399-rockpro64.dtb`, or `auto` to use the one compatible with the
+    /// running system's devicetree.  The selection is recorded in
+    /// `/etc/kernel/devicetree` for the kernel installation tooling of later updates.
+    #[clap(long, value_parser, value_name = "PATH|auto")]
+    #[serde(default)]
+    pub(crate) devicetree: Option<devicetree::DevicetreeSpec>,
 
-impl MountSpec {
-    const AUTO: &'static str = "auto";
+    /// Boot the provided kernel version from `/usr/lib/modules` in the image, e.g.
+    /// `6.2.9-300.rt14.fc38.x86_64`, for images shipping multiple kernels.  The kernel
+    /// and its initramfs are copied to `/boot` and referenced by the boot entries; like
+    /// the rescue entry, this is not preserved across updates.
+    #[clap(long, value_name = "KVER")]
+    #[serde(default)]
+    pub(crate) kernel: Option<String>,
 
-    pub(crate) fn new(src: &str, target: &str) -> Self {
-        MountSpec {
-            source: src.to_string(),
-            target: target.to_string(),
-            fstype: Self::AUTO.to_string(),
-            options: None,
-        }
-    }
+    /// Set an option in the ostree repository configuration; may be specified multiple times.
+    ///
+    /// Only a set of known-safe keys such as `core.min-free-space-percent` and
+    /// `sysroot.bootprefix` are accepted, unless `--ostree-config-unsafe` is given.
+    #[clap(long, value_name = "KEY=VALUE")]
+    #[serde(default)]
+    pub(crate) ostree_config: Vec<String>,
 
-    /// Construct a new mount that uses the provided uuid as a source.
-    pub(crate) fn new_uuid_src(uuid: &str, target: &str) -> Self {
-        Self::new(&format!("UUID={uuid}"), target)
-    }
+    /// Allow setting arbitrary keys via `--ostree-config`.
+    #[clap(long, requires = "ostree-config")]
+    #[serde(default)]
+    pub(crate) ostree_config_unsafe: bool,
 
-    pub(crate) fn get_source_uuid(&self) -> Option<&str> {
-        if let Some((t, rest)) = self.source.split_once('=') {
-            if t.eq_ignore_ascii_case("uuid") {
-                return Some(rest);
-            }
-        }
-        None
-    }
+    /// Copy the image's default configuration from `/usr/etc` into the deployment's `/etc`
+    /// at install time, rather than relying on the merge at first boot.
+    ///
+    /// Files already present in `/etc` are left unchanged.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) seed_etc: bool,
 
-    pub(crate) fn to_fstab(&self) -> String {
-        let options = self.options.as_deref().unwrap_or("defaults");
-        format!(
-            "{} {} {} {} 0 0",
-            self.source, self.target, self.fstype, options
-        )
-    }
-}
+    /// Path to a GRUB configuration fragment (e.g. menu entries or theme settings)
+    /// to be included from the installed GRUB config.
+    ///
+    /// Files it includes must be relative to `$prefix` or `$config_directory`.
+    #[clap(long, value_name = "PATH")]
+    #[serde(default)]
+    pub(crate) grub_config_fragment: Option<Utf8PathBuf>,
 
-impl FromStr for MountSpec {
-    type Err = anyhow::Error;
+    /// Number of layers to copy in parallel when falling back to copying the image
+    /// to a temporary OCI directory (with skopeo versions too old to read directly
+    /// from container storage).  Defaults to skopeo's default; ignored if skopeo does
+    /// not support parallel copies.
+    #[clap(long, value_name = "N")]
+    #[serde(default)]
+    pub(crate) copy_concurrency: Option<u32>,
 
-    fn from_str(s: &str) -> Result<Self> {
-        let mut parts = s.split_ascii_whitespace().fuse();
-        let source = parts.next().unwrap_or_default();
-        if source.is_empty() {
-            anyhow::bail!("Invalid empty mount specification");
-        }
-        let target = parts
-            .next()
-            .ok_or_else(|| anyhow!("Missing target in mount specification {s}"))?;
-        let fstype = parts.next().unwrap_or(Self::AUTO);
-        let options = parts.next().map(ToOwned::to_owned);
-        Ok(Self {
-            source: source.to_string(),
-            fstype: fstype.to_string(),
-            target: target.to_string(),
-            options,
-        })
-    }
-}
+    /// Add an entry for an existing filesystem to the installed system's `/etc/fstab`;
+    /// may be specified multiple times.
+    ///
+    /// The value is a whitespace separated `SOURCE TARGET [FSTYPE [OPTIONS]]`.  OPTIONS
+    /// may include `nofail` for non-critical mounts and the known `x-systemd.*`
+    /// options, such as `x-systemd.device-timeout=10s`.
+    #[clap(long, value_parser, value_name = "SPEC")]
+    #[serde(default)]
+    pub(crate) extra_mount: Vec<MountSpec>,
 
-fn bind_mount_from_host(src: impl AsRef<Utf8Path>, dest: impl AsRef<Utf8Path>) -> Result<()> {
-    let src = src.as_ref();
-    let dest = dest.as_ref();
-    tracing::debug!("Mounting host {src} to {dest}");
-    std::fs::create_dir_all(dest).with_context(|| format!("Creating {dest}"))?;
-    // Here's the magic trick; modern versions of the `mount` command support a `-N` argument
-    // to perform the mount in a distinct target namespace.  But, what we want to is the inverse
-    // of this - we want to grab a host/root filesystem mount point.  So we explicitly enter
-    // the host's mount namespace, then give `mount` our own pid (from which it finds the mount namespace).
-    let desc = format!("Bind mounting {src} from host");
-    let target = format!("{}", nix::unistd::getpid());
-    Task::new_cmd(desc, run_in_host_mountns("mount"))
-        .quiet()
-        .args(["--bind", "-N", target.as_str(), src.as_str(), dest.as_str()])
-        .run()
-}
+    /// After installation, verify that the default boot entry references an existing
+    /// kernel and initramfs, and a `root=` device which can be found; fail otherwise.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) verify_bootable: bool,
 
-#[context("Creating ostree deployment")]
-async fn initialize_ostree_root_from_self(
-    state: &State,
-    root_setup: &RootSetup,
-) -> Result<InstallAleph> {
-    let rootfs_dir = &root_setup.rootfs_fd;
-    let rootfs = root_setup.rootfs.as_path();
-    let opts = &state.target_opts;
-    let cancellable = gio::Cancellable::NONE;
+    /// Generate systemd `.mount` units for /boot, the ESP and any extra partitions
+    /// and mounts, instead of adding entries to `/etc/fstab`.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) mount_units: bool,
 
-    // Parse the target CLI image reference options
-    let target_sigverify = if opts.target_no_signature_verification {
-        SignatureSource::ContainerPolicyAllowInsecure
-    } else if let Some(remote) = opts.target_ostree_remote.as_deref() {
-        SignatureSource::OstreeRemote(remote.to_string())
-    } else {
-        SignatureSource::ContainerPolicy
-    };
-    let target_imgref = if let Some(imgref) = opts.target_imgref.as_ref() {
-        let transport = ostree_container::Transport::try_from(opts.target_transport.as_str())?;
-        let imgref = ostree_container::ImageReference {
-            transport,
-            name: imgref.to_string(),
-        };
-        ostree_container::OstreeImageReference {
-            sigverify: target_sigverify,
-            imgref,
-        }
-    } else {
-        ostree_container::OstreeImageReference {
-            sigverify: target_sigverify,
-            imgref: state.source_imageref.clone(),
-        }
-    };
+    /// Install this containers-policy.json(5) as `/etc/containers/policy.json` in the
+    /// target, used to verify signatures of updates.
+    #[clap(long, value_name = "PATH")]
+    #[serde(default)]
+    pub(crate) install_policy: Option<Utf8PathBuf>,
 
-    // TODO: make configurable?
-    let stateroot = STATEROOT_DEFAULT;
-    Task::new_and_run(
-        "Initializing ostree layout",
-        "ostree",
-        ["admin", "init-fs", "--modern", rootfs.as_str()],
-    )?;
+    /// Install this sigstore configuration (see containers-registries.d(5)) into
+    /// `/etc/containers/registries.d` in the target.
+    #[clap(long, value_name = "PATH")]
+    #[serde(default)]
+    pub(crate) install_sigstore_policy: Option<Utf8PathBuf>,
 
-    for (k, v) in [("sysroot.bootloader", "none"), ("sysroot.readonly", "true")] {
-        Task::new("Configuring ostree repo", "ostree")
-            .args(["config", "--repo", "ostree/repo", "set", k, v])
-            .cwd(rootfs_dir)?
-            .quiet()
-            .run()?;
-    }
-    Task::new("Initializing sysroot", "ostree")
-        .args(["admin", "os-init", stateroot, "--sysroot", "."])
-        .cwd(rootfs_dir)?
-        .run()?;
+    /// The deployment which the bootloader should boot by default, either as a stateroot
+    /// name or `STATEROOT/CHECKSUM.SERIAL`.  It must name a deployment created by this
+    /// installation; currently this is a single deployment in the `default` stateroot.
+    #[clap(long, value_name = "NAME")]
+    #[serde(default)]
+    pub(crate) default_boot_entry: Option<String>,
 
-    // Ensure everything in the ostree repo is labeled
-    lsm_label(&rootfs.join("ostree"), "/usr".into(), true)?;
+    /// Create swap in the installed system; currently only `swapfile:SIZE` is supported,
+    /// creating `/var/swap/swapfile` with an `/etc/fstab` entry.  Requires a btrfs, ext4
+    /// or xfs root filesystem.  Hibernation to the swapfile is not configured.
+    #[clap(
+        long,
+        value_parser,
+        value_name = "swapfile:SIZE",
+        conflicts_with = "mount-units"
+    )]
+    #[serde(default)]
+    pub(crate) swap: Option<swap::SwapSpec>,
+
+    /// Store the journal persistently, by creating `/var/log/journal` in the installed
+    /// system.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) persistent_journal: bool,
+
+    /// Write a journald.conf(5) drop-in setting `Storage=persistent` and this
+    /// additional setting of the `[Journal]` section; may be specified multiple times.
+    #[clap(
+        long,
+        value_parser,
+        value_name = "KEY=VALUE",
+        requires = "persistent-journal"
+    )]
+    #[serde(default)]
+    pub(crate) journald_conf: Vec<journal::JournaldSetting>,
+
+    /// Configure this IPv4 or IPv6 nameserver in the installed system, for networks
+    /// without DHCP; may be specified multiple times.
+    ///
+    /// If the image's `/etc/resolv.conf` links to systemd-resolved, a resolved.conf(5)
+    /// drop-in is written; otherwise `/etc/resolv.conf` is replaced, which supports at
+    /// most three nameservers.
+    #[clap(long, value_name = "ADDRESS")]
+    #[serde(default)]
+    pub(crate) dns: Vec<std::net::IpAddr>,
+
+    /// Configure this DNS search domain along with `--dns`; may be specified multiple
+    /// times.
+    #[clap(long, value_parser, value_name = "DOMAIN", requires = "dns")]
+    #[serde(default)]
+    pub(crate) dns_search: Vec<dns::SearchDomain>,
+
+    /// Use the running image from an OCI directory created by `bootc install-export-source`,
+    /// instead of fetching it from container storage.  The path is in the host's mount
+    /// namespace.
+    #[clap(long, value_name = "DIR")]
+    #[serde(default)]
+    pub(crate) source_oci_dir: Option<Utf8PathBuf>,
+
+    /// Use the running image from an OCI archive, e.g. on removable media, instead of
+    /// fetching it from container storage.  The archive must contain only this image;
+    /// the path is in the host's mount namespace.
+    #[clap(long, value_name = "PATH", conflicts_with = "source-oci-dir")]
+    #[serde(default)]
+    pub(crate) source_oci_archive: Option<Utf8PathBuf>,
+
+    /// Verify the `--source-oci-archive` against this checksum before importing it, to
+    /// catch corrupt media early; formatted as <type>-<hexvalue>, where <type> is
+    /// sha256 or sha512.
+    #[clap(
+        long,
+        value_name = "digest",
+        value_parser,
+        requires = "source-oci-archive"
+    )]
+    #[serde(default)]
+    pub(crate) source_checksum: Option<crate::ignition::IgnitionHash>,
+
+    /// The reference to the running image in the host's container storage.  By default
+    /// this is found via `/run/.containerenv`, or podman if that lacks it.
+    #[clap(long, value_name = "IMAGE")]
+    #[serde(default)]
+    pub(crate) source_imgref: Option<String>,
+
+    /// Allow the architecture of the source image or host to differ from that of this
+    /// installer; only supported when creating a disk image for a different architecture
+    /// which has a known partition layout (x86_64, aarch64).
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) skip_arch_check: bool,
+
+    /// Fail if the deployed image has properties which predict problems at upgrade
+    /// time, rather than only warning: if updates are fetched without signature
+    /// verification, or the image has uncompressed layers.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) strict: bool,
+
+    /// How to specify the /boot filesystem in the `boot=` kernel argument used by FIPS
+    /// checks in the initramfs.
+    #[clap(long, value_enum, default_value_t)]
+    #[serde(default)]
+    pub(crate) boot_karg_by: BootKargBy,
+
+    /// Where the EFI system partition is mounted in the installed system: `boot-efi` for
+    /// `/boot/efi`, or `efi` for `/efi`.  This is used for the generated fstab entry or
+    /// mount unit, and the bootloader is installed to the ESP at this location.  With
+    /// `efi`, the mountpoint is created in the deployment if the image lacks it.
+    #[clap(long, value_enum, default_value_t)]
+    #[serde(default)]
+    pub(crate) esp_mountpoint: EspMountpoint,
+
+    /// Also write the aleph, which records the image and kernel initially installed, to
+    /// this path relative to the deployment root (e.g. `etc/inventory/aleph.json`).  It
+    /// is always written as JSON to `.bootc-aleph.json` in the root filesystem, or
+    /// with `--adopt-existing-sysroot` in the directory of the new stateroot, which is
+    /// what `--require-existing-image` checks.
+    #[clap(long, value_parser, value_name = "PATH")]
+    #[serde(default)]
+    pub(crate) aleph_path: Option<aleph::AlephPath>,
+
+    /// The format of the aleph written to `--aleph-path`.
+    #[clap(long, value_enum, default_value_t, requires = "aleph-path")]
+    #[serde(default)]
+    pub(crate) aleph_format: aleph::AlephFormat,
+
+    /// Do not write `/etc/bootc-version`, containing the target image and digest.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) no_version_file: bool,
+
+    /// The layer compression to use when copying the image to a temporary OCI directory
+    /// (with skopeo versions too old to read directly from container storage).  Defaults
+    /// to skopeo's default; zstd requires skopeo 1.2 or newer.
+    #[clap(long, value_enum)]
+    #[serde(default)]
+    pub(crate) compression: Option<Compression>,
+
+    /// When the image is pulled from its registry, pull it into the host's container
+    /// storage first and keep it there after the installation, so that later
+    /// installations and updates of the host reuse its layers.  The container storage
+    /// must be writable.  Images read directly from container storage are always left
+    /// there, and copies made in an OCI directory are always removed.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) keep_cached_layers: bool,
+
+    /// The directory in which to copy the image when falling back to an OCI directory,
+    /// instead of `/var/tmp`.  As skopeo runs on the host, this must be the same path on
+    /// the host, e.g. via `-v /srv/tmp:/srv/tmp`, and writable there.  Without it, if
+    /// `/var/tmp` is not writable on the host, the image is copied to
+    /// `/run/bootc/<pid>`, which may be in memory.
+    #[clap(long, value_name = "PATH")]
+    #[serde(default)]
+    pub(crate) tempdir: Option<Utf8PathBuf>,
+
+    /// Fetch the image into an OCI directory in `--tempdir` (or with `--zstd-chunked` or
+    /// `--keep-cached-layers`, into the host's container storage) before partitioning or wiping anything, so that
+    /// failing to fetch it leaves the target untouched.  This only applies if the image
+    /// is pulled from its registry or copied anyway, rather than read directly from
+    /// container storage.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) pull_first: bool,
+
+    /// When the image is pulled from its registry, pull it into the host's container
+    /// storage first rather than fetching its layers in full; zstd:chunked layers are
+    /// then fetched partially, reusing the files already in the storage, if partial
+    /// pulls are enabled in its `storage.conf`.  The image is removed from the storage
+    /// after the installation, unless `--keep-cached-layers` is specified.  The
+    /// container storage must be writable.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) zstd_chunked: bool,
+
+    /// Save the image's SBOM to `/etc/bootc/sbom.json`, from an SBOM attached to the
+    /// image in the registry by cosign, or otherwise a JSON file embedded in the image
+    /// in `/usr/share/sbom` or `/usr/share/buildinfo`.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) save_sbom: bool,
+
+    /// Atomically write metrics such as whether the installation succeeded, the duration
+    /// of each phase and the installed image's digest to this path, in the OpenMetrics
+    /// text format used by e.g. the node_exporter textfile collector.
+    #[clap(long, value_name = "PATH")]
+    #[serde(default)]
+    pub(crate) metrics_file: Option<Utf8PathBuf>,
+
+    /// Before wiping, require that the target contains a previous installation of an
+    /// image matching this shell-style glob, e.g. `quay.io/example/os*`, according to
+    /// its `.bootc-aleph.json`.  Requires `--wipe`.
+    #[clap(long, value_name = "GLOB")]
+    #[serde(default)]
+    pub(crate) require_existing_image: Option<String>,
+
+    /// Wipe the target even if it does not contain an installation matching
+    /// `--require-existing-image`.
+    #[clap(long, requires = "require-existing-image")]
+    #[serde(default)]
+    pub(crate) force: bool,
+
+    /// The minimum free space ostree keeps on the root filesystem, refusing to write
+    /// updates which would use it; either a percentage of the filesystem (e.g. `3%`)
+    /// or a size (default specifier: M), e.g. `500M` or `2G`.
+    #[clap(long, value_parser, value_name = "PERCENT%|SIZE")]
+    #[serde(default)]
+    pub(crate) min_free_space: Option<MinFreeSpace>,
+
+    /// Remove repeated kernel arguments before deploying, and for keys which take a
+    /// single value (such as `root` and `rootflags`) keep only the last one, warning
+    /// about any which are overridden.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) normalize_kargs: bool,
+
+    /// An additional kernel argument key which takes a single value, for
+    /// `--normalize-kargs`; may be specified multiple times.
+    #[clap(long, value_name = "KEY", requires = "normalize-kargs")]
+    #[serde(default)]
+    pub(crate) single_valued_karg: Vec<String>,
+
+    /// After creating the deployment, mount the target filesystems and `/proc`, `/sys`
+    /// and `/dev` into it and run an interactive shell chrooted into it, instead of
+    /// finalizing the installation.  For debugging images; the mounts are cleaned up
+    /// when the shell exits, and the installation is left incomplete.
+    /// `BOOTC_TARGET_DEPLOYMENT` is set to the path at which this installation mounts
+    /// the deployment on the host, under `/run/bootc/<pid>`.
+    #[clap(long, conflicts_with = "print-env")]
+    #[serde(default)]
+    pub(crate) inspect_shell: bool,
+}
+
+/// Keys which may be set via `--ostree-config` without `--ostree-config-unsafe`.
+const OSTREE_CONFIG_ALLOWED: &[&str] = &[
+    "core.min-free-space-percent",
+    "core.min-free-space-size",
+    "core.fsync",
+    "core.per-object-fsync",
+    "core.payload-link-threshold",
+    "sysroot.bootprefix",
+];
+
+/// The ostree config keys for the minimum free space in the repository.
+const OSTREE_MIN_FREE_SPACE_PERCENT: &str = "core.min-free-space-percent";
+const OSTREE_MIN_FREE_SPACE_SIZE: &str = "core.min-free-space-size";
+
+/// The minimum free space to keep on the root filesystem, enforced by ostree.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, DeserializeFromStr, SerializeDisplay)]
+pub(crate) enum MinFreeSpace {
+    /// A percentage of the filesystem size
+    Percent(u8),
+    /// An absolute size, in MiB
+    Size(u64),
+}
+
+impl FromStr for MinFreeSpace {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(v) = s.strip_suffix('%') {
+            let v = v
+                .parse::<u8>()
+                .ok()
+                .filter(|&v| v <= 99)
+                .ok_or_else(|| anyhow!("Invalid percentage {s}: expected 0% to 99%"))?;
+            Ok(Self::Percent(v))
+        } else {
+            let v = crate::blockdev::parse_size_mib(s)
+                .with_context(|| format!("Invalid size {s}: expected e.g. 10% or 500M"))?;
+            Ok(Self::Size(v))
+        }
+    }
+}
+
+impl std::fmt::Display for MinFreeSpace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Percent(v) => write!(f, "{v}%"),
+            Self::Size(v) => write!(f, "{v}M"),
+        }
+    }
+}
+
+impl MinFreeSpace {
+    /// The ostree config key and value.
+    fn ostree_config(&self) -> (&'static str, String) {
+        match self {
+            Self::Percent(v) => (OSTREE_MIN_FREE_SPACE_PERCENT, v.to_string()),
+            // ostree uses binary units
+            Self::Size(v) => (OSTREE_MIN_FREE_SPACE_SIZE, format!("{v}MB")),
+        }
+    }
+}
+
+/// Keys which are always set by the installer, and hence may not be overridden.
+const OSTREE_CONFIG_MANAGED: &[&str] = &["sysroot.bootloader", "sysroot.readonly"];
+
+/// Kernel arguments used for the rescue boot entry by default
+const RESCUE_KARGS_DEFAULT: &[&str] = &["systemd.unit=rescue.target"];
+
+/// Keys of kernel arguments of the running system which are not inherited by
+/// `--inherit-kargs`, as they describe its root, boot or installation
+const INHERIT_KARGS_EXCLUDED: &[&str] = &[
+    "BOOT_IMAGE",
+    "initrd",
+    "root",
+    "rootflags",
+    "rootfstype",
+    "ro",
+    "rw",
+    "boot",
+    "ostree",
+    "resume",
+    "ip",
+];
+/// Prefixes of keys which are not inherited, as for [`INHERIT_KARGS_EXCLUDED`]
+const INHERIT_KARGS_EXCLUDED_PREFIXES: &[&str] = &[
+    "rd.live.",
+    "rd.luks.",
+    "rd.lvm.",
+    "rd.md.",
+    "inst.",
+    "ignition.",
+    "coreos.inst.",
+];
+
+/// Split a kernel command line into arguments; double quotes may be used to include
+/// spaces in an argument.
+fn split_cmdline(cmdline: &str) -> Vec<String> {
+    let mut r = Vec::new();
+    let mut cur = String::new();
+    let mut quoted = false;
+    for c in cmdline.trim().chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                cur.push(c);
+            }
+            c if c.is_ascii_whitespace() && !quoted => {
+                if !cur.is_empty() {
+                    r.push(std::mem::take(&mut cur));
+                }
+            }
+            c => cur.push(c),
+        }
+    }
+    if !cur.is_empty() {
+        r.push(cur);
+    }
+    r
+}
+
+/// The key of a kernel argument, i.e. the part before any `=`.
+fn karg_key(karg: &str) -> &str {
+    karg.split_once('=').map(|(k, _)| k).unwrap_or(karg)
+}
+
+/// The kernel arguments of the provided command line to carry into the installation.
+fn inheritable_kargs(cmdline: &str) -> Vec<String> {
+    split_cmdline(cmdline)
+        .into_iter()
+        .filter(|karg| {
+            let key = karg_key(karg);
+            !INHERIT_KARGS_EXCLUDED.contains(&key)
+                && !INHERIT_KARGS_EXCLUDED_PREFIXES
+                    .iter()
+                    .any(|p| key.starts_with(p))
+        })
+        .collect()
+}
+
+/// Kernel argument keys which may legitimately be given several times with different
+/// values
+const REPEATABLE_KARGS: &[&str] = &[
+    "console",
+    "ip",
+    "nameserver",
+    "rd.luks.uuid",
+    "rd.luks.name",
+    "rd.lvm.lv",
+    "rd.md.uuid",
+    "rd.dasd",
+    "rd.zfcp",
+    "rd.znet",
+];
+
+/// How a kernel argument from the options is added to the existing ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KargMode {
+    /// `--karg`: add it, failing if its key has another value, unless repeatable
+    Append,
+    /// `--karg-replace`: replace all arguments with its key
+    Replace,
+    /// `--karg-append-if-missing`: add it unless there is an argument with its key
+    AppendIfMissing,
+}
+
+/// Add a kernel argument to the provided ones according to the mode, comparing
+/// arguments by key.
+fn apply_karg(kargs: &mut Vec<String>, karg: &str, mode: KargMode) -> Result<()> {
+    let key = karg_key(karg);
+    let first = kargs.iter().position(|k| karg_key(k) == key);
+    match (mode, first) {
+        (_, None) => kargs.push(karg.to_string()),
+        (KargMode::Append, Some(_)) if kargs.iter().any(|k| k == karg) => {}
+        (KargMode::Append, Some(_)) if REPEATABLE_KARGS.contains(&key) => {
+            kargs.push(karg.to_string())
+        }
+        (KargMode::Append, Some(i)) => anyhow::bail!(
+            "Kernel argument {karg} conflicts with {}; use --karg-replace to replace it",
+            kargs[i]
+        ),
+        // The first one is replaced in place, keeping the order
+        (KargMode::Replace, Some(i)) => {
+            let rest = kargs.split_off(i + 1);
+            kargs[i] = karg.to_string();
+            kargs.extend(rest.into_iter().filter(|k| karg_key(k) != key));
+        }
+        (KargMode::AppendIfMissing, Some(_)) => {}
+    }
+    Ok(())
+}
+
+/// The kernel argument key added by ostree itself when deploying
+const OSTREE_KARG: &str = "ostree";
+/// Kernel argument keys required to boot an ostree deployment; all but `ostree=` are
+/// added by the installer.
+const OSTREE_REQUIRED_KARGS: &[&str] = &["root", OSTREE_KARG];
+
+/// Reject `--karg-delete` of kernel arguments required by ostree.
+fn check_karg_delete(karg_delete: &[String]) -> Result<()> {
+    for karg in karg_delete {
+        if OSTREE_REQUIRED_KARGS.contains(&karg_key(karg)) {
+            anyhow::bail!("Cannot delete kernel argument {karg}: it is required by ostree");
+        }
+    }
+    Ok(())
+}
+
+/// Verify that the final kernel arguments, after all user changes, still allow ostree
+/// to boot the deployment: `root=` must be present, and `ostree=` must be left to ostree.
+#[context("Validating kernel arguments")]
+fn validate_required_kargs(kargs: &[String]) -> Result<()> {
+    if let Some(karg) = kargs.iter().find(|k| karg_key(k) == OSTREE_KARG) {
+        anyhow::bail!("Kernel argument {karg} conflicts with the one set by ostree");
+    }
+    for &key in OSTREE_REQUIRED_KARGS.iter().filter(|&&k| k != OSTREE_KARG) {
+        let found = kargs
+            .iter()
+            .filter_map(|k| k.split_once('='))
+            .any(|(k, v)| k == key && !v.is_empty());
+        if !found {
+            anyhow::bail!("Missing required kernel argument {key}=");
+        }
+    }
+    Ok(())
+}
+
+/// Write the final kernel arguments to `path`, one per line; everything which needs them
+/// after this reads them back with [`read_kargs_file`], so they can't diverge.
+#[context("Writing kernel arguments to {path}")]
+fn write_kargs_file(path: &Utf8Path, kargs: &[String]) -> Result<()> {
+    if let Some(karg) = kargs.iter().find(|k| k.is_empty() || k.contains('\n')) {
+        anyhow::bail!("Invalid kernel argument {karg:?}");
+    }
+    // SAFETY: The path has a parent
+    let dir = path.parent().unwrap();
+    std::fs::create_dir_all(dir).with_context(|| format!("Creating {dir}"))?;
+    let contents = kargs.iter().map(|k| format!("{k}\n")).collect::<String>();
+    std::fs::write(path, contents)?;
+    Ok(())
+}
+
+/// Read the kernel arguments written by [`write_kargs_file`].
+#[context("Reading kernel arguments from {path}")]
+fn read_kargs_file(path: &Utf8Path) -> Result<Vec<String>> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(contents.lines().map(ToOwned::to_owned).collect())
+}
+
+/// Kernel argument keys of which only the last occurrence is used, for `--normalize-kargs`
+const SINGLE_VALUED_KARGS: &[&str] = &["root", "rootflags", "rootfstype", "boot"];
+
+/// How the `/boot` filesystem is specified in the `boot=` kernel argument.
+#[derive(clap::ValueEnum, Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum BootKargBy {
+    #[default]
+    Uuid,
+    Label,
+}
+
+/// Where the EFI system partition is mounted.
+#[derive(clap::ValueEnum, Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum EspMountpoint {
+    /// `/boot/efi`, on the /boot filesystem
+    #[default]
+    BootEfi,
+    /// `/efi`, on the root filesystem
+    Efi,
+}
+
+impl EspMountpoint {
+    /// The mountpoint in the installed system.
+    pub(crate) fn path(self) -> &'static str {
+        match self {
+            Self::BootEfi => "/boot/efi",
+            Self::Efi => "/efi",
+        }
+    }
+
+    /// The mountpoint, relative to the root.
+    pub(crate) fn relpath(self) -> &'static str {
+        self.path().trim_start_matches('/')
+    }
+}
+
+/// How far `install-to-filesystem` goes.
+#[derive(clap::ValueEnum, Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum InstallStage {
+    /// Perform the whole installation
+    #[default]
+    Full,
+    /// Stop after deploying and configuring the image, without installing the
+    /// bootloader, setting the immutable bit or finalizing the filesystems
+    DeployOnly,
+}
+
+/// The SELinux mode of the target system.
+#[derive(clap::ValueEnum, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum SelinuxMode {
+    /// Enforce the policy
+    Enforcing,
+    /// Only log policy violations
+    Permissive,
+}
+
+impl SelinuxMode {
+    /// The value of `SELINUX=` in `/etc/selinux/config`.
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Enforcing => "enforcing",
+            Self::Permissive => "permissive",
+        }
+    }
+}
+
+/// The SELinux mode to write into the target's configuration, if any: `disabled` if
+/// SELinux was overridden to be disabled, and otherwise the requested one.
+fn target_selinux_mode(
+    override_disable_selinux: bool,
+    requested: Option<SelinuxMode>,
+) -> Option<&'static str> {
+    if override_disable_selinux {
+        Some("disabled")
+    } else {
+        requested.map(SelinuxMode::as_str)
+    }
+}
+
+/// Generate the `boot=` kernel argument for the provided `/boot` mount, whose filesystem
+/// label is `label`.
+fn boot_karg(by: BootKargBy, boot: &MountSpec, label: Option<&str>) -> Result<String> {
+    match by {
+        BootKargBy::Uuid => Ok(format!("boot={}", boot.source)),
+        BootKargBy::Label => {
+            let label = label
+                .filter(|l| !l.is_empty())
+                .ok_or_else(|| anyhow!("No filesystem label found for /{BOOT}"))?;
+            Ok(format!("boot=LABEL={label}"))
+        }
+    }
+}
+
+/// The layout of the ostree physical root filesystem.
+#[derive(clap::ValueEnum, Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum SysrootLayout {
+    #[default]
+    Modern,
+    Legacy,
+}
+
+impl SysrootLayout {
+    /// Arguments for `ostree admin init-fs` to create this layout.
+    fn init_fs_args(self) -> &'static [&'static str] {
+        match self {
+            Self::Modern => &["--modern"],
+            Self::Legacy => &[],
+        }
+    }
+}
+
+/// Verify the available ostree supports the requested layout.
+#[context("Checking ostree support for sysroot layout")]
+fn ensure_sysroot_layout_supported(layout: SysrootLayout) -> Result<()> {
+    let help = Task::new("Querying ostree", "ostree")
+        .args(["admin", "init-fs", "--help"])
+        .quiet()
+        .read()?;
+    for arg in layout.init_fs_args() {
+        if !help.contains(arg) {
+            anyhow::bail!("The installed ostree does not support init-fs {arg}");
+        }
+    }
+    Ok(())
+}
+
+/// How strictly the physical root filesystem is locked down.
+#[derive(Debug, PartialEq, Eq)]
+struct RootMutability {
+    /// Set the immutable bit (`chattr +i`) on the root directory
+    immutable_bit: bool,
+    /// Set `sysroot.readonly` in the ostree repo config
+    sysroot_readonly: bool,
+}
+
+impl InstallConfigOpts {
+    fn root_mutability(&self) -> RootMutability {
+        let locked = !self.dev_mutable;
+        RootMutability {
+            immutable_bit: locked,
+            sysroot_readonly: locked,
+        }
+    }
+}
+
+impl InstallConfigOpts {
+    /// Parse and validate the `--ostree-config` options.
+    fn ostree_config(&self) -> Result<BTreeMap<String, String>> {
+        let mut r = BTreeMap::new();
+        for kv in self.ostree_config.iter() {
+            let (k, v) = kv
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid ostree config {kv}: expected KEY=VALUE"))?;
+            let valid_key = k
+                .split_once('.')
+                .map(|(group, name)| !group.is_empty() && !name.is_empty())
+                .unwrap_or(false);
+            if !valid_key {
+                anyhow::bail!("Invalid ostree config key {k}: expected <group>.<name>");
+            }
+            if OSTREE_CONFIG_MANAGED.contains(&k) {
+                anyhow::bail!("The ostree config key {k} is managed by the installer");
+            }
+            if !self.ostree_config_unsafe && !OSTREE_CONFIG_ALLOWED.contains(&k) {
+                anyhow::bail!(
+                    "Unsupported ostree config key {k} (use --ostree-config-unsafe to override)"
+                );
+            }
+            r.insert(k.to_string(), v.to_string());
+        }
+        if let Some(min_free_space) = self.min_free_space {
+            if [OSTREE_MIN_FREE_SPACE_PERCENT, OSTREE_MIN_FREE_SPACE_SIZE]
+                .iter()
+                .any(|&k| r.contains_key(k))
+            {
+                anyhow::bail!(
+                    "--min-free-space conflicts with --ostree-config core.min-free-space-*"
+                );
+            }
+            let (k, v) = min_free_space.ostree_config();
+            r.insert(k.to_string(), v);
+        }
+        Ok(r)
+    }
+}
+
+/// How the mounts created during installation are isolated.
+#[derive(Debug, PartialEq, Eq)]
+struct NamespaceSetup {
+    /// Enter a private mount namespace
+    unshare: bool,
+    /// Mount a tmpfs on /tmp and the host's /var/tmp over ours
+    private_mounts: bool,
+}
+
+impl NamespaceSetup {
+    /// Compute the setup; the `BOOTC_SKIP_UNSHARE` environment variable (used for testing)
+    /// only skips entering the namespace.
+    fn new(no_unshare: bool, skip_unshare_env: bool) -> Self {
+        Self {
+            unshare: !(no_unshare || skip_unshare_env),
+            private_mounts: !no_unshare,
+        }
+    }
+}
+
+/// Perform an installation to a block device.
+#[derive(Debug, Clone, clap::Parser, Serialize, Deserialize)]
+pub(crate) struct InstallOpts {
+    #[clap(flatten)]
+    #[serde(flatten)]
+    pub(crate) block_opts: InstallBlockDeviceOpts,
+
+    #[clap(flatten)]
+    #[serde(flatten)]
+    pub(crate) target_opts: InstallTargetOpts,
+
+    #[clap(flatten)]
+    #[serde(flatten)]
+    pub(crate) config_opts: InstallConfigOpts,
+
+    /// Boot into the installed system once the installation is complete; `kexec` boots
+    /// the installed kernel directly without going through the firmware, and reboots
+    /// instead if that is not possible.  Not supported when installing to a loopback
+    /// device.
+    #[clap(long, value_enum, default_value_t)]
+    #[serde(default)]
+    pub(crate) post_install: postinstall::PostInstall,
+}
+
+/// Export the running image to an OCI directory for use with `--source-oci-dir`.
+#[derive(Debug, Clone, clap::Parser, Serialize, Deserialize)]
+pub(crate) struct InstallExportSourceOpts {
+    /// The directory to create, in the host's mount namespace
+    pub(crate) dir: Utf8PathBuf,
+
+    /// The number of layers to copy in parallel
+    #[clap(long, value_name = "N")]
+    #[serde(default)]
+    pub(crate) copy_concurrency: Option<u32>,
+
+    /// The reference to the running image in the host's container storage
+    #[clap(long, value_name = "IMAGE")]
+    #[serde(default)]
+    pub(crate) source_imgref: Option<String>,
+}
+
+/// Options for installing to a filesystem
+#[derive(Debug, Clone, clap::Args)]
+pub(crate) struct InstallTargetFilesystemOpts {
+    /// Path to the mounted root filesystem.
+    ///
+    /// By default, the filesystem UUID will be discovered and used for mounting.
+    /// To override this, use `--root-mount-spec`.
+    pub(crate) root_path: Utf8PathBuf,
+
+    /// Source device specification for the root filesystem.  For example, UUID=2e9f4241-229b-4202-8429-62d2302382e1
+    #[clap(long)]
+    pub(crate) root_mount_spec: Option<String>,
+
+    /// Comma-separated mount options for the root filesystem.  For example: rw,prjquota
+    #[clap(long)]
+    pub(crate) root_options: Option<String>,
+
+    /// Mount specification for the /boot filesystem.
+    ///
+    /// At the current time, a separate /boot is required.  This restriction will be lifted in
+    /// future versions.  If not specified, the filesystem UUID will be used.
+    #[clap(long)]
+    pub(crate) boot_mount_spec: Option<String>,
+
+    /// Automatically wipe existing data on the filesystems.
+    #[clap(long)]
+    pub(crate) wipe: bool,
+
+    /// Reuse an existing EFI system partition which is not mounted at the
+    /// `--esp-mountpoint`.
+    ///
+    /// With `auto`, the partitions of the device backing the root filesystem are
+    /// scanned for an ESP; alternatively, the ESP device can be given directly.
+    /// The ESP is mounted for the installation, and an fstab entry for it
+    /// is generated.  Existing content (such as other operating systems' boot
+    /// loaders) on the ESP is preserved.
+    #[clap(long, default_value = "no", value_name = "auto|no|DEVICE")]
+    pub(crate) reuse_esp: ReuseEsp,
+
+    /// Operate in the caller's mount namespace instead of entering a private one.
+    ///
+    /// Use this if the target filesystems were mounted in a way that would not be
+    /// visible from a new mount namespace.  Note that in this mode any mounts bootc
+    /// creates (e.g. for `--reuse-esp`) are visible to the caller, and may be left
+    /// behind if the installation fails.  The host's `/var/tmp` is not mounted over
+    /// the container's, so a skopeo version supporting `containers-storage` is required,
+    /// and `/tmp` is used as is; it must support SELinux labels.
+    #[clap(long)]
+    pub(crate) no_unshare: bool,
+
+    /// If /boot is not a separate mounted filesystem, find the boot partition on the
+    /// device backing the root filesystem and mount it.
+    ///
+    /// The boot partition is identified by the Linux extended boot partition type,
+    /// or a partition or filesystem label of `boot`.
+    #[clap(long)]
+    pub(crate) discover_boot: bool,
+
+    /// Carry the SSH host keys (`/etc/ssh/ssh_host_*`) forward into the new installation.
+    ///
+    /// With `root`, the keys are read from the existing contents of the root filesystem
+    /// before it is wiped (this requires `--wipe`); with `host`, they are read from the
+    /// live host system.  Private keys accessible by group or other are rejected.
+    #[clap(long, value_name = "root|host")]
+    pub(crate) preserve_ssh_host_keys: Option<SshHostKeySource>,
+
+    /// Carry `/etc/machine-id` forward from the existing contents of the root filesystem
+    /// into the new installation, keeping the identity of the machine.  Requires `--wipe`.
+    /// If there is no existing machine ID, a new one is generated on first boot.
+    #[clap(long, requires = "wipe")]
+    pub(crate) preserve_machine_id: bool,
+
+    /// How far to go: with `deploy-only`, stop after deploying and configuring the image,
+    /// leaving installing the bootloader and finalizing the filesystems to a later stage
+    /// of the pipeline.  Options acting on the installed bootloader cannot be used.  The kernel arguments and mounts are recorded in the
+    /// deployment, and the mounted filesystems are left writable.
+    #[clap(long, value_enum, default_value_t)]
+    pub(crate) stage: InstallStage,
+
+    /// Install beside an existing ostree system instead of requiring an empty root.
+    ///
+    /// The target must contain an ostree sysroot whose repository is usable as is; the
+    /// image is deployed into the new stateroot given by `--stateroot`, leaving the
+    /// existing stateroots, their `/var` and boot entries, and the installed bootloader
+    /// untouched.  The aleph data is written in the new stateroot's directory, and
+    /// options changing the bootloader configuration cannot be used.
+    #[clap(long, requires = "stateroot", conflicts_with = "wipe")]
+    pub(crate) adopt_existing_sysroot: bool,
+
+    /// The name of the new stateroot for `--adopt-existing-sysroot`.
+    #[clap(long, requires = "adopt-existing-sysroot")]
+    pub(crate) stateroot: Option<String>,
+
+    /// Prepare a root which is booted by an external mechanism, such as a RAM-backed,
+    /// NFS or overlay root for PXE or live systems.
+    ///
+    /// The root filesystem and its backing device are not inspected, so the root must be
+    /// given with `--root-mount-spec`, for example `root=live:http://10.0.0.1/rootfs.img`.
+    /// `/boot` need not be a separate filesystem, and is only added to the mounts of the
+    /// target with `--boot-mount-spec`.  No bootloader is installed.
+    #[clap(
+        long,
+        requires = "root-mount-spec",
+        conflicts_with_all = &["discover-boot", "adopt-existing-sysroot"]
+    )]
+    pub(crate) stateless: bool,
+}
+
+/// Find the boot partition amongst the partitions of the provided device, excluding
+/// the root filesystem's partition.
+fn find_boot_partition<'a>(
+    device: &'a crate::blockdev::Device,
+    root_source: &str,
+) -> Result<Option<&'a crate::blockdev::Device>> {
+    let is_boot = |d: &&crate::blockdev::Device| {
+        let typecode = d.parttype.as_deref().unwrap_or_default();
+        typecode.eq_ignore_ascii_case(baseline::XBOOTLDR_TYPECODE)
+            || d.partlabel.as_deref() == Some(BOOT)
+            || d.label.as_deref() == Some(BOOT)
+    };
+    let mut candidates = device
+        .children
+        .iter()
+        .flatten()
+        .filter(|d| d.path() != root_source)
+        .filter(is_boot);
+    let boot = if let Some(boot) = candidates.next() {
+        boot
+    } else {
+        return Ok(None);
+    };
+    if let Some(next) = candidates.next() {
+        anyhow::bail!(
+            "Found multiple boot partitions ({} and {})",
+            boot.path(),
+            next.path()
+        );
+    }
+    if boot.uuid.is_none() {
+        anyhow::bail!("No filesystem found on boot partition {}", boot.path());
+    }
+    Ok(Some(boot))
+}
+
+/// How to find an existing EFI system partition for install-to-filesystem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ReuseEsp {
+    /// Only use an ESP already mounted at the ESP mountpoint
+    No,
+    /// Scan the backing device for an ESP
+    Auto,
+    /// Use this device
+    Device(Utf8PathBuf),
+}
+
+impl FromStr for ReuseEsp {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "no" => Ok(Self::No),
+            "auto" => Ok(Self::Auto),
+            o if o.starts_with("/dev/") => Ok(Self::Device(o.into())),
+            o => anyhow::bail!("Invalid value {o}: expected auto, no or a device path"),
+        }
+    }
+}
+
+impl ReuseEsp {
+    /// Find the ESP to use amongst the partitions of the provided device.  A device
+    /// specified explicitly must be a vfat partition of it.
+    fn select(&self, device: &crate::blockdev::Device) -> Result<Option<String>> {
+        let is_vfat = |d: &&crate::blockdev::Device| d.fstype.as_deref() == Some("vfat");
+        let partitions = device.children.as_deref().unwrap_or_default();
+        match self {
+            Self::No => Ok(None),
+            Self::Auto => {
+                let mut esps = partitions.iter().filter(is_vfat).filter(|d| {
+                    d.parttype
+                        .as_deref()
+                        .map_or(false, |t| t.eq_ignore_ascii_case(baseline::ESP_TYPECODE))
+                });
+                let esp = if let Some(esp) = esps.next() {
+                    esp
+                } else {
+                    return Ok(None);
+                };
+                if let Some(next) = esps.next() {
+                    anyhow::bail!(
+                        "Found multiple EFI system partitions ({} and {}); specify one explicitly",
+                        esp.path(),
+                        next.path()
+                    );
+                }
+                Ok(Some(esp.path()))
+            }
+            Self::Device(path) => {
+                let esp = partitions
+                    .iter()
+                    .find(|d| d.path() == path.as_str())
+                    .ok_or_else(|| anyhow!("{path} is not a partition of {}", device.path()))?;
+                if !is_vfat(&esp) {
+                    anyhow::bail!("{path} does not contain a vfat filesystem");
+                }
+                Ok(Some(esp.path()))
+            }
+        }
+    }
+}
+
+/// Perform an installation to a mounted filesystem.
+#[derive(Debug, Clone, clap::Parser)]
+pub(crate) struct InstallToFilesystemOpts {
+    #[clap(flatten)]
+    pub(crate) filesystem_opts: InstallTargetFilesystemOpts,
+
+    #[clap(flatten)]
+    pub(crate) target_opts: InstallTargetOpts,
+
+    #[clap(flatten)]
+    pub(crate) config_opts: InstallConfigOpts,
+}
+
+// Shared read-only global state
+pub(crate) struct State {
+    /// Image reference we'll pull from (today always containers-storage: type)
+    source_imageref: ostree_container::ImageReference,
+    /// The digest to use for pulls
+    source_digest: String,
+    /// How the source image is read, unless from `--source-oci-dir` or
+    /// `--source-oci-archive`
+    source_strategy: Option<source::SourceStrategy>,
+    /// The source image fetched with `--pull-first`
+    prefetched_source: Option<FetchedSource>,
+    /// The bytes fetched for `prefetched_source`, see [`fetchstats`]
+    prefetched_bytes: u64,
+    /// How SELinux is overridden, as the host cannot label the target
+    selinux_override: Option<SelinuxOverride>,
+    config_opts: InstallConfigOpts,
+    target_opts: InstallTargetOpts,
+    /// Cancelled if the installation is interrupted
+    cancellable: gio::Cancellable,
+    /// Validated `--ostree-config` options
+    ostree_config: BTreeMap<String, String>,
+    /// Contents of the validated `--grub-config-fragment`
+    grub_config_fragment: Option<String>,
+    /// The validated `--install-policy` and `--install-sigstore-policy`
+    install_policy: sigpolicy::InstallPolicy,
+    /// Warnings found so far
+    diagnostics: Diagnostics,
+    /// The architecture of the source image, as in container images
+    target_arch: String,
+    /// Kernel arguments of the running system, with `--inherit-kargs`
+    inherited_kargs: Vec<String>,
+    /// The directory holding the state of this installation, see [`lock::process_dir`]
+    process_dir: Utf8PathBuf,
+}
+
+/// Path to initially deployed version information
+const BOOTC_ALEPH_PATH: &str = ".bootc-aleph.json";
+/// Path to the installed image information for inventory tools, relative to the deployment
+const BOOTC_VERSION_PATH: &str = "etc/bootc-version";
+
+/// The contents of `/etc/bootc-version`, in the shell-compatible format of os-release(5).
+fn version_file_contents(image: &str, digest: &str) -> String {
+    format!("IMAGE=\"{image}\"\nDIGEST=\"{digest}\"\n")
+}
+
+/// The "aleph" version information is injected into /root/.bootc-aleph.json
+/// and contains the image ID that was initially used to install.  This can
+/// be used to trace things like the specific version of `mkfs.ext4` or
+/// kernel version that was used.
+#[derive(Debug, Serialize)]
+struct InstallAleph {
+    /// Digested pull spec for installed image
+    image: String,
+    kernel: String,
+    /// Non-default ostree repository configuration
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    ostree_config: BTreeMap<String, String>,
+    /// SSH host keys carried forward from the previous installation
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    preserved_ssh_host_keys: Vec<String>,
+    /// The update stream selected via `--target-stream`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream: Option<String>,
+    /// The image updates are fetched from, as derived from the stream
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target_image: Option<String>,
+    /// Whether the target was installed without labels, to be relabeled on first boot
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    selinux_firstboot_relabel: bool,
+}
+
+/// The installation metadata written to the metadata partition
+const METADATA_ALEPH: &str = "aleph.json";
+const METADATA_KARGS: &str = "kargs";
+const METADATA_FSTAB: &str = "fstab";
+
+/// Write information about the installation to the metadata partition.
+#[context("Writing installation metadata")]
+fn write_metadata(dir: &Dir, aleph: &InstallAleph, kargs: &[String], fstab: &str) -> Result<()> {
+    dir.atomic_replace_with(METADATA_ALEPH, |f| {
+        serde_json::to_writer_pretty(f, aleph)?;
+        anyhow::Ok(())
+    })
+    .with_context(|| format!("Writing {METADATA_ALEPH}"))?;
+    dir.atomic_write(METADATA_KARGS, format!("{}\n", kargs.join(" ")))
+        .with_context(|| format!("Writing {METADATA_KARGS}"))?;
+    dir.atomic_write(METADATA_FSTAB, fstab)
+        .with_context(|| format!("Writing {METADATA_FSTAB}"))?;
+    Ok(())
+}
+
+/// The result of creating the initial ostree deployment.
+#[derive(Debug)]
+pub(crate) struct InitialDeployment {
+    aleph: InstallAleph,
+    /// Path to the deployment root, relative to the physical root
+    path: Utf8PathBuf,
+    /// The manifest digest of the deployed image
+    digest: String,
+    /// The SBOM attached to the image in the registry, if requested and found
+    sbom: Option<Vec<u8>>,
+    /// The total size of the image's layers
+    layer_bytes: u64,
+    /// The bytes fetched to deploy the image, see [`fetchstats`]
+    fetched_bytes: u64,
+    /// Warnings about the deployed image
+    warnings: Vec<deploycheck::DeployWarning>,
+}
+
+impl InitialDeployment {
+    /// The `/var` of the deployment's stateroot, which is shared between deployments
+    /// and mounted at `/var` on boot; state created there at install time (e.g. the
+    /// swapfile or persistent journal) is preserved across upgrades.
+    fn stateroot_var(&self, physical_root: &Utf8Path) -> Result<Utf8PathBuf> {
+        // The path is `ostree/deploy/STATEROOT/deploy/CHECKSUM.SERIAL`
+        self.path
+            .parent()
+            .and_then(|p| p.parent())
+            .map(|p| physical_root.join(p).join("var"))
+            .ok_or_else(|| anyhow!("Invalid deployment path {}", self.path))
+    }
+}
+
+/// A mount specification is a subset of a line in `/etc/fstab`.
+///
+/// There are up to 4 (ASCII) whitespace separated values:
+///
+/// SOURCE TARGET [FSTYPE [OPTIONS]]
+///
+/// Examples:
+///   - /dev/vda3 /boot ext4 ro
+///   - /dev/nvme0n1p4 /
+///   - /dev/sda2 /var/mnt xfs
+///   - UUID=2e9f4241 /var/data xfs nofail,x-systemd.device-timeout=10s
+#[derive(Debug, Clone, DeserializeFromStr, SerializeDisplay)]
+pub(crate) struct MountSpec {
+    pub(crate) source: String,
+    pub(crate) target: String,
+    pub(crate) fstype: String,
+    pub(crate) options: Option<String>,
+    /// Validated `x-systemd.*` options, emitted after `options`
+    pub(crate) systemd_options: Vec<String>,
+}
+
+/// Whether an `x-systemd.*` mount option takes a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SystemdOptionValue {
+    Required,
+    Optional,
+    Forbidden,
+}
+
+/// The `x-systemd.*` mount options understood by systemd-fstab-generator; see systemd.mount(5).
+const SYSTEMD_MOUNT_OPTIONS: &[(&str, SystemdOptionValue)] = &[
+    ("x-systemd.requires", SystemdOptionValue::Required),
+    ("x-systemd.before", SystemdOptionValue::Required),
+    ("x-systemd.after", SystemdOptionValue::Required),
+    ("x-systemd.wanted-by", SystemdOptionValue::Required),
+    ("x-systemd.required-by", SystemdOptionValue::Required),
+    (
+        "x-systemd.requires-mounts-for",
+        SystemdOptionValue::Required,
+    ),
+    ("x-systemd.device-bound", SystemdOptionValue::Optional),
+    ("x-systemd.automount", SystemdOptionValue::Forbidden),
+    ("x-systemd.idle-timeout", SystemdOptionValue::Required),
+    ("x-systemd.device-timeout", SystemdOptionValue::Required),
+    ("x-systemd.mount-timeout", SystemdOptionValue::Required),
+    ("x-systemd.makefs", SystemdOptionValue::Forbidden),
+    ("x-systemd.growfs", SystemdOptionValue::Forbidden),
+    ("x-systemd.pcrfs", SystemdOptionValue::Forbidden),
+    ("x-systemd.rw-only", SystemdOptionValue::Forbidden),
+];
+
+/// Verify that the provided option is a known `x-systemd.*` mount option with a valid value.
+fn validate_systemd_mount_option(opt: &str) -> Result<()> {
+    let (key, value) = match opt.split_once('=') {
+        Some((k, v)) => (k, Some(v)),
+        None => (opt, None),
+    };
+    let kind = SYSTEMD_MOUNT_OPTIONS
+        .iter()
+        .find_map(|(k, kind)| (*k == key).then_some(*kind))
+        .ok_or_else(|| anyhow!("Unknown systemd mount option {key}"))?;
+    match (kind, value) {
+        (SystemdOptionValue::Required, None) | (SystemdOptionValue::Required, Some("")) => {
+            anyhow::bail!("Mount option {key} requires a value")
+        }
+        (SystemdOptionValue::Forbidden, Some(_)) => {
+            anyhow::bail!("Mount option {key} does not take a value")
+        }
+        _ => Ok(()),
+    }
+}
+
+impl MountSpec {
+    const AUTO: &'static str = "auto";
+
+    pub(crate) fn new(src: &str, target: &str) -> Self {
+        MountSpec {
+            source: src.to_string(),
+            target: target.to_string(),
+            fstype: Self::AUTO.to_string(),
+            options: None,
+            systemd_options: Vec::new(),
+        }
+    }
+
+    /// Construct a mount for the EFI system partition.
+    pub(crate) fn new_esp(src: &str, mountpoint: EspMountpoint) -> Self {
+        MountSpec {
+            fstype: "vfat".to_string(),
+            options: Some("umask=0077,shortname=winnt".to_string()),
+            ..Self::new(src, mountpoint.path())
+        }
+    }
+
+    /// Construct a new mount that uses the provided uuid as a source.
+    pub(crate) fn new_uuid_src(uuid: &str, target: &str) -> Self {
+        Self::new(&format!("UUID={uuid}"), target)
+    }
+
+    pub(crate) fn get_source_uuid(&self) -> Option<&str> {
+        if let Some((t, rest)) = self.source.split_once('=') {
+            if t.eq_ignore_ascii_case("uuid") {
+                return Some(rest);
+            }
+        }
+        None
+    }
+
+    pub(crate) fn to_fstab(&self) -> String {
+        self.to_fstab_with_passno(0)
+    }
+
+    /// Like [`Self::to_fstab`], but with the provided fsck pass number.
+    pub(crate) fn to_fstab_with_passno(&self, passno: u32) -> String {
+        let options = self.fstab_options();
+        format!(
+            "{} {} {} {options} 0 {passno}",
+            self.source, self.target, self.fstype
+        )
+    }
+
+    /// The options field, including any systemd options.
+    fn fstab_options(&self) -> String {
+        let options = self.options.as_deref().unwrap_or("defaults");
+        std::iter::once(options)
+            .chain(self.systemd_options.iter().map(|s| s.as_str()))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+impl std::fmt::Display for MountSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            self.source,
+            self.target,
+            self.fstype,
+            self.fstab_options()
+        )
+    }
+}
+
+/// Escape a path for use in a systemd unit name, as `systemd-escape --path` does.
+fn systemd_escape_path(path: &str) -> String {
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        return "-".to_string();
+    }
+    let mut r = String::new();
+    for (i, b) in path.bytes().enumerate() {
+        match b {
+            b'/' => r.push('-'),
+            b'.' if i == 0 => r.push_str("\\x2e"),
+            b if b.is_ascii_alphanumeric() || matches!(b, b':' | b'_' | b'.') => r.push(b as char),
+            b => r.push_str(&format!("\\x{b:02x}")),
+        }
+    }
+    r
+}
+
+/// Where generated systemd units are written, relative to the deployment root
+const SYSTEMD_UNIT_DIR: &str = "etc/systemd/system";
+
+impl MountSpec {
+    /// The name of the systemd mount unit for this mount's target.
+    pub(crate) fn mount_unit_name(&self) -> String {
+        format!("{}.mount", systemd_escape_path(&self.target))
+    }
+
+    fn nofail(&self) -> bool {
+        self.options
+            .as_deref()
+            .map_or(false, |o| o.split(',').any(|o| o == "nofail"))
+    }
+
+    /// The target which pulls in this mount; like systemd-fstab-generator,
+    /// `nofail` mounts are only wanted rather than required.
+    fn mount_unit_install_dir(&self) -> &'static str {
+        if self.nofail() {
+            "local-fs.target.wants"
+        } else {
+            "local-fs.target.requires"
+        }
+    }
+
+    /// Generate the contents of a systemd mount unit; `parents` are the mounts
+    /// this one is nested under.
+    pub(crate) fn to_mount_unit(&self, parents: &[&MountSpec]) -> String {
+        let mut r = String::from("# Generated by bootc install\n[Unit]\n");
+        if !self.nofail() {
+            r.push_str("Before=local-fs.target\n");
+        }
+        for parent in parents {
+            let name = parent.mount_unit_name();
+            r.push_str(&format!("Requires={name}\nAfter={name}\n"));
+        }
+        r.push_str("\n[Mount]\n");
+        r.push_str(&format!(
+            "What={}\n",
+            crate::mount::source_device_path(&self.source)
+        ));
+        r.push_str(&format!("Where={}\n", self.target));
+        if self.fstype != Self::AUTO {
+            r.push_str(&format!("Type={}\n", self.fstype));
+        }
+        r.push_str(&format!("Options={}\n", self.fstab_options()));
+        r
+    }
+}
+
+/// A generated systemd mount unit.
+#[derive(Debug, PartialEq, Eq)]
+struct MountUnit {
+    name: String,
+    contents: String,
+    /// The `.wants` or `.requires` directory which enables the unit
+    install_dir: &'static str,
+}
+
+/// Compute the systemd mount units for the mounts which would otherwise be added
+/// to `/etc/fstab`.  The root is mounted by the initramfs, and so has no unit.
+fn mount_units(root_setup: &RootSetup, extra_mounts: &[MountSpec]) -> Vec<MountUnit> {
+    let extra_partitions = root_setup
+        .extra_partitions
+        .iter()
+        .map(|p| p.mount_spec())
+        .collect::<Vec<_>>();
+    let mounts = root_setup
+        .boot
+        .iter()
+        .chain(root_setup.esp.iter())
+        .chain(extra_partitions.iter())
+        .chain(extra_mounts.iter())
+        .collect::<Vec<_>>();
+    mounts
+        .iter()
+        .map(|m| {
+            let target = Utf8Path::new(&m.target);
+            let parents = mounts
+                .iter()
+                .filter(|p| p.target != m.target && target.starts_with(&p.target))
+                .copied()
+                .collect::<Vec<_>>();
+            MountUnit {
+                name: m.mount_unit_name(),
+                contents: m.to_mount_unit(&parents),
+                install_dir: m.mount_unit_install_dir(),
+            }
+        })
+        .collect()
+}
+
+/// Write the systemd mount units into the deployment, and enable them.
+#[context("Writing mount units")]
+fn write_mount_units(root: &Dir, units: &[MountUnit]) -> Result<()> {
+    root.create_dir_all(SYSTEMD_UNIT_DIR)?;
+    let unitdir = root.open_dir(SYSTEMD_UNIT_DIR)?;
+    for unit in units {
+        let name = unit.name.as_str();
+        unitdir
+            .atomic_write(name, &unit.contents)
+            .with_context(|| format!("Writing {name}"))?;
+        unitdir.create_dir_all(unit.install_dir)?;
+        let link = Utf8Path::new(unit.install_dir).join(name);
+        unitdir.remove_file_optional(&link)?;
+        unitdir
+            .symlink(format!("../{name}"), &link)
+            .with_context(|| format!("Enabling {name}"))?;
+    }
+    Ok(())
+}
+
+/// Compute the content to append to `/etc/fstab` in the target.  If the image
+/// ships an fstab, we only add `/boot` and the ESP; otherwise we generate a complete
+/// minimal one.
+fn fstab_append_contents(
+    existing: Option<&str>,
+    root_setup: &RootSetup,
+    extra_mounts: &[MountSpec],
+) -> String {
+    let mut r = String::new();
+    match existing {
+        Some(existing) => {
+            if !(existing.is_empty() || existing.ends_with('\n')) {
+                r.push('\n');
+            }
+            if let Some(boot) = root_setup.boot.as_ref() {
+                r.push_str(&boot.to_fstab());
+                r.push('\n');
+            }
+            // The image can't know about the ESP, e.g. one reused with --reuse-esp
+            if let Some(esp) = root_setup.esp.as_ref() {
+                r.push_str(&esp.to_fstab());
+                r.push('\n');
+            }
+        }
+        None => {
+            r.push_str("# /etc/fstab\n# Created by bootc install\n#\n");
+            // With --stateless, the root is mounted by the external boot mechanism
+            if root_setup.device.is_some() {
+                r.push_str(&root_setup.root.to_fstab_with_passno(1));
+                r.push('\n');
+            }
+            if let Some(boot) = root_setup.boot.as_ref() {
+                r.push_str(&boot.to_fstab_with_passno(2));
+                r.push('\n');
+            }
+            if let Some(esp) = root_setup.esp.as_ref() {
+                r.push_str(&esp.to_fstab_with_passno(2));
+                r.push('\n');
+            }
+        }
+    }
+    // The image can't know about these, so always add them
+    for part in root_setup.extra_partitions.iter() {
+        r.push_str(&part.mount_spec().to_fstab_with_passno(2));
+        r.push('\n');
+    }
+    for mount in extra_mounts {
+        r.push_str(&mount.to_fstab());
+        r.push('\n');
+    }
+    r
+}
+
+impl FromStr for MountSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split_ascii_whitespace().fuse();
+        let source = parts.next().unwrap_or_default();
+        if source.is_empty() {
+            anyhow::bail!("Invalid empty mount specification");
+        }
+        let target = parts
+            .next()
+            .ok_or_else(|| anyhow!("Missing target in mount specification {s}"))?;
+        let fstype = parts.next().unwrap_or(Self::AUTO);
+        let mut options = Vec::new();
+        let mut systemd_options = Vec::new();
+        for opt in parts.next().into_iter().flat_map(|o| o.split(',')) {
+            if opt.starts_with("x-systemd.") {
+                validate_systemd_mount_option(opt)?;
+                systemd_options.push(opt.to_owned());
+            } else if !opt.is_empty() {
+                options.push(opt);
+            }
+        }
+        if let Some(extra) = parts.next() {
+            anyhow::bail!("Unexpected {extra} in mount specification {s}");
+        }
+        let options = (!options.is_empty()).then(|| options.join(","));
+        Ok(Self {
+            source: source.to_string(),
+            fstype: fstype.to_string(),
+            target: target.to_string(),
+            options,
+            systemd_options,
+        })
+    }
+}
+
+/// Construct a command that runs `cmd` via the shell inside the deployment rooted at `root`.
+fn run_in_target_command(root: &Utf8Path, cmd: &str, nspawn: bool) -> std::process::Command {
+    let env = targetdeploy::TARGET_DEPLOYMENT_ENV;
+    let inner = targetdeploy::TARGET_DEPLOYMENT_IN_TARGET;
+    let mut c = if nspawn {
+        let mut c = std::process::Command::new("systemd-nspawn");
+        c.args(["--quiet", "--register=no", "--private-network", "-D"]);
+        c.arg(root.as_str());
+        // The environment isn't passed through
+        c.arg(format!("--setenv={env}={inner}"));
+        c.arg("--");
+        c
+    } else {
+        let mut c = std::process::Command::new("chroot");
+        c.env(env, inner);
+        c.arg(root.as_str());
+        c
+    };
+    c.args(["/bin/sh", "-c", cmd]);
+    c
+}
+
+/// Copy everything from `usr/etc` in the deployment root into `etc` which does not
+/// already exist there, preserving metadata and hard links as [`copy::copy_tree`]
+/// does.  The `label` callback is invoked for each copied path, along
+/// with its path in the booted system.  Returns the number of copied entries.
+fn seed_etc(
+    root: &Utf8Path,
+    mut label: impl FnMut(&Utf8Path, &Utf8Path) -> Result<()>,
+) -> Result<u64> {
+    copy::copy_tree(
+        &root.join("usr/etc"),
+        &root.join("etc"),
+        Utf8Path::new("/etc"),
+        &mut label,
+    )
+}
+
+/// Execute the user-provided commands inside the target deployment.
+#[context("Running commands in target")]
+fn run_in_target(root: &Utf8Path, cmds: &[String]) -> Result<()> {
+    if cmds.is_empty() {
+        return Ok(());
+    }
+    let nspawn = Utf8Path::new("/usr/bin/systemd-nspawn").exists();
+    tracing::debug!("Using systemd-nspawn: {nspawn}");
+    for cmd in cmds {
+        Task::new_cmd(
+            format!("Running in target: {cmd}"),
+            run_in_target_command(root, cmd, nspawn),
+        )
+        .run()?;
+    }
+    Ok(())
+}
+
+/// Construct a command running an interactive shell chrooted into the deployment
+/// mounted at `root`, which is passed to it as the path of this installation's target
+/// deployment on the host.
+fn run_shell_command(root: &Utf8Path) -> std::process::Command {
+    let mut c = std::process::Command::new("chroot");
+    c.env(targetdeploy::TARGET_DEPLOYMENT_ENV, root.as_str());
+    c.args([root.as_str(), "/bin/sh", "-l"]);
+    c
+}
+
+/// Run an interactive shell chrooted into the deployment rooted at `root`; its exit
+/// status is ignored.
+fn run_shell(root: &Utf8Path) -> Result<()> {
+    println!("Running a shell in {root}; exit it to clean up");
+    let st = run_shell_command(root).status().context("Spawning shell")?;
+    tracing::debug!("Shell exited: {st:?}");
+    Ok(())
+}
+
+/// The mounts made in the deployment for `--inspect-shell`, as the source and the
+/// target relative to the deployment root, in mount order.
+fn inspect_shell_mounts(rootfs: &Utf8Path) -> [(Utf8PathBuf, &'static str); 5] {
+    [
+        (rootfs.join(BOOT), BOOT),
+        (rootfs.to_owned(), "sysroot"),
+        ("/proc".into(), "proc"),
+        ("/sys".into(), "sys"),
+        ("/dev".into(), "dev"),
+    ]
+}
+
+/// Mount everything into the deployment and run a shell in it; the mounts are
+/// removed in reverse order afterwards, including if a mount or the shell failed.
+#[context("Running inspection shell")]
+fn inspect_shell(
+    ops: &dyn InstallOps,
+    rootfs: &Utf8Path,
+    deployment_root: &Utf8Path,
+) -> Result<()> {
+    let mut mounted = Vec::new();
+    let r = (|| {
+        for (src, dest) in inspect_shell_mounts(rootfs) {
+            let target = deployment_root.join(dest);
+            ops.bind_mount(&src, &target)?;
+            mounted.push(target);
+        }
+        ops.run_shell(deployment_root)
+    })();
+    for target in mounted.iter().rev() {
+        ops.unmount_recursive(target)?;
+    }
+    r
+}
+
+/// Options for [`bind_mount_from_host`].
+#[derive(Debug, Default, Clone, Copy)]
+struct BindMountOpts {
+    /// Make the bind mount read-only
+    readonly: bool,
+    /// Also bind mount any submounts (`--rbind`)
+    recursive: bool,
+    /// If the host path does not exist, print a warning and skip the mount
+    optional: bool,
+}
+
+/// Errors from [`bind_mount_from_host`].
+#[derive(Debug)]
+enum BindMountError {
+    /// The source path does not exist in the host mount namespace
+    HostPathMissing(Utf8PathBuf),
+    /// Invoking `mount` failed
+    MountFailed(anyhow::Error),
+}
+
+impl std::fmt::Display for BindMountError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::HostPathMissing(p) => write!(f, "Host path {p} does not exist"),
+            Self::MountFailed(e) => write!(f, "Bind mount failed: {e:#}"),
+        }
+    }
+}
+
+impl std::error::Error for BindMountError {}
+
+/// Arguments for `mount` to bind `src` from the mount namespace of the host to `dest` in the
+/// mount namespace of process `pid`.
+fn bind_mount_args(src: &Utf8Path, dest: &Utf8Path, pid: &str, opts: BindMountOpts) -> Vec<String> {
+    let (bind, private) = if opts.recursive {
+        ("--rbind", "--make-rprivate")
+    } else {
+        ("--bind", "--make-private")
+    };
+    // Always make the mount private; if we didn't unshare (BOOTC_SKIP_UNSHARE) it
+    // must not propagate back to the host.
+    let mut r = vec![bind.to_string(), private.to_string()];
+    if opts.readonly {
+        r.extend(["-o".to_string(), "ro".to_string()]);
+    }
+    r.extend(["-N", pid, src.as_str(), dest.as_str()].map(ToOwned::to_owned));
+    r
+}
+
+fn bind_mount_from_host(
+    src: impl AsRef<Utf8Path>,
+    dest: impl AsRef<Utf8Path>,
+    opts: BindMountOpts,
+    diagnostics: &Diagnostics,
+) -> std::result::Result<(), BindMountError> {
+    let src = src.as_ref();
+    let dest = dest.as_ref();
+    if !crate::utils::host_path_exists(src.as_str()) {
+        if opts.optional {
+            diagnostics.warn(format!(
+                "Host path {src} does not exist; skipping bind mount"
+            ));
+            return std::result::Result::Ok(());
+        }
+        return Err(BindMountError::HostPathMissing(src.to_owned()));
+    }
+    tracing::debug!("Mounting host {src} to {dest}");
+    std::fs::create_dir_all(dest)
+        .with_context(|| format!("Creating {dest}"))
+        .map_err(BindMountError::MountFailed)?;
+    // Here's the magic trick; modern versions of the `mount` command support a `-N` argument
+    // to perform the mount in a distinct target namespace.  But, what we want to is the inverse
+    // of this - we want to grab a host/root filesystem mount point.  So we explicitly enter
+    // the host's mount namespace, then give `mount` our own pid (from which it finds the mount namespace).
+    let desc = format!("Bind mounting {src} from host");
+    let target = format!("{}", nix::unistd::getpid());
+    Task::new_cmd(desc, run_in_host_mountns("mount"))
+        .quiet()
+        .args(bind_mount_args(src, dest, &target, opts))
+        .run()
+        .map_err(BindMountError::MountFailed)
+}
+
+/// Find the deployment named by `--default-boot-entry`, given the stateroot and full
+/// `STATEROOT/CHECKSUM.SERIAL` name of each deployment.  A stateroot name must
+/// match exactly one deployment.
+fn find_default_deployment(deployments: &[(String, String)], name: &str) -> Result<usize> {
+    if let Some(i) = deployments.iter().position(|(_, full)| full == name) {
+        return Ok(i);
+    }
+    let mut matching = deployments
+        .iter()
+        .enumerate()
+        .filter(|(_, (stateroot, _))| stateroot == name)
+        .map(|(i, _)| i);
+    match (matching.next(), matching.next()) {
+        (Some(i), None) => Ok(i),
+        (Some(_), Some(_)) => {
+            anyhow::bail!(
+                "Multiple deployments in stateroot {name}; specify STATEROOT/CHECKSUM.SERIAL"
+            )
+        }
+        (None, _) => {
+            let names = deployments
+                .iter()
+                .map(|(_, full)| full.as_str())
+                .collect::<Vec<_>>();
+            anyhow::bail!(
+                "No deployment matches {name}; created: {}",
+                names.join(", ")
+            )
+        }
+    }
+}
+
+/// Make the named deployment the bootloader default, by moving it first in the
+/// deployment list.
+#[context("Setting default deployment")]
+fn set_default_deployment(
+    sysroot: &ostree::Sysroot,
+    name: &str,
+    cancellable: Option<&gio::Cancellable>,
+) -> Result<()> {
+    let mut deployments = sysroot.deployments();
+    let names = deployments
+        .iter()
+        .map(|d| {
+            let stateroot = d.osname().map(|s| s.to_string()).unwrap_or_default();
+            let csum = d.csum().map(|s| s.to_string()).unwrap_or_default();
+            let full = format!("{stateroot}/{csum}.{}", d.deployserial());
+            (stateroot, full)
+        })
+        .collect::<Vec<_>>();
+    let i = find_default_deployment(&names, name)?;
+    if i == 0 {
+        return Ok(());
+    }
+    println!("Setting default deployment: {}", names[i].1);
+    let deployment = deployments.remove(i);
+    deployments.insert(0, deployment);
+    sysroot.write_deployments(&deployments, cancellable)?;
+    sysroot.load(cancellable)?;
+    Ok(())
+}
+
+/// The options for deploying the image, with the kernel arguments read from the
+/// kernel arguments file.
+fn deploy_opts<'a>(
+    kargs: &'a [&'a str],
+    target_imgref: &'a ostree_container::OstreeImageReference,
+    proxy_cfg: ostree_container::store::ImageProxyConfig,
+) -> ostree_container::deploy::DeployOpts<'a> {
+    #[allow(clippy::needless_update)]
+    ostree_container::deploy::DeployOpts {
+        kargs: Some(kargs),
+        target_imgref: Some(target_imgref),
+        proxy_cfg: Some(proxy_cfg),
+        ..Default::default()
+    }
+}
+
+/// Create the ostree layout in the empty root, and configure the repository.
+fn init_ostree_layout(
+    state: &State,
+    rootfs_dir: &Dir,
+    rootfs: &Utf8Path,
+    layout: SysrootLayout,
+) -> Result<()> {
+    Task::new("Initializing ostree layout", "ostree")
+        .args(["admin", "init-fs"])
+        .args(layout.init_fs_args())
+        .args([rootfs.as_str()])
+        .run()?;
+
+    let sysroot_readonly = state
+        .config_opts
+        .root_mutability()
+        .sysroot_readonly
+        .to_string();
+    let config = [
+        ("sysroot.bootloader", "none"),
+        ("sysroot.readonly", sysroot_readonly.as_str()),
+    ];
+    let extra_config = state
+        .ostree_config
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()));
+    for (k, v) in config.into_iter().chain(extra_config) {
+        Task::new("Configuring ostree repo", "ostree")
+            .args(["config", "--repo", "ostree/repo", "set", k, v])
+            .cwd(rootfs_dir)?
+            .quiet()
+            .run()?;
+    }
+    Ok(())
+}
+
+#[context("Creating ostree deployment")]
+async fn initialize_ostree_root_from_self(
+    ops: &dyn InstallOps,
+    state: &State,
+    root_setup: &RootSetup,
+) -> Result<InitialDeployment> {
+    let rootfs_dir = &root_setup.rootfs_fd;
+    let rootfs = root_setup.rootfs.as_path();
+    let opts = &state.target_opts;
+    let cancellable = Some(&state.cancellable);
+
+    // Parse the target CLI image reference options
+    let target_imgref = target_imgref_from_opts(opts, &state.source_imageref)?;
+    let stream = opts.target_stream.as_deref();
+    let selinux_firstboot_relabel =
+        state.selinux_override == Some(SelinuxOverride::FirstbootRelabel);
+
+    let stateroot = root_setup.stateroot();
+    let adopt = root_setup.adopt_stateroot.is_some();
+    let layout = state.config_opts.sysroot_layout;
+    let default_boot_entry = state.config_opts.default_boot_entry.clone();
+    let save_sbom = state.config_opts.save_sbom;
+    let strict = state.config_opts.strict;
+    let diagnostics = &state.diagnostics;
+    ensure_sysroot_layout_supported(layout)?;
+    let ostree_config = &state.ostree_config;
+    // When adopting an existing sysroot, its layout and repository configuration are kept
+    if !adopt {
+        init_ostree_layout(state, rootfs_dir, rootfs, layout)?;
+    }
+    Task::new("Initializing sysroot", "ostree")
+        .args(["admin", "os-init", stateroot, "--sysroot", "."])
+        .cwd(rootfs_dir)?
+        .run()?;
+
+    // Ensure everything in the ostree repo is labeled; when adopting, only the new
+    // stateroot, leaving the existing ones untouched
+    let label_root = if adopt {
+        rootfs.join("ostree/deploy").join(stateroot)
+    } else {
+        rootfs.join("ostree")
+    };
+    lsm_label(&label_root, "/usr".into(), true)?;
 
     let sysroot = ostree::Sysroot::new(Some(&gio::File::for_path(rootfs)));
     sysroot.load(cancellable)?;
 
-    // We need to fetch the container image from the root mount namespace
-    let skopeo_cmd = run_in_host_mountns("skopeo");
-    let proxy_cfg = ostree_container::store::ImageProxyConfig {
-        skopeo_cmd: Some(skopeo_cmd),
-        ..Default::default()
+    // We need to fetch the container image from the root mount namespace
+    let skopeo_cmd = run_in_host_mountns("skopeo");
+    let proxy_cfg = ostree_container::store::ImageProxyConfig {
+        skopeo_cmd: Some(skopeo_cmd),
+        ..Default::default()
+    };
+
+    // The bytes fetched for the image, excluding any fetched with --pull-first
+    let fetch_counter = fetchstats::FetchCounter::start()?;
+    let mut fetched = None;
+    let src_imageref = if let Some(dir) = state.config_opts.source_oci_dir.as_deref() {
+        exported_source_imgref(dir, &state.source_digest)
+    } else if let Some(archive) = state.config_opts.source_oci_archive.as_deref() {
+        ostree_container::ImageReference {
+            transport: ostree_container::Transport::OciArchive,
+            name: archive.to_string(),
+        }
+    } else if let Some(prefetched) = state.prefetched_source.as_ref() {
+        prefetched.imgref(&state.source_digest)
+    } else if let Some(source::SourceStrategy::OciCopy(reason)) = state.source_strategy.as_ref() {
+        let copy = copy_source(
+            ops,
+            &state.config_opts,
+            &state.source_imageref,
+            &state.source_digest,
+            Some(reason),
+            &state.process_dir,
+            &state.diagnostics,
+        )?;
+        let r = copy.imgref(&state.source_digest);
+        fetched = Some(FetchedSource::Copy(copy));
+        r
+    } else if let Some(source::SourceStrategy::Registry(spec)) = state.source_strategy.as_ref() {
+        if pull_into_storage(&state.config_opts) {
+            let pull = pull_source(ops, &state.config_opts, spec, diagnostics)?;
+            let r = pull.imgref();
+            fetched = Some(FetchedSource::Storage(pull));
+            r
+        } else {
+            println!("Pulling {spec} from the registry, fetching its layers in full");
+            ostree_container::ImageReference {
+                transport: ostree_container::Transport::Registry,
+                name: spec.clone(),
+            }
+        }
+    } else {
+        // We always use exactly the digest of the running image to ensure predictability.
+        let spec =
+            crate::utils::digested_pullspec(&state.source_imageref.name, &state.source_digest);
+        ostree_container::ImageReference {
+            transport: ostree_container::Transport::ContainerStorage,
+            name: spec,
+        }
+    };
+    let src_imageref = ostree_container::OstreeImageReference {
+        // There are no signatures to verify since we're fetching the already
+        // pulled container, by digest.
+        sigverify: ostree_container::SignatureSource::ContainerPolicyAllowInsecure,
+        imgref: src_imageref,
+    };
+
+    let kargs = read_kargs_file(&lock::kargs_file(&state.process_dir))?;
+    let kargs = kargs.iter().map(|v| v.as_str()).collect::<Vec<_>>();
+    let options = deploy_opts(&kargs, &target_imgref, proxy_cfg);
+    println!("Creating initial deployment");
+    let state =
+        ostree_container::deploy::deploy(&sysroot, stateroot, &src_imageref, Some(options)).await?;
+    let target_image = target_imgref.to_string();
+    let digest = state.manifest_digest;
+    println!("Installed: {target_image}");
+    println!("   Digest: {digest}");
+    let layer_media_types = deploycheck::layer_media_types(&state.manifest);
+    let filtered_content = ostree_container::store::image_filtered_content_warning(
+        &sysroot.repo().unwrap(),
+        &src_imageref.imgref,
+    )?;
+    let warnings = deploycheck::find_warnings(
+        &layer_media_types,
+        &target_imgref.sigverify,
+        filtered_content,
+    );
+    for w in warnings.iter() {
+        diagnostics.warn(w.to_string());
+    }
+    // This was checked for the source image before modifying the target, but copying
+    // it may have changed the compression of its layers
+    if strict {
+        deploycheck::check_strict(&warnings)?;
+    }
+
+    let layer_bytes = state
+        .manifest
+        .layers()
+        .iter()
+        .map(|l| u64::try_from(l.size()).unwrap_or_default())
+        .sum::<u64>();
+    let fetched_bytes = fetch_counter.finish()?;
+    println!("Fetched {fetched_bytes} bytes for {layer_bytes} bytes of layers");
+    drop(fetched);
+
+    let sbom =
+        if save_sbom && target_imgref.imgref.transport == ostree_container::Transport::Registry {
+            let config = ostree_container::store::ImageProxyConfig {
+                skopeo_cmd: Some(run_in_host_mountns("skopeo")),
+                ..Default::default()
+            };
+            sbom::fetch_sbom_referrer(config, &target_imgref.imgref.name, &digest).await?
+        } else {
+            None
+        };
+
+    // Write the entry for /boot to /etc/fstab.  TODO: Encourage OSes to use the karg?
+    // Or better bind this with the grub data.
+    sysroot.load(cancellable)?;
+    if let Some(name) = default_boot_entry.as_deref() {
+        set_default_deployment(&sysroot, name, cancellable)?;
+    }
+    // With an adopted sysroot, there are deployments of other stateroots
+    let deployment = sysroot
+        .deployments()
+        .into_iter()
+        .find(|d| d.osname().as_deref() == Some(stateroot))
+        .ok_or_else(|| anyhow::anyhow!("Failed to find deployment"))?;
+    // SAFETY: There must be a path
+    let path = Utf8PathBuf::from(sysroot.deployment_dirpath(&deployment).unwrap().as_str());
+    let uname = cap_std_ext::rustix::process::uname();
+
+    let aleph = InstallAleph {
+        image: src_imageref.imgref.name.clone(),
+        kernel: uname.release().to_str()?.to_string(),
+        ostree_config: ostree_config.clone(),
+        preserved_ssh_host_keys: Vec::new(),
+        stream: stream.map(ToOwned::to_owned),
+        target_image: stream.map(|_| target_imgref.imgref.name.clone()),
+        selinux_firstboot_relabel,
+    };
+
+    Ok(InitialDeployment {
+        aleph,
+        path,
+        digest,
+        sbom,
+        layer_bytes,
+        fetched_bytes,
+        warnings,
+    })
+}
+
+/// The skopeo option to copy multiple layers in parallel
+const SKOPEO_PARALLEL_COPIES: &str = "--image-parallel-copies";
+
+/// The compression of layers copied to an OCI directory.
+#[derive(clap::ValueEnum, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Compression {
+    Gzip,
+    Zstd,
+    None,
+}
+
+impl Compression {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+            Self::None => "none",
+        }
+    }
+
+    /// Arguments for `skopeo copy` to use this compression.
+    fn skopeo_args(self) -> &'static [&'static str] {
+        match self {
+            Self::Gzip => &["--dest-compress", "--dest-compress-format=gzip"],
+            Self::Zstd => &["--dest-compress", "--dest-compress-format=zstd"],
+            Self::None => &["--dest-decompress"],
+        }
+    }
+
+    /// The minimum skopeo version (major, minor) supporting this compression.
+    fn min_skopeo_version(self) -> (u64, u64) {
+        match self {
+            Self::Gzip | Self::None => (1, 0),
+            Self::Zstd => (1, 2),
+        }
+    }
+
+    /// Verify that skopeo of the provided version (major, minor) supports this compression.
+    fn check_skopeo_version(self, version: (u64, u64)) -> Result<()> {
+        let (major, minor) = self.min_skopeo_version();
+        if version < (major, minor) {
+            anyhow::bail!(
+                "--compression={} requires skopeo {major}.{minor} or newer, found {}.{}",
+                self.as_str(),
+                version.0,
+                version.1
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Arguments for `skopeo` to copy the source image to an OCI directory.
+fn copy_to_oci_args(
+    src: &str,
+    dest: &str,
+    concurrency: Option<u32>,
+    compression: Option<Compression>,
+) -> Vec<String> {
+    let mut r = vec!["copy".to_string()];
+    // TODO: enable this once ostree is fixed "--dest-oci-accept-uncompressed-layers",
+    if let Some(n) = concurrency {
+        r.push(format!("{SKOPEO_PARALLEL_COPIES}={n}"));
+    }
+    if let Some(compression) = compression {
+        r.extend(compression.skopeo_args().iter().map(|&v| v.to_owned()));
+    }
+    r.extend([src.to_string(), dest.to_string()]);
+    r
+}
+
+#[context("Querying skopeo copy options")]
+fn skopeo_supports_parallel_copies() -> Result<bool> {
+    let help = Task::new_cmd("Querying skopeo", run_in_host_mountns("skopeo"))
+        .args(["copy", "--help"])
+        .quiet()
+        .read()?;
+    Ok(help.contains(SKOPEO_PARALLEL_COPIES))
+}
+
+#[context("Copying to oci")]
+fn copy_to_oci(
+    description: &str,
+    src_imageref: &ostree_container::ImageReference,
+    dest_imageref: ostree_container::ImageReference,
+    concurrency: Option<u32>,
+    compression: Option<Compression>,
+    diagnostics: &Diagnostics,
+) -> Result<ostree_container::ImageReference> {
+    tracing::debug!("Copying {src_imageref}");
+    let src_imageref = src_imageref.to_string();
+    let dest_imageref_str = dest_imageref.to_string();
+    let concurrency = match concurrency {
+        Some(_) if !skopeo_supports_parallel_copies()? => {
+            diagnostics.warn(format!(
+                "skopeo does not support {SKOPEO_PARALLEL_COPIES}; ignoring --copy-concurrency"
+            ));
+            None
+        }
+        o => o,
+    };
+    if let Some(compression) = compression {
+        compression.check_skopeo_version(skopeo_version()?)?;
+    }
+    Task::new_cmd(description, run_in_host_mountns("skopeo"))
+        .args(copy_to_oci_args(
+            src_imageref.as_str(),
+            dest_imageref_str.as_str(),
+            concurrency,
+            compression,
+        ))
+        .run()?;
+    Ok(dest_imageref)
+}
+
+/// The directory in which a copy of the source image is made.
+#[derive(Debug, PartialEq, Eq)]
+struct CopyParent {
+    /// The path in our mount namespace
+    path: Utf8PathBuf,
+    /// The path in the host mount namespace, in which skopeo runs
+    host_path: Utf8PathBuf,
+}
+
+/// Select the directory in which to copy the source image: `--tempdir`, or `/var/tmp`
+/// if skopeo can write to it on the host, which it can't e.g. if the host lacks it.
+/// Otherwise, the provided [`lock::process_dir`] is used, which skopeo reaches through
+/// our root in `/proc`.
+fn copy_parent(
+    tempdir: Option<&Utf8Path>,
+    process_dir: &Utf8Path,
+    host_writable: impl Fn(&Utf8Path) -> bool,
+) -> Result<CopyParent> {
+    let same = |path: &Utf8Path| CopyParent {
+        path: path.to_owned(),
+        host_path: path.to_owned(),
+    };
+    if let Some(tempdir) = tempdir {
+        if !host_writable(tempdir) {
+            anyhow::bail!(
+                "--tempdir {tempdir} is not writable on the host, where skopeo runs; it must be the same path there"
+            );
+        }
+        return Ok(same(tempdir));
+    }
+    let var_tmp = Utf8Path::new("/var/tmp");
+    if host_writable(var_tmp) {
+        return Ok(same(var_tmp));
+    }
+    let host_path = Utf8PathBuf::from(format!("/proc/{}/root{process_dir}", std::process::id()));
+    if !host_writable(&host_path) {
+        anyhow::bail!(
+            "Neither {var_tmp} nor {process_dir} is writable on the host, where skopeo runs; specify a writable --tempdir"
+        );
+    }
+    Ok(CopyParent {
+        path: process_dir.to_owned(),
+        host_path,
+    })
+}
+
+/// A copy of the source image in an OCI directory, for skopeo versions too old to
+/// read directly from container storage; it is removed when dropped.
+#[derive(Debug)]
+struct SourceCopy {
+    _dir: tempfile::TempDir,
+    /// The path of the copy on the host
+    host_dir: Utf8PathBuf,
+}
+
+impl SourceCopy {
+    /// Prepare a copy in the provided parent directory.
+    fn new(parent: &CopyParent) -> Result<Self> {
+        let path = &parent.path;
+        let dir = tempfile::tempdir_in(path).with_context(|| format!("Creating copy in {path}"))?;
+        // SAFETY: The name of a temporary directory is UTF-8
+        let name = dir.path().file_name().unwrap().to_str().unwrap();
+        let host_dir = parent.host_path.join(name);
+        Ok(Self {
+            _dir: dir,
+            host_dir,
+        })
+    }
+
+    /// The reference to the copy of the image with the provided digest, for skopeo.
+    fn imgref(&self, digest: &str) -> ostree_container::ImageReference {
+        exported_source_imgref(&self.host_dir, digest)
+    }
+}
+
+/// Whether an image pulled from its registry is pulled into the host's container
+/// storage first, with `--zstd-chunked` or `--keep-cached-layers`.
+fn pull_into_storage(config_opts: &InstallConfigOpts) -> bool {
+    config_opts.zstd_chunked || config_opts.keep_cached_layers
+}
+
+/// The source image pulled from its registry into the host's container storage; it is
+/// removed from the storage again when dropped, unless kept with `--keep-cached-layers`.
+#[derive(Debug)]
+struct StoragePull {
+    /// The digested pull spec
+    spec: String,
+    /// Keep the image and its layers in the storage for later pulls
+    keep: bool,
+}
+
+impl StoragePull {
+    fn imgref(&self) -> ostree_container::ImageReference {
+        ostree_container::ImageReference {
+            transport: ostree_container::Transport::ContainerStorage,
+            name: self.spec.clone(),
+        }
+    }
+
+    /// The skopeo arguments removing the image from the storage when dropped, if any.
+    fn cleanup_args(&self) -> Option<[String; 2]> {
+        (!self.keep).then(|| ["delete".to_string(), self.imgref().to_string()])
+    }
+}
+
+impl Drop for StoragePull {
+    fn drop(&mut self) {
+        let args = match self.cleanup_args() {
+            Some(args) => args,
+            None => {
+                println!("Keeping {} in container storage", self.spec);
+                return;
+            }
+        };
+        let r = Task::new_cmd(
+            format!("Removing {} from container storage", self.spec),
+            run_in_host_mountns("skopeo"),
+        )
+        .args(args)
+        .run();
+        if let Err(e) = r {
+            eprintln!("warning: {e:#}");
+        }
+    }
+}
+
+/// The source image, fetched before deploying it.
+#[derive(Debug)]
+enum FetchedSource {
+    /// Copied to an OCI directory
+    Copy(SourceCopy),
+    /// Pulled into the host's container storage
+    Storage(StoragePull),
+}
+
+impl FetchedSource {
+    /// The reference to the fetched image with the provided digest.
+    fn imgref(&self, digest: &str) -> ostree_container::ImageReference {
+        match self {
+            Self::Copy(copy) => copy.imgref(digest),
+            Self::Storage(pull) => pull.imgref(),
+        }
+    }
+}
+
+/// Pull the image with the provided digested pull spec from its registry into the
+/// host's container storage, see [`pull_into_storage`].
+#[context("Pulling {spec} into container storage")]
+fn pull_source(
+    ops: &dyn InstallOps,
+    config_opts: &InstallConfigOpts,
+    spec: &str,
+    diagnostics: &Diagnostics,
+) -> Result<StoragePull> {
+    let info = crate::podman::store_info()?;
+    if !crate::utils::host_path_writable(&info.graph_root) {
+        let opt = if config_opts.zstd_chunked {
+            "--zstd-chunked"
+        } else {
+            "--keep-cached-layers"
+        };
+        anyhow::bail!(
+            "{opt} requires writable container storage; {} is read-only",
+            info.graph_root
+        );
+    }
+    let src = ostree_container::ImageReference {
+        transport: ostree_container::Transport::Registry,
+        name: spec.to_owned(),
+    };
+    let pull = StoragePull {
+        spec: spec.to_owned(),
+        keep: config_opts.keep_cached_layers,
+    };
+    ops.copy_image(
+        &src,
+        pull.imgref(),
+        config_opts.copy_concurrency,
+        None,
+        diagnostics,
+    )?;
+    Ok(pull)
+}
+
+/// The image to fetch up front with `--pull-first`, if the source strategy fetches it
+/// rather than reading it directly from container storage.
+fn prefetch_imgref(
+    pull_first: bool,
+    strategy: Option<&source::SourceStrategy>,
+    source_imageref: &ostree_container::ImageReference,
+) -> Option<ostree_container::ImageReference> {
+    if !pull_first {
+        return None;
+    }
+    match strategy? {
+        source::SourceStrategy::ContainerStorage => None,
+        source::SourceStrategy::Registry(spec) => Some(ostree_container::ImageReference {
+            transport: ostree_container::Transport::Registry,
+            name: spec.clone(),
+        }),
+        source::SourceStrategy::OciCopy(_) => Some(source_imageref.clone()),
+    }
+}
+
+/// Fetch the source image with `--pull-first`, if [`prefetch_imgref`] selects one,
+/// recording it and the bytes fetched for it.
+fn prefetch_source(ops: &dyn InstallOps, state: &mut State) -> Result<()> {
+    let src = match prefetch_imgref(
+        state.config_opts.pull_first,
+        state.source_strategy.as_ref(),
+        &state.source_imageref,
+    ) {
+        Some(src) => src,
+        None => return Ok(()),
+    };
+    println!("Fetching {src} before modifying the target");
+    let fetch_counter = fetchstats::FetchCounter::start()?;
+    let fetched = match state.source_strategy.as_ref() {
+        Some(source::SourceStrategy::Registry(spec)) if pull_into_storage(&state.config_opts) => {
+            FetchedSource::Storage(pull_source(
+                ops,
+                &state.config_opts,
+                spec,
+                &state.diagnostics,
+            )?)
+        }
+        _ => FetchedSource::Copy(copy_source(
+            ops,
+            &state.config_opts,
+            &src,
+            &state.source_digest,
+            None,
+            &state.process_dir,
+            &state.diagnostics,
+        )?),
+    };
+    state.prefetched_bytes = fetch_counter.finish()?;
+    state.prefetched_source = Some(fetched);
+    Ok(())
+}
+
+/// Copy the source image `src` to an OCI directory selected by [`copy_parent`],
+/// warning about why with the provided reason.
+fn copy_source(
+    ops: &dyn InstallOps,
+    config_opts: &InstallConfigOpts,
+    src: &ostree_container::ImageReference,
+    digest: &str,
+    reason: Option<&str>,
+    process_dir: &Utf8Path,
+    diagnostics: &Diagnostics,
+) -> Result<SourceCopy> {
+    // Partial pulls of zstd:chunked layers can only be done into container storage;
+    // here all layers are read in full, and twice.
+    let parent = copy_parent(config_opts.tempdir.as_deref(), process_dir, |p| {
+        ops.host_path_writable(p)
+    })?;
+    if parent.path == process_dir {
+        std::fs::create_dir_all(process_dir).with_context(|| format!("Creating {process_dir}"))?;
+        diagnostics.warn(format!(
+            "/var/tmp is not writable on the host; copying the image to {process_dir}, which may be in memory"
+        ));
+    }
+    let copy = SourceCopy::new(&parent)?;
+    if let Some(reason) = reason {
+        diagnostics.warn(format!(
+            "{reason}; copying full layers, as zstd:chunked layers can only be fetched partially into container storage"
+        ));
+    }
+    ops.copy_image(
+        src,
+        copy.imgref(digest),
+        config_opts.copy_concurrency,
+        config_opts.compression,
+        diagnostics,
+    )?;
+    Ok(copy)
+}
+
+/// The reference to an image exported via `install-export-source`.  The image is
+/// tagged with the digest of the source, so that importing it into an installation
+/// from a different image fails.
+fn exported_source_imgref(dir: &Utf8Path, digest: &str) -> ostree_container::ImageReference {
+    ostree_container::ImageReference {
+        transport: ostree_container::Transport::OciDir,
+        name: format!("{dir}:{}", digest.replace(':', "-")),
+    }
+}
+
+/// Check the contents of a source archive against the expected checksum.
+fn verify_source_checksum(
+    input: &mut impl std::io::Read,
+    checksum: &crate::ignition::IgnitionHash,
+) -> Result<()> {
+    checksum
+        .validate(input)
+        .context("Checksum verification failed; the media may be corrupt")
+}
+
+/// Verify the provided archive, which is in the host mount namespace.
+#[context("Verifying {archive}")]
+fn verify_source_archive(
+    archive: &Utf8Path,
+    checksum: &crate::ignition::IgnitionHash,
+) -> Result<()> {
+    println!("Verifying {archive}");
+    let mut child = run_in_host_mountns("cat")
+        .arg(archive.as_str())
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .spawn()?;
+    let mut stdout = child.stdout.take().unwrap();
+    let r = verify_source_checksum(&mut stdout, checksum);
+    drop(stdout);
+    let st = child.wait()?;
+    if !st.success() {
+        anyhow::bail!("Reading failed: {st:?}");
+    }
+    r
+}
+
+/// Print the capabilities of this build of the installer as JSON.
+pub(crate) fn install_list_capabilities() -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    serde_json::to_writer_pretty(&mut stdout, &baseline::capabilities())?;
+    writeln!(stdout)?;
+    Ok(())
+}
+
+/// Export the running container image to a local OCI directory, which can be passed
+/// to subsequent installations from the same image via `--source-oci-dir`.
+#[context("Exporting source image")]
+pub(crate) async fn install_export_source(opts: InstallExportSourceOpts) -> Result<()> {
+    let container_info =
+        crate::containerenv::get_container_execution_info(opts.source_imgref.as_deref())?;
+    if !container_info.engine.starts_with("podman") {
+        anyhow::bail!("Currently this command only supports being executed via podman");
+    }
+    if container_info.imageid.is_empty() {
+        anyhow::bail!("Invalid empty imageid");
+    }
+    let digest = crate::podman::imageid_to_digest(&container_info.imageid)?;
+    let src = ostree_container::ImageReference {
+        transport: ostree_container::Transport::ContainerStorage,
+        name: crate::utils::digested_pullspec(&container_info.image, &digest),
+    };
+    let dest = exported_source_imgref(&opts.dir, &digest);
+    copy_to_oci(
+        "Exporting source image",
+        &src,
+        dest,
+        opts.copy_concurrency,
+        None,
+        &Diagnostics::default(),
+    )?;
+    println!("Exported {} to {}", container_info.image, opts.dir);
+    Ok(())
+}
+
+fn skopeo_supports_containers_storage() -> Result<bool> {
+    let (major, minor) = skopeo_version()?;
+    Ok(major > 1 || minor > 10)
+}
+
+/// Query the (major, minor) version of skopeo.
+#[context("Querying skopeo version")]
+fn skopeo_version() -> Result<(u64, u64)> {
+    let o = run_in_host_mountns("skopeo").arg("--version").output()?;
+    let st = o.status;
+    if !st.success() {
+        anyhow::bail!("Failed to run skopeo --version: {st:?}");
+    }
+    let stdout = String::from_utf8(o.stdout).context("Parsing skopeo version")?;
+    parse_skopeo_version(&stdout)
+}
+
+fn parse_skopeo_version(stdout: &str) -> Result<(u64, u64)> {
+    let mut v = stdout
+        .strip_prefix("skopeo version ")
+        .map(|v| v.split('.'))
+        .ok_or_else(|| anyhow::anyhow!("Unexpected output from skopeo version"))?;
+    let major = v
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Missing major version"))?;
+    let minor = v
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Missing minor version"))?;
+    Ok((major.parse::<u64>()?, minor.parse::<u64>()?))
+}
+
+/// The subset of `skopeo inspect` output used to verify the target image.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TargetImageInspect {
+    digest: String,
+    architecture: String,
+}
+
+/// Map a Rust or kernel (`uname -m`) architecture name to the one used in container
+/// image configurations.
+fn oci_arch(arch: &str) -> &str {
+    match arch {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "powerpc64" | "ppc64le" => "ppc64le",
+        "x86" | "i386" | "i686" => "386",
+        "armv7l" | "armv7" => "arm",
+        o => o,
+    }
+}
+
+/// Whether an architecture name, in the kernel or container image spelling, refers to
+/// the provided target architecture.
+fn arch_matches(arch: &str, target: &str) -> bool {
+    oci_arch(arch) == oci_arch(target)
+}
+
+/// Architectures (as in container images) accepted by `--karg-arch`
+const KNOWN_ARCHES: &[&str] = &["amd64", "arm64", "ppc64le", "s390x", "386", "arm"];
+
+/// A kernel argument which only applies to one architecture.
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeFromStr, SerializeDisplay)]
+pub(crate) struct ArchKarg {
+    arch: String,
+    karg: String,
+}
+
+impl FromStr for ArchKarg {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (arch, karg) = s
+            .split_once('=')
+            .filter(|(_, karg)| !karg.is_empty())
+            .ok_or_else(|| {
+                anyhow!("Invalid architecture kernel argument {s}: expected ARCH=KARG")
+            })?;
+        if !KNOWN_ARCHES.contains(&oci_arch(arch)) {
+            anyhow::bail!("Unknown architecture {arch}");
+        }
+        Ok(Self {
+            arch: arch.to_string(),
+            karg: karg.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for ArchKarg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.arch, self.karg)
+    }
+}
+
+/// The kernel arguments which apply to the target architecture, in order.
+fn arch_kargs<'a>(kargs: &'a [ArchKarg], target: &'a str) -> impl Iterator<Item = &'a str> {
+    kargs
+        .iter()
+        .filter(move |k| arch_matches(&k.arch, target))
+        .map(|k| k.karg.as_str())
+}
+
+/// Architectures (as in container images) for which we have a partition layout
+const PARTITIONING_ARCHES: &[&str] = &["amd64", "arm64"];
+
+/// The architecture of the host kernel; this differs from ours when the container is
+/// run via emulation, e.g. `podman run --arch amd64` on an aarch64 host.
+#[context("Querying host architecture")]
+fn host_arch() -> Result<String> {
+    let o = run_in_host_mountns("uname").arg("-m").output()?;
+    let st = o.status;
+    if !st.success() {
+        anyhow::bail!("Failed to run uname: {st:?}");
+    }
+    Ok(String::from_utf8(o.stdout)?.trim().to_string())
+}
+
+/// Verify that the source image and the host both match our architecture (`arch`).  With
+/// `skip`, a mismatch is allowed if we have a partition layout for the image.
+fn check_source_arch(
+    image_arch: &str,
+    host_arch: &str,
+    arch: &str,
+    skip: bool,
+    diagnostics: &Diagnostics,
+) -> Result<()> {
+    let expected = oci_arch(arch);
+    let image_arch = oci_arch(image_arch);
+    let host_arch = oci_arch(host_arch);
+    let mismatch = [("source image", image_arch), ("host", host_arch)]
+        .into_iter()
+        // The architecture may be unknown for old podman versions
+        .find(|(_, a)| !a.is_empty() && *a != expected);
+    let (what, found) = if let Some(m) = mismatch {
+        m
+    } else {
+        return Ok(());
+    };
+    if !skip {
+        anyhow::bail!(
+            "The {what} architecture is {found}, but this installer is for {expected}; use --skip-arch-check to override"
+        );
+    }
+    if !PARTITIONING_ARCHES.contains(&image_arch) {
+        anyhow::bail!("Installing a {image_arch} image is unsupported");
+    }
+    diagnostics.warn(format!(
+        "The {what} architecture is {found}, not {expected}; continuing due to --skip-arch-check"
+    ));
+    Ok(())
+}
+
+/// Verify that the inspected target image is usable on this architecture.
+fn check_target_inspect(name: &str, inspect: &TargetImageInspect, arch: &str) -> Result<()> {
+    let expected = oci_arch(arch);
+    if inspect.architecture != expected {
+        anyhow::bail!(
+            "Target image {name} is for architecture {}, expected {expected}",
+            inspect.architecture
+        );
+    }
+    Ok(())
+}
+
+/// Verify that the target image exists and matches this architecture, using
+/// the host's registry authentication and TLS configuration.
+#[context("Verifying target image")]
+fn check_target_imgref(imgref: &ostree_container::ImageReference) -> Result<()> {
+    if imgref.transport != ostree_container::Transport::Registry {
+        println!(
+            "Skipping verification of target image with transport {}",
+            imgref.transport
+        );
+        return Ok(());
+    }
+    let imgref_str = imgref.to_string();
+    let o = run_in_host_mountns("skopeo")
+        .args(["inspect", "--no-tags", imgref_str.as_str()])
+        .output()?;
+    if !o.status.success() {
+        let stderr = String::from_utf8_lossy(&o.stderr);
+        anyhow::bail!(
+            "Failed to inspect target image {imgref}: {}\n\
+             Use --skip-target-check to skip this verification (e.g. for offline installations)",
+            stderr.trim()
+        );
+    }
+    let inspect: TargetImageInspect =
+        serde_json::from_slice(&o.stdout).context("Parsing skopeo inspect output")?;
+    check_target_inspect(&imgref.name, &inspect, std::env::consts::ARCH)?;
+    println!("Verified target image {} ({})", imgref.name, inspect.digest);
+    Ok(())
+}
+
+pub(crate) struct RootSetup {
+    /// The device the bootloader is installed to; unset with `--stateless`, where the
+    /// root is booted by an external mechanism
+    device: Option<Utf8PathBuf>,
+    rootfs: Utf8PathBuf,
+    rootfs_fd: Dir,
+    root: MountSpec,
+    /// The type of the root filesystem; unset with `--stateless`, where it is not
+    /// inspected
+    root_fstype: Option<String>,
+    /// The /boot filesystem; unset with `--stateless` if /boot is part of the root
+    boot: Option<MountSpec>,
+    /// The EFI system partition, if any
+    esp: Option<MountSpec>,
+    /// Additional partitions created by the installer
+    extra_partitions: Vec<summary::CreatedPartition>,
+    /// The device of the metadata partition, if any; it is only mounted while the
+    /// metadata is written
+    metadata_partition: Option<String>,
+    /// The filesystem source (`UUID=...`) of the second ESP created for `--sync-esp`
+    sync_esp: Option<String>,
+    /// SSH host keys to carry forward into the deployment
+    ssh_host_keys: Vec<sshkeys::SshHostKey>,
+    /// The machine ID to carry forward into the deployment
+    machine_id: Option<String>,
+    /// The new stateroot to add to the existing ostree sysroot, with
+    /// `--adopt-existing-sysroot`
+    adopt_stateroot: Option<String>,
+    kargs: Vec<String>,
+}
+
+fn require_boot_uuid(spec: &MountSpec) -> Result<&str> {
+    spec.get_source_uuid()
+        .ok_or_else(|| anyhow!("/boot is not specified via UUID= (this is currently required)"))
+}
+
+impl RootSetup {
+    /// Get the UUID= mount specifier for the /boot filesystem.  At the current time this is
+    /// required.
+    fn get_boot_uuid(&self) -> Result<&str> {
+        let boot = self
+            .boot
+            .as_ref()
+            .ok_or_else(|| anyhow!("No separate /boot filesystem"))?;
+        require_boot_uuid(boot)
+    }
+
+    /// The stateroot to deploy into.
+    fn stateroot(&self) -> &str {
+        self.adopt_stateroot.as_deref().unwrap_or(STATEROOT_DEFAULT)
+    }
+}
+
+pub(crate) struct SourceData {
+    /// The embedded base OSTree commit checksum
+    #[allow(dead_code)]
+    pub(crate) commit: String,
+    /// Whether or not SELinux appears to be enabled in the source commit
+    pub(crate) selinux: bool,
+}
+
+#[context("Gathering source data")]
+fn gather_source_data() -> Result<SourceData> {
+    let cancellable = ostree::gio::Cancellable::NONE;
+    let commit = Task::new("Reading ostree commit", "ostree")
+        .args(["--repo=/ostree/repo", "rev-parse", "--single"])
+        .quiet()
+        .read()?;
+    let root = cap_std::fs::Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let repo = ostree::Repo::open_at_dir(&root, "ostree/repo")?;
+    let root = repo
+        .read_commit(commit.trim(), cancellable)
+        .context("Reading commit")?
+        .0;
+    let root = root.downcast_ref::<ostree::RepoFile>().unwrap();
+    let xattrs = root.xattrs(cancellable)?;
+    let selinux = crate::lsm::xattrs_have_selinux(&xattrs);
+    Ok(SourceData { commit, selinux })
+}
+
+/// The flag file making the target relabel itself on boot, relative to the deployment
+const AUTORELABEL_PATH: &str = ".autorelabel";
+
+/// How SELinux is overridden when the target enables it but the host does not support
+/// it, and so the target can't be labeled.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum SelinuxOverride {
+    /// Disable SELinux in the target, with `--disable-selinux`
+    Disable,
+    /// Relabel the target on first boot, with `--selinux-firstboot-relabel`
+    FirstbootRelabel,
+}
+
+/// The target enables SELinux but the host does not support it; this is only allowed
+/// with an override to disable SELinux in the target, or to relabel it on first boot.
+fn check_selinux_override(
+    override_disable_selinux: bool,
+    firstboot_relabel: bool,
+    diagnostics: &Diagnostics,
+) -> Result<SelinuxOverride> {
+    if firstboot_relabel {
+        diagnostics.warn("Target has SELinux enabled, overriding to relabel on first boot");
+        return Ok(SelinuxOverride::FirstbootRelabel);
+    }
+    if !override_disable_selinux {
+        anyhow::bail!(
+            "Host kernel does not have SELinux support, but target enables it by default"
+        );
+    }
+    diagnostics.warn("Target has SELinux enabled, overriding to disable");
+    Ok(SelinuxOverride::Disable)
+}
+
+/// If we detect that the target ostree commit has SELinux labels,
+/// and we aren't passed an override to disable it, then ensure
+/// the running process is labeled with install_t so it can
+/// write arbitrary labels.
+pub(crate) fn reexecute_self_for_selinux_if_needed(
+    srcdata: &SourceData,
+    override_disable_selinux: bool,
+    firstboot_relabel: bool,
+    diagnostics: &Diagnostics,
+) -> Result<Option<SelinuxOverride>> {
+    if firstboot_relabel && !srcdata.selinux {
+        anyhow::bail!("--selinux-firstboot-relabel requires a target with SELinux enabled");
+    }
+    let mut ret_override = None;
+    // If the target state has SELinux enabled, we need to check the host state.
+    if srcdata.selinux {
+        let host_selinux = crate::lsm::selinux_enabled()?;
+        tracing::debug!("Target has SELinux, host={host_selinux}");
+        if host_selinux {
+            // /sys/fs/selinuxfs is not normally mounted, so we do that now.
+            // Because SELinux enablement status is cached process-wide and was very likely
+            // already queried by something else (e.g. glib's constructor), we would also need
+            // to re-exec.  But, selinux_ensure_install does that unconditionally right now too,
+            // so let's just fall through to that.
+            crate::lsm::container_setup_selinux()?;
+            // This will re-execute the current process (once).
+            crate::lsm::selinux_ensure_install()?;
+        } else {
+            ret_override = Some(check_selinux_override(
+                override_disable_selinux,
+                firstboot_relabel,
+                diagnostics,
+            )?);
+        }
+    } else {
+        tracing::debug!("Target does not enable SELinux");
+    }
+    Ok(ret_override)
+}
+
+/// Trim, flush outstanding writes, and freeze/thaw the target mounted filesystem;
+/// these steps prepare the filesystem for its first booted use.
+pub(crate) fn finalize_filesystem(fs: &Utf8Path) -> Result<()> {
+    let fsname = fs.file_name().unwrap();
+    // fstrim ensures the underlying block device knows about unused space
+    Task::new_and_run(format!("Trimming {fsname}"), "fstrim", ["-v", fs.as_str()])?;
+    // Remounting readonly will flush outstanding writes and ensure we error out if there were background
+    // writeback problems.
+    Task::new(format!("Finalizing filesystem {fsname}"), "mount")
+        .args(["-o", "remount,ro", fs.as_str()])
+        .run()?;
+    // Finally, freezing (and thawing) the filesystem will flush the journal, which means the next boot is clean.
+    for a in ["-f", "-u"] {
+        Task::new("Flushing filesystem journal", "xfs_freeze")
+            .quiet()
+            .args([a, fs.as_str()])
+            .run()?;
+    }
+    Ok(())
+}
+
+/// Handle receipt of a signal interrupting the installation: cancel in-flight
+/// operations and clean up.  Returns the error to report.
+fn handle_interrupt(
+    cancellable: &gio::Cancellable,
+    signame: &str,
+    cleanup: impl FnOnce() -> Result<()>,
+) -> anyhow::Error {
+    cancellable.cancel();
+    if let Err(e) = cleanup() {
+        eprintln!("warning: Failed to clean up: {e:#}");
+    }
+    anyhow!("Installation interrupted by {signame}")
+}
+
+/// A future which resolves to the name and number of the first interrupting signal received.
+struct InterruptSignal {
+    sigint: tokio::signal::unix::Signal,
+    sigterm: tokio::signal::unix::Signal,
+}
+
+impl std::future::Future for InterruptSignal {
+    type Output = (&'static str, i32);
+
+    fn poll(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        if self.sigint.poll_recv(cx).is_ready() {
+            return std::task::Poll::Ready(("SIGINT", libc::SIGINT));
+        }
+        if self.sigterm.poll_recv(cx).is_ready() {
+            return std::task::Poll::Ready(("SIGTERM", libc::SIGTERM));
+        }
+        std::task::Poll::Pending
+    }
+}
+
+/// Spawn a thread which waits for SIGINT or SIGTERM, and then aborts the installation.
+/// This uses a separate thread because much of the installation blocks the main one.
+#[context("Installing signal handlers")]
+fn install_interrupt_handler(cancellable: gio::Cancellable) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()?;
+    let signal = {
+        let _guard = rt.enter();
+        InterruptSignal {
+            sigint: signal(SignalKind::interrupt())?,
+            sigterm: signal(SignalKind::terminate())?,
+        }
+    };
+    std::thread::spawn(move || {
+        let (signame, signo) = rt.block_on(signal);
+        let e = handle_interrupt(&cancellable, signame, baseline::cleanup_mounts);
+        eprintln!("error: {e}");
+        std::process::exit(128 + signo);
+    });
+    Ok(())
+}
+
+/// Conflicting options, and options lacking one they require, as clap checks only the CLI.
+fn option_conflicts(
+    config_opts: &InstallConfigOpts,
+    target_opts: &InstallTargetOpts,
+    block_opts: Option<&InstallBlockDeviceOpts>,
+    filesystem_opts: Option<&InstallTargetFilesystemOpts>,
+) -> Vec<String> {
+    let c = config_opts;
+    let t = target_opts;
+    let conflicts = [
+        (
+            t.target_stream.is_some() && t.target_imgref.is_some(),
+            "target-stream",
+            "target-imgref",
+        ),
+        (
+            c.selinux_firstboot_relabel && c.disable_selinux,
+            "selinux-firstboot-relabel",
+            "disable-selinux",
+        ),
+        (
+            c.selinux_enforcing.is_some() && c.disable_selinux,
+            "selinux-enforcing",
+            "disable-selinux",
+        ),
+        (
+            !c.selinux_boolean.is_empty() && c.disable_selinux,
+            "selinux-boolean",
+            "disable-selinux",
+        ),
+        (
+            c.format != report::OutputFormat::default() && c.print_env,
+            "format",
+            "print-env",
+        ),
+        (c.inspect_shell && c.print_env, "inspect-shell", "print-env"),
+        (c.swap.is_some() && c.mount_units, "swap", "mount-units"),
+        (
+            c.source_oci_archive.is_some() && c.source_oci_dir.is_some(),
+            "source-oci-archive",
+            "source-oci-dir",
+        ),
+    ];
+    let requires = [
+        (
+            c.ignition_hash.is_some() && c.ignition_file.is_none(),
+            "ignition-hash",
+            "ignition-file",
+        ),
+        (
+            !c.rescue_karg.is_empty() && !c.rescue_entry,
+            "rescue-karg",
+            "rescue-entry",
+        ),
+        (
+            c.ostree_config_unsafe && c.ostree_config.is_empty(),
+            "ostree-config-unsafe",
+            "ostree-config",
+        ),
+        (
+            !c.journald_conf.is_empty() && !c.persistent_journal,
+            "journald-conf",
+            "persistent-journal",
+        ),
+        (
+            !c.dns_search.is_empty() && c.dns.is_empty(),
+            "dns-search",
+            "dns",
+        ),
+        (
+            c.source_checksum.is_some() && c.source_oci_archive.is_none(),
+            "source-checksum",
+            "source-oci-archive",
+        ),
+        (
+            c.aleph_format != aleph::AlephFormat::default() && c.aleph_path.is_none(),
+            "aleph-format",
+            "aleph-path",
+        ),
+        (
+            c.force && c.require_existing_image.is_none(),
+            "force",
+            "require-existing-image",
+        ),
+        (
+            !c.single_valued_karg.is_empty() && !c.normalize_kargs,
+            "single-valued-karg",
+            "normalize-kargs",
+        ),
+        (
+            t.ostree_remote_gpg_key.is_some() && t.target_ostree_remote.is_none(),
+            "ostree-remote-gpg-key",
+            "target-ostree-remote",
+        ),
+    ];
+    let mut block_conflicts = Vec::new();
+    let mut block_requires = Vec::new();
+    if let Some(b) = block_opts {
+        block_requires.extend([
+            (b.vg_name.is_some() && !b.lvm, "vg-name", "lvm"),
+            (b.lv_name.is_some() && !b.lvm, "lv-name", "lvm"),
+        ]);
+        block_conflicts.extend([
+            (
+                b.layout.is_some() && b.root_size.is_some(),
+                "layout",
+                "root-size",
+            ),
+            (
+                b.bios_boot_size.is_some() && b.layout.is_some(),
+                "bios-boot-size",
+                "layout",
+            ),
+            (
+                b.boot_partition_type.is_some() && b.layout.is_some(),
+                "boot-partition-type",
+                "layout",
+            ),
+        ]);
+        if b.use_free_space {
+            block_conflicts.extend([
+                (b.wipe, "use-free-space", "wipe"),
+                (b.layout.is_some(), "use-free-space", "layout"),
+                (
+                    b.bios_boot_size.is_some(),
+                    "use-free-space",
+                    "bios-boot-size",
+                ),
+                (
+                    !b.extra_partition.is_empty(),
+                    "use-free-space",
+                    "extra-partition",
+                ),
+                (b.metadata_partition, "use-free-space", "metadata-partition"),
+            ]);
+        }
+    }
+    let mut filesystem_conflicts = Vec::new();
+    if filesystem_opts.map_or(false, |f| f.adopt_existing_sysroot) {
+        // These would change the existing bootloader configuration
+        filesystem_conflicts.extend([
+            (
+                c.grub_config_fragment.is_some(),
+                "grub-config-fragment",
+                "adopt-existing-sysroot",
+            ),
+            (
+                !c.firstboot_karg.is_empty(),
+                "firstboot-karg",
+                "adopt-existing-sysroot",
+            ),
+        ]);
+    }
+    if filesystem_opts.map_or(false, |f| f.stage == InstallStage::DeployOnly) {
+        // These act on the installed bootloader
+        filesystem_conflicts.extend([
+            (
+                c.grub_config_fragment.is_some(),
+                "grub-config-fragment",
+                "stage deploy-only",
+            ),
+            (
+                !c.firstboot_karg.is_empty(),
+                "firstboot-karg",
+                "stage deploy-only",
+            ),
+            (c.verify_bootable, "verify-bootable", "stage deploy-only"),
+        ]);
+    }
+    let conflicts = conflicts
+        .into_iter()
+        .chain(block_conflicts)
+        .chain(filesystem_conflicts)
+        .filter(|(found, _, _)| *found)
+        .map(|(_, a, b)| format!("--{a} conflicts with --{b}"));
+    let requires = requires
+        .into_iter()
+        .chain(block_requires)
+        .filter(|(found, _, _)| *found)
+        .map(|(_, a, b)| format!("--{a} requires --{b}"));
+    conflicts.chain(requires).collect()
+}
+
+/// Check the provided options for conflicts, reporting all of them at once.
+fn validate_options(
+    config_opts: &InstallConfigOpts,
+    target_opts: &InstallTargetOpts,
+    block_opts: Option<&InstallBlockDeviceOpts>,
+    filesystem_opts: Option<&InstallTargetFilesystemOpts>,
+) -> Result<()> {
+    let conflicts = option_conflicts(config_opts, target_opts, block_opts, filesystem_opts);
+    if !conflicts.is_empty() {
+        anyhow::bail!("Invalid options:\n  {}", conflicts.join("\n  "));
+    }
+    Ok(())
+}
+
+/// Preparation for an install; validates and prepares some (thereafter immutable) global state.
+async fn prepare_install(
+    config_opts: InstallConfigOpts,
+    target_opts: InstallTargetOpts,
+    block_opts: Option<&InstallBlockDeviceOpts>,
+    filesystem_opts: Option<&InstallTargetFilesystemOpts>,
+) -> Result<State> {
+    validate_options(&config_opts, &target_opts, block_opts, filesystem_opts)?;
+    let no_unshare = filesystem_opts.map_or(false, |f| f.no_unshare);
+    let ns_setup =
+        NamespaceSetup::new(no_unshare, std::env::var_os("BOOTC_SKIP_UNSHARE").is_some());
+    let diagnostics = Diagnostics::default();
+    let ostree_config = config_opts.ostree_config()?;
+    let inherited_kargs = if config_opts.inherit_kargs {
+        let cmdline = std::fs::read_to_string("/proc/cmdline").context("Reading /proc/cmdline")?;
+        let kargs = inheritable_kargs(&cmdline);
+        println!("Inheriting kernel arguments: {}", kargs.join(" "));
+        kargs
+    } else {
+        Vec::new()
+    };
+    for karg in config_opts.rescue_karg.iter() {
+        crate::bootloader::validate_karg(karg).context("Validating --rescue-karg")?;
+    }
+    for (opt, kargs) in [
+        ("--karg-replace", &config_opts.karg_replace),
+        (
+            "--karg-append-if-missing",
+            &config_opts.karg_append_if_missing,
+        ),
+    ] {
+        for karg in kargs {
+            crate::bootloader::validate_karg(karg).with_context(|| format!("Validating {opt}"))?;
+            if !karg.contains('=') {
+                anyhow::bail!("Invalid {opt} {karg}: expected KEY=VALUE");
+            }
+        }
+    }
+    check_karg_delete(&config_opts.karg_delete)?;
+    // We run from the image, so the kernel can be selected before modifying the target
+    if let Some(kver) = config_opts.kernel.as_deref() {
+        let root = Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+        kernel::find_kernel(&root, Some(kver))?;
+    }
+    if let Some(tries) = config_opts.boot_counting {
+        crate::bootloader::validate_boot_count(tries)?;
+        // bootupd installs GRUB, which ignores boot counters
+        if !filesystem_opts.map_or(false, |f| f.adopt_existing_sysroot || f.stateless) {
+            anyhow::bail!(
+                "--boot-counting requires systemd-boot, and can only be used with an existing or external bootloader (--adopt-existing-sysroot or --stateless)"
+            );
+        }
+    }
+    let grub_config_fragment = config_opts
+        .grub_config_fragment
+        .as_deref()
+        .map(crate::bootloader::read_grub_fragment)
+        .transpose()?;
+    let install_policy = sigpolicy::InstallPolicy::new(
+        config_opts.install_policy.as_deref(),
+        config_opts.install_sigstore_policy.as_deref(),
+    )?;
+
+    // We require --pid=host
+    let pid = std::fs::read_link("/proc/1/exe").context("reading /proc/1/exe")?;
+    let pid = pid
+        .to_str()
+        .ok_or_else(|| anyhow::anyhow!("Non-UTF8 /proc/1/exe"))?;
+    if !pid.contains("systemd") {
+        anyhow::bail!("This command must be run with --pid=host")
+    }
+
+    // This command currently *must* be run inside a privileged container.
+    let container_info =
+        crate::containerenv::get_container_execution_info(config_opts.source_imgref.as_deref())?;
+    if !container_info.engine.starts_with("podman") {
+        anyhow::bail!("Currently this command only supports being executed via podman");
+    }
+    if container_info.imageid.is_empty() {
+        anyhow::bail!("Invalid empty imageid");
+    }
+    let source_imageref = ostree_container::ImageReference {
+        transport: ostree_container::Transport::ContainerStorage,
+        name: container_info.image.clone(),
+    };
+    // Find the exact digested image we are running
+    let source_inspect = crate::podman::inspect(&container_info.imageid)?;
+    let source_digest = source_inspect.digest.clone();
+    // The architecture may be unknown for old podman versions
+    let target_arch = Some(oci_arch(&source_inspect.architecture))
+        .filter(|a| !a.is_empty())
+        .unwrap_or_else(|| oci_arch(std::env::consts::ARCH))
+        .to_string();
+    let host_arch = host_arch()?;
+    check_source_arch(
+        &source_inspect.architecture,
+        &host_arch,
+        std::env::consts::ARCH,
+        config_opts.skip_arch_check,
+        &diagnostics,
+    )?;
+
+    let source_strategy =
+        if config_opts.source_oci_dir.is_some() || config_opts.source_oci_archive.is_some() {
+            None
+        } else {
+            Some(source::source_strategy(&source_inspect)?)
+        };
+    if let Some(dir) = config_opts.tempdir.as_deref() {
+        if !dir.is_dir() || !crate::utils::host_path_exists(dir.as_str()) {
+            anyhow::bail!("--tempdir {dir} must be a directory in both the container and the host");
+        }
+    }
+
+    if let Some(dir) = config_opts.source_oci_dir.as_deref() {
+        if !crate::utils::host_path_exists(dir.join("index.json").as_str()) {
+            anyhow::bail!("Not an OCI directory: {dir}");
+        }
+    }
+    if let Some(archive) = config_opts.source_oci_archive.as_deref() {
+        if !crate::utils::host_path_exists(archive.as_str()) {
+            anyhow::bail!("No such OCI archive: {archive}");
+        }
+        if let Some(checksum) = config_opts.source_checksum.as_ref() {
+            verify_source_archive(archive, checksum)?;
+        }
+    }
+
+    if let Some(remote) = target_opts.target_ostree_remote.as_deref() {
+        remotekey::validate_remote_name(remote)?;
+    }
+    if let Some(path) = target_opts.ostree_remote_gpg_key.as_deref() {
+        remotekey::read_key(path)?;
+    }
+
+    // Catch typos in the target image now, rather than at the first upgrade
+    let has_target = target_opts.target_imgref.is_some() || target_opts.target_stream.is_some();
+    if has_target {
+        let target_imgref = target_imgref_from_opts(&target_opts, &source_imageref)?;
+        if let Some(stream) = target_opts.target_stream.as_deref() {
+            println!("Using update stream {stream}: {target_imgref}");
+        }
+        if !target_opts.skip_target_check {
+            check_target_imgref(&target_imgref.imgref)?;
+        }
+    }
+
+    // Even though we require running in a container, the mounts we create should be specific
+    // to this process, so let's enter a private mountns to avoid leaking them.
+    if ns_setup.unshare {
+        super::cli::ensure_self_unshared_mount_namespace().await?;
+    }
+
+    // Let's ensure we have a tmpfs on /tmp, because we need that to write the SELinux label
+    // (it won't work on the default overlayfs)
+    if ns_setup.private_mounts
+        && nix::sys::statfs::statfs("/tmp")?.filesystem_type() != nix::sys::statfs::TMPFS_MAGIC
+    {
+        Task::new("Creating tmpfs on /tmp", "mount")
+            .quiet()
+            .args(["-t", "tmpfs", "tmpfs", "/tmp"])
+            .run()?;
+    }
+
+    // Now, deal with SELinux state.
+    let srcdata = gather_source_data()?;
+    let selinux_override = reexecute_self_for_selinux_if_needed(
+        &srcdata,
+        config_opts.disable_selinux,
+        config_opts.selinux_firstboot_relabel,
+        &diagnostics,
+    )?;
+    if !config_opts.selinux_boolean.is_empty() {
+        if !srcdata.selinux {
+            anyhow::bail!("--selinux-boolean requires a target with SELinux enabled");
+        }
+        sebool::validate(&config_opts.selinux_boolean)?;
+    }
+
+    // Create our global (read-only) state which gets wrapped in an Arc
+    // so we can pass it to worker threads too. Right now this just
+    // combines our command line options along with some bind mounts from the host.
+    // Overmount /var/tmp with the host's, so we can use it to share state
+    // Hosts such as live ISOs may lack /var/tmp; in that case we fall back to the container's.
+    if ns_setup.private_mounts {
+        bind_mount_from_host(
+            "/var/tmp",
+            "/var/tmp",
+            BindMountOpts {
+                optional: true,
+                ..Default::default()
+            },
+            &diagnostics,
+        )?;
+    }
+    // Fail before modifying the target if the deployed image would fail --strict
+    if config_opts.strict {
+        let src = if let Some(dir) = config_opts.source_oci_dir.as_deref() {
+            exported_source_imgref(dir, &source_digest)
+        } else if let Some(archive) = config_opts.source_oci_archive.as_deref() {
+            ostree_container::ImageReference {
+                transport: ostree_container::Transport::OciArchive,
+                name: archive.to_string(),
+            }
+        } else {
+            ostree_container::ImageReference {
+                transport: ostree_container::Transport::ContainerStorage,
+                name: crate::utils::digested_pullspec(&source_imageref.name, &source_digest),
+            }
+        };
+        let proxy_cfg = ostree_container::store::ImageProxyConfig {
+            skopeo_cmd: Some(run_in_host_mountns("skopeo")),
+            ..Default::default()
+        };
+        let media_types = deploycheck::fetch_layer_media_types(proxy_cfg, &src).await?;
+        let target_imgref = target_imgref_from_opts(&target_opts, &source_imageref)?;
+        let warnings = deploycheck::find_image_warnings(&media_types, &target_imgref.sigverify);
+        deploycheck::check_strict(&warnings)?;
+    }
+    let state = State {
+        selinux_override,
+        source_imageref,
+        source_digest,
+        source_strategy,
+        prefetched_source: None,
+        prefetched_bytes: 0,
+        config_opts,
+        target_opts,
+        cancellable: gio::Cancellable::new(),
+        ostree_config,
+        grub_config_fragment,
+        install_policy,
+        diagnostics,
+        target_arch,
+        inherited_kargs,
+        process_dir: lock::process_dir(),
+    };
+    install_interrupt_handler(state.cancellable.clone())?;
+
+    Ok(state)
+}
+
+/// Add the kernel arguments implied by the install configuration.
+fn push_install_kargs(state: &State, rootfs: &mut RootSetup) -> Result<()> {
+    let opts = &state.config_opts;
+    let deleted = |karg: &&String| {
+        opts.karg_delete
+            .iter()
+            .any(|d| d == *karg || d == karg_key(karg))
+    };
+    let inherited = state.inherited_kargs.iter().filter(|k| !deleted(k));
+    rootfs.kargs.extend(inherited.cloned());
+    let modes = [
+        (KargMode::Append, opts.karg.as_deref().unwrap_or_default()),
+        (KargMode::Replace, opts.karg_replace.as_slice()),
+        (
+            KargMode::AppendIfMissing,
+            opts.karg_append_if_missing.as_slice(),
+        ),
+    ];
+    for (mode, kargs) in modes {
+        for karg in kargs
+            .iter()
+            .filter(|k| mode != KargMode::Append || !deleted(k))
+        {
+            apply_karg(&mut rootfs.kargs, karg, mode)?;
+        }
+    }
+    let karg_arch = &state.config_opts.karg_arch;
+    let arch_kargs = arch_kargs(karg_arch, &state.target_arch).map(ToOwned::to_owned);
+    rootfs.kargs.extend(arch_kargs);
+    if state.selinux_override == Some(SelinuxOverride::Disable) {
+        rootfs.kargs.push("selinux=0".to_string());
+    }
+    // This is interpreted by our GRUB fragment
+    if state.config_opts.ignition_file.is_some() {
+        rootfs
+            .kargs
+            .push(crate::ignition::PLATFORM_METAL_KARG.to_string());
+        rootfs
+            .kargs
+            .push(crate::bootloader::IGNITION_VARIABLE.to_string());
+    }
+    // Interpreted by our GRUB fragment; the actual arguments live in the grubenv
+    if !state.config_opts.firstboot_karg.is_empty() {
+        rootfs
+            .kargs
+            .push(crate::bootloader::FIRSTBOOT_KARGS_VARIABLE.to_string());
+    }
+    Ok(())
+}
+
+/// Remove exact repeats of kernel arguments, keeping the first, and for the provided
+/// single-valued keys keep only the last argument, warning about the others.
+fn normalize_kargs<'a>(
+    kargs: &[String],
+    single_valued: impl Iterator<Item = &'a str> + Clone,
+    diagnostics: &Diagnostics,
+) -> Vec<String> {
+    let mut r: Vec<String> = Vec::with_capacity(kargs.len());
+    for karg in kargs {
+        if r.contains(karg) {
+            continue;
+        }
+        let key = karg_key(karg);
+        if single_valued.clone().any(|k| k == key) {
+            r.retain(|prev| {
+                let same_key = karg_key(prev) == key;
+                if same_key {
+                    diagnostics.warn(format!("Kernel argument {prev} is overridden by {karg}"));
+                }
+                !same_key
+            });
+        }
+        r.push(karg.clone());
+    }
+    r
+}
+
+async fn install_to_filesystem_impl(
+    ops: &dyn InstallOps,
+    state: &State,
+    rootfs: &mut RootSetup,
+    stage: InstallStage,
+    metrics: &mut metrics::InstallMetrics,
+) -> Result<summary::InstallSummary> {
+    push_install_kargs(state, rootfs)?;
+    let opts = &state.config_opts;
+    if opts.normalize_kargs {
+        let single_valued = SINGLE_VALUED_KARGS
+            .iter()
+            .copied()
+            .chain(opts.single_valued_karg.iter().map(|k| k.as_str()));
+        rootfs.kargs = normalize_kargs(&rootfs.kargs, single_valued, &state.diagnostics);
+    }
+    validate_required_kargs(&rootfs.kargs)?;
+    write_kargs_file(&lock::kargs_file(&state.process_dir), &rootfs.kargs)?;
+    let start = metrics::Phase::Deploy.enter();
+    let deployment = ops.deploy(state, rootfs).await?;
+    metrics.phase(metrics::Phase::Deploy, start);
+    let target = targetdeploy::TargetDeployment::mount(
+        ops,
+        &rootfs.rootfs.join(&deployment.path),
+        &lock::target_deployment_dir(&state.process_dir),
+    )?;
+    if opts.inspect_shell {
+        inspect_shell(ops, &rootfs.rootfs, target.path())?;
+        anyhow::bail!("Installation was not finalized due to --inspect-shell");
+    }
+    let start = metrics::Phase::Finish.enter();
+    let summary = finish_install(state, rootfs, deployment, target.path(), stage, ops)?;
+    target.unmount()?;
+    metrics.phase(metrics::Phase::Finish, start);
+    metrics.image_size = summary.layer_bytes;
+    metrics.fetched_bytes = summary.fetched_bytes;
+    metrics.digest = Some(summary.digest.clone());
+    let bootfs = rootfs.rootfs.join("boot");
+    metrics.written_bytes = metrics::used_bytes(&[&rootfs.rootfs, &bootfs])?;
+    Ok(summary)
+}
+
+/// Write the mounts for the target system into the deployment, either as entries in
+/// `/etc/fstab` or as systemd mount units.
+#[context("Writing mounts")]
+fn write_target_mounts(
+    state: &State,
+    root_setup: &RootSetup,
+    deployment_root: &Utf8Path,
+    ops: &dyn InstallOps,
+) -> Result<()> {
+    let extra_mounts = state.config_opts.extra_mount.as_slice();
+    let root = Dir::open_ambient_dir(deployment_root, cap_std::ambient_authority())
+        .context("Opening deployment dir")?;
+    if state.config_opts.mount_units {
+        let units = mount_units(root_setup, extra_mounts);
+        write_mount_units(&root, &units)?;
+        if state.selinux_override.is_none() {
+            for unit in units.iter() {
+                let install_dir = Utf8Path::new(unit.install_dir);
+                for p in [
+                    Utf8Path::new(&unit.name),
+                    install_dir,
+                    &install_dir.join(&unit.name),
+                ] {
+                    let p = Utf8Path::new(SYSTEMD_UNIT_DIR).join(p);
+                    ops.lsm_label(
+                        &deployment_root.join(&p),
+                        &Utf8Path::new("/").join(&p),
+                        false,
+                    )?;
+                }
+            }
+        }
+    } else {
+        let existing_fstab = if let Some(mut f) = root.open_optional("etc/fstab")? {
+            let mut buf = String::new();
+            f.read_to_string(&mut buf).context("Reading etc/fstab")?;
+            Some(buf)
+        } else {
+            None
+        };
+        let mut f = {
+            let mut opts = cap_std::fs::OpenOptions::new();
+            root.open_with("etc/fstab", opts.append(true).write(true).create(true))
+                .context("Opening etc/fstab")
+                .map(BufWriter::new)?
+        };
+        let mut fstab = fstab_append_contents(existing_fstab.as_deref(), root_setup, extra_mounts);
+        if let Some(swap) = state.config_opts.swap.as_ref() {
+            fstab.push_str(&swap.fstab_entry());
+            fstab.push('\n');
+        }
+        f.write_all(fstab.as_bytes())?;
+        f.flush()?;
+    }
+    Ok(())
+}
+
+/// Complete the installation after the initial deployment has been created and mounted
+/// at `deployment_root`; all privileged operations are performed via `ops`.
+fn finish_install(
+    state: &State,
+    rootfs: &mut RootSetup,
+    mut deployment: InitialDeployment,
+    deployment_root: &Utf8Path,
+    stage: InstallStage,
+    ops: &dyn InstallOps,
+) -> Result<summary::InstallSummary> {
+    let label = |path: &Utf8Path, as_path: &Utf8Path| -> Result<()> {
+        if state.selinux_override.is_some() {
+            return Ok(());
+        }
+        ops.lsm_label(path, as_path, false)
+    };
+
+    let deployment_dir = Dir::open_ambient_dir(deployment_root, cap_std::ambient_authority())
+        .with_context(|| format!("Opening {deployment_root}"))?;
+    let var = deployment.stateroot_var(&rootfs.rootfs)?;
+    // Unlike /boot/efi, which is on the /boot filesystem, the image may lack /efi
+    let esp_mountpoint = state.config_opts.esp_mountpoint;
+    if rootfs.esp.is_some() && esp_mountpoint == EspMountpoint::Efi {
+        let dir = deployment_root.join(esp_mountpoint.relpath());
+        if dir.symlink_metadata().is_err() {
+            std::fs::create_dir(&dir).with_context(|| format!("Creating {dir}"))?;
+            label(&dir, Utf8Path::new(esp_mountpoint.path()))?;
+        }
+    }
+    // This must come before the fstab entry is written
+    if let Some(swap) = state.config_opts.swap.as_ref() {
+        let fstype = rootfs
+            .root_fstype
+            .as_deref()
+            .ok_or_else(|| anyhow!("--swap requires a root filesystem of known type"))?;
+        let nocow = swap::check_supported(fstype)?;
+        let path = var.join(swap::SWAPFILE_PATH.trim_start_matches("/var/"));
+        // SAFETY: The path has a parent
+        let dir = path.parent().unwrap();
+        std::fs::create_dir_all(dir).with_context(|| format!("Creating {dir}"))?;
+        label(dir, Utf8Path::new(swap::SWAPFILE_PATH).parent().unwrap())?;
+        ops.create_swapfile(&path, swap.size_mib, nocow)?;
+        label(&path, Utf8Path::new(swap::SWAPFILE_PATH))?;
+        println!("Created {} MiB swapfile", swap.size_mib);
+    }
+
+    if state.config_opts.persistent_journal {
+        let dir = journal::create_journal_dir(&var, deployment_root)?;
+        let as_path = Utf8Path::new(journal::JOURNAL_DIR);
+        // SAFETY: Both paths have a parent
+        label(dir.parent().unwrap(), as_path.parent().unwrap())?;
+        label(&dir, as_path)?;
+        let settings = &state.config_opts.journald_conf;
+        if !settings.is_empty() {
+            let path = journal::write_dropin(deployment_root, settings)?;
+            label(&path, &Utf8Path::new("/").join(journal::DROPIN_PATH))?;
+        }
+        println!("Configured persistent journal");
+    }
+
+    if !state.config_opts.dns.is_empty() {
+        let opts = &state.config_opts;
+        let path = dns::write_dns_config(deployment_root, &opts.dns, &opts.dns_search)?;
+        label(&deployment_root.join(path), &Utf8Path::new("/").join(path))?;
+        println!("Configured DNS in /{path}");
+    }
+    if let Some(key) = state.target_opts.ostree_remote_gpg_key.as_deref() {
+        // SAFETY: Checked when validating the options
+        let remote = state.target_opts.target_ostree_remote.as_deref().unwrap();
+        let contents = remotekey::read_key(key)?;
+        let (keypath, config) = remotekey::install_remote_key(deployment_root, remote, &contents)?;
+        label(
+            &deployment_root.join(&keypath),
+            &Utf8Path::new("/").join(&keypath),
+        )?;
+        println!("Configured ostree remote {remote} to verify with /{keypath} (in /{config})");
+    }
+
+    let selinux_mode = target_selinux_mode(
+        state.selinux_override == Some(SelinuxOverride::Disable),
+        state.config_opts.selinux_enforcing,
+    );
+    if let Some(mode) = selinux_mode {
+        crate::lsm::set_selinux_config_mode(deployment_root, mode)?;
+        let path = crate::lsm::SELINUX_CONFIG;
+        label(&deployment_root.join(path), &Utf8Path::new("/").join(path))?;
+        println!("Set SELinux mode to {mode}");
+    }
+    let booleans = &state.config_opts.selinux_boolean;
+    if !booleans.is_empty() {
+        let store = sebool::write_booleans(deployment_root, booleans)?;
+        ops.rebuild_selinux_policy(deployment_root)?;
+        // The rebuild replaces files in the policy store, which includes the policy
+        if state.selinux_override.is_none() {
+            let as_path = Utf8Path::new("/").join(&store);
+            ops.lsm_label(&deployment_root.join(&store), &as_path, true)?;
+        }
+        let booleans = booleans.iter().map(ToString::to_string).collect::<Vec<_>>();
+        println!("Set SELinux booleans: {}", booleans.join(", "));
+    }
+    if state.selinux_override == Some(SelinuxOverride::FirstbootRelabel) {
+        let path = deployment_root.join(AUTORELABEL_PATH);
+        std::fs::write(&path, "").with_context(|| format!("Writing {path}"))?;
+        println!("Created /{AUTORELABEL_PATH}; the first boot relabels the system");
+    }
+
+    write_target_mounts(state, rootfs, deployment_root, ops)?;
+
+    if !rootfs.ssh_host_keys.is_empty() {
+        sshkeys::restore_ssh_host_keys(deployment_root, &rootfs.ssh_host_keys, label)?;
+        let names = rootfs.ssh_host_keys.iter().map(|k| k.name.clone());
+        deployment.aleph.preserved_ssh_host_keys = names.collect();
+        println!(
+            "Preserved {} SSH host key files",
+            rootfs.ssh_host_keys.len()
+        );
+    }
+    if let Some(id) = rootfs.machine_id.as_deref() {
+        machineid::restore_machine_id(deployment_root, id, label)?;
+        println!("Preserved machine ID {id}");
+    }
+    // Write the aleph data that captures the system state at the time of provisioning for aid in future debugging.
+    // The root belongs to the existing system when adopting it
+    let path = if let Some(stateroot) = rootfs.adopt_stateroot.as_deref() {
+        format!("ostree/deploy/{stateroot}/{BOOTC_ALEPH_PATH}")
+    } else {
+        BOOTC_ALEPH_PATH.to_owned()
+    };
+    rootfs
+        .rootfs_fd
+        .atomic_replace_with(&path, |f| {
+            serde_json::to_writer(f, &deployment.aleph)?;
+            anyhow::Ok(())
+        })
+        .with_context(|| format!("Writing {path}"))?;
+    if let Some(path) = state.config_opts.aleph_path.as_ref() {
+        let format = state.config_opts.aleph_format;
+        aleph::write_aleph(&deployment_dir, path, format, &deployment.aleph)?;
+        label(&deployment_root.join(path), &Utf8Path::new("/").join(path))?;
+    }
+    if let Some(dev) = rootfs.metadata_partition.as_deref() {
+        // With --mount-units, the image may not have an fstab
+        let fstab = if let Some(mut f) = deployment_dir.open_optional("etc/fstab")? {
+            let mut buf = String::new();
+            f.read_to_string(&mut buf).context("Reading etc/fstab")?;
+            buf
+        } else {
+            String::new()
+        };
+        let tmp = tempfile::tempdir().context("Creating temporary directory")?;
+        let target = Utf8Path::from_path(tmp.path())
+            .ok_or_else(|| anyhow!("Non-UTF8 path {}", tmp.path().display()))?;
+        let mount = ops::MountGuard::mount(ops, dev, target)?;
+        let metadata_dir = Dir::open_ambient_dir(target, cap_std::ambient_authority())?;
+        let kargs = read_kargs_file(&lock::kargs_file(&state.process_dir))?;
+        write_metadata(&metadata_dir, &deployment.aleph, &kargs, &fstab)?;
+        drop(metadata_dir);
+        mount.unmount()?;
+    }
+    // These must come before the rescue entry is created from the default one
+    let kver = state.config_opts.kernel.as_deref();
+    if let Some(kver) = kver {
+        let bootfs = rootfs.rootfs.join("boot");
+        let bootfs = Dir::open_ambient_dir(&bootfs, cap_std::ambient_authority())
+            .with_context(|| format!("Opening {bootfs}"))?;
+        let (kernel, initrd) = kernel::install_kernel(&deployment_dir, kver, &bootfs)?;
+        crate::bootloader::set_kernel(&bootfs, &kernel, &initrd)?;
+        println!("Selected kernel {kver}");
+    }
+    if let Some(spec) = state.config_opts.devicetree.as_ref() {
+        let dtb =
+            devicetree::resolve_dtb(&deployment_dir, spec, kver, devicetree::host_compatible)?;
+        let bootfs = rootfs.rootfs.join("boot");
+        let bootfs = Dir::open_ambient_dir(&bootfs, cap_std::ambient_authority())
+            .with_context(|| format!("Opening {bootfs}"))?;
+        let installed = devicetree::install_dtb(&deployment_dir, &dtb, &bootfs)?;
+        crate::bootloader::set_devicetree(&bootfs, &installed)?;
+        devicetree::write_selection(&deployment_dir, &dtb)?;
+        let path = Utf8Path::new(devicetree::ETC_DEVICETREE);
+        // SAFETY: The path has a parent
+        for p in [path.parent().unwrap(), path] {
+            label(&deployment_root.join(p), &Utf8Path::new("/").join(p))?;
+        }
+        println!("Installed devicetree /{dtb}");
+    }
+
+    if state.config_opts.rescue_entry {
+        let kargs = if state.config_opts.rescue_karg.is_empty() {
+            RESCUE_KARGS_DEFAULT.iter().map(|&v| v.to_owned()).collect()
+        } else {
+            state.config_opts.rescue_karg.clone()
+        };
+        crate::bootloader::write_rescue_entry(&rootfs.rootfs, &kargs)?;
+    }
+    if let Some(tries) = state.config_opts.boot_counting {
+        let bootfs = rootfs.rootfs.join("boot");
+        let bootfs = Dir::open_ambient_dir(&bootfs, cap_std::ambient_authority())
+            .with_context(|| format!("Opening {bootfs}"))?;
+        let entry = crate::bootloader::set_boot_counting(&bootfs, tries)?;
+        let link = crate::bootloader::enable_boot_assessment(&deployment_dir)?;
+        let link = Utf8Path::new(&link);
+        let as_path = Utf8Path::new("/").join(link);
+        // SAFETY: Both paths have a parent
+        label(
+            &deployment_root.join(link.parent().unwrap()),
+            as_path.parent().unwrap(),
+        )?;
+        label(&deployment_root.join(link), &as_path)?;
+        println!("Enabled boot counting with {tries} attempts for {entry}");
+    }
+
+    // If Ignition is specified, enable it
+    if let Some(ignition_file) = state.config_opts.ignition_file.as_deref() {
+        let src = std::fs::File::open(ignition_file)
+            .with_context(|| format!("Opening {ignition_file}"))?;
+        let bootfs = rootfs.rootfs.join("boot");
+        crate::ignition::write_ignition(&bootfs, &state.config_opts.ignition_hash, &src)?;
+        crate::ignition::enable_firstboot(&bootfs)?;
+        println!("Installed Ignition config from {ignition_file}");
+    }
+
+    if state.config_opts.seed_etc {
+        let n = seed_etc(deployment_root, label).context("Seeding /etc")?;
+        println!("Seeded {n} entries from /usr/etc into /etc");
+    }
+
+    if state.config_opts.save_sbom {
+        let sbom = if let Some(sbom) = deployment.sbom.take() {
+            println!("Saving SBOM from registry");
+            Some(sbom)
+        } else if let Some((path, sbom)) = sbom::find_embedded_sbom(&deployment_dir)? {
+            println!("Saving SBOM from /{path}");
+            Some(sbom)
+        } else {
+            state.diagnostics.warn("No SBOM found for the image");
+            None
+        };
+        if let Some(sbom) = sbom {
+            sbom::write_sbom(&deployment_dir, &sbom)?;
+            label(
+                &deployment_root.join(sbom::SBOM_PATH),
+                &Utf8Path::new("/").join(sbom::SBOM_PATH),
+            )?;
+        }
+    }
+    if !state.config_opts.no_version_file {
+        let target = target_imgref_from_opts(&state.target_opts, &state.source_imageref)?;
+        let contents = version_file_contents(&target.imgref.name, &deployment.digest);
+        deployment_dir
+            .atomic_write_with_perms(
+                BOOTC_VERSION_PATH,
+                contents,
+                cap_std::fs::Permissions::from_mode(0o644),
+            )
+            .with_context(|| format!("Writing {BOOTC_VERSION_PATH}"))?;
+        label(
+            &deployment_root.join(BOOTC_VERSION_PATH),
+            &Utf8Path::new("/").join(BOOTC_VERSION_PATH),
+        )?;
+    }
+    for path in state.install_policy.write(&deployment_dir)? {
+        println!("Installed {path}");
+        label(
+            &deployment_root.join(&path),
+            &Utf8Path::new("/").join(&path),
+        )?;
+    }
+    check_update_sigpolicy(state, &deployment_dir)?;
+
+    ops.run_in_target(deployment_root, &state.config_opts.run_in_target)?;
+
+    if stage == InstallStage::DeployOnly {
+        println!("Stopping after deploying the image (--stage deploy-only)");
+        return install_summary(state, rootfs, deployment, Vec::new());
+    }
+
+    let bootloader = if rootfs.adopt_stateroot.is_some() {
+        // The existing bootloader reads the boot entries of all stateroots
+        println!("Keeping the existing bootloader");
+        Vec::new()
+    } else if let Some(device) = rootfs.device.as_deref() {
+        let boot_uuid = rootfs.get_boot_uuid()?;
+        let bootloader = ops.install_bootloader(
+            device,
+            &rootfs.rootfs,
+            state.config_opts.esp_mountpoint,
+            boot_uuid,
+        )?;
+        tracing::debug!("Installed bootloader");
+        bootloader
+    } else {
+        println!(
+            "Not installing a bootloader (--stateless); the installed root must be booted by an external mechanism"
+        );
+        Vec::new()
+    };
+    if let Some(fragment) = state.grub_config_fragment.as_deref() {
+        crate::bootloader::install_grub_fragment(&rootfs.rootfs, fragment)?;
+    }
+    if !state.config_opts.firstboot_karg.is_empty() {
+        crate::bootloader::write_firstboot_kargs(
+            &rootfs.rootfs,
+            &state.config_opts.firstboot_karg,
+        )?;
+    }
+    if let Some(source) = rootfs.sync_esp.as_deref() {
+        let esp = rootfs.rootfs.join(esp_mountpoint.relpath());
+        let tmp = tempfile::tempdir().context("Creating temporary directory")?;
+        let target = Utf8Path::from_path(tmp.path())
+            .ok_or_else(|| anyhow!("Non-UTF8 path {}", tmp.path().display()))?;
+        ops.mount(source, target)?;
+        let copied = syncesp::copy_esp(&esp, target);
+        ops.unmount(target)?;
+        let n = copied?;
+        for p in syncesp::write_units(&deployment_dir, esp_mountpoint, source)? {
+            label(&deployment_root.join(&p), &Utf8Path::new("/").join(&p))?;
+        }
+        println!("Copied {n} files to the second EFI system partition");
+    }
+
+    // ostree likes to have the immutable bit on the physical sysroot to ensure
+    // that it doesn't accumulate junk; all system state should be in deployments.
+    if state.config_opts.root_mutability().immutable_bit {
+        ops.set_immutable(&rootfs.rootfs_fd)?;
+    } else {
+        state
+            .diagnostics
+            .warn("Leaving root filesystem mutable (--dev-mutable)");
+    }
+
+    // Finalize mounted filesystems; with --stateless, they need not be backed by a
+    // block device, and are left as is
+    let bootfs = rootfs.rootfs.join("boot");
+    if rootfs.device.is_some() {
+        for fs in [bootfs.as_path(), rootfs.rootfs.as_path()] {
+            ops.finalize_filesystem(fs)?;
+        }
+    }
+
+    // The filesystems are now read-only, so this sees exactly what will be booted
+    if state.config_opts.verify_bootable {
+        let bootfs = Dir::open_ambient_dir(&bootfs, cap_std::ambient_authority())
+            .with_context(|| format!("Opening {bootfs}"))?;
+        crate::bootloader::verify_boot_chain(&bootfs, |spec| ops.root_device_exists(spec))?;
+        println!("Verified boot chain");
+    }
+
+    install_summary(state, rootfs, deployment, bootloader)
+}
+
+/// Summarize the installation.
+fn install_summary(
+    state: &State,
+    rootfs: &RootSetup,
+    deployment: InitialDeployment,
+    bootloader: Vec<crate::bootloader::BootloaderComponent>,
+) -> Result<summary::InstallSummary> {
+    let summary = summary::InstallSummary {
+        bootloader_device: rootfs.device.as_ref().map(ToString::to_string),
+        root_uuid: rootfs.root.get_source_uuid().map(ToOwned::to_owned),
+        boot_uuid: rootfs
+            .boot
+            .as_ref()
+            .map(require_boot_uuid)
+            .transpose()?
+            .map(ToOwned::to_owned),
+        stateroot: rootfs.stateroot().to_string(),
+        digest: deployment.digest,
+        bootloader,
+        ostree_config: state.ostree_config.clone(),
+        extra_partitions: rootfs.extra_partitions.clone(),
+        layer_bytes: deployment.layer_bytes,
+        fetched_bytes: state.prefetched_bytes + deployment.fetched_bytes,
+        warnings: state.diagnostics.warnings(),
+        deploy_warnings: deployment.warnings,
+        post_install: Default::default(),
+    };
+    Ok(summary)
+}
+
+/// Warn if updates are expected to be verified via the container signature policy, but
+/// the target's policy accepts any image.
+fn check_update_sigpolicy(state: &State, deployment: &Dir) -> Result<()> {
+    let target = target_imgref_from_opts(&state.target_opts, &state.source_imageref)?;
+    if target.sigverify != SignatureSource::ContainerPolicy
+        || target.imgref.transport != ostree_container::Transport::Registry
+    {
+        return Ok(());
+    }
+    let image = target.imgref.name.as_str();
+    match sigpolicy::deployment_accepts_anything(deployment, image)? {
+        Some(false) => {}
+        Some(true) => state.diagnostics.warn(format!(
+            "/{} in the target accepts any image for {image}; updates will not be verified.  \
+             Use --install-policy to install a stricter policy.",
+            sigpolicy::POLICY_PATH
+        )),
+        None => state.diagnostics.warn(format!(
+            "No /{} in the target; updates cannot be verified",
+            sigpolicy::POLICY_PATH
+        )),
+    }
+    Ok(())
+}
+
+/// If requested, redirect our stdout to stderr so that it only contains the `--print-env` output.
+/// This is done again by the processes we re-execute in `prepare_install`, which are given
+/// the original stdout.
+fn redirect_stdout_for_print_env(
+    config_opts: &InstallConfigOpts,
+) -> Result<Option<crate::utils::StdoutToStderr>> {
+    config_opts
+        .print_env
+        .then(crate::utils::StdoutToStderr::new)
+        .transpose()
+}
+
+fn installation_complete(
+    state: &State,
+    summary: &summary::InstallSummary,
+    stdout_redirect: Option<crate::utils::StdoutToStderr>,
+) -> Result<()> {
+    // Written now that the post-install action is decided
+    if let Some(path) = state.config_opts.write_anaconda_results.as_deref() {
+        let mut f = std::fs::File::create(path)
+            .with_context(|| format!("Creating {path}"))
+            .map(BufWriter::new)?;
+        summary.write_anaconda_results(&mut f)?;
+        f.flush()?;
+    }
+    state.diagnostics.write_summary(std::io::stdout().lock())?;
+    println!("Installation complete!");
+    if let Some(stdout_redirect) = stdout_redirect {
+        drop(stdout_redirect);
+        let mut stdout = std::io::stdout().lock();
+        summary.write_env(&mut stdout)?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+/// Partition the target block device and create the filesystems.  With `--pull-first`,
+/// the source image is fetched first, so that failing to fetch it leaves the device as is.
+fn create_rootfs(
+    ops: &dyn InstallOps,
+    state: &mut State,
+    block_opts: InstallBlockDeviceOpts,
+) -> Result<RootSetup> {
+    prefetch_source(ops, state)?;
+    ops.create_rootfs(
+        block_opts,
+        state.config_opts.boot_karg_by,
+        state.config_opts.esp_mountpoint,
+        &state.diagnostics,
+    )
+}
+
+/// Implementation of the `bootc install` CLI command.
+pub(crate) async fn install(opts: InstallOpts) -> Result<summary::InstallSummary> {
+    let metrics_file = opts.config_opts.metrics_file.clone();
+    let mut metrics = metrics::InstallMetrics::default();
+    let r = install_with_metrics(opts, &mut metrics).await;
+    metrics.write_outcome(metrics_file.as_deref(), r)
+}
+
+async fn install_with_metrics(
+    opts: InstallOpts,
+    metrics: &mut metrics::InstallMetrics,
+) -> Result<summary::InstallSummary> {
+    let block_opts = opts.block_opts;
+    let _lock = lock::lock_target(&block_opts.device)?;
+    let post_install = opts.post_install;
+    if post_install != postinstall::PostInstall::None
+        && postinstall::is_loopback(&block_opts.device)?
+    {
+        anyhow::bail!("--post-install is not supported when installing to a loopback device");
+    }
+    let stdout_redirect = redirect_stdout_for_print_env(&opts.config_opts)?;
+    let start = metrics::Phase::Prepare.enter();
+    let mut state =
+        prepare_install(opts.config_opts, opts.target_opts, Some(&block_opts), None).await?;
+    metrics.phase(metrics::Phase::Prepare, start);
+
+    // This is all blocking stuff
+    let start = metrics::Phase::Partition.enter();
+    if let Some(pattern) = state.config_opts.require_existing_image.as_deref() {
+        if !block_opts.wipe {
+            anyhow::bail!("--require-existing-image requires --wipe");
+        }
+        existing::check_device(
+            &block_opts.device,
+            pattern,
+            state.config_opts.force,
+            &state.diagnostics,
+        )?;
+    }
+    // Creating the filesystems generates UUIDs, which may block on entropy
+    let host_root = cap_std::fs::Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let luks = block_opts.block_setup == baseline::BlockSetup::Tpm2Luks;
+    let root_lv = block_opts.root_lv()?;
+    for msg in entropy::check(&host_root, entropy::rng_initialized()?, luks)? {
+        state.diagnostics.warn(msg);
+    }
+    let (state, mut rootfs) = tokio::task::spawn_blocking(move || {
+        let rootfs = create_rootfs(&ops::HostOps, &mut state, block_opts)?;
+        anyhow::Ok((Arc::new(state), rootfs))
+    })
+    .await??;
+    metrics.fetched_bytes = state.prefetched_bytes;
+    metrics.phase(metrics::Phase::Partition, start);
+
+    let mut summary = install_to_filesystem_impl(
+        &ops::HostOps,
+        &state,
+        &mut rootfs,
+        InstallStage::Full,
+        metrics,
+    )
+    .await?;
+    summary.post_install = post_install;
+    if post_install == postinstall::PostInstall::Kexec {
+        if let Err(e) = postinstall::load_kexec(&rootfs.rootfs.join("boot")) {
+            state.diagnostics.warn(format!("{e:#}; rebooting instead"));
+            summary.post_install = postinstall::PostInstall::Reboot;
+            summary.warnings = state.diagnostics.warnings();
+        }
+    }
+
+    // Drop all data about the root except the path to ensure any file descriptors etc. are closed.
+    let rootfs_path = rootfs.rootfs.clone();
+    drop(rootfs);
+
+    let start = metrics::Phase::Unmount.enter();
+    Task::new_and_run(
+        "Unmounting filesystems",
+        "umount",
+        ["-R", rootfs_path.as_str()],
+    )?;
+    if let Some(root_lv) = root_lv {
+        lvm::deactivate_vg(&root_lv.vg)?;
+    }
+    metrics.phase(metrics::Phase::Unmount, start);
+
+    installation_complete(&state, &summary, stdout_redirect)?;
+    Ok(summary)
+}
+
+#[context("Verifying empty rootfs")]
+fn require_empty_rootdir(rootfs_fd: &Dir, esp_mountpoint: EspMountpoint) -> Result<()> {
+    for e in rootfs_fd.entries()? {
+        let e = e?;
+        let name = e.file_name();
+        let name = name
+            .to_str()
+            .ok_or_else(|| anyhow!("Invalid non-UTF8 filename: {name:?}"))?;
+        if name == LOST_AND_FOUND || name == esp_mountpoint.relpath() {
+            continue;
+        }
+        // There must be a boot directory (that is empty)
+        if name == BOOT {
+            let mut entries = rootfs_fd.read_dir(BOOT)?;
+            if let Some(e) = entries.next() {
+                let e = e?;
+                let name = e.file_name();
+                let name = name
+                    .to_str()
+                    .ok_or_else(|| anyhow!("Invalid non-UTF8 filename: {name:?}"))?;
+                if matches!(name, LOST_AND_FOUND | crate::bootloader::EFI_DIR) {
+                    continue;
+                }
+                anyhow::bail!("Non-empty boot directory, found {name:?}");
+            }
+        } else {
+            anyhow::bail!("Non-empty root filesystem; found {name:?}");
+        }
+    }
+    Ok(())
+}
+
+/// Implementation of the `bootc install-to-filsystem` CLI command.
+pub(crate) async fn install_to_filesystem(
+    opts: InstallToFilesystemOpts,
+) -> Result<summary::InstallSummary> {
+    let metrics_file = opts.config_opts.metrics_file.clone();
+    let mut metrics = metrics::InstallMetrics::default();
+    let r = install_to_filesystem_with_metrics(opts, &mut metrics).await;
+    metrics.write_outcome(metrics_file.as_deref(), r)
+}
+
+/// Inspect the target root filesystem, returning the result and the mount specification
+/// of the root.  With `--stateless`, the root is booted by an external mechanism and may
+/// not be backed by a device at all, so it is not inspected.
+fn inspect_target_root(
+    ops: &dyn InstallOps,
+    root_path: &Utf8Path,
+    root_mount_spec: Option<String>,
+    stateless: bool,
+) -> Result<(Option<crate::mount::Filesystem>, String)> {
+    let inspect = if stateless {
+        None
+    } else {
+        Some(ops.inspect_filesystem(root_path)?)
     };
 
-    let mut temporary_dir = None;
-    let src_imageref = if skopeo_supports_containers_storage()? {
-        // We always use exactly the digest of the running image to ensure predictability.
-        let spec =
-            crate::utils::digested_pullspec(&state.source_imageref.name, &state.source_digest);
-        ostree_container::ImageReference {
-            transport: ostree_container::Transport::ContainerStorage,
-            name: spec,
+    // We support overriding the mount specification for root (i.e. LABEL vs UUID versus
+    // raw paths), optionally given as a kernel argument (e.g. root=live:...).
+    let root_mount_spec = if let Some(s) = root_mount_spec {
+        s.strip_prefix("root=").map(ToOwned::to_owned).unwrap_or(s)
+    } else {
+        let mut uuid = inspect
+            .as_ref()
+            .and_then(|i| i.uuid.clone())
+            .ok_or_else(|| anyhow!("No filesystem uuid found in target root"))?;
+        uuid.insert_str(0, "UUID=");
+        tracing::debug!("root {uuid}");
+        uuid
+    };
+    tracing::debug!("Root mount spec: {root_mount_spec}");
+    Ok((inspect, root_mount_spec))
+}
+
+async fn install_to_filesystem_with_metrics(
+    opts: InstallToFilesystemOpts,
+    metrics: &mut metrics::InstallMetrics,
+) -> Result<summary::InstallSummary> {
+    // Gather global state, destructuring the provided options
+    let stdout_redirect = redirect_stdout_for_print_env(&opts.config_opts)?;
+    let fsopts = opts.filesystem_opts;
+    let _lock = lock::lock_target(&fsopts.root_path)?;
+    let start = metrics::Phase::Prepare.enter();
+    let ops = ops::HostOps;
+    let mut state =
+        prepare_install(opts.config_opts, opts.target_opts, None, Some(&fsopts)).await?;
+    prefetch_source(&ops, &mut state)?;
+    let state = Arc::new(state);
+    metrics.fetched_bytes = state.prefetched_bytes;
+    metrics.phase(metrics::Phase::Prepare, start);
+
+    let root_path = &fsopts.root_path;
+    let rootfs_fd = Dir::open_ambient_dir(root_path, cap_std::ambient_authority())
+        .with_context(|| format!("Opening target root directory {root_path}"))?;
+    // The keys must be read before the root is wiped
+    let ssh_host_keys = match fsopts.preserve_ssh_host_keys {
+        Some(SshHostKeySource::Root) if !fsopts.wipe => {
+            anyhow::bail!("--preserve-ssh-host-keys=root requires --wipe")
+        }
+        Some(source) => sshkeys::stash_from_source(source, &rootfs_fd)?,
+        None => Vec::new(),
+    };
+    let machine_id = if fsopts.preserve_machine_id {
+        let id = machineid::stash_machine_id(&rootfs_fd)?;
+        if id.is_none() {
+            println!("No existing machine ID found; a new one will be generated on first boot");
         }
+        id
     } else {
-        let td = tempfile::tempdir_in("/var/tmp")?;
-        let path: &Utf8Path = td.path().try_into().unwrap();
-        let r = copy_to_oci(&state.source_imageref, path)?;
-        temporary_dir = Some(td);
-        r
+        None
     };
-    let src_imageref = ostree_container::OstreeImageReference {
-        // There are no signatures to verify since we're fetching the already
-        // pulled container.
-        sigverify: ostree_container::SignatureSource::ContainerPolicyAllowInsecure,
-        imgref: src_imageref,
+    if let Some(pattern) = state.config_opts.require_existing_image.as_deref() {
+        if !fsopts.wipe {
+            anyhow::bail!("--require-existing-image requires --wipe");
+        }
+        existing::check_root(
+            &rootfs_fd,
+            pattern,
+            state.config_opts.force,
+            &state.diagnostics,
+        )?;
+    }
+    let adopt_stateroot = if fsopts.adopt_existing_sysroot {
+        // SAFETY: Required by clap
+        let stateroot = fsopts.stateroot.unwrap();
+        adopt::validate_stateroot(&stateroot)?;
+        if !state.ostree_config.is_empty() {
+            anyhow::bail!("--ostree-config cannot be used with --adopt-existing-sysroot");
+        }
+        let existing = adopt::check_sysroot(&rootfs_fd, &stateroot)?;
+        println!(
+            "Adding stateroot {stateroot} beside existing stateroots: {}",
+            existing.join(" ")
+        );
+        Some(stateroot)
+    } else {
+        None
+    };
+    if adopt_stateroot.is_some() {
+        // The existing sysroot is kept
+    } else if fsopts.wipe {
+        let rootfs_fd = rootfs_fd.try_clone()?;
+        println!("Wiping contents of root");
+        tokio::task::spawn_blocking(move || {
+            for e in rootfs_fd.entries()? {
+                let e = e?;
+                rootfs_fd.remove_all_optional(e.file_name())?;
+            }
+            anyhow::Ok(())
+        })
+        .await??;
+    } else {
+        require_empty_rootdir(&rootfs_fd, state.config_opts.esp_mountpoint)?;
+    }
+
+    if fsopts.stateless && fsopts.reuse_esp != ReuseEsp::No {
+        anyhow::bail!("--reuse-esp cannot be used with --stateless");
+    }
+
+    // Gather data about the root filesystem
+    let (inspect, root_mount_spec) = inspect_target_root(
+        &ops,
+        &fsopts.root_path,
+        fsopts.root_mount_spec,
+        fsopts.stateless,
+    )?;
+    let root_fstype = inspect.as_ref().map(|i| i.fstype.clone());
+
+    // Find the real underlying backing device for the root.  This is currently just required
+    // for GRUB (BIOS) and in the future zipl (I think).
+    let backing_device = inspect.as_ref().map(|inspect| -> Result<String> {
+        let mut dev = inspect.source.clone();
+        loop {
+            tracing::debug!("Finding parents for {dev}");
+            let mut parents = ops.find_parent_devices(&dev)?.into_iter();
+            let parent = if let Some(f) = parents.next() {
+                f
+            } else {
+                break;
+            };
+            if let Some(next) = parents.next() {
+                anyhow::bail!(
+                    "Found multiple parent devices {parent} and {next}; not currently supported"
+                );
+            }
+            dev = parent;
+        }
+        Ok(dev)
+    });
+    let backing_device = backing_device.transpose()?;
+    tracing::debug!("Backing device: {backing_device:?}");
+    // The initramfs must assemble the multipath device before it can find the root
+    let multipath = match backing_device.as_deref() {
+        Some(dev) if ops.is_multipath(dev)? => {
+            println!("Root is on multipath device {dev}");
+            true
+        }
+        _ => false,
+    };
+
+    // Optionally find the boot partition and mount it ourselves
+    let mut discovered_boot = None;
+    let root_dev = rootfs_fd.dir_metadata()?.dev();
+    let boot_mounted = rootfs_fd
+        .symlink_metadata_optional(BOOT)?
+        .map_or(false, |m| m.dev() != root_dev);
+    if fsopts.discover_boot && !boot_mounted {
+        // SAFETY: --discover-boot conflicts with --stateless
+        let (backing_device, inspect) = (backing_device.as_deref().unwrap(), inspect.unwrap());
+        let device = ops.list_dev(Utf8Path::new(backing_device))?;
+        let bootpart = find_boot_partition(&device, &inspect.source)?
+            .ok_or_else(|| anyhow!("No boot partition found on {backing_device}"))?
+            .path();
+        let target = fsopts.root_path.join(BOOT);
+        std::fs::create_dir_all(&target)?;
+        discovered_boot = Some(ops::MountGuard::mount(&ops, &bootpart, &target)?);
+        println!("Using discovered boot partition {bootpart}");
+        if adopt_stateroot.is_some() {
+            // The existing boot entries are kept
+        } else if fsopts.wipe {
+            let bootfs_fd = rootfs_fd.open_dir(BOOT)?;
+            for e in bootfs_fd.entries()? {
+                bootfs_fd.remove_all_optional(e?.file_name())?;
+            }
+        } else {
+            require_empty_rootdir(&rootfs_fd, state.config_opts.esp_mountpoint)?;
+        }
+    }
+
+    // Verify /boot is a separate mount
+    let boot_dev = {
+        let boot_dev = rootfs_fd
+            .symlink_metadata_optional(BOOT)?
+            .ok_or_else(|| {
+                anyhow!("No /{BOOT} directory found in root; this is is currently required")
+            })?
+            .dev();
+        tracing::debug!("root_dev={root_dev} boot_dev={boot_dev}");
+        if root_dev == boot_dev && !fsopts.stateless {
+            anyhow::bail!("/{BOOT} must currently be a separate mounted filesystem");
+        }
+        boot_dev
+    };
+    let (boot, bootarg) = if fsopts.stateless {
+        // The external boot mechanism needs no boot filesystem
+        let boot = fsopts
+            .boot_mount_spec
+            .map(|spec| MountSpec::new(&spec, "/boot"));
+        (boot, None)
+    } else {
+        // Find the UUID of /boot because we need it for GRUB.
+        let boot_path = fsopts.root_path.join(BOOT);
+        let boot_fs = ops
+            .inspect_filesystem(&boot_path)
+            .context("Inspecting /{BOOT}")?;
+        let boot_uuid = boot_fs
+            .uuid
+            .ok_or_else(|| anyhow!("No UUID found for /{BOOT}"))?;
+        tracing::debug!("boot UUID: {boot_uuid}");
+        let boot = if let Some(spec) = fsopts.boot_mount_spec {
+            MountSpec::new(&spec, "/boot")
+        } else {
+            MountSpec::new_uuid_src(&boot_uuid, "/boot")
+        };
+        // By default, we inject a boot= karg because things like FIPS compliance currently
+        // require checking in the initramfs.
+        let bootarg = boot_karg(
+            state.config_opts.boot_karg_by,
+            &boot,
+            boot_fs.label.as_deref(),
+        )?;
+        (Some(boot), Some(bootarg))
+    };
+
+    let rootarg = format!("root={root_mount_spec}");
+    let mut root = MountSpec::new(&root_mount_spec, "/");
+    root.options = fsopts.root_options;
+    let mut kargs = vec![rootarg, RW_KARG.to_string()];
+    kargs.extend(bootarg);
+    if multipath {
+        kargs.push(MULTIPATH_KARG.to_string());
+    }
+
+    // If there's a separately mounted ESP, find it too; it's only used if we need to
+    // generate a new fstab.
+    let esp_mountpoint = state.config_opts.esp_mountpoint;
+    let esp_relpath = Utf8Path::new(esp_mountpoint.relpath());
+    let esp_parent_dev = match esp_mountpoint {
+        EspMountpoint::BootEfi => boot_dev,
+        EspMountpoint::Efi => root_dev,
+    };
+    let esp_mounted = |rootfs_fd: &Dir| -> Result<bool> {
+        Ok(rootfs_fd
+            .symlink_metadata_optional(esp_relpath)?
+            .map_or(false, |m| m.dev() != esp_parent_dev))
+    };
+    // Optionally find and mount an existing ESP ourselves.
+    let mut reused_esp = None;
+    match fsopts.reuse_esp {
+        ReuseEsp::No => {}
+        _ if esp_mounted(&rootfs_fd)? => {
+            println!("Using the ESP already mounted at /{esp_relpath}");
+        }
+        reuse_esp => {
+            let reuse_esp = match reuse_esp {
+                ReuseEsp::Device(p) => ReuseEsp::Device(p.canonicalize_utf8()?),
+                o => o,
+            };
+            // SAFETY: --reuse-esp was rejected with --stateless
+            let backing_device = backing_device.as_deref().unwrap();
+            let device = ops.list_dev(Utf8Path::new(backing_device))?;
+            if let Some(esp) = reuse_esp.select(&device)? {
+                let uuid = device
+                    .children
+                    .iter()
+                    .flatten()
+                    .find(|d| d.path() == esp)
+                    .and_then(|d| d.uuid.as_deref())
+                    .ok_or_else(|| anyhow!("No filesystem UUID found for {esp}"))?;
+                let spec = MountSpec::new_esp(&format!("UUID={uuid}"), esp_mountpoint);
+                let target = fsopts.root_path.join(esp_relpath);
+                std::fs::create_dir_all(&target)?;
+                let mount = ops::MountGuard::mount(&ops, &esp, &target)?;
+                println!("Reusing existing ESP {esp}");
+                reused_esp = Some((mount, spec));
+            } else {
+                println!("No existing ESP found on {backing_device}");
+            }
+        }
+    }
+    // The reused ESP is mounted in the target like one created by the installer
+    let esp = if let Some((_, spec)) = reused_esp.as_ref() {
+        Some(spec.clone())
+    } else if esp_mounted(&rootfs_fd)? {
+        ops.inspect_filesystem(&fsopts.root_path.join(esp_relpath))?
+            .uuid
+            .map(|uuid| MountSpec::new_esp(&format!("UUID={uuid}"), esp_mountpoint))
+    } else {
+        None
+    };
+
+    let mut rootfs = RootSetup {
+        device: backing_device.map(Into::into),
+        rootfs: fsopts.root_path,
+        rootfs_fd,
+        root,
+        root_fstype,
+        boot,
+        esp,
+        extra_partitions: Vec::new(),
+        metadata_partition: None,
+        sync_esp: None,
+        ssh_host_keys,
+        machine_id,
+        adopt_stateroot,
+        kargs,
+    };
+
+    let summary =
+        install_to_filesystem_impl(&ops, &state, &mut rootfs, fsopts.stage, metrics).await?;
+
+    // Drop all data about the root except the path to ensure any file descriptors etc. are closed.
+    drop(rootfs);
+
+    let start = metrics::Phase::Unmount.enter();
+    if let Some((esp, _)) = reused_esp {
+        esp.unmount()?;
+    }
+    if let Some(boot) = discovered_boot {
+        boot.unmount()?;
+    }
+    metrics.phase(metrics::Phase::Unmount, start);
+
+    installation_complete(&state, &summary, stdout_redirect)?;
+    Ok(summary)
+}
+
+#[test]
+fn install_opts_serializable() {
+    let c: InstallOpts = serde_json::from_value(serde_json::json!({
+        "device": "/dev/vda"
+    }))
+    .unwrap();
+    assert_eq!(c.block_opts.device, "/dev/vda");
+}
+
+#[test]
+fn test_reuse_esp() {
+    let lsblk = serde_json::json!({
+        "name": "vda",
+        "children": [
+            {"name": "vda1", "parttype": "21686148-6449-6e6f-744e-656564454649"},
+            {"name": "vda2", "fstype": "vfat", "uuid": "7B77-95E7",
+             "parttype": "c12a7328-f81f-11d2-ba4b-00a0c93ec93b"},
+            {"name": "vda3", "fstype": "ext4", "parttype": "0fc63daf-8483-4772-8e79-3d69d8477de4"},
+            {"name": "vda4", "fstype": "vfat", "parttype": "ebd0a0a2-b9e5-4433-87c0-68b6b72699c7"},
+        ]
+    });
+    let dev: crate::blockdev::Device = serde_json::from_value(lsblk.clone()).unwrap();
+    assert_eq!(ReuseEsp::No.select(&dev).unwrap(), None);
+    assert_eq!(ReuseEsp::Auto.select(&dev).unwrap().unwrap(), "/dev/vda2");
+    let explicit = |p: &str| ReuseEsp::from_str(p).unwrap().select(&dev);
+    assert_eq!(explicit("/dev/vda4").unwrap().unwrap(), "/dev/vda4");
+    assert!(explicit("/dev/vda3").is_err());
+    assert!(explicit("/dev/vdb2").is_err());
+
+    // Multiple ESPs are ambiguous
+    let mut multiple = lsblk.clone();
+    multiple["children"][3]["parttype"] = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B".into();
+    let dev: crate::blockdev::Device = serde_json::from_value(multiple).unwrap();
+    assert!(ReuseEsp::Auto.select(&dev).is_err());
+
+    // No partitions at all
+    let dev: crate::blockdev::Device =
+        serde_json::from_value(serde_json::json!({"name": "vda"})).unwrap();
+    assert_eq!(ReuseEsp::Auto.select(&dev).unwrap(), None);
+
+    assert_eq!(ReuseEsp::from_str("no").unwrap(), ReuseEsp::No);
+    assert_eq!(ReuseEsp::from_str("auto").unwrap(), ReuseEsp::Auto);
+    assert!(ReuseEsp::from_str("vda2").is_err());
+}
+
+#[test]
+fn test_write_metadata() {
+    let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    let aleph = InstallAleph {
+        image: "quay.io/example/os:latest".into(),
+        kernel: "6.0.9-300.fc37.x86_64".into(),
+        ostree_config: BTreeMap::new(),
+        preserved_ssh_host_keys: vec!["ssh_host_ed25519_key".into()],
+        stream: None,
+        target_image: None,
+        selinux_firstboot_relabel: true,
+    };
+    let kargs = ["root=UUID=rootuuid", "rw", "boot=UUID=bootuuid"].map(String::from);
+    let fstab = "UUID=rootuuid / auto defaults 0 1\n";
+    write_metadata(&td, &aleph, &kargs, fstab).unwrap();
+    let written: serde_json::Value =
+        serde_json::from_str(&td.read_to_string(METADATA_ALEPH).unwrap()).unwrap();
+    assert_eq!(
+        written,
+        serde_json::json!({
+            "image": "quay.io/example/os:latest",
+            "kernel": "6.0.9-300.fc37.x86_64",
+            "preserved_ssh_host_keys": ["ssh_host_ed25519_key"],
+            "selinux_firstboot_relabel": true,
+        })
+    );
+    assert_eq!(
+        td.read_to_string(METADATA_KARGS).unwrap(),
+        "root=UUID=rootuuid rw boot=UUID=bootuuid\n"
+    );
+    assert_eq!(td.read_to_string(METADATA_FSTAB).unwrap(), fstab);
+}
+
+#[test]
+fn test_find_boot_partition() {
+    let lsblk = serde_json::json!({
+        "name": "sda",
+        "children": [
+            {"name": "sda1", "parttype": "21686148-6449-6e6f-744e-656564454649"},
+            {"name": "sda2", "fstype": "vfat", "uuid": "7B77-95E7", "label": "EFI-SYSTEM",
+             "parttype": "c12a7328-f81f-11d2-ba4b-00a0c93ec93b"},
+            {"name": "sda3", "fstype": "ext4", "uuid": "0e2d7a53-7d9c-4ef6-a66c-8cd0b0cb8a4c",
+             "partlabel": "boot", "parttype": "0fc63daf-8483-4772-8e79-3d69d8477de4"},
+            {"name": "sda4", "fstype": "xfs", "uuid": "e4a8bcb9-9d93-44a4-9af7-6e2f4a1d8bd8",
+             "label": "root", "parttype": "0fc63daf-8483-4772-8e79-3d69d8477de4"},
+        ]
+    });
+    let parse = |v: &serde_json::Value| -> crate::blockdev::Device {
+        serde_json::from_value(v.clone()).unwrap()
+    };
+    let dev = parse(&lsblk);
+    let boot = find_boot_partition(&dev, "/dev/sda4").unwrap().unwrap();
+    assert_eq!(boot.path(), "/dev/sda3");
+    assert_eq!(
+        boot.uuid.as_deref(),
+        Some("0e2d7a53-7d9c-4ef6-a66c-8cd0b0cb8a4c")
+    );
+
+    // Found by type, or by filesystem label
+    let mut by_type = lsblk.clone();
+    by_type["children"][2]["partlabel"] = serde_json::Value::Null;
+    by_type["children"][2]["parttype"] = baseline::XBOOTLDR_TYPECODE.to_lowercase().into();
+    let dev = parse(&by_type);
+    assert_eq!(
+        find_boot_partition(&dev, "/dev/sda4")
+            .unwrap()
+            .unwrap()
+            .path(),
+        "/dev/sda3"
+    );
+    let mut by_label = lsblk.clone();
+    by_label["children"][2]["partlabel"] = serde_json::Value::Null;
+    by_label["children"][2]["label"] = "boot".into();
+    let dev = parse(&by_label);
+    assert_eq!(
+        find_boot_partition(&dev, "/dev/sda4")
+            .unwrap()
+            .unwrap()
+            .path(),
+        "/dev/sda3"
+    );
+
+    // The root partition is never used
+    let dev = parse(&lsblk);
+    assert!(find_boot_partition(&dev, "/dev/sda3").unwrap().is_none());
+
+    // Ambiguous
+    let mut multiple = lsblk.clone();
+    multiple["children"][3]["label"] = "boot".into();
+    let dev = parse(&multiple);
+    assert!(find_boot_partition(&dev, "/dev/sda1").is_err());
+
+    // No filesystem
+    let mut unformatted = lsblk;
+    unformatted["children"][2]["uuid"] = serde_json::Value::Null;
+    let dev = parse(&unformatted);
+    assert!(find_boot_partition(&dev, "/dev/sda4").is_err());
+}
+
+#[test]
+fn test_seed_etc() {
+    let td = tempfile::tempdir().unwrap();
+    let root = Utf8Path::from_path(td.path()).unwrap();
+    let usretc = root.join("usr/etc");
+    let etc = root.join("etc");
+    std::fs::create_dir_all(usretc.join("sub/nested")).unwrap();
+    std::fs::create_dir_all(etc.join("sub")).unwrap();
+    std::fs::write(usretc.join("default"), "default").unwrap();
+    std::fs::write(usretc.join("modified"), "default").unwrap();
+    std::fs::write(usretc.join("sub/nested/file"), "nested").unwrap();
+    std::os::unix::fs::symlink("default", usretc.join("link")).unwrap();
+    std::fs::write(etc.join("modified"), "local").unwrap();
+
+    let mut labeled = Vec::new();
+    let n = seed_etc(root, |path, as_path| {
+        assert!(path.symlink_metadata().is_ok());
+        labeled.push(as_path.to_string());
+        Ok(())
+    })
+    .unwrap();
+    labeled.sort();
+    assert_eq!(
+        labeled,
+        [
+            "/etc/default",
+            "/etc/link",
+            "/etc/sub/nested",
+            "/etc/sub/nested/file"
+        ]
+    );
+    assert_eq!(n, 4);
+    assert_eq!(
+        std::fs::read_to_string(etc.join("default")).unwrap(),
+        "default"
+    );
+    assert_eq!(
+        std::fs::read_to_string(etc.join("modified")).unwrap(),
+        "local"
+    );
+    assert_eq!(
+        std::fs::read_to_string(etc.join("sub/nested/file")).unwrap(),
+        "nested"
+    );
+    assert_eq!(etc.join("link").read_link_utf8().unwrap(), "default");
+
+    // Running again is a no-op
+    assert_eq!(seed_etc(root, |_, _| Ok(())).unwrap(), 0);
+}
+
+#[test]
+fn test_handle_interrupt() {
+    let cancellable = gio::Cancellable::new();
+    let mut cleaned = false;
+    let e = handle_interrupt(&cancellable, "SIGINT", || {
+        // In-flight work must be cancelled before we start tearing things down
+        assert!(cancellable.is_cancelled());
+        cleaned = true;
+        Ok(())
+    });
+    assert!(cleaned);
+    assert_eq!(e.to_string(), "Installation interrupted by SIGINT");
+
+    // A failure to clean up doesn't hide the interruption
+    let cancellable = gio::Cancellable::new();
+    let e = handle_interrupt(&cancellable, "SIGTERM", || anyhow::bail!("busy"));
+    assert!(cancellable.is_cancelled());
+    assert_eq!(e.to_string(), "Installation interrupted by SIGTERM");
+}
+
+#[test]
+fn test_run_in_target_command() {
+    let root = &lock::target_deployment_dir(&lock::process_dir());
+    let args = |c: &std::process::Command| {
+        std::iter::once(c.get_program())
+            .chain(c.get_args())
+            .map(|v| v.to_str().unwrap().to_owned())
+            .collect::<Vec<_>>()
+    };
+    let c = run_in_target_command(root, "systemctl enable foo.service", true);
+    assert_eq!(
+        args(&c),
+        [
+            "systemd-nspawn",
+            "--quiet",
+            "--register=no",
+            "--private-network",
+            "-D",
+            root.as_str(),
+            "--setenv=BOOTC_TARGET_DEPLOYMENT=/",
+            "--",
+            "/bin/sh",
+            "-c",
+            "systemctl enable foo.service"
+        ]
+    );
+    let c = run_in_target_command(root, "true", false);
+    assert_eq!(args(&c), ["chroot", root.as_str(), "/bin/sh", "-c", "true"]);
+    assert_eq!(
+        c.get_envs().collect::<Vec<_>>(),
+        [(
+            std::ffi::OsStr::new("BOOTC_TARGET_DEPLOYMENT"),
+            Some(std::ffi::OsStr::new("/"))
+        )]
+    );
+}
+
+#[test]
+fn test_run_shell_command() {
+    let root = lock::target_deployment_dir(&lock::process_dir());
+    let c = run_shell_command(&root);
+    assert_eq!(c.get_program(), "chroot");
+    assert_eq!(
+        c.get_args().collect::<Vec<_>>(),
+        [root.as_str(), "/bin/sh", "-l"]
+    );
+    // The shell is told where this installation's deployment is mounted on the host
+    assert_eq!(
+        c.get_envs().collect::<Vec<_>>(),
+        [(
+            std::ffi::OsStr::new("BOOTC_TARGET_DEPLOYMENT"),
+            Some(root.as_std_path().as_os_str())
+        )]
+    );
+    assert!(root.starts_with(lock::process_dir()));
+}
+
+#[test]
+fn test_bind_mount_args() {
+    let (src, dest) = (Utf8Path::new("/var/tmp"), Utf8Path::new("/mnt/tmp"));
+    assert_eq!(
+        bind_mount_args(src, dest, "42", Default::default()),
+        [
+            "--bind",
+            "--make-private",
+            "-N",
+            "42",
+            "/var/tmp",
+            "/mnt/tmp"
+        ]
+    );
+    let opts = BindMountOpts {
+        readonly: true,
+        recursive: true,
+        optional: false,
+    };
+    assert_eq!(
+        bind_mount_args(src, dest, "42", opts),
+        [
+            "--rbind",
+            "--make-rprivate",
+            "-o",
+            "ro",
+            "-N",
+            "42",
+            "/var/tmp",
+            "/mnt/tmp"
+        ]
+    );
+}
+
+#[test]
+fn test_fstab_append_contents() {
+    let root_setup = RootSetup {
+        device: Some("/dev/vda".into()),
+        rootfs: "/target".into(),
+        rootfs_fd: Dir::open_ambient_dir("/", cap_std::ambient_authority()).unwrap(),
+        root: MountSpec::new("UUID=rootuuid", "/"),
+        root_fstype: None,
+        boot: Some(MountSpec::new("UUID=bootuuid", "/boot")),
+        esp: Some(MountSpec::new_esp("UUID=ABCD-1234", EspMountpoint::BootEfi)),
+        extra_partitions: Vec::new(),
+        metadata_partition: None,
+        sync_esp: None,
+        ssh_host_keys: Vec::new(),
+        machine_id: None,
+        adopt_stateroot: None,
+        kargs: Vec::new(),
     };
+    // No fstab in the image; generate a full one
+    assert_eq!(
+        fstab_append_contents(None, &root_setup, &[]),
+        "# /etc/fstab\n# Created by bootc install\n#\n\
+         UUID=rootuuid / auto defaults 0 1\n\
+         UUID=bootuuid /boot auto defaults 0 2\n\
+         UUID=ABCD-1234 /boot/efi vfat umask=0077,shortname=winnt 0 2\n"
+    );
+    // An existing fstab just gets /boot and the ESP added
+    let boot = "UUID=bootuuid /boot auto defaults 0 0\n\
+                UUID=ABCD-1234 /boot/efi vfat umask=0077,shortname=winnt 0 0\n";
+    assert_eq!(fstab_append_contents(Some(""), &root_setup, &[]), boot);
+    assert_eq!(
+        fstab_append_contents(Some("tmpfs /tmp tmpfs defaults 0 0\n"), &root_setup, &[]),
+        boot
+    );
+    assert_eq!(
+        fstab_append_contents(Some("tmpfs /tmp tmpfs defaults 0 0"), &root_setup, &[]),
+        format!("\n{boot}")
+    );
 
-    let kargs = root_setup
-        .kargs
-        .iter()
-        .map(|v| v.as_str())
-        .collect::<Vec<_>>();
-    #[allow(clippy::needless_update)]
-    let options = ostree_container::deploy::DeployOpts {
-        kargs: Some(kargs.as_slice()),
-        target_imgref: Some(&target_imgref),
-        proxy_cfg: Some(proxy_cfg),
-        ..Default::default()
+    // Extra partitions are always added
+    let mut root_setup = root_setup;
+    root_setup.extra_partitions.push(summary::CreatedPartition {
+        label: "oem".into(),
+        uuid: "7B77-95E7".into(),
+        fstype: "vfat".into(),
+        mountpoint: "/run/media/oem".into(),
+    });
+    assert_eq!(
+        fstab_append_contents(Some(""), &root_setup, &[]),
+        format!("{boot}UUID=7B77-95E7 /run/media/oem vfat defaults 0 2\n")
+    );
+
+    // As are extra mounts
+    let extra: MountSpec = "/dev/sdb1 /var/data xfs nofail,x-systemd.device-timeout=10s"
+        .parse()
+        .unwrap();
+    assert_eq!(
+        fstab_append_contents(Some(""), &root_setup, &[extra]),
+        format!(
+            "{boot}UUID=7B77-95E7 /run/media/oem vfat defaults 0 2\n\
+             /dev/sdb1 /var/data xfs nofail,x-systemd.device-timeout=10s 0 0\n"
+        )
+    );
+
+    // With --stateless, the root is mounted externally and /boot is part of it
+    root_setup.device = None;
+    root_setup.boot = None;
+    root_setup.esp = None;
+    root_setup.extra_partitions.clear();
+    assert_eq!(
+        fstab_append_contents(None, &root_setup, &[]),
+        "# /etc/fstab\n# Created by bootc install\n#\n"
+    );
+    assert_eq!(fstab_append_contents(Some(""), &root_setup, &[]), "");
+}
+
+#[test]
+fn test_target_selinux_mode() {
+    use clap::Parser;
+    let mode = |args: &[&str]| {
+        let o = InstallOpts::try_parse_from(args).unwrap();
+        o.config_opts.selinux_enforcing
     };
-    println!("Creating initial deployment");
-    let state =
-        ostree_container::deploy::deploy(&sysroot, stateroot, &src_imageref, Some(options)).await?;
-    let target_image = target_imgref.to_string();
-    let digest = state.manifest_digest;
-    println!("Installed: {target_image}");
-    println!("   Digest: {digest}");
+    assert_eq!(mode(&["install", "/dev/vda"]), None);
+    for (arg, expected) in [
+        ("enforcing", SelinuxMode::Enforcing),
+        ("permissive", SelinuxMode::Permissive),
+    ] {
+        let args = ["install", "--selinux-enforcing", arg, "/dev/vda"];
+        assert_eq!(mode(&args), Some(expected));
+        assert_eq!(expected.as_str(), arg);
+    }
+    for invalid in ["disabled", "Permissive", "1"] {
+        let args = ["install", "--selinux-enforcing", invalid, "/dev/vda"];
+        assert!(InstallOpts::try_parse_from(args).is_err(), "{invalid}");
+    }
+    let args = [
+        "install",
+        "--selinux-enforcing",
+        "permissive",
+        "--disable-selinux",
+        "/dev/vda",
+    ];
+    assert!(InstallOpts::try_parse_from(args).is_err());
+    let o: InstallOpts = serde_json::from_value(serde_json::json!({
+        "device": "/dev/vda",
+        "selinux_enforcing": "permissive",
+        "disable_selinux": true,
+    }))
+    .unwrap();
+    assert_eq!(
+        option_conflicts(&o.config_opts, &o.target_opts, Some(&o.block_opts), None),
+        ["--selinux-enforcing conflicts with --disable-selinux"]
+    );
 
-    drop(temporary_dir);
+    assert_eq!(target_selinux_mode(false, None), None);
+    assert_eq!(
+        target_selinux_mode(false, Some(SelinuxMode::Permissive)),
+        Some("permissive")
+    );
+    // Disabling SELinux takes precedence
+    assert_eq!(target_selinux_mode(true, None), Some("disabled"));
+    assert_eq!(
+        target_selinux_mode(true, Some(SelinuxMode::Enforcing)),
+        Some("disabled")
+    );
+}
 
-    // Write the entry for /boot to /etc/fstab.  TODO: Encourage OSes to use the karg?
-    // Or better bind this with the grub data.
-    sysroot.load(cancellable)?;
-    let deployment = sysroot
-        .deployments()
-        .into_iter()
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("Failed to find deployment"))?;
-    // SAFETY: There must be a path
-    let path = sysroot.deployment_dirpath(&deployment).unwrap();
-    let root = rootfs_dir
-        .open_dir(path.as_str())
-        .context("Opening deployment dir")?;
-    let mut f = {
-        let mut opts = cap_std::fs::OpenOptions::new();
-        root.open_with("etc/fstab", opts.append(true).write(true).create(true))
-            .context("Opening etc/fstab")
-            .map(BufWriter::new)?
+#[test]
+fn test_esp_mountpoint() {
+    use clap::Parser;
+    let o = InstallOpts::try_parse_from(["install", "/dev/vda"]).unwrap();
+    assert_eq!(o.config_opts.esp_mountpoint, EspMountpoint::BootEfi);
+    let o =
+        InstallOpts::try_parse_from(["install", "--esp-mountpoint", "efi", "/dev/vda"]).unwrap();
+    assert_eq!(o.config_opts.esp_mountpoint, EspMountpoint::Efi);
+    let o = InstallOpts::try_parse_from(["install", "--esp-mountpoint", "boot-efi", "/dev/vda"])
+        .unwrap();
+    assert_eq!(o.config_opts.esp_mountpoint, EspMountpoint::BootEfi);
+    assert!(
+        InstallOpts::try_parse_from(["install", "--esp-mountpoint", "/efi", "/dev/vda"]).is_err()
+    );
+    assert_eq!(EspMountpoint::BootEfi.relpath(), "boot/efi");
+    assert_eq!(EspMountpoint::Efi.relpath(), crate::bootloader::EFI_DIR);
+
+    let esp = MountSpec::new_esp("UUID=ABCD-1234", EspMountpoint::Efi);
+    assert_eq!(esp.mount_unit_name(), "efi.mount");
+    let root_setup = RootSetup {
+        device: Some("/dev/vda".into()),
+        rootfs: "/target".into(),
+        rootfs_fd: Dir::open_ambient_dir("/", cap_std::ambient_authority()).unwrap(),
+        root: MountSpec::new("UUID=rootuuid", "/"),
+        root_fstype: None,
+        boot: Some(MountSpec::new("UUID=bootuuid", "/boot")),
+        esp: Some(esp),
+        extra_partitions: Vec::new(),
+        metadata_partition: None,
+        sync_esp: None,
+        ssh_host_keys: Vec::new(),
+        machine_id: None,
+        adopt_stateroot: None,
+        kargs: Vec::new(),
     };
-    writeln!(f, "{}", root_setup.boot.to_fstab())?;
-    f.flush()?;
+    assert_eq!(
+        fstab_append_contents(None, &root_setup, &[]),
+        "# /etc/fstab\n# Created by bootc install\n#\n\
+         UUID=rootuuid / auto defaults 0 1\n\
+         UUID=bootuuid /boot auto defaults 0 2\n\
+         UUID=ABCD-1234 /efi vfat umask=0077,shortname=winnt 0 2\n"
+    );
 
-    let uname = cap_std_ext::rustix::process::uname();
+    // The ESP mountpoint may exist in an otherwise empty root
+    let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    td.create_dir_all("boot/efi").unwrap();
+    require_empty_rootdir(&td, EspMountpoint::BootEfi).unwrap();
+    td.create_dir("efi").unwrap();
+    assert!(require_empty_rootdir(&td, EspMountpoint::BootEfi).is_err());
+    require_empty_rootdir(&td, EspMountpoint::Efi).unwrap();
+}
 
-    let aleph = InstallAleph {
-        image: src_imageref.imgref.name.clone(),
-        kernel: uname.release().to_str()?.to_string(),
+#[test]
+fn test_mountspec_systemd_options() {
+    let m: MountSpec =
+        "UUID=abcd /var/data xfs noatime,x-systemd.automount,nofail,x-systemd.idle-timeout=5min"
+            .parse()
+            .unwrap();
+    assert_eq!(m.options.as_deref(), Some("noatime,nofail"));
+    assert_eq!(
+        m.systemd_options,
+        ["x-systemd.automount", "x-systemd.idle-timeout=5min"]
+    );
+    assert_eq!(
+        m.to_fstab(),
+        "UUID=abcd /var/data xfs noatime,nofail,x-systemd.automount,x-systemd.idle-timeout=5min 0 0"
+    );
+    // Only systemd options
+    let m: MountSpec = "/dev/sdb1 /mnt auto x-systemd.device-bound"
+        .parse()
+        .unwrap();
+    assert_eq!(
+        m.to_fstab(),
+        "/dev/sdb1 /mnt auto defaults,x-systemd.device-bound 0 0"
+    );
+    // Round trip via serde
+    let v = serde_json::to_value(&m).unwrap();
+    assert_eq!(v, "/dev/sdb1 /mnt auto defaults,x-systemd.device-bound");
+    let m: MountSpec = serde_json::from_value(v).unwrap();
+    assert_eq!(m.options.as_deref(), Some("defaults"));
+    assert_eq!(m.systemd_options, ["x-systemd.device-bound"]);
+
+    for invalid in [
+        "/dev/sdb1 /mnt xfs x-systemd.unknown",
+        "/dev/sdb1 /mnt xfs x-systemd.device-timeout",
+        "/dev/sdb1 /mnt xfs x-systemd.device-timeout=",
+        "/dev/sdb1 /mnt xfs x-systemd.automount=yes",
+        "/dev/sdb1 /mnt xfs defaults 0 2",
+        "/dev/sdb1",
+    ] {
+        assert!(invalid.parse::<MountSpec>().is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn test_target_imgref_digest() {
+    let digest = "sha256:ebe3bdccc041864e5a485f1e755e242535c3b83d110c0357fe57f110b73b143e";
+    let source = ostree_container::ImageReference {
+        transport: ostree_container::Transport::ContainerStorage,
+        name: "localhost/self:latest".into(),
     };
+    let mut opts: InstallTargetOpts = serde_json::from_value(serde_json::json!({})).unwrap();
+    opts.target_transport = "registry".into();
+    let r = target_imgref_from_opts(&opts, &source).unwrap();
+    assert_eq!(r.imgref, source);
 
-    Ok(aleph)
+    opts.target_imgref = Some("quay.io/example/os:stable".into());
+    opts.target_imgref_digest = Some(digest.into());
+    let r = target_imgref_from_opts(&opts, &source).unwrap();
+    assert_eq!(r.imgref.transport, ostree_container::Transport::Registry);
+    assert_eq!(r.imgref.name, format!("quay.io/example/os:stable@{digest}"));
+
+    for invalid in [
+        "",
+        "ebe3bdcc",
+        "sha256:abc",
+        "md5:d41d8cd98f00b204e9800998ecf8427e",
+    ] {
+        opts.target_imgref_digest = Some(invalid.into());
+        assert!(target_imgref_from_opts(&opts, &source).is_err());
+    }
+    opts.target_imgref_digest = Some(digest.to_uppercase().replace("SHA256", "sha256"));
+    assert!(target_imgref_from_opts(&opts, &source).is_err());
 }
 
-#[context("Copying to oci")]
-fn copy_to_oci(
-    src_imageref: &ostree_container::ImageReference,
-    dir: &Utf8Path,
-) -> Result<ostree_container::ImageReference> {
-    tracing::debug!("Copying {src_imageref}");
-    let src_imageref = src_imageref.to_string();
-    let dest_imageref = ostree_container::ImageReference {
-        transport: ostree_container::Transport::OciDir,
-        name: dir.to_string(),
+#[test]
+fn test_target_stream() {
+    use clap::Parser;
+    let source = ostree_container::ImageReference {
+        transport: ostree_container::Transport::ContainerStorage,
+        name: "registry.example.com:5000/os:candidate".into(),
     };
-    let dest_imageref_str = dest_imageref.to_string();
-    Task::new_cmd(
-        "Copying to temporary OCI (skopeo is too old)",
-        run_in_host_mountns("skopeo"),
-    )
-    .args([
-        "copy",
-        // TODO: enable this once ostree is fixed "--dest-oci-accept-uncompressed-layers",
-        src_imageref.as_str(),
-        dest_imageref_str.as_str(),
-    ])
-    .run()?;
-    Ok(dest_imageref)
+    let o =
+        InstallOpts::try_parse_from(["install", "--target-stream", "stable", "/dev/vda"]).unwrap();
+    let r = target_imgref_from_opts(&o.target_opts, &source).unwrap();
+    assert_eq!(
+        r.to_string(),
+        "ostree-image-signed:docker://registry.example.com:5000/os:stable"
+    );
+    let mut opts = o.target_opts;
+    opts.target_stream = Some("bad/stream".into());
+    assert!(target_imgref_from_opts(&opts, &source).is_err());
+    // Conflicts with an explicit target, also when deserialized
+    let args = [
+        "install",
+        "--target-stream",
+        "stable",
+        "--target-imgref",
+        "quay.io/example/os:stable",
+        "/dev/vda",
+    ];
+    assert!(InstallOpts::try_parse_from(args).is_err());
+    opts.target_stream = Some("stable".into());
+    opts.target_imgref = Some("quay.io/example/os:stable".into());
+    assert!(target_imgref_from_opts(&opts, &source).is_err());
 }
 
-#[context("Querying skopeo version")]
-fn skopeo_supports_containers_storage() -> Result<bool> {
-    let o = run_in_host_mountns("skopeo").arg("--version").output()?;
-    let st = o.status;
-    if !st.success() {
-        anyhow::bail!("Failed to run skopeo --version: {st:?}");
+#[test]
+fn test_namespace_setup() {
+    assert_eq!(
+        NamespaceSetup::new(false, false),
+        NamespaceSetup {
+            unshare: true,
+            private_mounts: true
+        }
+    );
+    assert_eq!(
+        NamespaceSetup::new(false, true),
+        NamespaceSetup {
+            unshare: false,
+            private_mounts: true
+        }
+    );
+    for skip_unshare_env in [false, true] {
+        assert_eq!(
+            NamespaceSetup::new(true, skip_unshare_env),
+            NamespaceSetup {
+                unshare: false,
+                private_mounts: false
+            }
+        );
     }
-    let stdout = String::from_utf8(o.stdout).context("Parsing skopeo version")?;
-    let mut v = stdout
-        .strip_prefix("skopeo version ")
-        .map(|v| v.split('.'))
-        .ok_or_else(|| anyhow::anyhow!("Unexpected output from skopeo version"))?;
-    let major = v
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("Missing major version"))?;
-    let minor = v
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("Missing minor version"))?;
-    let (major, minor) = (major.parse::<u64>()?, minor.parse::<u64>()?);
-    Ok(major > 1 || minor > 10)
 }
 
-pub(crate) struct RootSetup {
-    device: Utf8PathBuf,
-    rootfs: Utf8PathBuf,
-    rootfs_fd: Dir,
-    boot: MountSpec,
-    kargs: Vec<String>,
+#[test]
+fn test_root_mutability() {
+    let mut c: InstallConfigOpts = serde_json::from_value(serde_json::json!({})).unwrap();
+    assert_eq!(
+        c.root_mutability(),
+        RootMutability {
+            immutable_bit: true,
+            sysroot_readonly: true
+        }
+    );
+    c.dev_mutable = true;
+    assert_eq!(
+        c.root_mutability(),
+        RootMutability {
+            immutable_bit: false,
+            sysroot_readonly: false
+        }
+    );
 }
 
-fn require_boot_uuid(spec: &MountSpec) -> Result<&str> {
-    spec.get_source_uuid()
-        .ok_or_else(|| anyhow!("/boot is not specified via UUID= (this is currently required)"))
+#[test]
+fn test_ostree_config() {
+    let opts = |v: serde_json::Value| -> InstallConfigOpts { serde_json::from_value(v).unwrap() };
+    let c = opts(serde_json::json!({}));
+    assert!(c.ostree_config().unwrap().is_empty());
+    let c = opts(serde_json::json!({
+        "ostree_config": [
+            "core.min-free-space-percent=0",
+            "sysroot.bootprefix=true",
+            "core.min-free-space-percent=1"
+        ]
+    }));
+    let expected: BTreeMap<_, _> = [
+        ("core.min-free-space-percent", "1"),
+        ("sysroot.bootprefix", "true"),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect();
+    assert_eq!(c.ostree_config().unwrap(), expected);
+    for invalid in [
+        "core.min-free-space-percent",
+        "=0",
+        "core=0",
+        ".foo=0",
+        "core.=0",
+        "sysroot.readonly=false",
+        "core.mode=bare",
+    ] {
+        let c = opts(serde_json::json!({ "ostree_config": [invalid] }));
+        assert!(c.ostree_config().is_err(), "{invalid}");
+    }
+    let mut c = opts(serde_json::json!({ "ostree_config": ["core.mode=bare"] }));
+    c.ostree_config_unsafe = true;
+    assert_eq!(c.ostree_config().unwrap().get("core.mode").unwrap(), "bare");
+    // Managed keys can't be set even in unsafe mode
+    let mut c = opts(serde_json::json!({ "ostree_config": ["sysroot.bootloader=grub2"] }));
+    c.ostree_config_unsafe = true;
+    assert!(c.ostree_config().is_err());
 }
 
-impl RootSetup {
-    /// Get the UUID= mount specifier for the /boot filesystem.  At the current time this is
-    /// required.
-    fn get_boot_uuid(&self) -> Result<&str> {
-        require_boot_uuid(&self.boot)
+#[test]
+fn test_min_free_space() {
+    let opts = |v: serde_json::Value| -> InstallConfigOpts { serde_json::from_value(v).unwrap() };
+    for (v, k, expected) in [
+        ("3%", "core.min-free-space-percent", "3"),
+        ("0%", "core.min-free-space-percent", "0"),
+        ("500M", "core.min-free-space-size", "500MB"),
+        ("2G", "core.min-free-space-size", "2048MB"),
+        ("100", "core.min-free-space-size", "100MB"),
+    ] {
+        let c = opts(serde_json::json!({ "min_free_space": v }));
+        let config = c.ostree_config().unwrap();
+        assert_eq!(config.len(), 1);
+        assert_eq!(config.get(k).unwrap(), expected, "{v}");
+        let m = MinFreeSpace::from_str(v).unwrap();
+        assert_eq!(MinFreeSpace::from_str(&m.to_string()).unwrap(), m);
     }
+    for invalid in ["100%", "-1%", "%", "1.5%", "", "10X", "3 %"] {
+        assert!(MinFreeSpace::from_str(invalid).is_err(), "{invalid}");
+    }
+    let c = opts(serde_json::json!({
+        "min_free_space": "3%",
+        "ostree_config": ["core.min-free-space-size=1GB"]
+    }));
+    assert!(c.ostree_config().is_err());
 }
 
-pub(crate) struct SourceData {
-    /// The embedded base OSTree commit checksum
-    #[allow(dead_code)]
-    pub(crate) commit: String,
-    /// Whether or not SELinux appears to be enabled in the source commit
-    pub(crate) selinux: bool,
+#[test]
+fn test_ignition_hash_requires_file() {
+    use clap::Parser;
+    let hash = "sha256-ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    assert!(InstallOpts::try_parse_from(["install", "--ignition-hash", hash, "/dev/vda"]).is_err());
+    let o = InstallOpts::try_parse_from([
+        "install",
+        "--ignition-file",
+        "/config.ign",
+        "--ignition-hash",
+        hash,
+        "/dev/vda",
+    ])
+    .unwrap();
+    assert!(o.config_opts.ignition_hash.is_some());
 }
 
-#[context("Gathering source data")]
-fn gather_source_data() -> Result<SourceData> {
-    let cancellable = ostree::gio::Cancellable::NONE;
-    let commit = Task::new("Reading ostree commit", "ostree")
-        .args(["--repo=/ostree/repo", "rev-parse", "--single"])
-        .quiet()
-        .read()?;
-    let root = cap_std::fs::Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
-    let repo = ostree::Repo::open_at_dir(&root, "ostree/repo")?;
-    let root = repo
-        .read_commit(commit.trim(), cancellable)
-        .context("Reading commit")?
-        .0;
-    let root = root.downcast_ref::<ostree::RepoFile>().unwrap();
-    let xattrs = root.xattrs(cancellable)?;
-    let selinux = crate::lsm::xattrs_have_selinux(&xattrs);
-    Ok(SourceData { commit, selinux })
+#[test]
+fn test_sysroot_layout() {
+    assert_eq!(SysrootLayout::default(), SysrootLayout::Modern);
+    assert_eq!(SysrootLayout::Modern.init_fs_args(), ["--modern"]);
+    assert!(SysrootLayout::Legacy.init_fs_args().is_empty());
+    let c: InstallConfigOpts =
+        serde_json::from_value(serde_json::json!({"sysroot_layout": "legacy"})).unwrap();
+    assert_eq!(c.sysroot_layout, SysrootLayout::Legacy);
 }
 
-/// If we detect that the target ostree commit has SELinux labels,
-/// and we aren't passed an override to disable it, then ensure
-/// the running process is labeled with install_t so it can
-/// write arbitrary labels.
-pub(crate) fn reexecute_self_for_selinux_if_needed(
-    srcdata: &SourceData,
-    override_disable_selinux: bool,
-) -> Result<bool> {
-    let mut ret_did_override = false;
-    // If the target state has SELinux enabled, we need to check the host state.
-    if srcdata.selinux {
-        let host_selinux = crate::lsm::selinux_enabled()?;
-        tracing::debug!("Target has SELinux, host={host_selinux}");
-        if host_selinux {
-            // /sys/fs/selinuxfs is not normally mounted, so we do that now.
-            // Because SELinux enablement status is cached process-wide and was very likely
-            // already queried by something else (e.g. glib's constructor), we would also need
-            // to re-exec.  But, selinux_ensure_install does that unconditionally right now too,
-            // so let's just fall through to that.
-            crate::lsm::container_setup_selinux()?;
-            // This will re-execute the current process (once).
-            crate::lsm::selinux_ensure_install()?;
-        } else if override_disable_selinux {
-            ret_did_override = true;
-            println!("notice: Target has SELinux enabled, overriding to disable")
-        } else {
-            anyhow::bail!(
-                "Host kernel does not have SELinux support, but target enables it by default"
-            );
-        }
-    } else {
-        tracing::debug!("Target does not enable SELinux");
+#[test]
+fn test_check_target_inspect() {
+    let inspect: TargetImageInspect = serde_json::from_value(serde_json::json!({
+        "Name": "quay.io/example/os",
+        "Digest": "sha256:0ba7ae1e0a0b2ba4e5fd0a6a3c6ed2e4c1b6e07a6f7ab7e8d7a7e2c4ab16b5b6",
+        "Architecture": "arm64",
+        "Os": "linux",
+    }))
+    .unwrap();
+    check_target_inspect("quay.io/example/os", &inspect, "aarch64").unwrap();
+    assert!(check_target_inspect("quay.io/example/os", &inspect, "x86_64").is_err());
+}
+
+#[test]
+fn test_source_arch() {
+    for (arch, expected) in [
+        ("x86_64", "amd64"),
+        ("aarch64", "arm64"),
+        ("powerpc64", "ppc64le"),
+        ("ppc64le", "ppc64le"),
+        ("s390x", "s390x"),
+        ("riscv64", "riscv64"),
+        ("x86", "386"),
+        ("i686", "386"),
+        ("armv7l", "arm"),
+        ("amd64", "amd64"),
+    ] {
+        assert_eq!(oci_arch(arch), expected, "{arch}");
     }
-    Ok(ret_did_override)
+    let d = Diagnostics::default();
+    check_source_arch("amd64", "x86_64", "x86_64", false, &d).unwrap();
+    check_source_arch("ppc64le", "ppc64le", "powerpc64", false, &d).unwrap();
+    check_source_arch("", "x86_64", "x86_64", false, &d).unwrap();
+    // An emulated container on an aarch64 host
+    assert!(check_source_arch("amd64", "aarch64", "x86_64", false, &d).is_err());
+    // A mismatched image
+    assert!(check_source_arch("arm64", "x86_64", "x86_64", false, &d).is_err());
+    check_source_arch("arm64", "x86_64", "x86_64", true, &d).unwrap();
+    assert!(check_source_arch("s390x", "x86_64", "x86_64", true, &d).is_err());
+    assert_eq!(
+        d.warnings(),
+        ["The source image architecture is arm64, not amd64; continuing due to --skip-arch-check"]
+    );
 }
 
-/// Trim, flush outstanding writes, and freeze/thaw the target mounted filesystem;
-/// these steps prepare the filesystem for its first booted use.
-pub(crate) fn finalize_filesystem(fs: &Utf8Path) -> Result<()> {
-    let fsname = fs.file_name().unwrap();
-    // fstrim ensures the underlying block device knows about unused space
-    Task::new_and_run(format!("Trimming {fsname}"), "fstrim", ["-v", fs.as_str()])?;
-    // Remounting readonly will flush outstanding writes and ensure we error out if there were background
-    // writeback problems.
-    Task::new(format!("Finalizing filesystem {fsname}"), "mount")
-        .args(["-o", "remount,ro", fs.as_str()])
-        .run()?;
-    // Finally, freezing (and thawing) the filesystem will flush the journal, which means the next boot is clean.
-    for a in ["-f", "-u"] {
-        Task::new("Flushing filesystem journal", "xfs_freeze")
-            .quiet()
-            .args([a, fs.as_str()])
-            .run()?;
+#[test]
+fn test_copy_to_oci_args() {
+    let src = "containers-storage:quay.io/example/os:latest";
+    let dest = "oci:/var/tmp/.tmpXYZ";
+    assert_eq!(copy_to_oci_args(src, dest, None, None), ["copy", src, dest]);
+    assert_eq!(
+        copy_to_oci_args(src, dest, Some(8), None),
+        ["copy", "--image-parallel-copies=8", src, dest]
+    );
+    for (compression, args) in [
+        (
+            Compression::Gzip,
+            &["--dest-compress", "--dest-compress-format=gzip"][..],
+        ),
+        (
+            Compression::Zstd,
+            &["--dest-compress", "--dest-compress-format=zstd"],
+        ),
+        (Compression::None, &["--dest-decompress"]),
+    ] {
+        let mut expected = vec!["copy"];
+        expected.extend(args);
+        expected.extend([src, dest]);
+        assert_eq!(
+            copy_to_oci_args(src, dest, None, Some(compression)),
+            expected
+        );
     }
-    Ok(())
 }
 
-/// Preparation for an install; validates and prepares some (thereafter immutable) global state.
-async fn prepare_install(
-    config_opts: InstallConfigOpts,
-    target_opts: InstallTargetOpts,
-) -> Result<Arc<State>> {
-    // We require --pid=host
-    let pid = std::fs::read_link("/proc/1/exe").context("reading /proc/1/exe")?;
-    let pid = pid
-        .to_str()
-        .ok_or_else(|| anyhow::anyhow!("Non-UTF8 /proc/1/exe"))?;
-    if !pid.contains("systemd") {
-        anyhow::bail!("This command must be run with --pid=host")
+#[test]
+fn test_skopeo_version() {
+    let v = parse_skopeo_version("skopeo version 1.9.3\n").unwrap();
+    assert_eq!(v, (1, 9));
+    for c in [Compression::Gzip, Compression::Zstd, Compression::None] {
+        c.check_skopeo_version(v).unwrap();
     }
+    let v = parse_skopeo_version("skopeo version 1.1.1\n").unwrap();
+    Compression::None.check_skopeo_version(v).unwrap();
+    let e = Compression::Zstd.check_skopeo_version(v).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "--compression=zstd requires skopeo 1.2 or newer, found 1.1"
+    );
+    let v = parse_skopeo_version("skopeo version 0.1.41\n").unwrap();
+    assert_eq!(v, (0, 1));
+    assert!(Compression::Gzip.check_skopeo_version(v).is_err());
+    assert!(parse_skopeo_version("skopeo 1.9.3").is_err());
+}
 
-    // This command currently *must* be run inside a privileged container.
-    let container_info = crate::containerenv::get_container_execution_info()?;
-    if !container_info.engine.starts_with("podman") {
-        anyhow::bail!("Currently this command only supports being executed via podman");
+#[test]
+fn test_exported_source_imgref() {
+    let digest = "sha256:0123abcd";
+    let imgref = exported_source_imgref(Utf8Path::new("/var/cache/os"), digest);
+    assert_eq!(imgref.to_string(), "oci:/var/cache/os:sha256-0123abcd");
+    // The export and import must agree for the same image
+    let src = ostree_container::ImageReference {
+        transport: ostree_container::Transport::ContainerStorage,
+        name: crate::utils::digested_pullspec("quay.io/example/os:latest", digest),
+    };
+    assert_eq!(
+        copy_to_oci_args(&src.to_string(), &imgref.to_string(), None, None),
+        [
+            "copy",
+            "containers-storage:quay.io/example/os:latest@sha256:0123abcd",
+            "oci:/var/cache/os:sha256-0123abcd"
+        ]
+    );
+    assert_ne!(
+        exported_source_imgref(Utf8Path::new("/var/cache/os"), "sha256:4567ef01"),
+        imgref
+    );
+}
+
+#[test]
+fn test_source_checksum() {
+    use crate::ignition::IgnitionHash;
+    use clap::Parser;
+    use std::io::Seek;
+    let archive = b"not really an OCI archive\n";
+    let mut tmpf = tempfile::tempfile().unwrap();
+    tmpf.write_all(archive).unwrap();
+    let sha256 = openssl::sha::sha256(archive);
+    let valid = format!("sha256-{}", hex::encode(sha256));
+    let valid: IgnitionHash = valid.parse().unwrap();
+    tmpf.rewind().unwrap();
+    verify_source_checksum(&mut tmpf, &valid).unwrap();
+
+    let mut corrupt = sha256;
+    corrupt[0] ^= 1;
+    let corrupt: IgnitionHash = format!("sha256-{}", hex::encode(corrupt)).parse().unwrap();
+    tmpf.rewind().unwrap();
+    let e = verify_source_checksum(&mut tmpf, &corrupt).unwrap_err();
+    assert!(format!("{e:#}").contains("media may be corrupt"));
+    // A truncated archive
+    let e = verify_source_checksum(&mut &archive[..10], &valid).unwrap_err();
+    assert!(format!("{e:#}").contains("hash mismatch"));
+
+    let valid = valid.to_string();
+    let o = InstallOpts::try_parse_from([
+        "install",
+        "--source-oci-archive",
+        "/run/media/os.ociarchive",
+        "--source-checksum",
+        valid.as_str(),
+        "/dev/vda",
+    ])
+    .unwrap();
+    assert!(o.config_opts.source_checksum.is_some());
+    // The checksum is only used for archives
+    let o = InstallOpts::try_parse_from(["install", "--source-checksum", &valid, "/dev/vda"]);
+    assert!(o.is_err());
+}
+
+#[test]
+fn test_mount_units() {
+    assert_eq!(systemd_escape_path("/"), "-");
+    assert_eq!(systemd_escape_path("/boot/efi"), "boot-efi");
+    assert_eq!(
+        systemd_escape_path("/var/my-data/.hidden"),
+        "var-my\\x2ddata-.hidden"
+    );
+    assert_eq!(systemd_escape_path("/.snapshots"), "\\x2esnapshots");
+
+    let mut root_setup = RootSetup {
+        device: Some("/dev/vda".into()),
+        rootfs: "/target".into(),
+        rootfs_fd: Dir::open_ambient_dir("/", cap_std::ambient_authority()).unwrap(),
+        root: MountSpec::new("UUID=rootuuid", "/"),
+        root_fstype: None,
+        boot: Some(MountSpec::new("UUID=bootuuid", "/boot")),
+        esp: Some(MountSpec::new_esp("UUID=ABCD-1234", EspMountpoint::BootEfi)),
+        extra_partitions: Vec::new(),
+        metadata_partition: None,
+        sync_esp: None,
+        ssh_host_keys: Vec::new(),
+        machine_id: None,
+        adopt_stateroot: None,
+        kargs: Vec::new(),
+    };
+    let units = mount_units(&root_setup, &[]);
+    assert_eq!(
+        units,
+        [
+            MountUnit {
+                name: "boot.mount".into(),
+                contents: "# Generated by bootc install\n\
+                           [Unit]\n\
+                           Before=local-fs.target\n\
+                           \n\
+                           [Mount]\n\
+                           What=/dev/disk/by-uuid/bootuuid\n\
+                           Where=/boot\n\
+                           Options=defaults\n"
+                    .into(),
+                install_dir: "local-fs.target.requires",
+            },
+            MountUnit {
+                name: "boot-efi.mount".into(),
+                contents: "# Generated by bootc install\n\
+                           [Unit]\n\
+                           Before=local-fs.target\n\
+                           Requires=boot.mount\n\
+                           After=boot.mount\n\
+                           \n\
+                           [Mount]\n\
+                           What=/dev/disk/by-uuid/ABCD-1234\n\
+                           Where=/boot/efi\n\
+                           Type=vfat\n\
+                           Options=umask=0077,shortname=winnt\n"
+                    .into(),
+                install_dir: "local-fs.target.requires",
+            }
+        ]
+    );
+
+    // Non-critical mounts are only wanted
+    root_setup.esp = None;
+    let extra: MountSpec = "LABEL=data /var/data xfs nofail,x-systemd.device-timeout=10s"
+        .parse()
+        .unwrap();
+    let units = mount_units(&root_setup, &[extra]);
+    assert_eq!(units.len(), 2);
+    assert_eq!(
+        units[1],
+        MountUnit {
+            name: "var-data.mount".into(),
+            contents: "# Generated by bootc install\n\
+                       [Unit]\n\
+                       \n\
+                       [Mount]\n\
+                       What=/dev/disk/by-label/data\n\
+                       Where=/var/data\n\
+                       Type=xfs\n\
+                       Options=nofail,x-systemd.device-timeout=10s\n"
+                .into(),
+            install_dir: "local-fs.target.wants",
+        }
+    );
+
+    let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    write_mount_units(&td, &units).unwrap();
+    assert_eq!(
+        td.read_to_string("etc/systemd/system/var-data.mount")
+            .unwrap(),
+        units[1].contents
+    );
+    assert_eq!(
+        td.read_link("etc/systemd/system/local-fs.target.requires/boot.mount")
+            .unwrap(),
+        std::path::Path::new("../boot.mount")
+    );
+    assert!(td
+        .try_exists("etc/systemd/system/local-fs.target.wants/var-data.mount")
+        .unwrap());
+    // Rewriting is idempotent
+    write_mount_units(&td, &units).unwrap();
+}
+
+#[test]
+fn test_mount_units_opts() {
+    use clap::Parser;
+    let o = InstallOpts::try_parse_from(["install", "--mount-units", "/dev/vda"]).unwrap();
+    assert!(o.config_opts.mount_units);
+    let args = [
+        "install",
+        "--mount-units",
+        "--swap",
+        "swapfile:1G",
+        "/dev/vda",
+    ];
+    assert!(InstallOpts::try_parse_from(args).is_err());
+    // The same names apply to the configuration file
+    let o: InstallOpts = serde_json::from_value(serde_json::json!({
+        "device": "/dev/vda",
+        "mount_units": true,
+        "swap": "swapfile:1G",
+    }))
+    .unwrap();
+    assert!(o.config_opts.mount_units);
+    assert_eq!(
+        option_conflicts(&o.config_opts, &o.target_opts, None, None),
+        ["--swap conflicts with --mount-units"]
+    );
+}
+
+/// Set up a deployment in `rootfs` and the state to finish installing it.
+#[cfg(test)]
+fn finish_install_fixture(rootfs: &Utf8Path) -> (State, RootSetup, InitialDeployment) {
+    let deployment_path = Utf8PathBuf::from("ostree/deploy/default/deploy/abcd.0");
+    let deployment_root = rootfs.join(&deployment_path);
+    std::fs::create_dir_all(deployment_root.join("etc")).unwrap();
+    std::fs::create_dir_all(deployment_root.join("usr/etc")).unwrap();
+    std::fs::create_dir(rootfs.join(BOOT)).unwrap();
+    std::fs::write(deployment_root.join("etc/fstab"), "").unwrap();
+    std::fs::write(deployment_root.join("usr/etc/motd"), "hello").unwrap();
+
+    let config_opts: InstallConfigOpts = serde_json::from_value(serde_json::json!({
+        "firstboot_karg": ["console=ttyS0"],
+        "run_in_target": ["systemctl enable foo.service"],
+        "seed_etc": true,
+        "extra_mount": ["/dev/sdb1 /var/data xfs nofail"],
+        "verify_bootable": false,
+        "swap": "swapfile:1G",
+        "esp_mountpoint": "efi",
+    }))
+    .unwrap();
+    let target_opts: InstallTargetOpts = serde_json::from_value(serde_json::json!({})).unwrap();
+    let state = State {
+        source_imageref: ostree_container::ImageReference {
+            transport: ostree_container::Transport::ContainerStorage,
+            name: "quay.io/example/os:latest".into(),
+        },
+        source_digest: "sha256:abcd".into(),
+        source_strategy: Some(source::SourceStrategy::ContainerStorage),
+        prefetched_source: None,
+        prefetched_bytes: 0,
+        selinux_override: None,
+        config_opts,
+        target_opts,
+        cancellable: gio::Cancellable::new(),
+        ostree_config: BTreeMap::new(),
+        grub_config_fragment: None,
+        install_policy: Default::default(),
+        diagnostics: Default::default(),
+        target_arch: "amd64".into(),
+        inherited_kargs: Vec::new(),
+        process_dir: rootfs.join("run/bootc/123"),
+    };
+    // The root filesystem type is inspected, as for a real installation
+    let ops = ops::FakeOps {
+        filesystems: vec![(rootfs.to_owned(), fake_root_filesystem("btrfs"))],
+        ..Default::default()
+    };
+    let (inspect, root_mount_spec) = inspect_target_root(&ops, rootfs, None, false).unwrap();
+    let root_setup = RootSetup {
+        device: Some("/dev/vda".into()),
+        rootfs: rootfs.to_owned(),
+        rootfs_fd: Dir::open_ambient_dir(rootfs, cap_std::ambient_authority()).unwrap(),
+        root: MountSpec::new(&root_mount_spec, "/"),
+        root_fstype: inspect.map(|i| i.fstype),
+        boot: Some(MountSpec::new("UUID=bootuuid", "/boot")),
+        esp: Some(MountSpec::new_esp("UUID=ABCD-1234", EspMountpoint::Efi)),
+        extra_partitions: Vec::new(),
+        metadata_partition: None,
+        sync_esp: None,
+        ssh_host_keys: Vec::new(),
+        machine_id: None,
+        adopt_stateroot: None,
+        kargs: ["root=UUID=rootuuid", RW_KARG, "boot=UUID=bootuuid"]
+            .map(String::from)
+            .to_vec(),
+    };
+    let deployment = InitialDeployment {
+        aleph: InstallAleph {
+            image: "quay.io/example/os:latest".into(),
+            kernel: "6.0.9-300.fc37.x86_64".into(),
+            ostree_config: BTreeMap::new(),
+            preserved_ssh_host_keys: Vec::new(),
+            stream: None,
+            target_image: None,
+            selinux_firstboot_relabel: false,
+        },
+        path: deployment_path,
+        digest: "sha256:abcd".into(),
+        sbom: None,
+        layer_bytes: 812345678,
+        fetched_bytes: 203086419,
+        warnings: Vec::new(),
+    };
+    (state, root_setup, deployment)
+}
+
+#[cfg(test)]
+fn fake_root_filesystem(fstype: &str) -> crate::mount::Filesystem {
+    crate::mount::Filesystem {
+        source: "/dev/vda4".into(),
+        fstype: fstype.into(),
+        uuid: Some("rootuuid".into()),
+        label: None,
     }
-    if container_info.imageid.is_empty() {
-        anyhow::bail!("Invalid empty imageid");
+}
+
+#[test]
+fn test_inspect_target_root() {
+    let root = Utf8Path::new("/target");
+    let ops = ops::FakeOps {
+        filesystems: vec![(root.to_owned(), fake_root_filesystem("xfs"))],
+        ..Default::default()
+    };
+    let (inspect, spec) = inspect_target_root(&ops, root, None, false).unwrap();
+    assert_eq!(inspect.unwrap().fstype, "xfs");
+    assert_eq!(spec, "UUID=rootuuid");
+    let (_, spec) = inspect_target_root(&ops, root, Some("LABEL=root".into()), false).unwrap();
+    assert_eq!(spec, "LABEL=root");
+    // With --stateless, the root is not inspected
+    let (inspect, spec) = inspect_target_root(
+        &ops::FakeOps::default(),
+        root,
+        Some("root=live:http://10.0.0.1/rootfs.img".into()),
+        true,
+    )
+    .unwrap();
+    assert!(inspect.is_none());
+    assert_eq!(spec, "live:http://10.0.0.1/rootfs.img");
+    assert!(inspect_target_root(&ops::FakeOps::default(), root, None, false).is_err());
+}
+
+#[test]
+fn test_finish_install() {
+    let td = tempfile::tempdir().unwrap();
+    let rootfs = Utf8Path::from_path(td.path()).unwrap();
+    let deployment_root = rootfs.join("ostree/deploy/default/deploy/abcd.0");
+    let (mut state, mut root_setup, deployment) = finish_install_fixture(rootfs);
+    state.config_opts.persistent_journal = true;
+    assert_eq!(
+        deployment.stateroot_var(rootfs).unwrap(),
+        rootfs.join("ostree/deploy/default/var")
+    );
+
+    push_install_kargs(&state, &mut root_setup).unwrap();
+    assert_eq!(
+        root_setup.kargs,
+        [
+            "root=UUID=rootuuid",
+            "rw",
+            "boot=UUID=bootuuid",
+            crate::bootloader::FIRSTBOOT_KARGS_VARIABLE
+        ]
+    );
+
+    let ops = ops::FakeOps::default();
+    let summary = finish_install(
+        &state,
+        &mut root_setup,
+        deployment,
+        &deployment_root,
+        InstallStage::Full,
+        &ops,
+    )
+    .unwrap();
+    // The full sequence of operations; the tests of individual options only check
+    // what they change
+    assert_eq!(
+        *ops.calls.borrow(),
+        [
+            "label /efi".to_string(),
+            "label /var/swap".to_string(),
+            "create-swapfile 1024 nocow=true".to_string(),
+            "label /var/swap/swapfile".to_string(),
+            "label /var/log".to_string(),
+            "label /var/log/journal".to_string(),
+            "install-bootloader /dev/vda /efi bootuuid".to_string(),
+            "label /etc/motd".to_string(),
+            "label /etc/bootc-version".to_string(),
+            "run-in-target systemctl enable foo.service".to_string(),
+            "set-immutable".to_string(),
+            format!("finalize {rootfs}/boot"),
+            format!("finalize {rootfs}"),
+        ]
+    );
+    assert_eq!(
+        std::fs::read_to_string(deployment_root.join("etc/fstab")).unwrap(),
+        "UUID=bootuuid /boot auto defaults 0 0\nUUID=ABCD-1234 /efi vfat umask=0077,shortname=winnt 0 0\n/dev/sdb1 /var/data xfs nofail 0 0\n/var/swap/swapfile none swap defaults 0 0\n"
+    );
+    assert!(rootfs
+        .join("ostree/deploy/default/var/swap/swapfile")
+        .exists());
+    // The journal is in the stateroot's /var, not the deployment's
+    let meta = rootfs
+        .join("ostree/deploy/default/var/log/journal")
+        .metadata()
+        .unwrap();
+    assert!(meta.is_dir());
+    assert_eq!(meta.mode() & 0o7777, 0o2755);
+    assert!(deployment_root
+        .join("var/log/journal")
+        .symlink_metadata()
+        .is_err());
+    assert!(deployment_root.join("efi").is_dir());
+    assert_eq!(
+        std::fs::read_to_string(deployment_root.join("etc/motd")).unwrap(),
+        "hello"
+    );
+    assert!(std::fs::read_to_string(rootfs.join("boot/grub2/grubenv"))
+        .unwrap()
+        .contains("console=ttyS0"));
+    let aleph: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(rootfs.join(BOOTC_ALEPH_PATH)).unwrap())
+            .unwrap();
+    assert_eq!(aleph["image"], "quay.io/example/os:latest");
+    assert_eq!(
+        std::fs::read_to_string(deployment_root.join(BOOTC_VERSION_PATH)).unwrap(),
+        "IMAGE=\"quay.io/example/os:latest\"\nDIGEST=\"sha256:abcd\"\n"
+    );
+    assert_eq!(summary.root_uuid.as_deref(), Some("rootuuid"));
+    assert_eq!(summary.boot_uuid.as_deref(), Some("bootuuid"));
+    assert_eq!(summary.digest, "sha256:abcd");
+    assert!(summary.warnings.is_empty());
+}
+
+#[test]
+fn test_install_to_filesystem_impl() {
+    let td = tempfile::tempdir().unwrap();
+    let rootfs = Utf8Path::from_path(td.path()).unwrap();
+    let deployment_root = rootfs.join("ostree/deploy/default/deploy/abcd.0");
+    let (state, mut root_setup, deployment) = finish_install_fixture(rootfs);
+    let target = lock::target_deployment_dir(&state.process_dir);
+    fn block_on<T>(f: impl std::future::Future<Output = T>) -> T {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(f)
     }
-    let source_imageref = ostree_container::ImageReference {
-        transport: ostree_container::Transport::ContainerStorage,
-        name: container_info.image.clone(),
+
+    let ops = ops::FakeOps {
+        deployment: Some(deployment).into(),
+        emulate_bind_mounts: true,
+        ..Default::default()
     };
-    // Find the exact digested image we are running
-    let source_digest = crate::podman::imageid_to_digest(&container_info.imageid)?;
+    let mut metrics = metrics::InstallMetrics::default();
+    let summary = block_on(install_to_filesystem_impl(
+        &ops,
+        &state,
+        &mut root_setup,
+        InstallStage::Full,
+        &mut metrics,
+    ))
+    .unwrap();
+    // The deployment is finished through the target deployment mount, which is removed
+    // afterwards
+    let calls = ops.calls.borrow();
+    assert_eq!(
+        calls[..3],
+        [
+            "deploy".to_string(),
+            format!("bind-mount {deployment_root} {target}"),
+            "label /efi".to_string(),
+        ]
+    );
+    assert_eq!(
+        calls[calls.len() - 5..],
+        [
+            "run-in-target systemctl enable foo.service".to_string(),
+            "set-immutable".to_string(),
+            format!("finalize {rootfs}/boot"),
+            format!("finalize {rootfs}"),
+            format!("unmount-recursive {target}"),
+        ]
+    );
+    drop(calls);
+    assert!(target.read_dir_utf8().unwrap().next().is_none());
+    // The final kernel arguments are used for the deployment, and written through the
+    // mount
+    assert_eq!(
+        read_kargs_file(&lock::kargs_file(&state.process_dir)).unwrap(),
+        root_setup.kargs
+    );
+    assert!(root_setup
+        .kargs
+        .iter()
+        .any(|k| k == crate::bootloader::FIRSTBOOT_KARGS_VARIABLE));
+    assert!(std::fs::read_to_string(deployment_root.join("etc/fstab"))
+        .unwrap()
+        .contains("/var/data"));
+    assert_eq!(summary.digest, "sha256:abcd");
+    assert_eq!(metrics.image_size, 812345678);
+    assert_eq!(metrics.fetched_bytes, 203086419);
+    assert_eq!(metrics.digest.as_deref(), Some("sha256:abcd"));
 
-    // Even though we require running in a container, the mounts we create should be specific
-    // to this process, so let's enter a private mountns to avoid leaking them.
-    if std::env::var_os("BOOTC_SKIP_UNSHARE").is_none() {
-        super::cli::ensure_self_unshared_mount_namespace().await?;
-    }
+    // With --inspect-shell, the shell runs in the target deployment and nothing is
+    // finalized
+    let td = tempfile::tempdir().unwrap();
+    let rootfs = Utf8Path::from_path(td.path()).unwrap();
+    let (mut state, mut root_setup, deployment) = finish_install_fixture(rootfs);
+    state.config_opts.inspect_shell = true;
+    let target = lock::target_deployment_dir(&state.process_dir);
+    let ops = ops::FakeOps {
+        deployment: Some(deployment).into(),
+        ..Default::default()
+    };
+    let e = block_on(install_to_filesystem_impl(
+        &ops,
+        &state,
+        &mut root_setup,
+        InstallStage::Full,
+        &mut metrics::InstallMetrics::default(),
+    ))
+    .unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "Installation was not finalized due to --inspect-shell"
+    );
+    let calls = ops.calls.borrow();
+    assert!(calls.contains(&format!("run-shell {target}")));
+    assert_eq!(
+        calls.last().unwrap(),
+        &format!("unmount-recursive {target}")
+    );
+    assert!(!calls.iter().any(|c| c.starts_with("finalize")));
+    drop(calls);
 
-    // Let's ensure we have a tmpfs on /tmp, because we need that to write the SELinux label
-    // (it won't work on the default overlayfs)
-    if nix::sys::statfs::statfs("/tmp")?.filesystem_type() != nix::sys::statfs::TMPFS_MAGIC {
-        Task::new("Creating tmpfs on /tmp", "mount")
-            .quiet()
-            .args(["-t", "tmpfs", "tmpfs", "/tmp"])
-            .run()?;
+    // Nothing is mounted if the deployment fails
+    let td = tempfile::tempdir().unwrap();
+    let rootfs = Utf8Path::from_path(td.path()).unwrap();
+    let (state, mut root_setup, _) = finish_install_fixture(rootfs);
+    let ops = ops::FakeOps::default();
+    assert!(block_on(install_to_filesystem_impl(
+        &ops,
+        &state,
+        &mut root_setup,
+        InstallStage::Full,
+        &mut metrics::InstallMetrics::default(),
+    ))
+    .is_err());
+    assert_eq!(*ops.calls.borrow(), ["deploy"]);
+}
+
+/// Run [`finish_install`] for the [`finish_install_fixture`] in `rootfs`, after
+/// `modify` adjusted it, returning the state, the calls made and the summary.
+#[cfg(test)]
+fn finish_install_with(
+    rootfs: &Utf8Path,
+    stage: InstallStage,
+    modify: impl FnOnce(&mut State, &mut RootSetup),
+) -> (State, Vec<String>, summary::InstallSummary) {
+    let (mut state, mut root_setup, deployment) = finish_install_fixture(rootfs);
+    modify(&mut state, &mut root_setup);
+    push_install_kargs(&state, &mut root_setup).unwrap();
+    let deployment_root = rootfs.join(&deployment.path);
+    let ops = ops::FakeOps::default();
+    let summary = finish_install(
+        &state,
+        &mut root_setup,
+        deployment,
+        &deployment_root,
+        stage,
+        &ops,
+    )
+    .unwrap();
+    (state, ops.calls.take(), summary)
+}
+
+#[test]
+fn test_finish_install_deploy_only() {
+    let td = tempfile::tempdir().unwrap();
+    let rootfs = Utf8Path::from_path(td.path()).unwrap();
+    let deployment_root = rootfs.join("ostree/deploy/default/deploy/abcd.0");
+    let (_, calls, summary) = finish_install_with(rootfs, InstallStage::DeployOnly, |_, _| {});
+    // The deployment is configured, but there is no bootloader, immutable bit or finalizing
+    assert!(calls.contains(&"run-in-target systemctl enable foo.service".to_string()));
+    for skipped in ["install-bootloader ", "set-immutable", "finalize "] {
+        assert!(!calls.iter().any(|c| c.starts_with(skipped)), "{skipped}");
     }
+    assert!(summary.bootloader.is_empty());
+    assert!(rootfs
+        .join("boot/grub2/grubenv")
+        .symlink_metadata()
+        .is_err());
+    // The mounts and the aleph data are written for the later stage
+    assert!(std::fs::read_to_string(deployment_root.join("etc/fstab"))
+        .unwrap()
+        .contains("/var/data"));
+    assert!(rootfs.join(BOOTC_ALEPH_PATH).exists());
+}
 
-    // Now, deal with SELinux state.
-    let srcdata = gather_source_data()?;
-    let override_disable_selinux =
-        reexecute_self_for_selinux_if_needed(&srcdata, config_opts.disable_selinux)?;
+#[test]
+fn test_finish_install_aleph_path() {
+    let td = tempfile::tempdir().unwrap();
+    let rootfs = Utf8Path::from_path(td.path()).unwrap();
+    let deployment_root = rootfs.join("ostree/deploy/default/deploy/abcd.0");
+    let (_, calls, _) = finish_install_with(rootfs, InstallStage::Full, |state, _| {
+        state.config_opts.aleph_path = Some("etc/inventory/aleph.toml".parse().unwrap());
+        state.config_opts.aleph_format = aleph::AlephFormat::Toml;
+    });
+    // Both the default and the custom aleph are written
+    assert!(rootfs.join(BOOTC_ALEPH_PATH).exists());
+    let custom = std::fs::read_to_string(deployment_root.join("etc/inventory/aleph.toml")).unwrap();
+    assert!(
+        custom.contains("image = \"quay.io/example/os:latest\"\n"),
+        "{custom}"
+    );
+    assert!(calls.contains(&"label /etc/inventory/aleph.toml".to_string()));
+}
 
-    // Create our global (read-only) state which gets wrapped in an Arc
-    // so we can pass it to worker threads too. Right now this just
-    // combines our command line options along with some bind mounts from the host.
-    // Overmount /var/tmp with the host's, so we can use it to share state
-    bind_mount_from_host("/var/tmp", "/var/tmp")?;
-    let state = Arc::new(State {
-        override_disable_selinux,
-        source_imageref,
-        source_digest,
-        config_opts,
-        target_opts,
+#[test]
+fn test_installation_complete_anaconda_results() {
+    let td = tempfile::tempdir().unwrap();
+    let rootfs = Utf8Path::from_path(td.path()).unwrap();
+    let results = rootfs.join("bootc-results");
+    let (state, _, mut summary) = finish_install_with(rootfs, InstallStage::Full, |state, _| {
+        state.config_opts.write_anaconda_results = Some(results.clone());
     });
+    // Only written once the post-install action is known, e.g. after a kexec fallback
+    assert!(!results.exists());
+    summary.post_install = postinstall::PostInstall::Reboot;
+    installation_complete(&state, &summary, None).unwrap();
+    let results = std::fs::read_to_string(results).unwrap();
+    assert!(results.contains("POST_INSTALL=reboot\n"), "{results}");
+}
 
-    Ok(state)
+#[test]
+fn test_finish_install_sync_esp() {
+    let td = tempfile::tempdir().unwrap();
+    let rootfs = Utf8Path::from_path(td.path()).unwrap();
+    let deployment_root = rootfs.join("ostree/deploy/default/deploy/abcd.0");
+    std::fs::create_dir_all(rootfs.join("efi/EFI/BOOT")).unwrap();
+    std::fs::write(rootfs.join("efi/EFI/BOOT/BOOTX64.EFI"), "shim").unwrap();
+    let (_, calls, _) = finish_install_with(rootfs, InstallStage::Full, |_, root_setup| {
+        root_setup.sync_esp = Some("UUID=5678-9ABC".into());
+    });
+    // The second ESP is populated after the bootloader is installed
+    let i = calls
+        .iter()
+        .position(|c| c.starts_with("install-bootloader "))
+        .unwrap();
+    let mount = calls[i + 1].strip_prefix("mount UUID=5678-9ABC ").unwrap();
+    assert_eq!(calls[i + 2], format!("unmount {mount}"));
+    // The copy is done in the temporary mountpoint, which is removed
+    assert!(Utf8Path::new(mount).symlink_metadata().is_err());
+    // And kept in sync afterwards
+    assert!(calls.contains(&"label /etc/systemd/system/bootc-sync-esp.service".to_string()));
+    let service =
+        std::fs::read_to_string(deployment_root.join("etc/systemd/system/bootc-sync-esp.service"))
+            .unwrap();
+    assert!(service.contains("/dev/disk/by-uuid/5678-9ABC"));
 }
 
-async fn install_to_filesystem_impl(state: &State, rootfs: &mut RootSetup) -> Result<()> {
-    if state.override_disable_selinux {
-        rootfs.kargs.push("selinux=0".to_string());
-    }
-    // This is interpreted by our GRUB fragment
-    if state.config_opts.ignition_file.is_some() {
-        rootfs
-            .kargs
-            .push(crate::ignition::PLATFORM_METAL_KARG.to_string());
-        rootfs
-            .kargs
-            .push(crate::bootloader::IGNITION_VARIABLE.to_string());
-    }
+#[test]
+fn test_finish_install_adopt() {
+    let td = tempfile::tempdir().unwrap();
+    let rootfs = Utf8Path::from_path(td.path()).unwrap();
+    let (_, calls, summary) = finish_install_with(rootfs, InstallStage::Full, |_, root_setup| {
+        root_setup.adopt_stateroot = Some("fedora".into());
+        std::fs::create_dir_all(rootfs.join("ostree/deploy/fedora")).unwrap();
+    });
+    // The existing bootloader is kept
+    assert!(!calls.iter().any(|c| c.starts_with("install-bootloader ")));
+    assert!(summary.bootloader.is_empty());
+    assert_eq!(summary.stateroot, "fedora");
+    // The aleph data of the existing system is kept
+    assert!(rootfs
+        .join("ostree/deploy/fedora")
+        .join(BOOTC_ALEPH_PATH)
+        .exists());
+    assert!(!rootfs.join(BOOTC_ALEPH_PATH).exists());
+}
 
-    // Write the aleph data that captures the system state at the time of provisioning for aid in future debugging.
-    {
-        let aleph = initialize_ostree_root_from_self(state, rootfs).await?;
-        rootfs
-            .rootfs_fd
-            .atomic_replace_with(BOOTC_ALEPH_PATH, |f| {
-                serde_json::to_writer(f, &aleph)?;
-                anyhow::Ok(())
-            })
-            .context("Writing aleph version")?;
-    }
+#[test]
+fn test_finish_install_stateless() {
+    let td = tempfile::tempdir().unwrap();
+    let rootfs = Utf8Path::from_path(td.path()).unwrap();
+    let deployment_root = rootfs.join("ostree/deploy/default/deploy/abcd.0");
+    let (_, calls, summary) =
+        finish_install_with(rootfs, InstallStage::Full, |state, root_setup| {
+            state.config_opts.swap = None;
+            root_setup.device = None;
+            root_setup.root = MountSpec::new("live:http://10.0.0.1/rootfs.img", "/");
+            root_setup.root_fstype = None;
+            root_setup.boot = None;
+            root_setup.esp = None;
+        });
+    // No bootloader, nothing to mount and no block devices to finalize
+    assert!(!calls
+        .iter()
+        .any(|c| c.starts_with("install-bootloader ") || c.starts_with("finalize ")));
+    assert_eq!(
+        std::fs::read_to_string(deployment_root.join("etc/fstab")).unwrap(),
+        ""
+    );
+    assert_eq!(summary.bootloader_device, None);
+    assert_eq!(summary.root_uuid, None);
+}
 
-    let boot_uuid = rootfs.get_boot_uuid()?;
-    crate::bootloader::install_via_bootupd(&rootfs.device, &rootfs.rootfs, boot_uuid)?;
-    tracing::debug!("Installed bootloader");
+#[test]
+fn test_selinux_override_warning() {
+    let d = Diagnostics::default();
+    assert!(check_selinux_override(false, false, &d).is_err());
+    assert!(d.warnings().is_empty());
+    assert_eq!(
+        check_selinux_override(true, false, &d).unwrap(),
+        SelinuxOverride::Disable
+    );
+    assert_eq!(
+        d.warnings(),
+        ["Target has SELinux enabled, overriding to disable"]
+    );
+    let mut buf = Vec::new();
+    d.write_summary(&mut buf).unwrap();
+    assert_eq!(
+        String::from_utf8(buf).unwrap(),
+        "Warnings (1):\n  Target has SELinux enabled, overriding to disable\n"
+    );
 
-    // If Ignition is specified, enable it
-    if let Some(ignition_file) = state.config_opts.ignition_file.as_deref() {
-        let src = std::fs::File::open(ignition_file)
-            .with_context(|| format!("Opening {ignition_file}"))?;
-        let bootfs = rootfs.rootfs.join("boot");
-        crate::ignition::write_ignition(&bootfs, &state.config_opts.ignition_hash, &src)?;
-        crate::ignition::enable_firstboot(&bootfs)?;
-        println!("Installed Ignition config from {ignition_file}");
-    }
+    let d = Diagnostics::default();
+    assert_eq!(
+        check_selinux_override(false, true, &d).unwrap(),
+        SelinuxOverride::FirstbootRelabel
+    );
+    assert_eq!(
+        d.warnings(),
+        ["Target has SELinux enabled, overriding to relabel on first boot"]
+    );
+    // The relabeling requires SELinux in the target
+    let srcdata = SourceData {
+        commit: "abcd".into(),
+        selinux: false,
+    };
+    assert!(reexecute_self_for_selinux_if_needed(&srcdata, false, true, &d).is_err());
+    assert_eq!(
+        reexecute_self_for_selinux_if_needed(&srcdata, false, false, &d).unwrap(),
+        None
+    );
 
-    // ostree likes to have the immutable bit on the physical sysroot to ensure
-    // that it doesn't accumulate junk; all system state should be in deployments.
-    Task::new("Setting root immutable bit", "chattr")
-        .cwd(&rootfs.rootfs_fd)?
-        .args(["+i", "."])
-        .run()?;
+    use clap::Parser;
+    let args = ["install", "--selinux-firstboot-relabel", "/dev/vda"];
+    assert!(
+        InstallOpts::try_parse_from(args)
+            .unwrap()
+            .config_opts
+            .selinux_firstboot_relabel
+    );
+    let args = [
+        "install",
+        "--selinux-firstboot-relabel",
+        "--disable-selinux",
+        "/dev/vda",
+    ];
+    assert!(InstallOpts::try_parse_from(args).is_err());
+}
 
-    // Finalize mounted filesystems
-    let bootfs = rootfs.rootfs.join("boot");
-    for fs in [bootfs.as_path(), rootfs.rootfs.as_path()] {
-        finalize_filesystem(fs)?;
+#[test]
+fn test_inspect_shell() {
+    let rootfs = Utf8Path::new("/target");
+    let deployment_root = rootfs.join("ostree/deploy/default/deploy/abcd.0");
+    let ops = ops::FakeOps::default();
+    inspect_shell(&ops, rootfs, &deployment_root).unwrap();
+    let d = deployment_root.as_str();
+    let expected = [
+        format!("bind-mount /target/boot {d}/boot"),
+        format!("bind-mount /target {d}/sysroot"),
+        format!("bind-mount /proc {d}/proc"),
+        format!("bind-mount /sys {d}/sys"),
+        format!("bind-mount /dev {d}/dev"),
+        format!("run-shell {d}"),
+        format!("unmount-recursive {d}/dev"),
+        format!("unmount-recursive {d}/sys"),
+        format!("unmount-recursive {d}/proc"),
+        format!("unmount-recursive {d}/sysroot"),
+        format!("unmount-recursive {d}/boot"),
+    ];
+    assert_eq!(*ops.calls.borrow(), expected);
+}
+
+#[test]
+fn test_find_default_deployment() {
+    let deployments = [
+        ("default", "default/0123abcd.0"),
+        ("fedora", "fedora/4567ef01.0"),
+        ("fedora", "fedora/89abcdef.0"),
+    ]
+    .map(|(s, f)| (s.to_string(), f.to_string()));
+    assert_eq!(find_default_deployment(&deployments, "default").unwrap(), 0);
+    assert_eq!(
+        find_default_deployment(&deployments, "fedora/89abcdef.0").unwrap(),
+        2
+    );
+    // Ambiguous, or not created
+    assert!(find_default_deployment(&deployments, "fedora").is_err());
+    assert!(find_default_deployment(&deployments, "rhel").is_err());
+    assert!(find_default_deployment(&deployments, "default/0123abcd.1").is_err());
+    assert!(find_default_deployment(&[], "default").is_err());
+}
+
+#[test]
+fn test_kargs_file() {
+    let td = tempfile::tempdir().unwrap();
+    let path = Utf8Path::from_path(td.path()).unwrap().join("1234/kargs");
+    let kargs = [
+        "root=UUID=rootuuid",
+        "rw",
+        "boot=UUID=bootuuid",
+        "dyndbg=\"file drivers/usb/* +p\"",
+        "console=ttyS0,115200n8",
+    ]
+    .map(String::from);
+    write_kargs_file(&path, &kargs).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "root=UUID=rootuuid\nrw\nboot=UUID=bootuuid\ndyndbg=\"file drivers/usb/* +p\"\nconsole=ttyS0,115200n8\n"
+    );
+
+    // What is deployed is exactly the contents of the file
+    let read = read_kargs_file(&path).unwrap();
+    assert_eq!(read, kargs);
+    let read = read.iter().map(|v| v.as_str()).collect::<Vec<_>>();
+    let target_imgref: ostree_container::OstreeImageReference =
+        "ostree-unverified-registry:quay.io/example/os:latest"
+            .parse()
+            .unwrap();
+    let opts = deploy_opts(&read, &target_imgref, Default::default());
+    assert_eq!(opts.kargs.unwrap(), kargs);
+
+    // An empty vector round-trips too
+    write_kargs_file(&path, &[]).unwrap();
+    assert!(read_kargs_file(&path).unwrap().is_empty());
+    for invalid in ["", "foo\nbar"] {
+        assert!(write_kargs_file(&path, &[invalid.to_string()]).is_err());
     }
+}
 
-    Ok(())
+#[test]
+fn test_boot_karg() {
+    let boot = MountSpec::new_uuid_src("bootuuid", "/boot");
+    assert_eq!(
+        boot_karg(BootKargBy::default(), &boot, Some("boot")).unwrap(),
+        "boot=UUID=bootuuid"
+    );
+    assert_eq!(
+        boot_karg(BootKargBy::Label, &boot, Some("boot")).unwrap(),
+        "boot=LABEL=boot"
+    );
+    // The label must be known
+    assert!(boot_karg(BootKargBy::Label, &boot, None).is_err());
+    assert!(boot_karg(BootKargBy::Label, &boot, Some("")).is_err());
+    assert_eq!(
+        boot_karg(BootKargBy::Uuid, &boot, None).unwrap(),
+        "boot=UUID=bootuuid"
+    );
 }
 
-fn installation_complete() {
-    println!("Installation complete!");
+#[test]
+fn test_normalize_kargs() {
+    let normalize = |kargs: &[&str], extra: &[&str]| {
+        let kargs = kargs.iter().map(|&k| k.to_string()).collect::<Vec<_>>();
+        let single_valued = SINGLE_VALUED_KARGS.iter().chain(extra).copied();
+        normalize_kargs(&kargs, single_valued, &Diagnostics::default())
+    };
+    // Exact repeats are removed, keeping the first
+    assert_eq!(
+        normalize(&["rw", "console=tty0", "quiet", "rw", "console=tty0"], &[]),
+        ["rw", "console=tty0", "quiet"]
+    );
+    // Other keys may be repeated with different values
+    assert_eq!(
+        normalize(
+            &["console=tty0", "console=ttyS0,115200", "console=tty0"],
+            &[]
+        ),
+        ["console=tty0", "console=ttyS0,115200"]
+    );
+    // Only the last value of single-valued keys is kept
+    assert_eq!(
+        normalize(
+            &[
+                "root=UUID=rootuuid",
+                "rw",
+                "rootflags=subvol=root",
+                "root=LABEL=root",
+                "rootflags=subvol=root",
+                "rootflags=compress=zstd"
+            ],
+            &[]
+        ),
+        ["rw", "root=LABEL=root", "rootflags=compress=zstd"]
+    );
+    assert_eq!(
+        normalize(&["selinux=1", "selinux=0"], &[]),
+        ["selinux=1", "selinux=0"]
+    );
+    assert_eq!(
+        normalize(&["selinux=1", "selinux=0"], &["selinux"]),
+        ["selinux=0"]
+    );
+    // A flag is a value of its key
+    assert_eq!(normalize(&["root=/dev/vda3", "root"], &[]), ["root"]);
+    assert!(normalize(&[], &[]).is_empty());
 }
 
-/// Implementation of the `bootc install` CLI command.
-pub(crate) async fn install(opts: InstallOpts) -> Result<()> {
-    let block_opts = opts.block_opts;
-    let state = prepare_install(opts.config_opts, opts.target_opts).await?;
+#[test]
+fn test_version_file_contents() {
+    assert_eq!(
+        version_file_contents(
+            "quay.io/example/os:latest",
+            "sha256:0ba7ae1e0a0b2ba4e5fd0a6a3c6ed2e4c1b6e07a6f7ab7e8d7a7e2c4ab16b5b6"
+        ),
+        "IMAGE=\"quay.io/example/os:latest\"\nDIGEST=\"sha256:0ba7ae1e0a0b2ba4e5fd0a6a3c6ed2e4c1b6e07a6f7ab7e8d7a7e2c4ab16b5b6\"\n"
+    );
+}
 
-    // This is all blocking stuff
-    let mut rootfs = {
-        tokio::task::spawn_blocking(move || baseline::install_create_rootfs(block_opts)).await??
+#[test]
+fn test_source_copy() {
+    let td = tempfile::tempdir().unwrap();
+    let parent = Utf8Path::from_path(td.path()).unwrap();
+    let parent = CopyParent {
+        path: parent.to_owned(),
+        host_path: "/proc/123/root/run/bootc/123".into(),
     };
+    let copy = SourceCopy::new(&parent).unwrap();
+    // skopeo is given the path on the host
+    let name = copy.host_dir.file_name().unwrap();
+    let dir = parent.path.join(name);
+    assert!(dir.is_dir());
+    assert_eq!(
+        copy.imgref("sha256:0123abcd").to_string(),
+        format!("oci:/proc/123/root/run/bootc/123/{name}:sha256-0123abcd")
+    );
+    // The copy is always removed afterwards
+    drop(copy);
+    assert!(!dir.exists());
+}
 
-    install_to_filesystem_impl(&state, &mut rootfs).await?;
+#[test]
+fn test_copy_parent() {
+    let process_dir = Utf8Path::new("/run/bootc/123");
+    let writable = |paths: &'static [&'static str]| move |p: &Utf8Path| paths.contains(&p.as_str());
+    let same = |p: &str| CopyParent {
+        path: p.into(),
+        host_path: p.into(),
+    };
+    let tempdir = Some(Utf8Path::new("/srv/tmp"));
+    assert_eq!(
+        copy_parent(None, process_dir, writable(&["/var/tmp"])).unwrap(),
+        same("/var/tmp")
+    );
+    assert_eq!(
+        copy_parent(tempdir, process_dir, writable(&["/srv/tmp"])).unwrap(),
+        same("/srv/tmp")
+    );
+    // --tempdir is not replaced by another directory
+    let e = copy_parent(tempdir, process_dir, writable(&["/var/tmp"])).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "--tempdir /srv/tmp is not writable on the host, where skopeo runs; it must be the same path there"
+    );
 
-    // Drop all data about the root except the path to ensure any file descriptors etc. are closed.
-    let rootfs_path = rootfs.rootfs.clone();
-    drop(rootfs);
+    // Without /var/tmp on the host, skopeo writes to our state through /proc
+    let host_path = format!("/proc/{}/root/run/bootc/123", std::process::id());
+    let host_writable = |p: &Utf8Path| p == host_path;
+    assert_eq!(
+        copy_parent(None, process_dir, host_writable).unwrap(),
+        CopyParent {
+            path: process_dir.to_owned(),
+            host_path: host_path.clone().into(),
+        }
+    );
+    let e = copy_parent(None, process_dir, writable(&[])).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "Neither /var/tmp nor /run/bootc/123 is writable on the host, where skopeo runs; specify a writable --tempdir"
+    );
+}
 
-    Task::new_and_run(
-        "Unmounting filesystems",
-        "umount",
-        ["-R", rootfs_path.as_str()],
-    )?;
+#[test]
+fn test_storage_pull_cleanup() {
+    let spec = "quay.io/example/os@sha256:0123abcd";
+    let mut config_opts: InstallConfigOpts = serde_json::from_value(serde_json::json!({})).unwrap();
+    assert!(!pull_into_storage(&config_opts));
+    config_opts.zstd_chunked = true;
+    assert!(pull_into_storage(&config_opts));
 
-    installation_complete();
+    // Removed from the storage afterwards
+    let pull = StoragePull {
+        spec: spec.into(),
+        keep: config_opts.keep_cached_layers,
+    };
+    assert_eq!(
+        pull.cleanup_args().unwrap(),
+        [
+            "delete",
+            "containers-storage:quay.io/example/os@sha256:0123abcd"
+        ]
+    );
+    std::mem::forget(pull);
 
-    Ok(())
+    // Unless kept with --keep-cached-layers, which also pulls into the storage
+    let mut config_opts: InstallConfigOpts = serde_json::from_value(serde_json::json!({
+        "keep_cached_layers": true
+    }))
+    .unwrap();
+    assert!(pull_into_storage(&config_opts));
+    config_opts.zstd_chunked = true;
+    let pull = StoragePull {
+        spec: spec.into(),
+        keep: config_opts.keep_cached_layers,
+    };
+    assert!(pull.cleanup_args().is_none());
+    // Dropping it does nothing
+    drop(pull);
 }
 
-#[context("Verifying empty rootfs")]
-fn require_empty_rootdir(rootfs_fd: &Dir) -> Result<()> {
-    for e in rootfs_fd.entries()? {
-        let e = e?;
-        let name = e.file_name();
-        let name = name
-            .to_str()
-            .ok_or_else(|| anyhow!("Invalid non-UTF8 filename: {name:?}"))?;
-        if name == LOST_AND_FOUND {
-            continue;
-        }
-        // There must be a boot directory (that is empty)
-        if name == BOOT {
-            let mut entries = rootfs_fd.read_dir(BOOT)?;
-            if let Some(e) = entries.next() {
-                let e = e?;
-                let name = e.file_name();
-                let name = name
-                    .to_str()
-                    .ok_or_else(|| anyhow!("Invalid non-UTF8 filename: {name:?}"))?;
-                if matches!(name, LOST_AND_FOUND | crate::bootloader::EFI_DIR) {
-                    continue;
-                }
-                anyhow::bail!("Non-empty boot directory, found {name:?}");
-            }
-        } else {
-            anyhow::bail!("Non-empty root filesystem; found {name:?}");
+#[test]
+fn test_arch_kargs() {
+    for (rust, go) in [
+        ("x86_64", "amd64"),
+        ("aarch64", "arm64"),
+        ("s390x", "s390x"),
+        ("ppc64le", "ppc64le"),
+    ] {
+        for (a, b) in [(rust, go), (go, rust), (rust, rust), (go, go)] {
+            assert!(arch_matches(a, b), "{a} {b}");
         }
     }
-    Ok(())
-}
+    assert!(!arch_matches("aarch64", "amd64"));
+    assert!(!arch_matches("x86_64", "arm64"));
+    assert!(!arch_matches("s390x", "ppc64le"));
 
-/// Implementation of the `bootc install-to-filsystem` CLI command.
-pub(crate) async fn install_to_filesystem(opts: InstallToFilesystemOpts) -> Result<()> {
-    // Gather global state, destructuring the provided options
-    let state = prepare_install(opts.config_opts, opts.target_opts).await?;
-    let fsopts = opts.filesystem_opts;
+    let kargs = [
+        "x86_64=console=ttyS0,115200n8",
+        "arm64=console=ttyAMA0",
+        "aarch64=earlycon",
+        "s390x=cio_ignore=all,!condev",
+    ]
+    .map(|s| ArchKarg::from_str(s).unwrap());
+    let filtered = |target| arch_kargs(&kargs, target).collect::<Vec<_>>();
+    assert_eq!(filtered("amd64"), ["console=ttyS0,115200n8"]);
+    assert_eq!(filtered("arm64"), ["console=ttyAMA0", "earlycon"]);
+    assert_eq!(filtered("s390x"), ["cio_ignore=all,!condev"]);
+    assert!(filtered("ppc64le").is_empty());
+    assert_eq!(kargs[0].to_string(), "x86_64=console=ttyS0,115200n8");
 
-    let root_path = &fsopts.root_path;
-    let rootfs_fd = Dir::open_ambient_dir(root_path, cap_std::ambient_authority())
-        .with_context(|| format!("Opening target root directory {root_path}"))?;
-    if fsopts.wipe {
-        let rootfs_fd = rootfs_fd.try_clone()?;
-        println!("Wiping contents of root");
-        tokio::task::spawn_blocking(move || {
-            for e in rootfs_fd.entries()? {
-                let e = e?;
-                rootfs_fd.remove_all_optional(e.file_name())?;
-            }
-            anyhow::Ok(())
-        })
-        .await??;
-    } else {
-        require_empty_rootdir(&rootfs_fd)?;
+    for invalid in ["console=ttyS0", "amd64=", "=quiet", "riscv=quiet"] {
+        assert!(ArchKarg::from_str(invalid).is_err(), "{invalid}");
     }
+}
 
-    // Gather data about the root filesystem
-    let inspect = crate::mount::inspect_filesystem(&fsopts.root_path)?;
+#[test]
+fn test_recovery_karg_opts() {
+    use clap::Parser;
+    let args = [
+        "install",
+        "--rescue-entry",
+        "--recovery-karg",
+        "single",
+        "--rescue-karg",
+        "nomodeset",
+        "/dev/vda",
+    ];
+    let o = InstallOpts::try_parse_from(args).unwrap();
+    assert_eq!(o.config_opts.rescue_karg, ["single", "nomodeset"]);
+    // Only with a rescue entry
+    let o = InstallOpts::try_parse_from(["install", "--recovery-karg", "single", "/dev/vda"]);
+    assert!(o.is_err());
+    let c: InstallConfigOpts =
+        serde_json::from_value(serde_json::json!({"recovery_karg": ["single"]})).unwrap();
+    assert_eq!(c.rescue_karg, ["single"]);
+}
 
-    // We support overriding the mount specification for root (i.e. LABEL vs UUID versus
-    // raw paths).
-    let root_mount_spec = if let Some(s) = fsopts.root_mount_spec {
-        s
-    } else {
-        let mut uuid = inspect
-            .uuid
-            .ok_or_else(|| anyhow!("No filesystem uuid found in target root"))?;
-        uuid.insert_str(0, "UUID=");
-        tracing::debug!("root {uuid}");
-        uuid
-    };
-    tracing::debug!("Root mount spec: {root_mount_spec}");
+#[test]
+fn test_inheritable_kargs() {
+    assert_eq!(
+        split_cmdline("  quiet dyndbg=\"file drm* +p\"\tconsole=ttyS0,115200\n"),
+        ["quiet", "dyndbg=\"file drm* +p\"", "console=ttyS0,115200"]
+    );
+    assert!(split_cmdline("\n").is_empty());
 
-    // Verify /boot is a separate mount
-    {
-        let root_dev = rootfs_fd.dir_metadata()?.dev();
-        let boot_dev = rootfs_fd
-            .symlink_metadata_optional(BOOT)?
-            .ok_or_else(|| {
-                anyhow!("No /{BOOT} directory found in root; this is is currently required")
-            })?
-            .dev();
-        tracing::debug!("root_dev={root_dev} boot_dev={boot_dev}");
-        if root_dev == boot_dev {
-            anyhow::bail!("/{BOOT} must currently be a separate mounted filesystem");
-        }
-    }
-    // Find the UUID of /boot because we need it for GRUB.
-    let boot_path = fsopts.root_path.join(BOOT);
-    let boot_uuid = crate::mount::inspect_filesystem(&boot_path)
-        .context("Inspecting /{BOOT}")?
-        .uuid
-        .ok_or_else(|| anyhow!("No UUID found for /{BOOT}"))?;
-    tracing::debug!("boot UUID: {boot_uuid}");
+    // An installed ostree system
+    let cmdline = "BOOT_IMAGE=(hd0,gpt3)/ostree/default-5e0b/vmlinuz-6.0.9-300.fc37.x86_64 \
+                   rw root=UUID=4d8e7a5b boot=UUID=0e2d7a53 \
+                   ostree=/ostree/boot.1/default/5e0b/0 rd.luks.uuid=luks-0123 \
+                   intel_iommu=on pci=noaer console=ttyS0,115200n8 mitigations=off\n";
+    assert_eq!(
+        inheritable_kargs(cmdline),
+        [
+            "intel_iommu=on",
+            "pci=noaer",
+            "console=ttyS0,115200n8",
+            "mitigations=off"
+        ]
+    );
+    // A live ISO
+    let cmdline = "BOOT_IMAGE=/images/pxeboot/vmlinuz root=live:CDLABEL=Fedora-37 ro \
+                   rd.live.image rd.live.check inst.stage2=hd:LABEL=Fedora \
+                   coreos.inst.install_dev=/dev/sda ignition.firstboot ip=dhcp \
+                   quiet nomodeset";
+    assert_eq!(inheritable_kargs(cmdline), ["quiet", "nomodeset"]);
+}
 
-    // Find the real underlying backing device for the root.  This is currently just required
-    // for GRUB (BIOS) and in the future zipl (I think).
-    let backing_device = {
-        let mut dev = inspect.source;
-        loop {
-            tracing::debug!("Finding parents for {dev}");
-            let mut parents = crate::blockdev::find_parent_devices(&dev)?.into_iter();
-            let parent = if let Some(f) = parents.next() {
-                f
+#[test]
+fn test_apply_karg() {
+    let base = [
+        "root=UUID=rootuuid",
+        "rw",
+        "console=tty0",
+        "rd.luks.uuid=luks-1234",
+    ];
+    let apply = |kargs: &[&str]| -> Result<Vec<String>> {
+        let mut r = base.map(String::from).to_vec();
+        for karg in kargs {
+            let (mode, karg) = if let Some(k) = karg.strip_prefix("replace:") {
+                (KargMode::Replace, k)
+            } else if let Some(k) = karg.strip_prefix("if-missing:") {
+                (KargMode::AppendIfMissing, k)
             } else {
-                break;
+                (KargMode::Append, *karg)
             };
-            if let Some(next) = parents.next() {
-                anyhow::bail!(
-                    "Found multiple parent devices {parent} and {next}; not currently supported"
-                );
-            }
-            dev = parent;
+            apply_karg(&mut r, karg, mode)?;
         }
-        dev
+        Ok(r)
     };
-    tracing::debug!("Backing device: {backing_device}");
 
-    let rootarg = format!("root={root_mount_spec}");
-    let boot = if let Some(spec) = fsopts.boot_mount_spec {
-        MountSpec::new(&spec, "/boot")
-    } else {
-        MountSpec::new_uuid_src(&boot_uuid, "/boot")
-    };
-    // By default, we inject a boot= karg because things like FIPS compliance currently
-    // require checking in the initramfs.
-    let bootarg = format!("boot={}", &boot.source);
-    let kargs = vec![rootarg, RW_KARG.to_string(), bootarg];
-
-    let mut rootfs = RootSetup {
-        device: backing_device.into(),
-        rootfs: fsopts.root_path,
-        rootfs_fd,
-        boot,
-        kargs,
-    };
+    // Identical arguments are not repeated, for both flags and values
+    assert_eq!(
+        apply(&["rw", "console=tty0", "quiet", "quiet"]).unwrap(),
+        [
+            "root=UUID=rootuuid",
+            "rw",
+            "console=tty0",
+            "rd.luks.uuid=luks-1234",
+            "quiet"
+        ]
+    );
+    // Keys which legitimately repeat are added
+    assert_eq!(
+        apply(&["console=ttyS0,115200", "rd.luks.uuid=luks-5678"]).unwrap(),
+        [
+            "root=UUID=rootuuid",
+            "rw",
+            "console=tty0",
+            "rd.luks.uuid=luks-1234",
+            "console=ttyS0,115200",
+            "rd.luks.uuid=luks-5678"
+        ]
+    );
+    // Others conflict; the key ends at the first =
+    let e = apply(&["root=/dev/vda4"]).unwrap_err().to_string();
+    assert_eq!(
+        e,
+        "Kernel argument root=/dev/vda4 conflicts with root=UUID=rootuuid; use --karg-replace to replace it"
+    );
+    assert!(apply(&["foo=a=b", "foo=a=c"]).is_err());
+    assert!(apply(&["foo=a=b", "foo=a=b", "foo"]).is_err());
 
-    install_to_filesystem_impl(&state, &mut rootfs).await?;
+    // Replacing keeps the position of the first instance and drops the others
+    assert_eq!(
+        apply(&["console=ttyS0", "replace:console=ttyS1,115200"]).unwrap(),
+        [
+            "root=UUID=rootuuid",
+            "rw",
+            "console=ttyS1,115200",
+            "rd.luks.uuid=luks-1234"
+        ]
+    );
+    assert_eq!(
+        apply(&["replace:root=/dev/vda4", "replace:quiet=1"]).unwrap(),
+        [
+            "root=/dev/vda4",
+            "rw",
+            "console=tty0",
+            "rd.luks.uuid=luks-1234",
+            "quiet=1"
+        ]
+    );
 
-    // Drop all data about the root except the path to ensure any file descriptors etc. are closed.
-    drop(rootfs);
+    // Appending if missing compares keys only
+    assert_eq!(
+        apply(&["if-missing:console=ttyS0", "if-missing:loglevel=3"]).unwrap(),
+        [
+            "root=UUID=rootuuid",
+            "rw",
+            "console=tty0",
+            "rd.luks.uuid=luks-1234",
+            "loglevel=3"
+        ]
+    );
+    assert_eq!(apply(&["if-missing:rd.luks.uuid=luks-5678"]).unwrap(), base);
+}
 
-    installation_complete();
+#[test]
+fn test_required_kargs() {
+    use clap::Parser;
+    let kargs = |v: &[&str]| v.iter().map(|&k| k.to_owned()).collect::<Vec<_>>();
+    check_karg_delete(&kargs(&["quiet", "console", "rootflags"])).unwrap();
+    for invalid in [
+        "ostree=/ostree/boot.1/default/5e0b/0",
+        "ostree",
+        "root=UUID=4d8e7a5b",
+        "root",
+    ] {
+        assert!(check_karg_delete(&kargs(&[invalid])).is_err(), "{invalid}");
+    }
+    // Rejected before any installation
+    let opts = InstallOpts::try_parse_from([
+        "install",
+        "--karg-delete",
+        "ostree=/ostree/boot.1/default/5e0b/0",
+        "/dev/vda",
+    ])
+    .unwrap();
+    assert!(check_karg_delete(&opts.config_opts.karg_delete).is_err());
 
-    Ok(())
+    validate_required_kargs(&kargs(&["rw", "root=UUID=4d8e7a5b", "quiet"])).unwrap();
+    for invalid in [
+        &["rw", "quiet"][..],
+        &["rw", "root="],
+        &["root=UUID=4d8e7a5b", "ostree=/ostree/boot.0/default/5e0b/0"],
+    ] {
+        assert!(
+            validate_required_kargs(&kargs(invalid)).is_err(),
+            "{invalid:?}"
+        );
+    }
 }
 
 #[test]
-fn install_opts_serializable() {
-    let c: InstallOpts = serde_json::from_value(serde_json::json!({
-        "device": "/dev/vda"
-    }))
+fn test_validate_options() {
+    use clap::Parser;
+    let parse = |v: serde_json::Value| -> InstallOpts { serde_json::from_value(v).unwrap() };
+    let conflicts = |o: &InstallOpts| {
+        option_conflicts(&o.config_opts, &o.target_opts, Some(&o.block_opts), None)
+    };
+    let o = parse(serde_json::json!({"device": "/dev/vda"}));
+    assert!(conflicts(&o).is_empty());
+    validate_options(&o.config_opts, &o.target_opts, Some(&o.block_opts), None).unwrap();
+    let o = parse(serde_json::json!({
+        "device": "/dev/vda",
+        "layout": "/etc/layout.json",
+        "root_size": "20G",
+        "disable_selinux": true,
+        "selinux_firstboot_relabel": true,
+        "dns_search": ["example.com"],
+    }));
+    assert_eq!(
+        conflicts(&o),
+        [
+            "--selinux-firstboot-relabel conflicts with --disable-selinux",
+            "--layout conflicts with --root-size",
+            "--dns-search requires --dns",
+        ]
+    );
+    // All conflicts are reported at once
+    let e =
+        validate_options(&o.config_opts, &o.target_opts, Some(&o.block_opts), None).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "Invalid options:\n  \
+         --selinux-firstboot-relabel conflicts with --disable-selinux\n  \
+         --layout conflicts with --root-size\n  \
+         --dns-search requires --dns"
+    );
+    let o = parse(serde_json::json!({
+        "device": "/dev/vda",
+        "use_free_space": true,
+        "wipe": true,
+        "metadata_partition": true,
+        "target_imgref": "quay.io/example/os:latest",
+        "target_stream": "stable",
+        "force": true,
+        "ostree_remote_gpg_key": "/etc/fedora.gpg",
+        "vg_name": "sys",
+    }));
+    assert_eq!(
+        conflicts(&o),
+        [
+            "--target-stream conflicts with --target-imgref",
+            "--use-free-space conflicts with --wipe",
+            "--use-free-space conflicts with --metadata-partition",
+            "--force requires --require-existing-image",
+            "--ostree-remote-gpg-key requires --target-ostree-remote",
+            "--vg-name requires --lvm",
+        ]
+    );
+    // Block device options only apply to installing to a disk
+    assert_eq!(
+        option_conflicts(&o.config_opts, &o.target_opts, None, None),
+        [
+            "--target-stream conflicts with --target-imgref",
+            "--force requires --require-existing-image",
+            "--ostree-remote-gpg-key requires --target-ostree-remote",
+        ]
+    );
+    let o = parse(serde_json::json!({
+        "device": "/dev/vda",
+        "persistent_journal": true,
+        "journald_conf": ["SystemMaxUse=1G"],
+        "rescue_entry": true,
+        "rescue_karg": ["single"],
+    }));
+    assert!(conflicts(&o).is_empty());
+
+    // Options acting on the bootloader conflict with keeping the existing one, or
+    // stopping before installing it
+    let args = [
+        "install-to-filesystem",
+        "--grub-config-fragment=/etc/bootc/grub.cfg",
+        "--firstboot-karg=ignition.firstboot",
+        "--verify-bootable",
+        "--rescue-entry",
+        "/target",
+    ];
+    let o = InstallToFilesystemOpts::try_parse_from(args).unwrap();
+    let conflicts = |o: &InstallToFilesystemOpts| {
+        option_conflicts(
+            &o.config_opts,
+            &o.target_opts,
+            None,
+            Some(&o.filesystem_opts),
+        )
+    };
+    assert!(conflicts(&o).is_empty());
+    let o = InstallToFilesystemOpts::try_parse_from(
+        args.iter()
+            .chain(&["--adopt-existing-sysroot", "--stateroot=fedora"]),
+    )
     .unwrap();
-    assert_eq!(c.block_opts.device, "/dev/vda");
+    assert_eq!(
+        conflicts(&o),
+        [
+            "--grub-config-fragment conflicts with --adopt-existing-sysroot",
+            "--firstboot-karg conflicts with --adopt-existing-sysroot",
+        ]
+    );
+    let o = InstallToFilesystemOpts::try_parse_from(args.iter().chain(&["--stage=deploy-only"]))
+        .unwrap();
+    assert_eq!(
+        conflicts(&o),
+        [
+            "--grub-config-fragment conflicts with --stage deploy-only",
+            "--firstboot-karg conflicts with --stage deploy-only",
+            "--verify-bootable conflicts with --stage deploy-only",
+        ]
+    );
+}
+
+#[test]
+fn test_prefetch_imgref() {
+    use source::SourceStrategy;
+    let src = ostree_container::ImageReference {
+        transport: ostree_container::Transport::ContainerStorage,
+        name: "quay.io/example/os:latest".into(),
+    };
+    let registry = SourceStrategy::Registry("quay.io/example/os:latest@sha256:5e0b".into());
+    let oci_copy = SourceStrategy::OciCopy("skopeo is too old".into());
+    // Without --pull-first, the image is fetched after partitioning
+    for strategy in [None, Some(&registry), Some(&oci_copy)] {
+        assert_eq!(prefetch_imgref(false, strategy, &src), None);
+    }
+    // The image is already local, and read directly
+    assert_eq!(
+        prefetch_imgref(true, Some(&SourceStrategy::ContainerStorage), &src),
+        None
+    );
+    // From --source-oci-dir or --source-oci-archive
+    assert_eq!(prefetch_imgref(true, None, &src), None);
+    assert_eq!(
+        prefetch_imgref(true, Some(&registry), &src).unwrap(),
+        ostree_container::ImageReference {
+            transport: ostree_container::Transport::Registry,
+            name: "quay.io/example/os:latest@sha256:5e0b".into(),
+        }
+    );
+    assert_eq!(prefetch_imgref(true, Some(&oci_copy), &src).unwrap(), src);
+
+    // The image is fetched before the target device is modified
+    let td = tempfile::tempdir().unwrap();
+    let td: &Utf8Path = td.path().try_into().unwrap();
+    let (mut state, _, _) = finish_install_fixture(&td.join("root"));
+    state.config_opts.pull_first = true;
+    state.config_opts.tempdir = Some(td.to_owned());
+    state.source_strategy = Some(registry);
+    let block_opts: InstallBlockDeviceOpts =
+        serde_json::from_value(serde_json::json!({"device": "/dev/vda"})).unwrap();
+    let ops = ops::FakeOps::default();
+    assert!(create_rootfs(&ops, &mut state, block_opts).is_err());
+    assert_eq!(
+        *ops.calls.borrow(),
+        [
+            format!("host-path-writable {td}"),
+            "copy-image docker://quay.io/example/os:latest@sha256:5e0b".to_string(),
+            "create-rootfs /dev/vda".to_string(),
+        ]
+    );
+    assert!(state.prefetched_source.is_some());
 }
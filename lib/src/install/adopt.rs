@@ -0,0 +1,165 @@
+//! # Adding a stateroot to an existing ostree sysroot
+//!
+//! With `--adopt-existing-sysroot`, install-to-filesystem deploys into a new stateroot
+//! of the ostree sysroot already in the target root, e.g. to migrate to another
+//! operating system on the same disk.  The existing stateroots, with their `/var` and
+//! deployments, are left untouched, as is the repository configuration; so the
+//! repository must be usable as is.
+
+use std::io::Read;
+
+use anyhow::{Context, Result};
+use cap_std::fs::Dir;
+use cap_std_ext::cap_std;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+
+/// The ostree repository configuration, relative to the root
+const REPO_CONFIG: &str = "ostree/repo/config";
+/// The directory of stateroots, relative to the root
+const DEPLOY_DIR: &str = "ostree/deploy";
+/// The repository mode required for a sysroot
+const REPO_MODE: &str = "bare";
+/// The settings of the `[ex-integrity]` group which we don't set up for a deployment
+const INTEGRITY_KEYS: &[&str] = &["composefs", "fsverity"];
+
+/// Check that the provided name is usable as a stateroot name.
+pub(crate) fn validate_stateroot(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        anyhow::bail!("Invalid stateroot name {name:?}");
+    }
+    Ok(())
+}
+
+/// Find the value of `key` in the provided group of an ostree repository configuration.
+fn config_value<'a>(config: &'a str, group: &str, key: &str) -> Option<&'a str> {
+    let mut in_group = false;
+    for line in config.lines().map(str::trim) {
+        if let Some(name) = line.strip_prefix('[') {
+            in_group = name.strip_suffix(']') == Some(group);
+        } else if in_group {
+            if let Some((k, v)) = line.split_once('=') {
+                if k.trim() == key {
+                    return Some(v.trim());
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Verify that the root contains an ostree sysroot to which the provided stateroot can
+/// be added, returning the names of the existing stateroots.
+#[context("Checking existing ostree sysroot")]
+pub(crate) fn check_sysroot(rootfs: &Dir, stateroot: &str) -> Result<Vec<String>> {
+    let mut config = String::new();
+    rootfs
+        .open_optional(REPO_CONFIG)?
+        .ok_or_else(|| anyhow::anyhow!("No ostree sysroot found; missing /{REPO_CONFIG}"))?
+        .read_to_string(&mut config)
+        .with_context(|| format!("Reading {REPO_CONFIG}"))?;
+    match config_value(&config, "core", "mode") {
+        Some(REPO_MODE) => {}
+        mode => anyhow::bail!(
+            "Incompatible ostree repository mode {}; expected {REPO_MODE}",
+            mode.unwrap_or("(unset)")
+        ),
+    }
+    // The new deployment would lack what these require, e.g. a composefs image
+    for key in INTEGRITY_KEYS {
+        match config_value(&config, "ex-integrity", key) {
+            None | Some("false" | "no" | "0") => {}
+            Some(v) => {
+                anyhow::bail!("Incompatible ostree repository setting [ex-integrity] {key}={v}")
+            }
+        }
+    }
+    let deploy = rootfs
+        .open_dir_optional(DEPLOY_DIR)?
+        .ok_or_else(|| anyhow::anyhow!("No ostree sysroot found; missing /{DEPLOY_DIR}"))?;
+    let mut stateroots = Vec::new();
+    for e in deploy.entries()? {
+        let e = e?;
+        if !e.file_type()?.is_dir() {
+            continue;
+        }
+        let name = e.file_name();
+        let name = name
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Invalid non-UTF8 stateroot: {name:?}"))?;
+        stateroots.push(name.to_owned());
+    }
+    stateroots.sort();
+    if stateroots.iter().any(|s| s == stateroot) {
+        anyhow::bail!("Stateroot {stateroot} already exists");
+    }
+    Ok(stateroots)
+}
+
+#[test]
+fn test_validate_stateroot() {
+    for valid in ["default", "fedora", "rhel-9.4", "os_b"] {
+        validate_stateroot(valid).unwrap();
+    }
+    for invalid in ["", ".", "..", "a/b", "a b", ".hidden"] {
+        assert!(validate_stateroot(invalid).is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn test_check_sysroot() {
+    let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    let e = check_sysroot(&td, "fedora").unwrap_err();
+    assert!(
+        format!("{e:#}").contains("missing /ostree/repo/config"),
+        "{e:#}"
+    );
+
+    td.create_dir_all("ostree/repo").unwrap();
+    for (config, msg) in [
+        (
+            "[core]\nrepo_version=1\nmode=archive-z2\n",
+            "mode archive-z2",
+        ),
+        (
+            "[core]\nrepo_version=1\n[remote \"x\"]\nmode=bare\n",
+            "mode (unset)",
+        ),
+    ] {
+        td.write(REPO_CONFIG, config).unwrap();
+        let e = check_sysroot(&td, "fedora").unwrap_err();
+        assert!(format!("{e:#}").contains(msg), "{e:#}");
+    }
+    for (config, msg) in [
+        (
+            "[core]\nmode=bare\n[ex-integrity]\ncomposefs=true\n",
+            "[ex-integrity] composefs=true",
+        ),
+        (
+            "[core]\nmode=bare\n[ex-integrity]\nfsverity=maybe\n",
+            "[ex-integrity] fsverity=maybe",
+        ),
+    ] {
+        td.write(REPO_CONFIG, config).unwrap();
+        let e = check_sysroot(&td, "fedora").unwrap_err();
+        assert!(format!("{e:#}").contains(msg), "{e:#}");
+    }
+    td.write(
+        REPO_CONFIG,
+        "[core]\nrepo_version=1\nmode = bare\n[ex-integrity]\ncomposefs=false\n",
+    )
+    .unwrap();
+    let e = check_sysroot(&td, "fedora").unwrap_err();
+    assert!(format!("{e:#}").contains("missing /ostree/deploy"), "{e:#}");
+
+    td.create_dir_all("ostree/deploy/rhcos/var").unwrap();
+    td.create_dir_all("ostree/deploy/default/deploy").unwrap();
+    assert_eq!(check_sysroot(&td, "fedora").unwrap(), ["default", "rhcos"]);
+    let e = check_sysroot(&td, "default").unwrap_err();
+    assert!(format!("{e:#}").contains("Stateroot default already exists"));
+}
@@ -0,0 +1,219 @@
+//! # Custom aleph location
+//!
+//! The aleph is always written as JSON to `.bootc-aleph.json` in the root filesystem,
+//! or in the directory of the stateroot when adopting an existing sysroot.  For tools
+//! which look for it elsewhere, it can additionally be written to a path in the
+//! deployment, i.e. in the installed system, as JSON or TOML.
+
+use std::fmt::Display;
+use std::os::unix::fs::PermissionsExt;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use cap_std::fs::{Dir, Permissions};
+use cap_std_ext::cap_std;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use serde::{Deserialize, Serialize};
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+
+/// The serialization format of the aleph.
+#[derive(clap::ValueEnum, Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum AlephFormat {
+    #[default]
+    Json,
+    Toml,
+}
+
+impl AlephFormat {
+    /// Serialize the provided aleph.
+    fn serialize(self, aleph: &impl Serialize) -> Result<String> {
+        let r = match self {
+            AlephFormat::Json => serde_json::to_string(aleph)?,
+            // Via a table, which puts values before nested tables as TOML requires
+            AlephFormat::Toml => toml::to_string(&toml::Value::try_from(aleph)?)?,
+        };
+        Ok(r)
+    }
+}
+
+/// A path for the aleph, relative to the deployment root; it may not contain `..`.
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeFromStr, SerializeDisplay)]
+pub(crate) struct AlephPath(Utf8PathBuf);
+
+impl FromStr for AlephPath {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let path = Utf8Path::new(s);
+        let mut components = path.components().peekable();
+        if components.peek().is_none() {
+            anyhow::bail!("Invalid empty aleph path");
+        }
+        for c in components {
+            match c {
+                Utf8Component::Normal(_) | Utf8Component::CurDir => {}
+                Utf8Component::RootDir | Utf8Component::Prefix(_) => {
+                    anyhow::bail!("Invalid aleph path {s}: must be relative to the deployment")
+                }
+                Utf8Component::ParentDir => {
+                    anyhow::bail!("Invalid aleph path {s}: must be within the deployment")
+                }
+            }
+        }
+        // Excluding `.` components, and any trailing `/`
+        let path: Utf8PathBuf = path
+            .components()
+            .filter(|c| matches!(c, Utf8Component::Normal(_)))
+            .collect();
+        if path.file_name().is_none() {
+            anyhow::bail!("Invalid aleph path {s}: must name a file");
+        }
+        Ok(Self(path))
+    }
+}
+
+impl Display for AlephPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl AsRef<Utf8Path> for AlephPath {
+    fn as_ref(&self) -> &Utf8Path {
+        &self.0
+    }
+}
+
+/// Write the aleph to the provided path in the deployment in the provided format,
+/// creating missing parent directories.  The deployment is accessed via `cap-std`,
+/// so symbolic links cannot lead outside of it either.
+#[context("Writing aleph to {path}")]
+pub(crate) fn write_aleph(
+    deployment: &Dir,
+    path: &AlephPath,
+    format: AlephFormat,
+    aleph: &impl Serialize,
+) -> Result<()> {
+    let contents = format.serialize(aleph)?;
+    if let Some(parent) = path.0.parent().filter(|p| !p.as_str().is_empty()) {
+        deployment
+            .create_dir_all(parent)
+            .with_context(|| format!("Creating {parent}"))?;
+    }
+    deployment.atomic_write_with_perms(&path.0, contents, Permissions::from_mode(0o644))?;
+    Ok(())
+}
+
+#[test]
+fn test_aleph_path() {
+    for (input, expected) in [
+        ("aleph.json", "aleph.json"),
+        ("etc/inventory/aleph.toml", "etc/inventory/aleph.toml"),
+        ("./usr/./share/aleph.json/", "usr/share/aleph.json"),
+    ] {
+        assert_eq!(AlephPath::from_str(input).unwrap().to_string(), expected);
+    }
+    for invalid in [
+        "",
+        ".",
+        "/etc/aleph.json",
+        "../aleph.json",
+        "var/lib/../aleph.json",
+    ] {
+        assert!(AlephPath::from_str(invalid).is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn test_write_aleph() {
+    use std::collections::BTreeMap;
+    use std::os::unix::fs::MetadataExt;
+
+    #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+    struct Aleph {
+        image: String,
+        kernel: String,
+        ostree_config: BTreeMap<String, String>,
+        preserved_ssh_host_keys: Vec<String>,
+    }
+    let aleph = Aleph {
+        image: "quay.io/example/os@sha256:abcd".into(),
+        kernel: "6.2.9-300.fc38.x86_64".into(),
+        ostree_config: [("sysroot.readonly".to_string(), "true".to_string())].into(),
+        preserved_ssh_host_keys: vec!["ssh_host_ed25519_key".into()],
+    };
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let td = Dir::open_ambient_dir(tempdir.path(), cap_std::ambient_authority()).unwrap();
+    let path: AlephPath = "etc/inventory/aleph.json".parse().unwrap();
+    write_aleph(&td, &path, AlephFormat::Json, &aleph).unwrap();
+    let contents = td.read_to_string("etc/inventory/aleph.json").unwrap();
+    assert_eq!(serde_json::from_str::<Aleph>(&contents).unwrap(), aleph);
+    assert_eq!(
+        td.metadata("etc/inventory/aleph.json").unwrap().mode() & 0o7777,
+        0o644
+    );
+
+    // Replacing an existing file, in a directory which exists
+    let path: AlephPath = "etc/inventory/aleph.toml".parse().unwrap();
+    td.write("etc/inventory/aleph.toml", "stale").unwrap();
+    write_aleph(&td, &path, AlephFormat::Toml, &aleph).unwrap();
+    let contents = td.read_to_string("etc/inventory/aleph.toml").unwrap();
+    assert!(contents.contains("image = \"quay.io/example/os@sha256:abcd\"\n"));
+    assert_eq!(toml::from_str::<Aleph>(&contents).unwrap(), aleph);
+
+    // At the top of the deployment
+    let path: AlephPath = "aleph.toml".parse().unwrap();
+    write_aleph(&td, &path, AlephFormat::Toml, &aleph).unwrap();
+    let contents = td.read_to_string("aleph.toml").unwrap();
+    assert_eq!(toml::from_str::<Aleph>(&contents).unwrap(), aleph);
+
+    // Symbolic links can't be used to escape
+    let outside = tempfile::tempdir().unwrap();
+    std::os::unix::fs::symlink(outside.path(), tempdir.path().join("escape")).unwrap();
+    let path: AlephPath = "escape/aleph.json".parse().unwrap();
+    assert!(write_aleph(&td, &path, AlephFormat::Json, &aleph).is_err());
+    assert!(!outside.path().join("aleph.json").exists());
+}
+
+#[test]
+fn test_aleph_opts() {
+    use clap::Parser;
+    let o = super::InstallOpts::try_parse_from([
+        "install",
+        "--aleph-path",
+        "etc/aleph.toml",
+        "--aleph-format",
+        "toml",
+        "/dev/vda",
+    ])
+    .unwrap();
+    assert_eq!(
+        o.config_opts.aleph_path.unwrap().to_string(),
+        "etc/aleph.toml"
+    );
+    assert_eq!(o.config_opts.aleph_format, AlephFormat::Toml);
+    let o = super::InstallOpts::try_parse_from(["install", "/dev/vda"]).unwrap();
+    assert_eq!(o.config_opts.aleph_path, None);
+    assert_eq!(o.config_opts.aleph_format, AlephFormat::Json);
+    for args in [
+        &["install", "--aleph-format", "toml", "/dev/vda"][..],
+        &["install", "--aleph-path", "/etc/aleph.json", "/dev/vda"],
+        &[
+            "install",
+            "--aleph-path",
+            "aleph.json",
+            "--aleph-format",
+            "yaml",
+            "/dev/vda",
+        ],
+    ] {
+        assert!(
+            super::InstallOpts::try_parse_from(args).is_err(),
+            "{args:?}"
+        );
+    }
+}
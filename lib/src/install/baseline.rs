@@ -9,6 +9,8 @@ use std::borrow::Cow;
 use std::fmt::Display;
 use std::process::Command;
 use std::process::Stdio;
+use std::str::FromStr;
+use std::time::Duration;
 
 use anyhow::Ok;
 use anyhow::{Context, Result};
@@ -19,10 +21,12 @@ use cap_std_ext::cap_std;
 use clap::ArgEnum;
 use fn_error_context::context;
 use serde::{Deserialize, Serialize};
+use serde_with::{DeserializeFromStr, SerializeDisplay};
 
+use super::lvm;
+use super::summary::CreatedPartition;
 use super::MountSpec;
 use super::RootSetup;
-use super::RUN_BOOTC;
 use super::RW_KARG;
 use crate::lsm::lsm_label;
 use crate::mount;
@@ -44,6 +48,7 @@ pub(crate) const PREPPN: u32 = 1;
 pub(crate) const RESERVEDPN: u32 = 1;
 
 #[derive(clap::ValueEnum, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub(crate) enum Filesystem {
     Xfs,
     Ext4,
@@ -76,11 +81,23 @@ impl Default for BlockSetup {
     }
 }
 
+impl BlockSetup {
+    /// Whether this build can set up the target block device this way.
+    pub(crate) fn is_supported(self) -> bool {
+        match self {
+            Self::Direct => true,
+            // TODO
+            Self::Tpm2Luks => false,
+        }
+    }
+}
+
 /// Options for installing to a block device
 #[derive(Debug, Clone, clap::Args, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub(crate) struct InstallBlockDeviceOpts {
-    /// Target block device for installation.  The entire device will be wiped.
+    /// Target block device for installation.  The entire device will be wiped, unless
+    /// `--use-free-space` is given.
     pub(crate) device: Utf8PathBuf,
 
     /// Automatically wipe all existing data on device
@@ -101,11 +118,696 @@ pub(crate) struct InstallBlockDeviceOpts {
     #[serde(default)]
     pub(crate) filesystem: Filesystem,
 
+    /// The filesystem type of /boot.
+    ///
+    /// By default this depends on the architecture: `xfs` on s390x, where zipl records
+    /// the location of the kernel and initramfs rather than reading the filesystem, and
+    /// `ext4` elsewhere, as it is readable by all GRUB versions and GRUB can write its
+    /// environment block to it.  FAT is not supported, as ostree requires symbolic links
+    /// in /boot.
+    #[clap(long, value_enum)]
+    #[serde(default)]
+    pub(crate) boot_filesystem: Option<Filesystem>,
+
+    /// The GPT partition type of /boot: `xbootldr` for the Linux extended boot partition
+    /// type of the Discoverable Partitions Specification, or a partition type GUID.  By
+    /// default, the partition has the generic Linux filesystem type.
+    #[clap(long, value_name = "xbootldr|GUID", conflicts_with = "layout")]
+    #[serde(default)]
+    pub(crate) boot_partition_type: Option<BootPartitionType>,
+
     /// Size of the root partition (default specifier: M).  Allowed specifiers: M (mebibytes), G (gibibytes), T (tebibytes).
     ///
-    /// By default, all remaining space on the disk will be used.
+    /// By default, all remaining space on the disk will be used.  With `--lvm`, this is
+    /// the size of the root logical volume instead.
     #[clap(long)]
     pub(crate) root_size: Option<String>,
+
+    /// Create the root filesystem on an LVM logical volume, in a volume group whose
+    /// physical volume is the root partition.  The partition uses all remaining space;
+    /// with `--root-size`, the rest of the volume group is left free.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) lvm: bool,
+
+    /// The name of the volume group created with `--lvm`; defaults to `bootc`.
+    #[clap(long, value_name = "NAME", requires = "lvm")]
+    #[serde(default)]
+    pub(crate) vg_name: Option<String>,
+
+    /// The name of the root logical volume created with `--lvm`; defaults to `root`.
+    #[clap(long, value_name = "NAME", requires = "lvm")]
+    #[serde(default)]
+    pub(crate) lv_name: Option<String>,
+
+    /// Path to a JSON file describing the full partition layout to create.
+    ///
+    /// The file must contain an array of partition objects with the keys `number`, `name`,
+    /// and optionally `typecode`, `size` (same format as `--root-size`; if omitted, all
+    /// remaining space is used), `filesystem` and `mountpoint`.  Partitions for `/` and
+    /// `/boot` are required, as is an EFI system partition with mountpoint `/boot/efi`
+    /// on architectures using UEFI; it is mounted according to `--esp-mountpoint`.
+    #[clap(long, conflicts_with = "root-size")]
+    pub(crate) layout: Option<Utf8PathBuf>,
+
+    /// Size of the BIOS-BOOT partition (x86_64 only); defaults to 1M.  At most 8M is allowed.
+    #[clap(long, conflicts_with = "layout")]
+    pub(crate) bios_boot_size: Option<String>,
+
+    /// Create an additional partition at the end of the disk; may be specified multiple times.
+    ///
+    /// FSTYPE is `vfat` or one of the `--filesystem` types.  The contents of SOURCE_DIR
+    /// (if given) are copied into the new filesystem, which is mounted in the installed
+    /// system at MOUNTPOINT, by default `/run/media/LABEL`.
+    #[clap(
+        long,
+        value_parser,
+        value_name = "LABEL:SIZE:FSTYPE[:SOURCE_DIR[:MOUNTPOINT]]"
+    )]
+    #[serde(default)]
+    pub(crate) extra_partition: Vec<ExtraPartition>,
+
+    /// Create a small FAT partition at the end of the disk holding information about the
+    /// installation (such as the kernel arguments and fstab), which can be inspected
+    /// without mounting the root filesystem.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) metadata_partition: bool,
+
+    /// Also create an EFI system partition on this device, e.g. the second disk of a
+    /// dual-disk system, so that the system remains bootable if the target device fails.
+    /// It is populated with a copy of the primary ESP after installing the bootloader,
+    /// and bootloader updates are copied to it.  The device must be an unpartitioned
+    /// whole disk other than the target device; with `--wipe`, it is wiped too.
+    #[clap(long, value_name = "DEVICE")]
+    #[serde(default)]
+    pub(crate) sync_esp: Option<Utf8PathBuf>,
+
+    /// Additional argument for `mkfs` when creating the root filesystem; may be specified
+    /// multiple times.  Requested filesystem features are checked against the image's kernel.
+    #[clap(long, value_name = "ARG", allow_hyphen_values = true)]
+    #[serde(default)]
+    pub(crate) root_mkfs_opt: Vec<String>,
+
+    /// Bytes of root filesystem space per inode (ext4 only).  Use a smaller ratio for
+    /// workloads with many small files, which could otherwise run out of inodes.  By
+    /// default, the mkfs default is used.
+    #[clap(long, value_name = "BYTES")]
+    #[serde(default)]
+    pub(crate) root_inode_ratio: Option<u64>,
+
+    /// Size of each inode of the root filesystem in bytes (ext4 and xfs); must be a power
+    /// of two.  By default, the mkfs default is used.
+    #[clap(long, value_name = "BYTES")]
+    #[serde(default)]
+    pub(crate) root_inode_size: Option<u64>,
+
+    /// The size up to which the ext4 root filesystem can be grown while mounted (same
+    /// format as `--root-size`).  mkfs reserves space for the block group descriptors
+    /// needed to reach it (`-E resize=`, see mke2fs(8)); it must be larger than the
+    /// root filesystem.
+    #[clap(long, value_name = "SIZE")]
+    #[serde(default)]
+    pub(crate) ext4_resize_limit: Option<String>,
+
+    /// How long to wait for udev to create the device nodes and symlinks for the new
+    /// partitions and filesystems, in seconds; defaults to 30.  Large disk arrays may
+    /// take much longer to settle.
+    #[clap(long, value_name = "SECONDS")]
+    #[serde(default)]
+    pub(crate) udev_settle_timeout: Option<u64>,
+
+    /// Write systemd-repart definitions (see repart.d(5)) describing the partitions
+    /// created, with their types, labels, UUIDs and sizes, to this directory.
+    #[clap(long, value_name = "DIR")]
+    #[serde(default)]
+    pub(crate) emit_repart_dir: Option<Utf8PathBuf>,
+
+    /// Keep the existing GPT partition table, e.g. to dual boot with Windows, creating
+    /// the /boot and root partitions in its largest contiguous free space and reusing its
+    /// EFI system partition.  Without `--root-size`, the root partition uses all of that
+    /// space.  Fails if there is not enough free space.
+    #[clap(
+        long,
+        conflicts_with_all = &["wipe", "layout", "bios-boot-size", "extra-partition", "metadata-partition"]
+    )]
+    #[serde(default)]
+    pub(crate) use_free_space: bool,
+}
+
+impl InstallBlockDeviceOpts {
+    /// The logical volume to create for the root filesystem, if any.
+    pub(crate) fn root_lv(&self) -> Result<Option<RootLv>> {
+        if !self.lvm {
+            return Ok(None);
+        }
+        let vg = self.vg_name.as_deref().unwrap_or(lvm::DEFAULT_VG_NAME);
+        let lv = self.lv_name.as_deref().unwrap_or(lvm::DEFAULT_LV_NAME);
+        lvm::validate_name(vg)?;
+        lvm::validate_name(lv)?;
+        Ok(Some(RootLv {
+            vg: vg.to_owned(),
+            lv: lv.to_owned(),
+        }))
+    }
+
+    /// How long to wait for udev to settle.
+    fn udev_settle_timeout(&self) -> Duration {
+        self.udev_settle_timeout.map_or(
+            crate::blockdev::DEFAULT_UDEV_SETTLE_TIMEOUT,
+            Duration::from_secs,
+        )
+    }
+}
+
+/// The logical volume holding the root filesystem, with `--lvm`.
+#[derive(Debug)]
+pub(crate) struct RootLv {
+    pub(crate) vg: String,
+    pub(crate) lv: String,
+}
+
+/// The mount of the root filesystem with the provided UUID, and the kernel arguments
+/// with which the initramfs finds it.  A logical volume is mounted by its device-mapper
+/// path, which is stable, and must be activated by the initramfs.
+fn root_mount(root_uuid: &str, root_lv: Option<&RootLv>) -> (MountSpec, Vec<String>) {
+    if let Some(RootLv { vg, lv }) = root_lv {
+        let path = lvm::dm_path(vg, lv);
+        (
+            MountSpec::new(path.as_str(), "/"),
+            vec![format!("root={path}"), lvm::karg(vg, lv)],
+        )
+    } else {
+        (
+            MountSpec::new_uuid_src(root_uuid, "/"),
+            vec![format!("root=UUID={root_uuid}")],
+        )
+    }
+}
+
+/// The filesystem label of the metadata partition
+const METADATA_PARTITION_LABEL: &str = "BOOTC-META";
+/// The size of the metadata partition
+const METADATA_PARTITION_SIZE: &str = "16M";
+
+/// The parent directory of the default mountpoint for extra partitions
+const EXTRA_PARTITION_MOUNT_DIR: &str = "/run/media";
+/// Partition type GUID for the Microsoft basic data partitions we use for FAT
+const BASIC_DATA_TYPECODE: &str = "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7";
+
+/// The filesystem of an extra partition.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum ExtraFilesystem {
+    Vfat,
+    Linux(Filesystem),
+}
+
+impl ExtraFilesystem {
+    /// The maximum filesystem label length
+    fn max_label_len(self) -> usize {
+        match self {
+            Self::Vfat => 11,
+            Self::Linux(Filesystem::Xfs) => 12,
+            Self::Linux(Filesystem::Ext4) => 16,
+            Self::Linux(Filesystem::Btrfs) => 255,
+        }
+    }
+}
+
+impl Display for ExtraFilesystem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Vfat => f.write_str("vfat"),
+            Self::Linux(fs) => fs.fmt(f),
+        }
+    }
+}
+
+/// The partition type of /boot requested via `--boot-partition-type`.
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeFromStr, SerializeDisplay)]
+pub(crate) enum BootPartitionType {
+    /// The Linux extended boot partition type
+    Xbootldr,
+    /// A partition type GUID, in upper case
+    Guid(String),
+}
+
+impl BootPartitionType {
+    /// The partition type GUID.
+    fn typecode(&self) -> &str {
+        match self {
+            Self::Xbootldr => XBOOTLDR_TYPECODE,
+            Self::Guid(guid) => guid,
+        }
+    }
+}
+
+impl FromStr for BootPartitionType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "xbootldr" {
+            return Ok(Self::Xbootldr);
+        }
+        let guid = uuid::Uuid::try_parse(s).map_err(|_| {
+            anyhow::anyhow!("Invalid boot partition type {s}: expected xbootldr or a GUID")
+        })?;
+        Ok(Self::Guid(guid.hyphenated().to_string().to_uppercase()))
+    }
+}
+
+impl Display for BootPartitionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Xbootldr => f.write_str("xbootldr"),
+            Self::Guid(guid) => f.write_str(guid),
+        }
+    }
+}
+
+/// An additional partition requested via `--extra-partition`.
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeFromStr, SerializeDisplay)]
+pub(crate) struct ExtraPartition {
+    pub(crate) label: String,
+    pub(crate) size: String,
+    pub(crate) filesystem: ExtraFilesystem,
+    /// Directory whose contents are copied into the new filesystem
+    pub(crate) source: Option<Utf8PathBuf>,
+    pub(crate) mountpoint: Option<Utf8PathBuf>,
+}
+
+impl ExtraPartition {
+    /// The mountpoint in the target system.
+    fn mountpoint(&self) -> Utf8PathBuf {
+        self.mountpoint
+            .clone()
+            .unwrap_or_else(|| Utf8Path::new(EXTRA_PARTITION_MOUNT_DIR).join(&self.label))
+    }
+}
+
+impl FromStr for ExtraPartition {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts = s.split(':').collect::<Vec<_>>();
+        let (label, size, filesystem, source, mountpoint) = match parts.as_slice() {
+            [l, s, f] => (*l, *s, *f, "", ""),
+            [l, s, f, src] => (*l, *s, *f, *src, ""),
+            [l, s, f, src, m] => (*l, *s, *f, *src, *m),
+            _ => anyhow::bail!(
+                "Invalid extra partition {s}: expected LABEL:SIZE:FSTYPE[:SOURCE_DIR[:MOUNTPOINT]]"
+            ),
+        };
+        let filesystem = match filesystem {
+            "vfat" => ExtraFilesystem::Vfat,
+            o => ExtraFilesystem::Linux(
+                Filesystem::from_str(o, false)
+                    .map_err(|_| anyhow::anyhow!("Unsupported filesystem {o}"))?,
+            ),
+        };
+        if label.is_empty()
+            || !label
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            anyhow::bail!("Invalid partition label {label:?}");
+        }
+        // These are used for the filesystems we create by default
+        if matches!(label, "boot" | "root" | "EFI-SYSTEM") {
+            anyhow::bail!("Partition label {label} is reserved");
+        }
+        let max_label_len = filesystem.max_label_len();
+        if label.len() > max_label_len {
+            anyhow::bail!(
+                "Label {label} is longer than {max_label_len} characters for {filesystem}"
+            );
+        }
+        crate::blockdev::parse_size_mib(size)
+            .with_context(|| format!("Parsing size of partition {label}"))?;
+        let source = (!source.is_empty()).then(|| Utf8PathBuf::from(source));
+        let mountpoint = (!mountpoint.is_empty()).then(|| Utf8PathBuf::from(mountpoint));
+        if let Some(m) = mountpoint.as_deref() {
+            if !m.is_absolute() || matches!(m.as_str(), "/" | "/boot" | ESP_MOUNTPOINT) {
+                anyhow::bail!("Invalid mountpoint {m}");
+            }
+        }
+        Ok(Self {
+            label: label.to_string(),
+            size: size.to_string(),
+            filesystem,
+            source,
+            mountpoint,
+        })
+    }
+}
+
+impl Display for ExtraPartition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.label, self.size, self.filesystem)?;
+        if self.source.is_some() || self.mountpoint.is_some() {
+            write!(f, ":{}", self.source.as_deref().map_or("", |s| s.as_str()))?;
+        }
+        if let Some(m) = self.mountpoint.as_deref() {
+            write!(f, ":{m}")?;
+        }
+        std::fmt::Result::Ok(())
+    }
+}
+
+/// Verify the extra partitions are distinct, and compute their partition numbers,
+/// which follow those in the layout.
+fn extra_partition_numbers(layout: &[PartitionSpec], extra: &[ExtraPartition]) -> Result<Vec<u32>> {
+    let mut labels = std::collections::HashSet::new();
+    let mut mountpoints = std::collections::HashSet::new();
+    for part in extra {
+        if !labels.insert(part.label.as_str()) {
+            anyhow::bail!("Duplicate extra partition label {}", part.label);
+        }
+        if !mountpoints.insert(part.mountpoint()) {
+            anyhow::bail!("Duplicate extra partition mountpoint {}", part.mountpoint());
+        }
+    }
+    let start = layout.iter().map(|p| p.number).max().unwrap_or(0) + 1;
+    Ok((start..).take(extra.len()).collect())
+}
+
+/// Add the arguments to create the extra partitions to an `sgdisk` invocation.  These
+/// must come before the layout partitions, since they are allocated from the end of the disk
+/// and the root partition may use all remaining space.
+fn extra_partitions_to_sgdisk(
+    sgdisk: &mut Command,
+    numbers: &[u32],
+    extra: &[ExtraPartition],
+) -> Result<()> {
+    // Allocating from the end, so go in reverse to preserve the order on disk
+    for (&number, part) in numbers.iter().zip(extra).rev() {
+        let size = crate::blockdev::parse_size_mib(&part.size)?;
+        let typecode = match part.filesystem {
+            ExtraFilesystem::Vfat => BASIC_DATA_TYPECODE,
+            ExtraFilesystem::Linux(_) => LINUX_TYPECODE,
+        };
+        sgdisk_partition(
+            sgdisk,
+            number,
+            format!("-{size}M:0"),
+            &part.label,
+            Some(typecode),
+        );
+    }
+    Ok(())
+}
+
+/// Create the filesystem on an extra partition, returning its UUID.
+fn mkfs_extra_partition(dev: &str, part: &ExtraPartition) -> Result<String> {
+    match part.filesystem {
+        ExtraFilesystem::Vfat => {
+            let volid = uuid::Uuid::new_v4().as_fields().0;
+            Task::new("Creating filesystem", "mkfs.fat")
+                .args([dev, "-n", part.label.as_str(), "-i"])
+                .args([format!("{volid:08X}")])
+                .quiet_output()
+                .run()?;
+            Ok(format!("{:04X}-{:04X}", volid >> 16, volid & 0xFFFF))
+        }
+        ExtraFilesystem::Linux(fs) => Ok(mkfs(dev, fs, Some(&part.label), [])?.to_string()),
+    }
+}
+
+/// Create the filesystem on an extra partition and populate it.
+#[context("Creating partition {}", part.label)]
+fn create_extra_partition(
+    dev: &str,
+    part: &ExtraPartition,
+    mntdir: &Utf8Path,
+) -> Result<CreatedPartition> {
+    let uuid = mkfs_extra_partition(dev, part)?;
+    let mountpoint = part.mountpoint();
+    if let Some(source) = part.source.as_deref() {
+        let target = mntdir.join(format!("extra-{}", part.label));
+        std::fs::create_dir_all(&target)?;
+        mount::mount(dev, &target)?;
+        match part.filesystem {
+            // FAT can't represent ownership, most modes, symbolic links or xattrs
+            ExtraFilesystem::Vfat => {
+                Task::new(format!("Copying {source}"), "cp")
+                    .args(["-r", "--", format!("{source}/.").as_str(), target.as_str()])
+                    .run()?;
+            }
+            ExtraFilesystem::Linux(_) => {
+                println!("Copying {source}");
+                super::copy::copy_tree(source, &target, &mountpoint, &mut |_, _| Ok(()))?;
+                // One pass over the whole tree, rather than a process per file
+                lsm_label(&target, &mountpoint, true)?;
+            }
+        }
+        mount::unmount(&target, false)?;
+    }
+    Ok(CreatedPartition {
+        label: part.label.clone(),
+        uuid,
+        fstype: part.filesystem.to_string(),
+        mountpoint: mountpoint.into_string(),
+    })
+}
+
+/// Default size of the BIOS-BOOT partition in MiB
+const BIOS_BOOT_SIZE_MB: u64 = 1;
+/// There's no reason for a GRUB core image to be larger than this
+const BIOS_BOOT_SIZE_MAX_MB: u64 = 8;
+
+/// What this build of the installer supports, for tooling which wraps it.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Capabilities {
+    /// The architecture this build is for
+    architecture: &'static str,
+    /// The filesystems which can be created for the root and /boot
+    filesystems: Vec<Filesystem>,
+    default_filesystem: Filesystem,
+    default_boot_filesystem: Filesystem,
+    /// The ways the target block device can be set up
+    block_setups: Vec<BlockSetup>,
+    /// The default partition layout, if installing to a block device is supported on
+    /// this architecture
+    partition_layout: Option<Vec<PartitionSpec>>,
+}
+
+/// Find the capabilities of this build.
+pub(crate) fn capabilities() -> Capabilities {
+    let arch = std::env::consts::ARCH;
+    Capabilities {
+        architecture: arch,
+        filesystems: Filesystem::value_variants().to_vec(),
+        default_filesystem: Filesystem::default(),
+        default_boot_filesystem: boot_filesystem(None, arch),
+        block_setups: BlockSetup::value_variants()
+            .iter()
+            .copied()
+            .filter(|b| b.is_supported())
+            .collect(),
+        partition_layout: default_layout(None, BIOS_BOOT_SIZE_MB, None).ok(),
+    }
+}
+
+/// The filesystem type of /boot for the given architecture, unless one was requested.
+fn boot_filesystem(requested: Option<Filesystem>, arch: &str) -> Filesystem {
+    requested.unwrap_or(match arch {
+        "s390x" => Filesystem::Xfs,
+        _ => Filesystem::Ext4,
+    })
+}
+
+/// Parse and validate the requested BIOS-BOOT partition size for the given architecture.
+fn parse_bios_boot_size(size: Option<&str>, arch: &str) -> Result<u64> {
+    let size = if let Some(size) = size {
+        size
+    } else {
+        return Ok(BIOS_BOOT_SIZE_MB);
+    };
+    if arch != "x86_64" {
+        anyhow::bail!("--bios-boot-size is only supported on x86_64, not {arch}");
+    }
+    let v = crate::blockdev::parse_size_mib(size).context("Parsing BIOS-BOOT size")?;
+    if !(1..=BIOS_BOOT_SIZE_MAX_MB).contains(&v) {
+        anyhow::bail!(
+            "Invalid BIOS-BOOT size {size}; must be between 1M and {BIOS_BOOT_SIZE_MAX_MB}M"
+        );
+    }
+    Ok(v)
+}
+
+/// Partition type GUID for the EFI system partition
+pub(crate) const ESP_TYPECODE: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+/// Partition type GUID for generic Linux filesystem data
+pub(crate) const LINUX_TYPECODE: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+/// Partition type GUID for the Linux extended boot partition (XBOOTLDR)
+pub(crate) const XBOOTLDR_TYPECODE: &str = "BC13C2FF-59E6-4262-A352-B275FD6F7172";
+/// Mountpoint of the EFI system partition
+const ESP_MOUNTPOINT: &str = "/boot/efi";
+
+/// Describes a partition to create on the target block device.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct PartitionSpec {
+    /// The partition number
+    pub(crate) number: u32,
+    /// The GPT partition name
+    pub(crate) name: String,
+    /// The GPT partition type GUID
+    #[serde(default)]
+    pub(crate) typecode: Option<String>,
+    /// Size of the partition; if not set, all remaining space is used
+    #[serde(default)]
+    pub(crate) size: Option<String>,
+    /// Filesystem to create; only valid for `/` and `/boot`.  The ESP is always FAT.
+    #[serde(default)]
+    pub(crate) filesystem: Option<Filesystem>,
+    /// Where the partition is mounted in the target: one of `/`, `/boot` or `/boot/efi`
+    #[serde(default)]
+    pub(crate) mountpoint: Option<String>,
+}
+
+impl PartitionSpec {
+    fn new(number: u32, name: &str, typecode: Option<&str>, size: Option<String>) -> Self {
+        Self {
+            number,
+            name: name.to_string(),
+            typecode: typecode.map(ToOwned::to_owned),
+            size,
+            filesystem: None,
+            mountpoint: None,
+        }
+    }
+
+    fn mounted_at(mut self, mountpoint: &str) -> Self {
+        self.mountpoint = Some(mountpoint.to_string());
+        self
+    }
+
+    /// Add the arguments to create this partition to an `sgdisk` invocation.
+    fn add_to_sgdisk(&self, sgdisk: &mut Command) -> Result<()> {
+        let size = self
+            .size
+            .as_deref()
+            .map(crate::blockdev::parse_size_mib)
+            .transpose()?
+            .map(|v| Cow::Owned(format!("0:+{v}M")))
+            .unwrap_or(Cow::Borrowed("0:0"));
+        sgdisk_partition(
+            sgdisk,
+            self.number,
+            size,
+            &self.name,
+            self.typecode.as_deref(),
+        );
+        Ok(())
+    }
+}
+
+/// The default partition layout for the current architecture, with the provided
+/// partition type for /boot, if any.
+fn default_layout(
+    root_size: Option<String>,
+    bios_boot_size: u64,
+    boot_typecode: Option<&str>,
+) -> Result<Vec<PartitionSpec>> {
+    let mut r = Vec::new();
+    if cfg!(target_arch = "x86_64") {
+        r.push(PartitionSpec::new(
+            1,
+            "BIOS-BOOT",
+            Some("21686148-6449-6E6F-744E-656564454649"),
+            Some(format!("{bios_boot_size}M")),
+        ));
+    } else if cfg!(target_arch = "aarch64") {
+        r.push(PartitionSpec::new(
+            1,
+            "reserved",
+            Some("8DA63339-0007-60C0-C436-083AC8230908"),
+            Some("1M".into()),
+        ));
+    } else {
+        anyhow::bail!("Unsupported architecture: {}", std::env::consts::ARCH);
+    }
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    r.push(
+        PartitionSpec::new(
+            EFIPN,
+            "EFI-SYSTEM",
+            Some(ESP_TYPECODE),
+            Some(format!("{EFIPN_SIZE_MB}M")),
+        )
+        .mounted_at(ESP_MOUNTPOINT),
+    );
+    r.push(
+        PartitionSpec::new(
+            BOOTPN,
+            "boot",
+            boot_typecode,
+            Some(format!("{BOOTPN_SIZE_MB}M")),
+        )
+        .mounted_at("/boot"),
+    );
+    r.push(PartitionSpec::new(ROOTPN, "root", Some(LINUX_TYPECODE), root_size).mounted_at("/"));
+    Ok(r)
+}
+
+/// Verify that a partition layout is usable for installation.
+fn validate_layout(layout: &[PartitionSpec]) -> Result<()> {
+    let mut numbers = std::collections::HashSet::new();
+    for part in layout {
+        if part.number == 0 || !numbers.insert(part.number) {
+            anyhow::bail!("Invalid or duplicate partition number {}", part.number);
+        }
+        if let Some(size) = part.size.as_deref() {
+            crate::blockdev::parse_size_mib(size)
+                .with_context(|| format!("Parsing size of partition {}", part.number))?;
+        }
+        match (part.mountpoint.as_deref(), part.filesystem) {
+            (Some("/" | "/boot") | None, _) => {}
+            (Some(ESP_MOUNTPOINT), None) => {}
+            (Some(ESP_MOUNTPOINT), Some(_)) => {
+                anyhow::bail!("The filesystem of the EFI system partition cannot be changed")
+            }
+            (Some(o), _) => anyhow::bail!("Unsupported mountpoint {o} in layout"),
+        }
+        if part.mountpoint.is_none() && part.filesystem.is_some() {
+            anyhow::bail!(
+                "Partition {} has a filesystem but no mountpoint",
+                part.number
+            );
+        }
+    }
+    let mut required = vec!["/", "/boot"];
+    if cfg!(any(target_arch = "x86_64", target_arch = "aarch64")) {
+        required.push(ESP_MOUNTPOINT);
+    }
+    for mountpoint in required {
+        let n = layout
+            .iter()
+            .filter(|p| p.mountpoint.as_deref() == Some(mountpoint))
+            .count();
+        if n != 1 {
+            anyhow::bail!("Layout must contain exactly one partition for {mountpoint}, found {n}");
+        }
+    }
+    Ok(())
+}
+
+/// Load a partition layout from a JSON file.
+#[context("Loading layout from {path}")]
+fn load_layout(path: &Utf8Path) -> Result<Vec<PartitionSpec>> {
+    let f = std::fs::File::open(path).map(std::io::BufReader::new)?;
+    let layout: Vec<PartitionSpec> = serde_json::from_reader(f)?;
+    validate_layout(&layout)?;
+    Ok(layout)
+}
+
+/// Find the partition with the given mountpoint.
+fn find_mountpoint<'a>(layout: &'a [PartitionSpec], mountpoint: &str) -> Option<&'a PartitionSpec> {
+    layout
+        .iter()
+        .find(|p| p.mountpoint.as_deref() == Some(mountpoint))
 }
 
 fn sgdisk_partition(
@@ -125,6 +827,92 @@ fn sgdisk_partition(
     }
 }
 
+/// The `mkfs` arguments for the requested inode ratio and size of a filesystem.
+fn inode_mkfs_args(fs: Filesystem, ratio: Option<u64>, size: Option<u64>) -> Result<Vec<String>> {
+    let mut r = Vec::new();
+    if let Some(size) = size {
+        let range = match fs {
+            Filesystem::Ext4 => 128..=4096,
+            Filesystem::Xfs => 256..=2048,
+            Filesystem::Btrfs => anyhow::bail!("--root-inode-size is not supported with btrfs"),
+        };
+        if !size.is_power_of_two() || !range.contains(&size) {
+            anyhow::bail!(
+                "Invalid --root-inode-size {size} for {fs}: must be a power of two between {} and {}",
+                range.start(),
+                range.end()
+            );
+        }
+        match fs {
+            Filesystem::Ext4 => r.extend(["-I".to_string(), size.to_string()]),
+            Filesystem::Xfs => r.extend(["-i".to_string(), format!("size={size}")]),
+            Filesystem::Btrfs => unreachable!(),
+        }
+    }
+    if let Some(ratio) = ratio {
+        match fs {
+            Filesystem::Ext4 => {}
+            // Both allocate inodes dynamically
+            Filesystem::Xfs | Filesystem::Btrfs => {
+                anyhow::bail!("--root-inode-ratio is not supported with {fs}")
+            }
+        }
+        // The limits of mke2fs
+        let range = 1024..=(64 << 20);
+        if !range.contains(&ratio) {
+            anyhow::bail!(
+                "Invalid --root-inode-ratio {ratio}: must be between {} and {}",
+                range.start(),
+                range.end()
+            );
+        }
+        r.extend(["-i".to_string(), ratio.to_string()]);
+    }
+    Ok(r)
+}
+
+/// The block size requested by the provided `mke2fs` arguments, if any.
+fn ext4_block_size(mkfs_opts: &[String]) -> Result<Option<u64>> {
+    let mut opts = mkfs_opts.iter();
+    while let Some(opt) = opts.next() {
+        let value = match opt.strip_prefix("-b") {
+            Some("") => opts.next().map(String::as_str),
+            Some(v) => Some(v),
+            None => continue,
+        };
+        let value = value.ok_or_else(|| anyhow::anyhow!("Missing value for -b"))?;
+        let size = value
+            .parse::<u64>()
+            .with_context(|| format!("Parsing block size {value}"))?;
+        return Ok(Some(size));
+    }
+    Ok(None)
+}
+
+/// The `mkfs` arguments for an ext4 filesystem of `fs_bytes` which can be grown online
+/// up to `limit_mib`, created with the provided additional `mkfs_opts`.
+fn resize_mkfs_args(
+    fs: Filesystem,
+    limit_mib: u64,
+    fs_bytes: u64,
+    mkfs_opts: &[String],
+) -> Result<Vec<String>> {
+    if fs != Filesystem::Ext4 {
+        anyhow::bail!("--ext4-resize-limit is not supported with {fs}");
+    }
+    if limit_mib << 20 <= fs_bytes {
+        anyhow::bail!(
+            "--ext4-resize-limit {limit_mib}M must be larger than the root filesystem ({}M)",
+            fs_bytes >> 20
+        );
+    }
+    // The limit is in filesystem blocks; mke2fs uses 4k blocks for all but tiny
+    // filesystems
+    let block_size = ext4_block_size(mkfs_opts)?.unwrap_or(4096);
+    let blocks = (limit_mib << 20) / block_size;
+    Ok(vec!["-E".to_string(), format!("resize={blocks}")])
+}
+
 fn mkfs<'a>(
     dev: &str,
     fs: Filesystem,
@@ -155,33 +943,244 @@ fn mkfs<'a>(
     Ok(u)
 }
 
+/// Unmount everything mounted underneath the provided directory.
+fn unmount_all_under(
+    mntdir: &Utf8Path,
+    mountinfo: &str,
+    mut unmount: impl FnMut(&Utf8Path) -> Result<()>,
+) -> Result<()> {
+    for mountpoint in mount::mounts_under(mountinfo, mntdir) {
+        println!("Unmounting {mountpoint}");
+        unmount(&mountpoint)?;
+    }
+    Ok(())
+}
+
+/// Remove a mount directory left over from a previous (likely failed) run, first
+/// unmounting anything still mounted underneath it.
+#[context("Cleaning up {mntdir}")]
+pub(crate) fn clean_mntdir(
+    mntdir: &Utf8Path,
+    mountinfo: &str,
+    unmount: impl FnMut(&Utf8Path) -> Result<()>,
+) -> Result<()> {
+    unmount_all_under(mntdir, mountinfo, unmount)?;
+    std::fs::remove_dir_all(mntdir)?;
+    Ok(())
+}
+
+/// Detach everything we may have mounted; used when the installation is interrupted.
+#[context("Unmounting filesystems")]
+pub(crate) fn cleanup_mounts() -> Result<()> {
+    let mntdir = super::lock::mounts_dir();
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+    unmount_all_under(&mntdir, &mountinfo, |p| mount::unmount(p, true))
+}
+
+/// Resolve a device path, which may be a symlink such as `/dev/disk/by-id/...`, to the
+/// kernel name of the device relative to `devdir`.
+fn device_name_in(devdir: &Utf8Path, device: &Utf8Path) -> Result<Utf8PathBuf> {
+    let canonical = device
+        .canonicalize_utf8()
+        .with_context(|| format!("Resolving {device}"))?;
+    let name = canonical
+        .strip_prefix(devdir)
+        .with_context(|| format!("Device {device} resolved to {canonical}, not in {devdir}"))?;
+    Ok(name.to_owned())
+}
+
+/// The path to the partition with the provided number; as with the kernel naming, there
+/// is a `p` separator if the device name ends in a digit, e.g. `nvme0n1p1`.
+fn partition_path(device: &Utf8Path, number: u32) -> String {
+    if device.as_str().ends_with(|c: char| c.is_ascii_digit()) {
+        format!("{device}p{number}")
+    } else {
+        format!("{device}{number}")
+    }
+}
+
+/// The filesystem type reported by lsblk for a LUKS container
+const LUKS_FSTYPE: &str = "crypto_LUKS";
+
+/// A step in wiping a device before partitioning it.
+#[derive(Debug, PartialEq, Eq)]
+enum WipeStep {
+    /// Erase the LUKS keyslots, making the encrypted data unrecoverable
+    LuksErase(String),
+    /// Remove all filesystem, RAID and partition table signatures
+    Wipefs(String),
+}
+
+fn is_luks(device: &crate::blockdev::Device) -> bool {
+    device.fstype.as_deref() == Some(LUKS_FSTYPE)
+}
+
+/// Find any LUKS containers on the device or its partitions.
+fn find_luks(device: &crate::blockdev::Device) -> Vec<String> {
+    std::iter::once(device)
+        .chain(device.children.iter().flatten())
+        .filter(|d| is_luks(d))
+        .map(|d| d.path())
+        .collect()
+}
+
+/// Plan wiping the device: its partitions first, then the device itself.  The keyslots
+/// of LUKS containers are erased before their header signature is removed, as wipefs
+/// alone would leave them in place.
+fn plan_wipe(device: &crate::blockdev::Device) -> Result<Vec<WipeStep>> {
+    let mut r = Vec::new();
+    for dev in device
+        .children
+        .iter()
+        .flatten()
+        .chain(std::iter::once(device))
+    {
+        let path = dev.path();
+        if is_luks(dev) {
+            if dev.has_children() {
+                anyhow::bail!(
+                    "{path} is an open LUKS container; close it first with `cryptsetup close`"
+                );
+            }
+            r.push(WipeStep::LuksErase(path.clone()));
+        }
+        r.push(WipeStep::Wipefs(path));
+    }
+    Ok(r)
+}
+
+/// Check that `sync` is suitable for the second ESP of `--sync-esp`: a whole disk
+/// other than the target device, without data unless it is to be wiped.
+fn validate_sync_esp_device(
+    target: &crate::blockdev::Device,
+    sync: &crate::blockdev::Device,
+    wipe: bool,
+) -> Result<()> {
+    let path = sync.path();
+    if std::iter::once(target)
+        .chain(target.children.iter().flatten())
+        .any(|d| d.name == sync.name)
+    {
+        anyhow::bail!("{path} is on the target device; --sync-esp requires another disk");
+    }
+    if sync.parttype.is_some() {
+        anyhow::bail!("{path} is a partition; --sync-esp requires a whole disk");
+    }
+    if wipe {
+        return Ok(());
+    }
+    if let Some(luks) = find_luks(sync).first() {
+        anyhow::bail!(
+            "Detected encrypted data (LUKS) on {luks}; use --wipe if you intend to overwrite"
+        );
+    }
+    if sync.has_children() || sync.fstype.is_some() {
+        anyhow::bail!("Detected existing data on {path}; use --wipe if you intend to overwrite");
+    }
+    Ok(())
+}
+
+/// Wipe the device, including its partitions.
+fn wipe_device(device: &crate::blockdev::Device) -> Result<()> {
+    for step in plan_wipe(device)? {
+        match step {
+            WipeStep::LuksErase(dev) => {
+                println!("Erasing existing LUKS container on {dev}");
+                Task::new("Erasing LUKS keyslots", "cryptsetup")
+                    .args(["erase", "--batch-mode", dev.as_str()])
+                    .run()?;
+            }
+            WipeStep::Wipefs(dev) => crate::blockdev::wipefs(Utf8Path::new(&dev))?,
+        }
+    }
+    Ok(())
+}
+
+/// Create the FAT filesystem of an ESP, returning its `UUID=` source.
+fn mkfs_esp(dev: &str) -> Result<String> {
+    // FAT uses a 32 bit volume ID instead of a UUID
+    let volid = uuid::Uuid::new_v4().as_fields().0;
+    Task::new("Creating ESP filesystem", "mkfs.fat")
+        .args([dev, "-n", "EFI-SYSTEM", "-i"])
+        .args([format!("{volid:08X}")])
+        .quiet_output()
+        .run()?;
+    Ok(format!("UUID={:04X}-{:04X}", volid >> 16, volid & 0xFFFF))
+}
+
+/// Create an ESP spanning the start of `device`, which must be empty, returning its
+/// `UUID=` source.
+#[context("Creating ESP on {device}")]
+fn create_sync_esp(device: &Utf8Path, settle_timeout: Duration) -> Result<String> {
+    let mut sgdisk = Task::new("Initializing partitions", "sgdisk");
+    sgdisk.cmd.stdout(Stdio::null());
+    sgdisk.cmd.arg("-Z");
+    sgdisk.cmd.arg(device);
+    sgdisk.cmd.args(["-U", "R"]);
+    sgdisk_partition(
+        &mut sgdisk.cmd,
+        1,
+        format!("0:+{EFIPN_SIZE_MB}M"),
+        "EFI-SYSTEM",
+        Some(ESP_TYPECODE),
+    );
+    sgdisk.run()?;
+    {
+        let mut f = std::fs::OpenOptions::new()
+            .write(true)
+            .open(device)
+            .with_context(|| format!("opening {device}"))?;
+        crate::blockdev::reread_partition_table(&mut f, true)
+            .context("Rereading partition table")?;
+    }
+    let espdev = partition_path(device, 1);
+    crate::blockdev::udev_settle_and_verify(&[Utf8PathBuf::from(&espdev)], settle_timeout)?;
+    let src = mkfs_esp(&espdev)?;
+    // SAFETY: mkfs_esp() returns a UUID= source
+    let uuid = src.strip_prefix("UUID=").unwrap();
+    crate::blockdev::udev_settle_for_uuids(&[uuid], settle_timeout)?;
+    Ok(src)
+}
+
 #[context("Creating rootfs")]
-pub(crate) fn install_create_rootfs(opts: InstallBlockDeviceOpts) -> Result<RootSetup> {
+pub(crate) fn install_create_rootfs(
+    opts: InstallBlockDeviceOpts,
+    boot_karg_by: super::BootKargBy,
+    esp_mountpoint: super::EspMountpoint,
+    diagnostics: &super::diagnostics::Diagnostics,
+) -> Result<RootSetup> {
     // Verify that the target is empty (if not already wiped in particular, but it's
     // also good to verify that the wipe worked)
     let device = crate::blockdev::list_dev(&opts.device)?;
+    let sync_device = if let Some(dev) = opts.sync_esp.as_deref() {
+        let sync = crate::blockdev::list_dev(dev)?;
+        validate_sync_esp_device(&device, &sync, opts.wipe)?;
+        Some(sync)
+    } else {
+        None
+    };
 
     // Handle wiping any existing data
     if opts.wipe {
-        let dev = &opts.device;
-        for child in device.children.iter().flatten() {
-            let child = child.path();
-            println!("Wiping {child}");
-            crate::blockdev::wipefs(Utf8Path::new(&child))?;
-        }
-        println!("Wiping {dev}");
-        crate::blockdev::wipefs(dev)?;
-    } else if device.has_children() {
+        wipe_device(&device)?;
+        if let Some(sync) = sync_device.as_ref() {
+            wipe_device(sync)?;
+        }
+    } else if let Some(luks) = find_luks(&device).first() {
+        anyhow::bail!(
+            "Detected encrypted data (LUKS) on {luks}; use --wipe if you intend to overwrite"
+        );
+    } else if device.has_children() && !opts.use_free_space {
         anyhow::bail!(
             "Detected existing partitions on {}; use e.g. `wipefs` if you intend to overwrite",
             opts.device
         );
     }
 
-    let run_bootc = Utf8Path::new(RUN_BOOTC);
-    let mntdir = run_bootc.join("mounts");
+    let mntdir = super::lock::mounts_dir();
     if mntdir.exists() {
-        std::fs::remove_dir_all(&mntdir)?;
+        let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+        clean_mntdir(&mntdir, &mountinfo, |p| mount::unmount(p, false))?;
     }
     let devdir = mntdir.join("dev");
     std::fs::create_dir_all(&devdir)?;
@@ -193,18 +1192,109 @@ pub(crate) fn install_create_rootfs(opts: InstallBlockDeviceOpts) -> Result<Root
 
     // Now at this point, our /dev is a stale snapshot because we don't have udev running.
     // So from hereon after, we prefix devices with our temporary devtmpfs mount.
-    let reldevice = opts
-        .device
-        .strip_prefix("/dev/")
-        .context("Absolute device path in /dev/ required")?;
+    let reldevice = device_name_in(Utf8Path::new("/dev"), &opts.device)?;
     let device = devdir.join(reldevice);
 
+    let root_lv = opts.root_lv()?;
     let root_size = opts
         .root_size
         .as_deref()
         .map(crate::blockdev::parse_size_mib)
         .transpose()
         .context("Parsing root size")?;
+    // With LVM, the size is that of the logical volume
+    let (root_size, lv_size) = if root_lv.is_some() {
+        (None, root_size)
+    } else {
+        (root_size, None)
+    };
+    let free_space = if opts.use_free_space {
+        let table = crate::blockdev::partition_table(&device)?;
+        let f = std::fs::File::open(&device).with_context(|| format!("opening {device}"))?;
+        let sector_size = crate::blockdev::sector_size(&f)?;
+        let plan = super::freespace::plan(&table, sector_size, BOOTPN_SIZE_MB.into(), root_size)?;
+        println!("Reusing EFI system partition {}", plan.esp);
+        Some(plan)
+    } else {
+        None
+    };
+
+    let boot_typecode = opts
+        .boot_partition_type
+        .as_ref()
+        .map(BootPartitionType::typecode);
+    let mut layout = if let Some(plan) = free_space.as_ref() {
+        // The ESP already exists, so isn't part of the layout
+        vec![
+            PartitionSpec::new(plan.boot.number, "boot", boot_typecode, None).mounted_at("/boot"),
+            PartitionSpec::new(plan.root.number, "root", Some(LINUX_TYPECODE), None)
+                .mounted_at("/"),
+        ]
+    } else if let Some(path) = opts.layout.as_deref() {
+        load_layout(path)?
+    } else {
+        let bios_boot_size =
+            parse_bios_boot_size(opts.bios_boot_size.as_deref(), std::env::consts::ARCH)?;
+        let layout = default_layout(
+            root_size.map(|v| format!("{v}M")),
+            bios_boot_size,
+            boot_typecode,
+        )?;
+        validate_layout(&layout)?;
+        layout
+    };
+    // A provided layout is used as is
+    if root_lv.is_some() && opts.layout.is_none() {
+        for part in layout.iter_mut() {
+            if part.mountpoint.as_deref() == Some("/") {
+                part.typecode = Some(lvm::LVM_TYPECODE.to_owned());
+            }
+        }
+    }
+    for part in opts.extra_partition.iter() {
+        if let Some(source) = part.source.as_deref() {
+            if !source.is_dir() {
+                anyhow::bail!(
+                    "Source for partition {} is not a directory: {source}",
+                    part.label
+                );
+            }
+        }
+    }
+    // The metadata partition is handled like an extra partition, except that it
+    // isn't mounted in the target system.
+    let metadata_part = opts.metadata_partition.then(|| ExtraPartition {
+        label: METADATA_PARTITION_LABEL.to_string(),
+        size: METADATA_PARTITION_SIZE.to_string(),
+        filesystem: ExtraFilesystem::Vfat,
+        source: None,
+        mountpoint: None,
+    });
+    let mut extra = opts.extra_partition.clone();
+    extra.extend(metadata_part.clone());
+    let extra_numbers = extra_partition_numbers(&layout, &extra)?;
+    // SAFETY: These were checked by validate_layout()
+    let rootpart = find_mountpoint(&layout, "/").unwrap();
+    let bootpart = find_mountpoint(&layout, "/boot").unwrap();
+    let esppart = find_mountpoint(&layout, ESP_MOUNTPOINT);
+    if sync_device.is_some() && esppart.is_none() && free_space.is_none() {
+        anyhow::bail!("--sync-esp requires an EFI system partition");
+    }
+    let rootfs_type = rootpart.filesystem.unwrap_or(opts.filesystem);
+    super::fsfeatures::check_mkfs_opts(rootfs_type, &opts.root_mkfs_opt, diagnostics)?;
+    let root_inode_args =
+        inode_mkfs_args(rootfs_type, opts.root_inode_ratio, opts.root_inode_size)?;
+    let resize_limit = opts
+        .ext4_resize_limit
+        .as_deref()
+        .map(crate::blockdev::parse_size_mib)
+        .transpose()
+        .context("Parsing ext4 resize limit")?;
+    if let Some(limit) = resize_limit {
+        // Check what we can before partitioning; the size is known later
+        let size = root_size.or(lv_size).unwrap_or_default() << 20;
+        resize_mkfs_args(rootfs_type, limit, size, &opts.root_mkfs_opt)?;
+    }
 
     // Create a temporary directory to use for mount points.  Note that we're
     // in a mount namespace, so these should not be visible on the host.
@@ -217,62 +1307,28 @@ pub(crate) fn install_create_rootfs(opts: InstallBlockDeviceOpts) -> Result<Root
     let mut sgdisk = Task::new("Initializing partitions", "sgdisk");
     // sgdisk is too verbose
     sgdisk.cmd.stdout(Stdio::null());
-    sgdisk.cmd.arg("-Z");
-    sgdisk.cmd.arg(&device);
-    sgdisk.cmd.args(["-U", "R"]);
-    #[allow(unused_assignments)]
-    if cfg!(target_arch = "x86_64") {
-        // BIOS-BOOT
-        sgdisk_partition(
-            &mut sgdisk.cmd,
-            1,
-            "0:+1M",
-            "BIOS-BOOT",
-            Some("21686148-6449-6E6F-744E-656564454649"),
-        );
-    } else if cfg!(target_arch = "aarch64") {
-        // reserved
-        sgdisk_partition(
-            &mut sgdisk.cmd,
-            1,
-            "0:+1M",
-            "reserved",
-            Some("8DA63339-0007-60C0-C436-083AC8230908"),
-        );
+    if let Some(plan) = free_space.as_ref() {
+        // Only add our partitions, leaving the existing ones and the disk GUID as is
+        sgdisk.cmd.arg(&device);
+        for (part, planned) in layout.iter().zip([&plan.boot, &plan.root]) {
+            sgdisk_partition(
+                &mut sgdisk.cmd,
+                part.number,
+                planned.extent.to_sgdisk(),
+                &part.name,
+                part.typecode.as_deref(),
+            );
+        }
     } else {
-        anyhow::bail!("Unsupported architecture: {}", std::env::consts::ARCH);
+        sgdisk.cmd.arg("-Z");
+        sgdisk.cmd.arg(&device);
+        sgdisk.cmd.args(["-U", "R"]);
+        extra_partitions_to_sgdisk(&mut sgdisk.cmd, &extra_numbers, &extra)?;
+        for part in layout.iter() {
+            part.add_to_sgdisk(&mut sgdisk.cmd)?;
+        }
     }
-
-    let espdev = if cfg!(any(target_arch = "x86_64", target_arch = "aarch64")) {
-        sgdisk_partition(
-            &mut sgdisk.cmd,
-            EFIPN,
-            format!("0:+{EFIPN_SIZE_MB}M"),
-            "EFI-SYSTEM",
-            Some("C12A7328-F81F-11D2-BA4B-00A0C93EC93B"),
-        );
-        Some(format!("{device}{EFIPN}"))
-    } else {
-        None
-    };
-
-    sgdisk_partition(
-        &mut sgdisk.cmd,
-        BOOTPN,
-        format!("0:+{BOOTPN_SIZE_MB}M"),
-        "boot",
-        None,
-    );
-    let root_size = root_size
-        .map(|v| Cow::Owned(format!("0:{v}M")))
-        .unwrap_or_else(|| Cow::Borrowed("0:0"));
-    sgdisk_partition(
-        &mut sgdisk.cmd,
-        ROOTPN,
-        root_size,
-        "root",
-        Some("0FC63DAF-8483-4772-8E79-3D69D8477DE4"),
-    );
+    let espdev = esppart.map(|p| partition_path(&device, p.number));
     sgdisk.run()?;
 
     // Reread the partition table
@@ -285,29 +1341,80 @@ pub(crate) fn install_create_rootfs(opts: InstallBlockDeviceOpts) -> Result<Root
             .context("Rereading partition table")?;
     }
 
-    crate::blockdev::udev_settle()?;
+    // Wait for udev to finish processing the new partitions before we try to use them
+    let bootdev = &partition_path(&device, bootpart.number);
+    let rootdev = &partition_path(&device, rootpart.number);
+    let mut partitions = vec![Utf8PathBuf::from(bootdev), Utf8PathBuf::from(rootdev)];
+    partitions.extend(espdev.as_deref().map(Utf8PathBuf::from));
+    let extra_devs = extra_numbers
+        .iter()
+        .map(|&n| partition_path(&device, n))
+        .collect::<Vec<_>>();
+    partitions.extend(extra_devs.iter().map(Utf8PathBuf::from));
+    let settle_timeout = opts.udev_settle_timeout();
+    crate::blockdev::udev_settle_and_verify(&partitions, settle_timeout)?;
 
-    match opts.block_setup {
-        BlockSetup::Direct => {}
-        // TODO
-        BlockSetup::Tpm2Luks => anyhow::bail!("tpm2-luks is not implemented yet"),
+    if let Some(dir) = opts.emit_repart_dir.as_deref() {
+        let table = crate::blockdev::partition_table(&device)?;
+        let f = std::fs::File::open(&device).with_context(|| format!("opening {device}"))?;
+        let sector_size = crate::blockdev::sector_size(&f)?;
+        let definitions = super::repart::definitions(&table, sector_size)?;
+        super::repart::write_definitions(dir, &definitions)?;
     }
 
-    // TODO: make this configurable
-    let bootfs_type = Filesystem::Ext4;
+    if !opts.block_setup.is_supported() {
+        anyhow::bail!(
+            "{} is not implemented yet",
+            opts.block_setup.to_possible_value().unwrap().get_name()
+        );
+    }
+
+    // A filesystem in the layout takes precedence, as for the root
+    let bootfs_type = boot_filesystem(
+        bootpart.filesystem.or(opts.boot_filesystem),
+        std::env::consts::ARCH,
+    );
 
     // Initialize the /boot filesystem
-    let bootdev = &format!("{device}{BOOTPN}");
     let boot_uuid = mkfs(bootdev, bootfs_type, Some("boot"), []).context("Initializing /boot")?;
 
+    // The root filesystem is on the root partition, or a logical volume on it
+    let rootdev = &if let Some(RootLv { vg, lv }) = root_lv.as_ref() {
+        let path = lvm::create_lv(rootdev, vg, lv, lv_size)?;
+        crate::blockdev::udev_settle_and_verify(std::slice::from_ref(&path), settle_timeout)?;
+        println!("Created logical volume {vg}/{lv}");
+        path.into_string()
+    } else {
+        rootdev.clone()
+    };
+
     // Initialize rootfs
-    let rootdev = &format!("{device}{ROOTPN}");
-    let root_uuid = mkfs(rootdev, opts.filesystem, Some("root"), [])?;
-    let rootarg = format!("root=UUID={root_uuid}");
+    let resize_args = if let Some(limit) = resize_limit {
+        let f = std::fs::File::open(rootdev).with_context(|| format!("opening {rootdev}"))?;
+        let size = crate::blockdev::device_size(&f)?;
+        resize_mkfs_args(rootfs_type, limit, size, &opts.root_mkfs_opt)?
+    } else {
+        Vec::new()
+    };
+    let root_mkfs_opts = root_inode_args
+        .iter()
+        .chain(resize_args.iter())
+        .chain(opts.root_mkfs_opt.iter())
+        .map(|s| s.as_str());
+    let root_uuid = mkfs(rootdev, rootfs_type, Some("root"), root_mkfs_opts)?;
+    // The target system will find these filesystems by UUID, so ensure udev knows about them.
+    crate::blockdev::udev_settle_for_uuids(
+        &[
+            boot_uuid.to_string().as_str(),
+            root_uuid.to_string().as_str(),
+        ],
+        settle_timeout,
+    )?;
     let bootsrc = format!("UUID={boot_uuid}");
-    let bootarg = format!("boot={bootsrc}");
     let boot = MountSpec::new(bootsrc.as_str(), "/boot");
-    let kargs = vec![rootarg, RW_KARG.to_string(), bootarg];
+    let bootarg = super::boot_karg(boot_karg_by, &boot, Some("boot"))?;
+    let (root, mut kargs) = root_mount(&root_uuid.to_string(), root_lv.as_ref());
+    kargs.extend([RW_KARG.to_string(), bootarg]);
 
     mount::mount(rootdev, &rootfs)?;
     lsm_label(&rootfs, "/".into(), false)?;
@@ -321,21 +1428,636 @@ pub(crate) fn install_create_rootfs(opts: InstallBlockDeviceOpts) -> Result<Root
     lsm_label(&bootfs, "/boot".into(), false)?;
 
     // Create the EFI system partition, if applicable
-    if let Some(espdev) = espdev {
-        Task::new("Creating ESP filesystem", "mkfs.fat")
-            .args([espdev.as_str(), "-n", "EFI-SYSTEM"])
-            .quiet_output()
-            .run()?;
-        let efifs_path = bootfs.join(crate::bootloader::EFI_DIR);
+    let esp = if let Some(espdev) = espdev {
+        let src = mkfs_esp(&espdev)?;
+        let efifs_path = rootfs.join(esp_mountpoint.relpath());
+        std::fs::create_dir(&efifs_path).context("Creating efi dir")?;
+        mount::mount(&espdev, &efifs_path)?;
+        Some(MountSpec::new_esp(&src, esp_mountpoint))
+    } else if let Some(plan) = free_space.as_ref() {
+        let espdev = partition_path(&device, plan.esp_number);
+        let efifs_path = rootfs.join(esp_mountpoint.relpath());
         std::fs::create_dir(&efifs_path).context("Creating efi dir")?;
         mount::mount(&espdev, &efifs_path)?;
+        let uuid = mount::inspect_filesystem(&efifs_path)?
+            .uuid
+            .ok_or_else(|| anyhow::anyhow!("No filesystem UUID found for {espdev}"))?;
+        Some(MountSpec::new_esp(&format!("UUID={uuid}"), esp_mountpoint))
+    } else {
+        None
+    };
+    let sync_esp = if let Some(dev) = opts.sync_esp.as_deref() {
+        let sync_device = devdir.join(device_name_in(Utf8Path::new("/dev"), dev)?);
+        let src = create_sync_esp(&sync_device, settle_timeout)?;
+        println!("Created second EFI system partition on {dev}");
+        Some(src)
+    } else {
+        None
+    };
+
+    let mut extra_devs = extra_devs.into_iter();
+    let extra_partitions = extra_devs
+        .by_ref()
+        .zip(opts.extra_partition.iter())
+        .map(|(dev, part)| create_extra_partition(&dev, part, &mntdir))
+        .collect::<Result<Vec<_>>>()?;
+    let metadata_partition = if let Some(part) = metadata_part {
+        // SAFETY: We allocated a partition number for this above
+        let dev = extra_devs.next().unwrap();
+        mkfs_extra_partition(&dev, &part).context("Creating metadata partition")?;
+        Some(dev)
+    } else {
+        None
+    };
+    if !extra_partitions.is_empty() {
+        let uuids = extra_partitions
+            .iter()
+            .map(|p| p.uuid.as_str())
+            .collect::<Vec<_>>();
+        crate::blockdev::udev_settle_for_uuids(&uuids, settle_timeout)?;
     }
 
     Ok(RootSetup {
-        device,
+        device: Some(device),
         rootfs,
         rootfs_fd,
-        boot,
+        root,
+        root_fstype: Some(rootfs_type.to_string()),
+        boot: Some(boot),
+        esp,
+        extra_partitions,
+        metadata_partition,
+        sync_esp,
+        ssh_host_keys: Vec::new(),
+        machine_id: None,
+        adopt_stateroot: None,
         kargs,
     })
 }
+
+#[test]
+fn test_layout() {
+    let default = default_layout(None, BIOS_BOOT_SIZE_MB, None).unwrap();
+    validate_layout(&default).unwrap();
+    assert_eq!(find_mountpoint(&default, "/").unwrap().number, ROOTPN);
+    assert_eq!(find_mountpoint(&default, "/boot").unwrap().number, BOOTPN);
+
+    let mut parts = serde_json::json!([
+        { "number": 1, "name": "BIOS-BOOT", "typecode": "21686148-6449-6E6F-744E-656564454649", "size": "1M" },
+        { "number": 2, "name": "EFI-SYSTEM", "typecode": ESP_TYPECODE, "size": "1G", "mountpoint": "/boot/efi" },
+        { "number": 5, "name": "boot", "size": "1G", "filesystem": "xfs", "mountpoint": "/boot" },
+        { "number": 6, "name": "root", "size": "20G", "filesystem": "btrfs", "mountpoint": "/" },
+        { "number": 7, "name": "data" },
+    ]);
+    let layout: Vec<PartitionSpec> = serde_json::from_value(parts.clone()).unwrap();
+    validate_layout(&layout).unwrap();
+    let root = find_mountpoint(&layout, "/").unwrap();
+    assert_eq!(root.number, 6);
+    assert_eq!(root.filesystem, Some(Filesystem::Btrfs));
+    assert_eq!(layout[4].size, None);
+
+    // Duplicate partition numbers
+    parts[4]["number"] = 6.into();
+    let layout: Vec<PartitionSpec> = serde_json::from_value(parts.clone()).unwrap();
+    assert!(validate_layout(&layout).is_err());
+    // Missing root
+    parts.as_array_mut().unwrap().truncate(3);
+    let layout: Vec<PartitionSpec> = serde_json::from_value(parts.clone()).unwrap();
+    assert!(validate_layout(&layout).is_err());
+    // The ESP can't be reformatted
+    let mut layout = default.clone();
+    if let Some(esp) = layout
+        .iter_mut()
+        .find(|p| p.mountpoint.as_deref() == Some(ESP_MOUNTPOINT))
+    {
+        esp.filesystem = Some(Filesystem::Ext4);
+        assert!(validate_layout(&layout).is_err());
+    }
+}
+
+#[test]
+fn test_extra_partition() {
+    let p = ExtraPartition::from_str("oem:128M:vfat").unwrap();
+    assert_eq!(p.filesystem, ExtraFilesystem::Vfat);
+    assert_eq!(p.source, None);
+    assert_eq!(p.mountpoint(), "/run/media/oem");
+    let p = ExtraPartition::from_str("config:1G:ext4:/srv/config:/var/config").unwrap();
+    assert_eq!(p.filesystem, ExtraFilesystem::Linux(Filesystem::Ext4));
+    assert_eq!(p.source.as_deref().unwrap(), "/srv/config");
+    assert_eq!(p.mountpoint(), "/var/config");
+    let p = ExtraPartition::from_str("OEM:64:xfs::/oem").unwrap();
+    assert_eq!(p.source, None);
+    assert_eq!(p.mountpoint(), "/oem");
+    for v in [
+        "oem:128M:vfat",
+        "oem:128M:vfat:/srv/oem",
+        "oem:128M:btrfs::/oem",
+        "oem:128M:ext4:/srv/oem:/oem",
+    ] {
+        assert_eq!(ExtraPartition::from_str(v).unwrap().to_string(), v);
+    }
+    for invalid in [
+        "oem",
+        "oem:128M",
+        ":128M:vfat",
+        "oem:128Q:vfat",
+        "oem:128M:ntfs",
+        "oem/x:128M:vfat",
+        "averylonglabel:128M:vfat",
+        "boot:128M:ext4",
+        "oem:128M:vfat::relative",
+        "oem:128M:vfat::/boot",
+        "oem:128M:vfat:/src:/oem:extra",
+    ] {
+        assert!(ExtraPartition::from_str(invalid).is_err(), "{invalid}");
+    }
+
+    // Numbering follows the layout
+    let layout = default_layout(None, BIOS_BOOT_SIZE_MB, None).unwrap();
+    let extra = ["oem:128M:vfat", "data:1G:xfs:/srv/data"].map(|v| v.parse().unwrap());
+    let numbers = extra_partition_numbers(&layout, &extra).unwrap();
+    assert_eq!(numbers, [ROOTPN + 1, ROOTPN + 2]);
+    let mut sgdisk = Command::new("sgdisk");
+    extra_partitions_to_sgdisk(&mut sgdisk, &numbers, &extra).unwrap();
+    let args = sgdisk
+        .get_args()
+        .map(|v| v.to_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        args,
+        [
+            "-n",
+            "6:-1024M:0",
+            "-c",
+            "6:data",
+            "-t",
+            &format!("6:{LINUX_TYPECODE}"),
+            "-n",
+            "5:-128M:0",
+            "-c",
+            "5:oem",
+            "-t",
+            &format!("5:{BASIC_DATA_TYPECODE}"),
+        ]
+    );
+
+    // Labels and mountpoints must be unique
+    let dup = ["oem:128M:vfat", "oem:1G:xfs::/data"].map(|v| v.parse().unwrap());
+    assert!(extra_partition_numbers(&layout, &dup).is_err());
+    let dup = ["oem:128M:vfat::/data", "data:1G:xfs"].map(|v| v.parse().unwrap());
+    assert!(extra_partition_numbers(&layout, &dup).is_ok());
+    let dup = ["oem:128M:vfat::/run/media/data", "data:1G:xfs"].map(|v| v.parse().unwrap());
+    assert!(extra_partition_numbers(&layout, &dup).is_err());
+}
+
+#[test]
+fn test_root_mount() {
+    use clap::Parser;
+    let uuid = "2e9f4241-9f7e-4a3b-a1b2-6a3c1b0c6f0e";
+    let (root, kargs) = root_mount(uuid, None);
+    assert_eq!(root.source, format!("UUID={uuid}"));
+    assert_eq!(kargs, [format!("root=UUID={uuid}")]);
+
+    let parse = |args: &[&str]| {
+        let args = ["install", "/dev/vda"].iter().chain(args);
+        super::InstallOpts::try_parse_from(args).unwrap().block_opts
+    };
+    assert!(parse(&[]).root_lv().unwrap().is_none());
+    let root_lv = parse(&["--lvm"]).root_lv().unwrap().unwrap();
+    let (root, kargs) = root_mount(uuid, Some(&root_lv));
+    assert_eq!(root.source, "/dev/mapper/bootc-root");
+    assert_eq!(root.target, "/");
+    assert_eq!(
+        kargs,
+        ["root=/dev/mapper/bootc-root", "rd.lvm.lv=bootc/root"]
+    );
+    let root_lv = parse(&["--lvm", "--vg-name", "vg-sys", "--lv-name", "os"])
+        .root_lv()
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        root_mount(uuid, Some(&root_lv)).1,
+        ["root=/dev/mapper/vg--sys-os", "rd.lvm.lv=vg-sys/os"]
+    );
+    assert!(parse(&["--lvm", "--vg-name", "-vg"]).root_lv().is_err());
+    assert!(
+        super::InstallOpts::try_parse_from(["install", "--vg-name", "vg", "/dev/vda"]).is_err()
+    );
+}
+
+#[test]
+fn test_boot_partition_type() {
+    let parse = |s: &str| BootPartitionType::from_str(s);
+    assert_eq!(parse("xbootldr").unwrap(), BootPartitionType::Xbootldr);
+    assert_eq!(parse("xbootldr").unwrap().typecode(), XBOOTLDR_TYPECODE);
+    let t = parse("bc13c2ff-59e6-4262-a352-b275fd6f7172").unwrap();
+    assert_eq!(t.typecode(), XBOOTLDR_TYPECODE);
+    assert_eq!(t.to_string(), XBOOTLDR_TYPECODE);
+    for invalid in [
+        "",
+        "XBOOTLDR",
+        "esp",
+        "BC13C2FF-59E6-4262-A352",
+        "not-a-guid",
+    ] {
+        assert!(parse(invalid).is_err(), "{invalid}");
+    }
+
+    let boot_args = |typecode: Option<&str>| {
+        let layout = default_layout(None, BIOS_BOOT_SIZE_MB, typecode).unwrap();
+        let mut cmd = Command::new("sgdisk");
+        find_mountpoint(&layout, "/boot")
+            .unwrap()
+            .add_to_sgdisk(&mut cmd)
+            .unwrap();
+        cmd.get_args()
+            .map(|v| v.to_str().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+    // By default, there's no type
+    assert_eq!(
+        boot_args(None),
+        [
+            "-n",
+            &format!("{BOOTPN}:0:+{BOOTPN_SIZE_MB}M"),
+            "-c",
+            &format!("{BOOTPN}:boot")
+        ]
+    );
+    let typecode = parse("xbootldr").unwrap();
+    assert_eq!(
+        boot_args(Some(typecode.typecode())),
+        [
+            "-n",
+            &format!("{BOOTPN}:0:+{BOOTPN_SIZE_MB}M"),
+            "-c",
+            &format!("{BOOTPN}:boot"),
+            "-t",
+            &format!("{BOOTPN}:{XBOOTLDR_TYPECODE}"),
+        ]
+    );
+}
+
+#[test]
+fn test_bios_boot_size() {
+    assert_eq!(parse_bios_boot_size(None, "x86_64").unwrap(), 1);
+    assert_eq!(parse_bios_boot_size(None, "aarch64").unwrap(), 1);
+    assert_eq!(parse_bios_boot_size(Some("4M"), "x86_64").unwrap(), 4);
+    for invalid in ["0", "9M", "1G", "foo"] {
+        assert!(parse_bios_boot_size(Some(invalid), "x86_64").is_err());
+    }
+    let e = parse_bios_boot_size(Some("4M"), "aarch64").unwrap_err();
+    assert!(e.to_string().contains("only supported on x86_64"));
+
+    if cfg!(target_arch = "x86_64") {
+        let layout = default_layout(None, 4, None).unwrap();
+        let mut cmd = Command::new("sgdisk");
+        layout[0].add_to_sgdisk(&mut cmd).unwrap();
+        let args = cmd
+            .get_args()
+            .map(|v| v.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            args,
+            [
+                "-n",
+                "1:0:+4M",
+                "-c",
+                "1:BIOS-BOOT",
+                "-t",
+                "1:21686148-6449-6E6F-744E-656564454649"
+            ]
+        );
+    }
+}
+
+#[test]
+fn test_capabilities() {
+    let v = serde_json::to_value(capabilities()).unwrap();
+    assert_eq!(v["architecture"], std::env::consts::ARCH);
+    assert_eq!(
+        v["filesystems"],
+        serde_json::json!(["xfs", "ext4", "btrfs"])
+    );
+    assert_eq!(v["default-filesystem"], "xfs");
+    // tpm2-luks is not implemented
+    assert_eq!(v["block-setups"], serde_json::json!(["direct"]));
+    let layout = &v["partition-layout"];
+    if cfg!(any(target_arch = "x86_64", target_arch = "aarch64")) {
+        let numbers = |mountpoint: &str| {
+            layout
+                .as_array()
+                .unwrap()
+                .iter()
+                .filter(|p| p["mountpoint"] == mountpoint)
+                .map(|p| p["number"].as_u64().unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(numbers("/"), [u64::from(ROOTPN)]);
+        assert_eq!(numbers("/boot"), [u64::from(BOOTPN)]);
+        assert_eq!(numbers("/boot/efi"), [u64::from(EFIPN)]);
+    } else {
+        assert!(layout.is_null());
+    }
+}
+
+#[test]
+fn test_boot_filesystem() {
+    use clap::Parser;
+    for arch in ["x86_64", "aarch64", "powerpc64"] {
+        assert_eq!(boot_filesystem(None, arch), Filesystem::Ext4, "{arch}");
+    }
+    assert_eq!(boot_filesystem(None, "s390x"), Filesystem::Xfs);
+    for fs in [Filesystem::Xfs, Filesystem::Ext4, Filesystem::Btrfs] {
+        assert_eq!(boot_filesystem(Some(fs), "x86_64"), fs);
+        assert_eq!(boot_filesystem(Some(fs), "s390x"), fs);
+    }
+
+    let o = crate::install::InstallOpts::try_parse_from(["install", "/dev/vda"]).unwrap();
+    assert_eq!(o.block_opts.boot_filesystem, None);
+    let o = crate::install::InstallOpts::try_parse_from([
+        "install",
+        "--boot-filesystem",
+        "btrfs",
+        "/dev/vda",
+    ])
+    .unwrap();
+    assert_eq!(o.block_opts.boot_filesystem, Some(Filesystem::Btrfs));
+    assert!(crate::install::InstallOpts::try_parse_from([
+        "install",
+        "--boot-filesystem",
+        "vfat",
+        "/dev/vda"
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_clean_mntdir() {
+    let td = tempfile::tempdir().unwrap();
+    let td = Utf8Path::from_path(td.path()).unwrap();
+    let mntdir = td.join("mounts");
+    let bootfs = mntdir.join("rootfs/boot");
+    std::fs::create_dir_all(&bootfs).unwrap();
+    std::fs::create_dir_all(mntdir.join("dev")).unwrap();
+    std::fs::write(bootfs.join("somefile"), "").unwrap();
+    let mountinfo = format!(
+        "22 1 253:0 / / rw shared:1 - xfs /dev/vda4 rw\n\
+         98 22 0:5 / {mntdir}/dev rw shared:2 - devtmpfs devtmpfs rw\n\
+         99 22 253:4 / {mntdir}/rootfs rw shared:3 - xfs /dev/vdb4 rw\n\
+         100 99 253:3 / {mntdir}/rootfs/boot rw shared:4 - ext4 /dev/vdb3 rw\n"
+    );
+    let mut unmounted = Vec::new();
+    clean_mntdir(&mntdir, &mountinfo, |p| {
+        // Everything must be unmounted before anything is removed
+        assert!(p.exists());
+        unmounted.push(p.strip_prefix(&mntdir).unwrap().to_string());
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(unmounted, ["rootfs/boot", "rootfs", "dev"]);
+    assert!(!mntdir.exists());
+
+    // An unmount failure must stop us from removing mounted content
+    std::fs::create_dir_all(&bootfs).unwrap();
+    assert!(clean_mntdir(&mntdir, &mountinfo, |_| anyhow::bail!("busy")).is_err());
+    assert!(bootfs.exists());
+}
+
+#[test]
+fn test_device_name() {
+    let td = tempfile::tempdir().unwrap();
+    let td = Utf8Path::from_path(td.path())
+        .unwrap()
+        .canonicalize_utf8()
+        .unwrap();
+    let devdir = td.join("dev");
+    std::fs::create_dir_all(devdir.join("disk/by-id")).unwrap();
+    std::fs::create_dir_all(devdir.join("disk/by-path")).unwrap();
+    for dev in ["sda", "nvme0n1"] {
+        std::fs::write(devdir.join(dev), "").unwrap();
+    }
+    let byid = devdir.join("disk/by-id/ata-EXAMPLE_SERIAL");
+    std::os::unix::fs::symlink("../../sda", &byid).unwrap();
+    let bypath = devdir.join("disk/by-path/pci-0000:01:00.0-nvme-1");
+    std::os::unix::fs::symlink("../../nvme0n1", &bypath).unwrap();
+
+    assert_eq!(device_name_in(&devdir, &devdir.join("sda")).unwrap(), "sda");
+    assert_eq!(device_name_in(&devdir, &byid).unwrap(), "sda");
+    assert_eq!(device_name_in(&devdir, &bypath).unwrap(), "nvme0n1");
+    // Devices must exist, and resolve into the device directory
+    assert!(device_name_in(&devdir, &devdir.join("sdb")).is_err());
+    assert!(device_name_in(&devdir, &td).is_err());
+
+    let mntdev = Utf8Path::new("/run/bootc/mounts/dev");
+    let device = mntdev.join(device_name_in(&devdir, &byid).unwrap());
+    assert_eq!(partition_path(&device, 3), "/run/bootc/mounts/dev/sda3");
+    let device = mntdev.join(device_name_in(&devdir, &bypath).unwrap());
+    assert_eq!(
+        partition_path(&device, 3),
+        "/run/bootc/mounts/dev/nvme0n1p3"
+    );
+    assert_eq!(
+        partition_path(Utf8Path::new("/dev/mmcblk0"), 1),
+        "/dev/mmcblk0p1"
+    );
+}
+
+#[test]
+fn test_udev_settle_timeout() {
+    use clap::Parser;
+    let parse = |args: &[&str]| {
+        super::InstallOpts::try_parse_from(args)
+            .map(|o| o.block_opts.udev_settle_timeout())
+            .ok()
+    };
+    assert_eq!(
+        parse(&["install", "/dev/vda"]).unwrap(),
+        crate::blockdev::DEFAULT_UDEV_SETTLE_TIMEOUT
+    );
+    assert_eq!(
+        parse(&["install", "--udev-settle-timeout", "300", "/dev/vda"]).unwrap(),
+        Duration::from_secs(300)
+    );
+    assert!(parse(&["install", "--udev-settle-timeout", "-1", "/dev/vda"]).is_none());
+}
+
+#[test]
+fn test_plan_wipe() {
+    let device =
+        |v: serde_json::Value| -> crate::blockdev::Device { serde_json::from_value(v).unwrap() };
+    let part = |name: &str, fstype: &str| serde_json::json!({ "name": name, "fstype": fstype });
+
+    // A whole-disk LUKS container
+    let disk = device(serde_json::json!({ "name": "vda", "fstype": "crypto_LUKS" }));
+    assert_eq!(find_luks(&disk), ["/dev/vda"]);
+    assert_eq!(
+        plan_wipe(&disk).unwrap(),
+        [
+            WipeStep::LuksErase("/dev/vda".into()),
+            WipeStep::Wipefs("/dev/vda".into())
+        ]
+    );
+
+    // A previous installation with an encrypted root
+    let disk = device(serde_json::json!({
+        "name": "vda",
+        "children": [part("vda1", "vfat"), part("vda2", "ext4"), part("vda3", "crypto_LUKS")]
+    }));
+    assert_eq!(find_luks(&disk), ["/dev/vda3"]);
+    assert_eq!(
+        plan_wipe(&disk).unwrap(),
+        [
+            WipeStep::Wipefs("/dev/vda1".into()),
+            WipeStep::Wipefs("/dev/vda2".into()),
+            WipeStep::LuksErase("/dev/vda3".into()),
+            WipeStep::Wipefs("/dev/vda3".into()),
+            WipeStep::Wipefs("/dev/vda".into()),
+        ]
+    );
+
+    // Unencrypted
+    let disk = device(serde_json::json!({
+        "name": "nvme0n1",
+        "children": [part("nvme0n1p1", "xfs")]
+    }));
+    assert!(find_luks(&disk).is_empty());
+    assert_eq!(
+        plan_wipe(&disk).unwrap(),
+        [
+            WipeStep::Wipefs("/dev/nvme0n1p1".into()),
+            WipeStep::Wipefs("/dev/nvme0n1".into()),
+        ]
+    );
+
+    // An open LUKS container can't be wiped
+    let disk = device(serde_json::json!({
+        "name": "vda",
+        "children": [{
+            "name": "vda3",
+            "fstype": "crypto_LUKS",
+            "children": [part("luks-0123", "xfs")]
+        }]
+    }));
+    let e = plan_wipe(&disk).unwrap_err();
+    assert!(e.to_string().contains("cryptsetup close"));
+}
+
+#[test]
+fn test_validate_sync_esp_device() {
+    let device =
+        |v: serde_json::Value| -> crate::blockdev::Device { serde_json::from_value(v).unwrap() };
+    let target = device(serde_json::json!({
+        "name": "vda",
+        "children": [{ "name": "vda1", "parttype": ESP_TYPECODE.to_lowercase() }]
+    }));
+    let empty = device(serde_json::json!({ "name": "vdb" }));
+    validate_sync_esp_device(&target, &empty, false).unwrap();
+
+    for (sync, wipe, msg) in [
+        (
+            serde_json::json!({ "name": "vda" }),
+            true,
+            "is on the target device",
+        ),
+        (
+            serde_json::json!({ "name": "vda1", "parttype": ESP_TYPECODE }),
+            true,
+            "is on the target device",
+        ),
+        (
+            serde_json::json!({ "name": "vdb1", "parttype": ESP_TYPECODE }),
+            true,
+            "is a partition",
+        ),
+        (
+            serde_json::json!({ "name": "vdb", "children": [{ "name": "vdb1" }] }),
+            false,
+            "Detected existing data on /dev/vdb",
+        ),
+        (
+            serde_json::json!({ "name": "vdb", "fstype": "xfs" }),
+            false,
+            "Detected existing data on /dev/vdb",
+        ),
+        (
+            serde_json::json!({ "name": "vdb", "fstype": "crypto_LUKS" }),
+            false,
+            "LUKS",
+        ),
+    ] {
+        let e = validate_sync_esp_device(&target, &device(sync), wipe)
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains(msg), "{e}");
+    }
+    // Existing data is fine if the device is to be wiped
+    let used = device(serde_json::json!({ "name": "vdb", "children": [{ "name": "vdb1" }] }));
+    validate_sync_esp_device(&target, &used, true).unwrap();
+}
+
+#[test]
+fn test_inode_mkfs_args() {
+    let args = |fs, ratio, size| inode_mkfs_args(fs, ratio, size).unwrap();
+    assert!(args(Filesystem::Xfs, None, None).is_empty());
+    assert!(args(Filesystem::Btrfs, None, None).is_empty());
+    assert_eq!(
+        args(Filesystem::Ext4, Some(4096), Some(256)),
+        ["-I", "256", "-i", "4096"]
+    );
+    assert_eq!(args(Filesystem::Ext4, Some(8192), None), ["-i", "8192"]);
+    assert_eq!(args(Filesystem::Xfs, None, Some(512)), ["-i", "size=512"]);
+    // Unsupported by the filesystem
+    for (fs, ratio, size) in [
+        (Filesystem::Xfs, Some(4096), None),
+        (Filesystem::Btrfs, Some(4096), None),
+        (Filesystem::Btrfs, None, Some(256)),
+    ] {
+        assert!(inode_mkfs_args(fs, ratio, size).is_err());
+    }
+    // Out of range
+    for (fs, ratio, size) in [
+        (Filesystem::Ext4, None, Some(300)),
+        (Filesystem::Ext4, None, Some(64)),
+        (Filesystem::Xfs, None, Some(128)),
+        (Filesystem::Ext4, Some(512), None),
+        (Filesystem::Ext4, Some(128 << 20), None),
+    ] {
+        assert!(inode_mkfs_args(fs, ratio, size).is_err());
+    }
+    let e = inode_mkfs_args(Filesystem::Xfs, Some(4096), None).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "--root-inode-ratio is not supported with xfs"
+    );
+}
+
+#[test]
+fn test_resize_mkfs_args() {
+    let opts = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    let gib = 1u64 << 30;
+    // 1T in 4k blocks
+    assert_eq!(
+        resize_mkfs_args(Filesystem::Ext4, 1 << 20, 10 * gib, &[]).unwrap(),
+        ["-E", "resize=268435456"]
+    );
+    for mkfs_opts in [
+        &["-b", "1024"][..],
+        &["-b1024"],
+        &["-O", "^has_journal", "-b", "1024"],
+    ] {
+        assert_eq!(
+            resize_mkfs_args(Filesystem::Ext4, 1024, 0, &opts(mkfs_opts)).unwrap(),
+            ["-E", "resize=1048576"]
+        );
+    }
+    assert!(resize_mkfs_args(Filesystem::Ext4, 1024, 0, &opts(&["-b"])).is_err());
+    assert!(resize_mkfs_args(Filesystem::Ext4, 1024, 0, &opts(&["-b", "4k"])).is_err());
+    // Not larger than the filesystem
+    let e = resize_mkfs_args(Filesystem::Ext4, 10240, 10 * gib, &[]).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "--ext4-resize-limit 10240M must be larger than the root filesystem (10240M)"
+    );
+    assert!(resize_mkfs_args(Filesystem::Xfs, 1 << 20, gib, &[]).is_err());
+}
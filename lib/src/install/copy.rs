@@ -0,0 +1,534 @@
+//! # Copying files into the target
+//!
+//! Trees injected into the target, such as the image's `/usr/etc` or the `SOURCE_DIR`
+//! of an `--extra-partition`, may be large.  Files are reflinked (`FICLONE`) where the
+//! filesystem supports it, and otherwise copied in the kernel via `copy_file_range(2)`,
+//! falling back to reading and writing; holes are preserved in either case.  Ownership,
+//! mode, timestamps, extended attributes and hard links within a tree are preserved,
+//! except for SELinux labels, which are set for the target via a callback instead.
+//! Device nodes and FIFOs are recreated, without their extended attributes.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fs::{File, Metadata};
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use fn_error_context::context;
+use nix::errno::Errno;
+use nix::sys::stat::{Mode, SFlag, UtimensatFlags};
+use nix::sys::time::TimeSpec;
+use nix::unistd::{FchownatFlags, Whence};
+
+/// The SELinux label, which is set for the target rather than copied
+const SELINUX_XATTR: &[u8] = b"security.selinux";
+/// The size of the buffer when copying by reading and writing
+const BUFFER_SIZE: u64 = 1024 * 1024;
+
+/// How the contents of a file were copied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CopyMethod {
+    /// Shared with the source, on the same filesystem
+    Reflink,
+    /// In the kernel, via `copy_file_range(2)`
+    CopyFileRange,
+    /// By reading and writing, as the kernel can't copy between the filesystems
+    Buffered,
+}
+
+/// The ioctl making a file share the extents of another; see ioctl_ficlone(2)
+const FICLONE: libc::Ioctl =
+    nix::request_code_write!(0x94, 9, std::mem::size_of::<i32>()) as libc::Ioctl;
+
+/// The segments of the file containing data, as offset and length, skipping holes.
+fn data_segments(f: &File, len: u64) -> Result<Vec<(u64, u64)>> {
+    let fd = f.as_raw_fd();
+    let mut r = Vec::new();
+    let mut pos = 0;
+    while pos < len {
+        let start = match nix::unistd::lseek(fd, i64::try_from(pos)?, Whence::SeekData) {
+            Ok(o) => u64::try_from(o)?,
+            // Only a hole remains
+            Err(Errno::ENXIO) => break,
+            // The filesystem doesn't support finding holes
+            Err(Errno::EINVAL) => {
+                r.push((pos, len - pos));
+                break;
+            }
+            Err(e) => return Err(e).context("Seeking data"),
+        };
+        let end = nix::unistd::lseek(fd, i64::try_from(start)?, Whence::SeekHole)
+            .context("Seeking hole")?;
+        let end = u64::try_from(end)?.min(len);
+        r.push((start, end - start));
+        pos = end;
+    }
+    Ok(r)
+}
+
+/// Copy the provided segment of `src` to the same offset of `dest`, via
+/// `copy_file_range(2)` unless `method` is already [`CopyMethod::Buffered`]; `method`
+/// is updated if that is not supported.
+fn copy_segment(
+    src: &File,
+    dest: &File,
+    (start, len): (u64, u64),
+    method: &mut CopyMethod,
+) -> Result<()> {
+    let mut off = start;
+    let end = start + len;
+    while off < end {
+        let remaining = end - off;
+        if *method == CopyMethod::CopyFileRange {
+            let mut off_in = i64::try_from(off)?;
+            let mut off_out = off_in;
+            let n = usize::try_from(remaining).unwrap_or(usize::MAX);
+            match nix::fcntl::copy_file_range(
+                src.as_raw_fd(),
+                Some(&mut off_in),
+                dest.as_raw_fd(),
+                Some(&mut off_out),
+                n,
+            ) {
+                // The source was truncated
+                Ok(0) => return Ok(()),
+                Ok(n) => {
+                    off += n as u64;
+                    continue;
+                }
+                Err(Errno::EXDEV | Errno::ENOSYS | Errno::EOPNOTSUPP | Errno::EINVAL) => {
+                    *method = CopyMethod::Buffered;
+                }
+                Err(e) => return Err(e).context("copy_file_range"),
+            }
+        }
+        let mut buf = vec![0u8; remaining.min(BUFFER_SIZE) as usize];
+        let n = src.read_at(&mut buf, off)?;
+        if n == 0 {
+            return Ok(());
+        }
+        dest.write_all_at(&buf[..n], off)?;
+        off += n as u64;
+    }
+    Ok(())
+}
+
+/// Make the empty `dest` share the extents of `src`, returning whether the filesystem
+/// supports it.
+#[allow(unsafe_code)]
+fn reflink(src: &File, dest: &File) -> bool {
+    // SAFETY: Both are valid file descriptors, and the argument is passed by value
+    let r = unsafe { libc::ioctl(dest.as_raw_fd(), FICLONE, src.as_raw_fd()) };
+    r == 0
+}
+
+/// Copy the contents of `src` of the provided length to the empty `dest`.
+fn copy_contents(src: &File, dest: &File, len: u64) -> Result<CopyMethod> {
+    if reflink(src, dest) {
+        return Ok(CopyMethod::Reflink);
+    }
+    let mut method = CopyMethod::CopyFileRange;
+    for segment in data_segments(src, len)? {
+        copy_segment(src, dest, segment, &mut method)?;
+    }
+    // Any trailing hole
+    dest.set_len(len)?;
+    Ok(method)
+}
+
+/// Call the provided function with a buffer of the size it returns when called with
+/// an empty buffer, as for the xattr system calls.
+fn read_sized(mut f: impl FnMut(&mut [u8]) -> isize) -> std::result::Result<Vec<u8>, Errno> {
+    let n = f(&mut []);
+    let n = usize::try_from(n).map_err(|_| Errno::last())?;
+    let mut buf = vec![0u8; n];
+    let n = usize::try_from(f(&mut buf)).map_err(|_| Errno::last())?;
+    buf.truncate(n);
+    Ok(buf)
+}
+
+/// Copy the extended attributes of `src` to `dest`, except for the SELinux label.
+#[allow(unsafe_code)]
+fn copy_xattrs(src: &File, dest: &File) -> Result<()> {
+    let (src_fd, dest_fd) = (src.as_raw_fd(), dest.as_raw_fd());
+    // SAFETY: The buffer is valid for its length
+    let names =
+        read_sized(|buf| unsafe { libc::flistxattr(src_fd, buf.as_mut_ptr().cast(), buf.len()) });
+    let names = match names {
+        Ok(n) => n,
+        Err(Errno::EOPNOTSUPP) => return Ok(()),
+        Err(e) => return Err(e).context("Listing xattrs"),
+    };
+    for name in names.split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        if name == SELINUX_XATTR {
+            continue;
+        }
+        // SAFETY: There are no interior NULs after splitting
+        let name = CString::new(name).unwrap();
+        let desc = || format!("xattr {}", name.to_string_lossy());
+        let value = get_xattr(src_fd, &name).with_context(|| format!("Reading {}", desc()))?;
+        set_xattr(dest_fd, &name, &value).with_context(|| format!("Setting {}", desc()))?;
+    }
+    Ok(())
+}
+
+#[allow(unsafe_code)]
+fn get_xattr(fd: i32, name: &CStr) -> std::result::Result<Vec<u8>, Errno> {
+    // SAFETY: The name is NUL-terminated, and the buffer is valid for its length
+    read_sized(|buf| unsafe {
+        libc::fgetxattr(fd, name.as_ptr(), buf.as_mut_ptr().cast(), buf.len())
+    })
+}
+
+#[allow(unsafe_code)]
+fn set_xattr(fd: i32, name: &CStr, value: &[u8]) -> std::result::Result<(), Errno> {
+    // SAFETY: The name is NUL-terminated, and the value is valid for its length
+    let r = unsafe { libc::fsetxattr(fd, name.as_ptr(), value.as_ptr().cast(), value.len(), 0) };
+    Errno::result(r).map(drop)
+}
+
+/// The access and modification times in the provided metadata.
+fn timestamps(meta: &Metadata) -> (TimeSpec, TimeSpec) {
+    (
+        TimeSpec::new(meta.atime(), meta.atime_nsec()),
+        TimeSpec::new(meta.mtime(), meta.mtime_nsec()),
+    )
+}
+
+/// Copy the ownership, mode, extended attributes and timestamps of `src`, with the
+/// provided metadata, to `dest`.
+fn copy_metadata(src: &File, dest: &File, meta: &Metadata) -> Result<()> {
+    nix::unistd::fchown(
+        dest.as_raw_fd(),
+        Some(meta.uid().into()),
+        Some(meta.gid().into()),
+    )
+    .context("Setting ownership")?;
+    copy_xattrs(src, dest)?;
+    // After changing the ownership, which clears setuid and setgid bits
+    dest.set_permissions(meta.permissions())
+        .context("Setting mode")?;
+    let (atime, mtime) = timestamps(meta);
+    nix::sys::stat::futimens(dest.as_raw_fd(), &atime, &mtime).context("Setting timestamps")?;
+    Ok(())
+}
+
+/// Copy the regular file `src` to `dest`, which must not exist, returning how its
+/// contents were copied.
+#[context("Copying {src} to {dest}")]
+pub(crate) fn copy_file(src: &Utf8Path, dest: &Utf8Path) -> Result<CopyMethod> {
+    let src_f = File::open(src)?;
+    let meta = src_f.metadata()?;
+    let dest_f = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dest)?;
+    let method = copy_contents(&src_f, &dest_f, meta.len())?;
+    copy_metadata(&src_f, &dest_f, &meta)?;
+    Ok(method)
+}
+
+/// Copy the symbolic link `src` to `dest`, which must not exist.
+fn copy_symlink(src: &Utf8Path, dest: &Utf8Path, meta: &Metadata) -> Result<()> {
+    std::os::unix::fs::symlink(src.read_link()?, dest)?;
+    nix::unistd::fchownat(
+        None,
+        dest.as_std_path(),
+        Some(meta.uid().into()),
+        Some(meta.gid().into()),
+        FchownatFlags::NoFollowSymlink,
+    )
+    .context("Setting ownership")?;
+    let (atime, mtime) = timestamps(meta);
+    nix::sys::stat::utimensat(
+        None,
+        dest.as_std_path(),
+        &atime,
+        &mtime,
+        UtimensatFlags::NoFollowSymlink,
+    )
+    .context("Setting timestamps")?;
+    Ok(())
+}
+
+/// Recreate the device node or FIFO `src` at `dest`, which must not exist.
+fn copy_special(src: &Utf8Path, dest: &Utf8Path, meta: &Metadata) -> Result<()> {
+    let ft = meta.file_type();
+    let kind = if ft.is_char_device() {
+        SFlag::S_IFCHR
+    } else if ft.is_block_device() {
+        SFlag::S_IFBLK
+    } else if ft.is_fifo() {
+        SFlag::S_IFIFO
+    } else {
+        anyhow::bail!("Unsupported file type of {src}");
+    };
+    let mode = Mode::from_bits_truncate(meta.mode() & 0o7777);
+    nix::sys::stat::mknod(dest.as_std_path(), kind, mode, meta.rdev())
+        .with_context(|| format!("Creating {dest}"))?;
+    nix::unistd::fchownat(
+        None,
+        dest.as_std_path(),
+        Some(meta.uid().into()),
+        Some(meta.gid().into()),
+        FchownatFlags::NoFollowSymlink,
+    )
+    .context("Setting ownership")?;
+    // Not subject to the umask, and after changing the ownership
+    std::fs::set_permissions(dest, meta.permissions()).context("Setting mode")?;
+    let (atime, mtime) = timestamps(meta);
+    nix::sys::stat::utimensat(
+        None,
+        dest.as_std_path(),
+        &atime,
+        &mtime,
+        UtimensatFlags::NoFollowSymlink,
+    )
+    .context("Setting timestamps")?;
+    Ok(())
+}
+
+/// State of a tree copy.
+struct TreeCopy<'l> {
+    /// The first copy of each file with multiple links, by device and inode
+    links: HashMap<(u64, u64), Utf8PathBuf>,
+    label: &'l mut dyn FnMut(&Utf8Path, &Utf8Path) -> Result<()>,
+    copied: u64,
+}
+
+impl TreeCopy<'_> {
+    /// Copy the contents of the directory `src` into the directory `dest`.
+    fn copy_dir(&mut self, src: &Utf8Path, dest: &Utf8Path, as_path: &Utf8Path) -> Result<()> {
+        for e in src.read_dir_utf8()? {
+            let e = e?;
+            let name = e.file_name();
+            self.copy_entry(e.path(), &dest.join(name), &as_path.join(name))?;
+        }
+        Ok(())
+    }
+
+    /// Copy `src` to `dest` unless that already exists; directories are merged.
+    fn copy_entry(&mut self, src: &Utf8Path, dest: &Utf8Path, as_path: &Utf8Path) -> Result<()> {
+        let meta = src
+            .symlink_metadata()
+            .with_context(|| format!("Querying {src}"))?;
+        let existing = dest.symlink_metadata().ok();
+        if meta.is_dir() {
+            match existing {
+                Some(d) if d.is_dir() => return self.copy_dir(src, dest, as_path),
+                Some(_) => return Ok(()),
+                None => {}
+            }
+            std::fs::create_dir(dest).with_context(|| format!("Creating {dest}"))?;
+            (self.label)(dest, as_path)?;
+            self.copied += 1;
+            self.copy_dir(src, dest, as_path)?;
+            // After the contents, which change the modification time
+            copy_metadata(&File::open(src)?, &File::open(dest)?, &meta)
+                .with_context(|| format!("Copying {src} to {dest}"))?;
+            return Ok(());
+        }
+        if existing.is_some() {
+            return Ok(());
+        }
+        if meta.file_type().is_symlink() {
+            copy_symlink(src, dest, &meta).with_context(|| format!("Copying {src} to {dest}"))?;
+        } else {
+            let key = (meta.dev(), meta.ino());
+            if let Some(first) = self.links.get(&key) {
+                std::fs::hard_link(first, dest)
+                    .with_context(|| format!("Linking {dest} to {first}"))?;
+                self.copied += 1;
+                return Ok(());
+            }
+            if meta.is_file() {
+                let method = copy_file(src, dest)?;
+                tracing::trace!("Copied {src} via {method:?}");
+            } else {
+                copy_special(src, dest, &meta)
+                    .with_context(|| format!("Copying {src} to {dest}"))?;
+            }
+            if meta.nlink() > 1 {
+                self.links.insert(key, dest.to_owned());
+            }
+        }
+        (self.label)(dest, as_path)?;
+        self.copied += 1;
+        Ok(())
+    }
+}
+
+/// Copy the contents of the directory `src` into the directory `dest`, which is
+/// `as_path` in the target, keeping any existing entries.  The `label` callback is
+/// invoked for each copied path, along with its path in the target.  Returns the
+/// number of copied entries.
+#[context("Copying {src} to {dest}")]
+pub(crate) fn copy_tree(
+    src: &Utf8Path,
+    dest: &Utf8Path,
+    as_path: &Utf8Path,
+    label: &mut dyn FnMut(&Utf8Path, &Utf8Path) -> Result<()>,
+) -> Result<u64> {
+    let mut copy = TreeCopy {
+        links: HashMap::new(),
+        label,
+        copied: 0,
+    };
+    copy.copy_dir(src, dest, as_path)?;
+    Ok(copy.copied)
+}
+
+#[test]
+fn test_copy_file_sparse() {
+    use std::io::{Seek, SeekFrom, Write};
+    const GIB: u64 = 1024 * 1024 * 1024;
+    let td = tempfile::tempdir().unwrap();
+    let dir = Utf8Path::from_path(td.path()).unwrap();
+    let src = dir.join("disk.img");
+    let mut f = File::create(&src).unwrap();
+    f.set_len(4 * GIB).unwrap();
+    for (off, data) in [(0, "start"), (GIB + 7, "middle"), (3 * GIB, "end")] {
+        f.seek(SeekFrom::Start(off)).unwrap();
+        f.write_all(data.as_bytes()).unwrap();
+    }
+    drop(f);
+    std::fs::set_permissions(&src, std::os::unix::fs::PermissionsExt::from_mode(0o640)).unwrap();
+
+    let dest = dir.join("copy.img");
+    let method = copy_file(&src, &dest).unwrap();
+    // The destination must not exist
+    assert!(copy_file(&src, &dest).is_err());
+    let meta = dest.metadata().unwrap();
+    assert_eq!(meta.len(), 4 * GIB);
+    assert_eq!(meta.mode() & 0o7777, 0o640);
+    assert_eq!(meta.mtime(), src.metadata().unwrap().mtime());
+    // Only the written blocks are allocated, however the data was copied
+    assert!(meta.blocks() * 512 < 16 * 1024 * 1024, "{method:?}");
+    let f = File::open(&dest).unwrap();
+    for (off, data) in [(0, "start"), (GIB + 7, "middle"), (3 * GIB, "end")] {
+        let mut buf = vec![0u8; data.len()];
+        f.read_exact_at(&mut buf, off).unwrap();
+        assert_eq!(buf, data.as_bytes());
+    }
+    let mut buf = [0xffu8; 16];
+    f.read_exact_at(&mut buf, 2 * GIB).unwrap();
+    assert_eq!(buf, [0u8; 16]);
+
+    // Reflinks are used where the filesystem supports them
+    let probe = File::create(dir.join("probe")).unwrap();
+    let reflinks = reflink(&File::open(&src).unwrap(), &probe);
+    assert_eq!(method == CopyMethod::Reflink, reflinks, "{method:?}");
+
+    // Copying segments by reading and writing preserves holes too
+    let src_f = File::open(&src).unwrap();
+    let dest_f = File::create(dir.join("buffered.img")).unwrap();
+    let mut method = CopyMethod::Buffered;
+    for segment in data_segments(&src_f, 4 * GIB).unwrap() {
+        copy_segment(&src_f, &dest_f, segment, &mut method).unwrap();
+    }
+    dest_f.set_len(4 * GIB).unwrap();
+    assert!(dest_f.metadata().unwrap().blocks() * 512 < 16 * 1024 * 1024);
+    let mut buf = [0u8; 3];
+    let dest_f = File::open(dir.join("buffered.img")).unwrap();
+    dest_f.read_exact_at(&mut buf, 3 * GIB).unwrap();
+    assert_eq!(&buf, b"end");
+}
+
+#[test]
+fn test_copy_tree() {
+    let td = tempfile::tempdir().unwrap();
+    let dir = Utf8Path::from_path(td.path()).unwrap();
+    let src = dir.join("src");
+    std::fs::create_dir_all(src.join("sub/empty")).unwrap();
+    std::fs::write(src.join("a"), "a").unwrap();
+    std::fs::write(src.join("sub/b"), "b").unwrap();
+    std::fs::hard_link(src.join("sub/b"), src.join("sub/b-link")).unwrap();
+    std::os::unix::fs::symlink("sub/b", src.join("link")).unwrap();
+    // Extended attributes may not be supported by the temporary directory
+    let f = File::open(src.join("a")).unwrap();
+    let name = CString::new("user.test").unwrap();
+    let xattrs = set_xattr(f.as_raw_fd(), &name, b"value").is_ok();
+
+    let dest = dir.join("dest");
+    std::fs::create_dir_all(dest.join("sub")).unwrap();
+    std::fs::write(dest.join("a"), "existing").unwrap();
+    let mut labeled = Vec::new();
+    let mut label = |path: &Utf8Path, as_path: &Utf8Path| {
+        assert!(path.symlink_metadata().is_ok(), "{path}");
+        labeled.push(as_path.to_string());
+        Ok(())
+    };
+    let n = copy_tree(&src, &dest, Utf8Path::new("/etc"), &mut label).unwrap();
+    // Either of the hard links is copied and labeled, the other linked to it
+    assert_eq!(labeled.len(), 3, "{labeled:?}");
+    assert!(labeled.contains(&"/etc/link".to_string()));
+    assert!(labeled.contains(&"/etc/sub/empty".to_string()));
+    assert_eq!(n, 4);
+    // Existing entries are kept
+    assert_eq!(std::fs::read_to_string(dest.join("a")).unwrap(), "existing");
+    assert_eq!(std::fs::read_to_string(dest.join("sub/b")).unwrap(), "b");
+    let b = dest.join("sub/b").metadata().unwrap();
+    let b_link = dest.join("sub/b-link").metadata().unwrap();
+    assert_eq!(b.ino(), b_link.ino());
+    assert_eq!(b.nlink(), 2);
+    assert_eq!(
+        dest.join("link").read_link().unwrap(),
+        Utf8Path::new("sub/b")
+    );
+    assert!(dest.join("sub/empty").is_dir());
+
+    if xattrs {
+        std::fs::remove_file(dest.join("a")).unwrap();
+        copy_tree(&src, &dest, Utf8Path::new("/etc"), &mut |_, _| Ok(())).unwrap();
+        let f = File::open(dest.join("a")).unwrap();
+        let value = get_xattr(f.as_raw_fd(), &name).unwrap();
+        assert_eq!(value, b"value");
+    }
+}
+
+#[test]
+fn test_copy_tree_special() {
+    use std::os::unix::fs::PermissionsExt;
+    let td = tempfile::tempdir().unwrap();
+    let dir = Utf8Path::from_path(td.path()).unwrap();
+    let src = dir.join("src");
+    std::fs::create_dir(&src).unwrap();
+    nix::unistd::mkfifo(
+        src.join("fifo").as_std_path(),
+        Mode::from_bits_truncate(0o600),
+    )
+    .unwrap();
+    std::fs::set_permissions(src.join("fifo"), PermissionsExt::from_mode(0o620)).unwrap();
+    // Creating device nodes requires privileges
+    let null = nix::sys::stat::makedev(1, 3);
+    let devices = nix::sys::stat::mknod(
+        src.join("null").as_std_path(),
+        SFlag::S_IFCHR,
+        Mode::from_bits_truncate(0o666),
+        null,
+    )
+    .is_ok();
+
+    let dest = dir.join("dest");
+    std::fs::create_dir(&dest).unwrap();
+    let n = copy_tree(&src, &dest, Utf8Path::new("/srv"), &mut |_, _| Ok(())).unwrap();
+    assert_eq!(n, if devices { 2 } else { 1 });
+    let meta = dest.join("fifo").symlink_metadata().unwrap();
+    assert!(meta.file_type().is_fifo());
+    assert_eq!(meta.mode() & 0o7777, 0o620);
+    assert_eq!(
+        meta.mtime(),
+        src.join("fifo").symlink_metadata().unwrap().mtime()
+    );
+    if devices {
+        let meta = dest.join("null").symlink_metadata().unwrap();
+        assert!(meta.file_type().is_char_device());
+        assert_eq!(meta.rdev(), null);
+        assert_eq!(
+            meta.mode(),
+            src.join("null").symlink_metadata().unwrap().mode()
+        );
+    }
+}
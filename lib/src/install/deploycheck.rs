@@ -0,0 +1,250 @@
+//! # Checking the deployed image
+//!
+//! Deploying an image can succeed even though it has properties which predict
+//! problems at upgrade time, such as uncompressed layers or content which ostree can't
+//! represent.  These are reported after deploying as warnings, classified so that
+//! `--strict` can turn the classes in [`WarningClass::strict`] into errors.  As those
+//! classes only depend on the image and the target, `--strict` checks them before
+//! the target is modified too.
+
+use std::fmt::Display;
+
+use anyhow::Result;
+use fn_error_context::context;
+use ostree_ext::container as ostree_container;
+use ostree_ext::oci_spec::image::ImageManifest;
+use serde::Serialize;
+
+/// A class of warnings about the deployed image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum WarningClass {
+    /// Updates are fetched without verifying signatures
+    SignatureDowngrade,
+    /// Layers are not compressed, so every update transfers them in full
+    UncompressedLayer,
+    /// Layers use the Docker media types instead of the OCI ones
+    DockerMediaType,
+    /// Content of the image which ostree can't represent (e.g. in `/var`) was dropped
+    FilteredContent,
+}
+
+impl WarningClass {
+    /// Whether `--strict` turns warnings of this class into errors.  Any new class must
+    /// be added here, with a test.
+    pub(crate) fn strict(self) -> bool {
+        match self {
+            Self::SignatureDowngrade => true,
+            Self::UncompressedLayer => true,
+            Self::DockerMediaType => false,
+            Self::FilteredContent => false,
+        }
+    }
+}
+
+impl Display for WarningClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::SignatureDowngrade => "signature-downgrade",
+            Self::UncompressedLayer => "uncompressed-layer",
+            Self::DockerMediaType => "docker-media-type",
+            Self::FilteredContent => "filtered-content",
+        };
+        f.write_str(s)
+    }
+}
+
+/// A warning about the deployed image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct DeployWarning {
+    pub(crate) class: WarningClass,
+    pub(crate) message: String,
+}
+
+impl Display for DeployWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, self.class)
+    }
+}
+
+/// Whether a layer media type is an uncompressed tarball.
+fn is_uncompressed(media_type: &str) -> bool {
+    media_type.ends_with(".tar")
+}
+
+/// The media types of the layers of the provided image manifest.
+pub(crate) fn layer_media_types(manifest: &ImageManifest) -> Vec<String> {
+    manifest
+        .layers()
+        .iter()
+        .map(|l| l.media_type().to_string())
+        .collect()
+}
+
+/// Fetch the media types of the layers of the provided image.
+#[context("Fetching layer media types of {imgref}")]
+pub(crate) async fn fetch_layer_media_types(
+    config: ostree_container::store::ImageProxyConfig,
+    imgref: &ostree_container::ImageReference,
+) -> Result<Vec<String>> {
+    let proxy = containers_image_proxy::ImageProxy::new_with_config(config).await?;
+    let img = proxy.open_image(&imgref.to_string()).await?;
+    let (_, manifest) = proxy.fetch_manifest(&img).await?;
+    proxy.close_image(&img).await?;
+    proxy.finalize().await?;
+    Ok(layer_media_types(&manifest))
+}
+
+/// Find the warnings which can be determined before deploying an image, given the
+/// media types of its layers and how updates are verified.
+pub(crate) fn find_image_warnings(
+    layer_media_types: &[String],
+    sigverify: &ostree_container::SignatureSource,
+) -> Vec<DeployWarning> {
+    let mut r = Vec::new();
+    if *sigverify == ostree_container::SignatureSource::ContainerPolicyAllowInsecure {
+        r.push(DeployWarning {
+            class: WarningClass::SignatureDowngrade,
+            message: "Updates are fetched without signature verification".into(),
+        });
+    }
+    let count = |f: fn(&str) -> bool| layer_media_types.iter().filter(|t| f(t)).count();
+    let n = count(is_uncompressed);
+    if n > 0 {
+        r.push(DeployWarning {
+            class: WarningClass::UncompressedLayer,
+            message: format!(
+                "{n} of {} layers are uncompressed; updates transfer them in full",
+                layer_media_types.len()
+            ),
+        });
+    }
+    let n = count(|t| t.starts_with("application/vnd.docker."));
+    if n > 0 {
+        r.push(DeployWarning {
+            class: WarningClass::DockerMediaType,
+            message: format!(
+                "{n} of {} layers use Docker media types instead of OCI ones",
+                layer_media_types.len()
+            ),
+        });
+    }
+    r
+}
+
+/// Find the warnings about a deployed image, given the media types of its layers, how
+/// updates are verified, and the ostree warning about filtered content, if any.
+pub(crate) fn find_warnings(
+    layer_media_types: &[String],
+    sigverify: &ostree_container::SignatureSource,
+    filtered_content: Option<String>,
+) -> Vec<DeployWarning> {
+    let mut r = find_image_warnings(layer_media_types, sigverify);
+    if let Some(message) = filtered_content {
+        r.push(DeployWarning {
+            class: WarningClass::FilteredContent,
+            message,
+        });
+    }
+    r
+}
+
+/// Fail if any of the provided warnings is of a class which `--strict` turns into an
+/// error.
+pub(crate) fn check_strict(warnings: &[DeployWarning]) -> Result<()> {
+    let fatal = warnings
+        .iter()
+        .filter(|w| w.class.strict())
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    if !fatal.is_empty() {
+        anyhow::bail!("With --strict: {}", fatal.join("; "));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_warning_class_strict() {
+    // Every class must have a conscious decision here
+    for (class, strict) in [
+        (WarningClass::SignatureDowngrade, true),
+        (WarningClass::UncompressedLayer, true),
+        (WarningClass::DockerMediaType, false),
+        (WarningClass::FilteredContent, false),
+    ] {
+        assert_eq!(class.strict(), strict, "{class}");
+        assert_eq!(
+            serde_json::to_value(class).unwrap(),
+            serde_json::Value::String(class.to_string())
+        );
+    }
+}
+
+#[test]
+fn test_find_warnings() {
+    use ostree_container::SignatureSource;
+    let media_types = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    let gzip = "application/vnd.oci.image.layer.v1.tar+gzip";
+    let zstd = "application/vnd.oci.image.layer.v1.tar+zstd";
+    let uncompressed = "application/vnd.oci.image.layer.v1.tar";
+    let docker = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+    let verified = SignatureSource::ContainerPolicy;
+
+    let warnings = find_warnings(&media_types(&[gzip, zstd]), &verified, None);
+    assert!(warnings.is_empty());
+    check_strict(&warnings).unwrap();
+
+    let warnings = find_warnings(
+        &media_types(&[gzip, docker]),
+        &SignatureSource::OstreeRemote("fedora".into()),
+        Some("Image contains non-ostree compatible file paths: /var: 3".into()),
+    );
+    let classes = warnings.iter().map(|w| w.class).collect::<Vec<_>>();
+    assert_eq!(
+        classes,
+        [WarningClass::DockerMediaType, WarningClass::FilteredContent]
+    );
+    assert_eq!(
+        warnings[0].message,
+        "1 of 2 layers use Docker media types instead of OCI ones"
+    );
+    // Neither is fatal
+    check_strict(&warnings).unwrap();
+
+    let warnings = find_warnings(
+        &media_types(&[uncompressed, gzip, uncompressed]),
+        &SignatureSource::ContainerPolicyAllowInsecure,
+        None,
+    );
+    let classes = warnings.iter().map(|w| w.class).collect::<Vec<_>>();
+    assert_eq!(
+        classes,
+        [
+            WarningClass::SignatureDowngrade,
+            WarningClass::UncompressedLayer
+        ]
+    );
+    assert_eq!(
+        warnings[1].to_string(),
+        "2 of 3 layers are uncompressed; updates transfer them in full (uncompressed-layer)"
+    );
+    let e = check_strict(&warnings).unwrap_err().to_string();
+    assert!(e.contains("(signature-downgrade)"), "{e}");
+    assert!(e.contains("(uncompressed-layer)"), "{e}");
+    assert_eq!(
+        serde_json::to_value(&warnings[0]).unwrap(),
+        serde_json::json!({
+            "class": "signature-downgrade",
+            "message": "Updates are fetched without signature verification"
+        })
+    );
+    // Before deploying, the same strict classes are found
+    assert_eq!(
+        find_image_warnings(
+            &media_types(&[uncompressed, gzip, uncompressed]),
+            &SignatureSource::ContainerPolicyAllowInsecure,
+        ),
+        warnings
+    );
+}
@@ -0,0 +1,458 @@
+//! # Selecting a devicetree
+//!
+//! Kernels for many aarch64 boards ship a devicetree blob for each board in
+//! `/usr/lib/modules/$kver/dtb`, and the right one has to be chosen at install time.
+//! With `--devicetree`, the blob is copied to `/boot` and referenced by the boot
+//! entries.  With `auto`, it is the blob whose root `compatible` property matches the
+//! most specific entry of the running system's.  The selection is recorded in
+//! `/etc/kernel/devicetree`, where kernel installation tooling finds it.
+
+use std::fmt::Display;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use cap_std::fs::{Dir, Permissions};
+use cap_std_ext::cap_std;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+
+use super::kernel;
+
+/// The root `compatible` property of the running system's devicetree
+const HOST_COMPATIBLE: &str = "/proc/device-tree/compatible";
+/// The directory the devicetree blob is installed to, relative to `/boot`
+pub(crate) const BOOT_DEVICETREE_DIR: &str = "bootc-dtb";
+/// The selected devicetree blob, relative to the kernel's `dtb` directory
+pub(crate) const ETC_DEVICETREE: &str = "etc/kernel/devicetree";
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+
+/// The devicetree blob to install.
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeFromStr, SerializeDisplay)]
+pub(crate) enum DevicetreeSpec {
+    /// Match the running system's devicetree
+    Auto,
+    /// A path relative to the kernel's `dtb` directory
+    Path(Utf8PathBuf),
+}
+
+impl FromStr for DevicetreeSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "auto" {
+            return Ok(Self::Auto);
+        }
+        let path = Utf8Path::new(s);
+        if s.is_empty()
+            || !path
+                .components()
+                .all(|c| matches!(c, Utf8Component::Normal(_)))
+        {
+            anyhow::bail!(
+                "Invalid devicetree {s}: expected a path relative to the kernel's dtb directory"
+            );
+        }
+        Ok(Self::Path(path.into()))
+    }
+}
+
+impl Display for DevicetreeSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Auto => f.write_str("auto"),
+            Self::Path(p) => p.fmt(f),
+        }
+    }
+}
+
+/// Split a devicetree string list property.
+fn split_stringlist(value: &[u8]) -> Vec<String> {
+    value
+        .split(|&b| b == 0)
+        .filter(|s| !s.is_empty())
+        .map(|s| String::from_utf8_lossy(s).into_owned())
+        .collect()
+}
+
+/// Read a big-endian u32 from a flattened devicetree.
+fn fdt_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let b = data
+        .get(offset..offset + 4)
+        .ok_or_else(|| anyhow::anyhow!("Truncated devicetree blob"))?;
+    Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Read a NUL-terminated string from a flattened devicetree.
+fn fdt_str(data: &[u8], offset: usize) -> Result<&[u8]> {
+    let s = data
+        .get(offset..)
+        .ok_or_else(|| anyhow::anyhow!("Truncated devicetree blob"))?;
+    let len = s
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or_else(|| anyhow::anyhow!("Unterminated string in devicetree blob"))?;
+    Ok(&s[..len])
+}
+
+/// Parse the `compatible` property of the root node of a flattened devicetree blob,
+/// as specified by the Devicetree Specification.
+fn root_compatible(data: &[u8]) -> Result<Vec<String>> {
+    let align = |n: usize| (n + 3) & !3;
+    if fdt_u32(data, 0)? != FDT_MAGIC {
+        anyhow::bail!("Not a devicetree blob");
+    }
+    let strings = fdt_u32(data, 12)? as usize;
+    let mut pos = fdt_u32(data, 8)? as usize;
+    while fdt_u32(data, pos)? == FDT_NOP {
+        pos += 4;
+    }
+    if fdt_u32(data, pos)? != FDT_BEGIN_NODE {
+        anyhow::bail!("Missing root node in devicetree blob");
+    }
+    // The name of the root node is empty, but skip it anyway
+    pos = align(pos + 4 + fdt_str(data, pos + 4)?.len() + 1);
+    // The properties of a node come before its children
+    loop {
+        match fdt_u32(data, pos)? {
+            FDT_NOP => pos += 4,
+            FDT_PROP => {
+                let len = fdt_u32(data, pos + 4)? as usize;
+                let name = fdt_str(data, strings + fdt_u32(data, pos + 8)? as usize)?;
+                let value = data
+                    .get(pos + 12..pos + 12 + len)
+                    .ok_or_else(|| anyhow::anyhow!("Truncated devicetree blob"))?;
+                if name == b"compatible" {
+                    return Ok(split_stringlist(value));
+                }
+                pos = align(pos + 12 + len);
+            }
+            _ => return Ok(Vec::new()),
+        }
+    }
+}
+
+/// Read the root `compatible` property of the running system, most specific first.
+pub(crate) fn host_compatible() -> Result<Vec<String>> {
+    let value = std::fs::read(HOST_COMPATIBLE).with_context(|| {
+        format!("Reading {HOST_COMPATIBLE}; is the system booted with a devicetree?")
+    })?;
+    Ok(split_stringlist(&value))
+}
+
+/// Find the `dtb` directory of the selected (or single) kernel in the provided
+/// deployment, relative to it.
+fn find_dtb_dir(deployment: &Dir, kver: Option<&str>) -> Result<Utf8PathBuf> {
+    let kernel = kernel::find_kernel(deployment, kver)?;
+    let dtb = kernel.join("dtb");
+    if !deployment.try_exists(&dtb)? {
+        anyhow::bail!("No devicetree blobs found for the kernel in /{kernel}");
+    }
+    Ok(dtb)
+}
+
+/// List the devicetree blobs in the provided directory recursively, sorted.
+fn list_dtbs(deployment: &Dir, dir: &Utf8Path) -> Result<Vec<Utf8PathBuf>> {
+    let mut r = Vec::new();
+    for e in deployment.read_dir(dir)? {
+        let e = e?;
+        let name = e.file_name();
+        let name = name
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Invalid non-UTF8 filename: {name:?}"))?;
+        let path = dir.join(name);
+        let ty = e.file_type()?;
+        if ty.is_dir() {
+            r.extend(list_dtbs(deployment, &path)?);
+        } else if ty.is_file() && name.ends_with(".dtb") {
+            r.push(path);
+        }
+    }
+    r.sort();
+    Ok(r)
+}
+
+/// Find the devicetree blob to install in the provided deployment, returning its path
+/// relative to the deployment.  `host_compatible` provides the running system's root
+/// `compatible` property for [`DevicetreeSpec::Auto`].  With multiple kernels, the
+/// blob is taken from the kernel version `kver`.
+#[context("Finding devicetree")]
+pub(crate) fn resolve_dtb(
+    deployment: &Dir,
+    spec: &DevicetreeSpec,
+    kver: Option<&str>,
+    host_compatible: impl FnOnce() -> Result<Vec<String>>,
+) -> Result<Utf8PathBuf> {
+    let dtb_dir = find_dtb_dir(deployment, kver)?;
+    let path = match spec {
+        DevicetreeSpec::Path(p) => {
+            let path = dtb_dir.join(p);
+            let found = deployment
+                .metadata_optional(&path)?
+                .map_or(false, |m| m.is_file());
+            if !found {
+                anyhow::bail!("Devicetree blob /{path} not found");
+            }
+            path
+        }
+        DevicetreeSpec::Auto => {
+            let host = host_compatible()?;
+            let mut candidates = Vec::new();
+            for path in list_dtbs(deployment, &dtb_dir)? {
+                let mut data = Vec::new();
+                deployment.open(&path)?.read_to_end(&mut data)?;
+                let compatible =
+                    root_compatible(&data).with_context(|| format!("Parsing /{path}"))?;
+                candidates.push((path, compatible));
+            }
+            // The blob matching the most specific entry of the host wins; among those,
+            // the one for which that entry is the most specific, then the first one
+            candidates
+                .into_iter()
+                .filter_map(|(path, compatible)| {
+                    host.iter()
+                        .enumerate()
+                        .find_map(|(i, c)| {
+                            let j = compatible.iter().position(|v| v == c)?;
+                            Some((i, j))
+                        })
+                        .map(|rank| (rank, path))
+                })
+                .min()
+                .map(|(_, path)| path)
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "No devicetree blob in /{dtb_dir} is compatible with this system ({})",
+                        host.join(", ")
+                    )
+                })?
+        }
+    };
+    Ok(path)
+}
+
+/// Copy the devicetree blob from the deployment to the provided `/boot`, returning its
+/// path relative to `/boot`.
+#[context("Installing devicetree")]
+pub(crate) fn install_dtb(deployment: &Dir, path: &Utf8Path, bootfs: &Dir) -> Result<Utf8PathBuf> {
+    // SAFETY: The path was resolved to a file
+    let name = path.file_name().unwrap();
+    let target = Utf8Path::new(BOOT_DEVICETREE_DIR).join(name);
+    let mut data = Vec::new();
+    deployment.open(path)?.read_to_end(&mut data)?;
+    bootfs.create_dir_all(BOOT_DEVICETREE_DIR)?;
+    bootfs
+        .atomic_write(&target, data)
+        .with_context(|| format!("Writing {target}"))?;
+    Ok(target)
+}
+
+/// Record the devicetree blob at the provided path in the deployment as selected, so
+/// that it is used for the kernels of later updates too.
+#[context("Recording devicetree")]
+pub(crate) fn write_selection(deployment: &Dir, path: &Utf8Path) -> Result<()> {
+    // Strip the kernel version and dtb directory
+    let relpath = path
+        .strip_prefix(kernel::MODULES_DIR)
+        .ok()
+        .map(|p| p.components().skip(2).collect::<Utf8PathBuf>())
+        .filter(|p| !p.as_str().is_empty())
+        .ok_or_else(|| anyhow::anyhow!("Not a kernel's devicetree blob: /{path}"))?;
+    // SAFETY: The path has a parent
+    deployment.create_dir_all(Utf8Path::new(ETC_DEVICETREE).parent().unwrap())?;
+    deployment
+        .atomic_write_with_perms(
+            ETC_DEVICETREE,
+            format!("{relpath}\n"),
+            Permissions::from_mode(0o644),
+        )
+        .with_context(|| format!("Writing {ETC_DEVICETREE}"))?;
+    Ok(())
+}
+
+/// Build a flattened devicetree with only a root `compatible` property.
+#[cfg(test)]
+fn test_fdt(compatible: &[&str]) -> Vec<u8> {
+    let value = compatible
+        .iter()
+        .flat_map(|c| [c.as_bytes(), b"\0"].concat())
+        .collect::<Vec<_>>();
+    let mut dt_struct = Vec::new();
+    for v in [FDT_BEGIN_NODE, 0, FDT_NOP, FDT_PROP, 4, 0] {
+        dt_struct.extend(u32::to_be_bytes(v));
+    }
+    // A #address-cells property before compatible
+    dt_struct.extend(2u32.to_be_bytes());
+    dt_struct.extend(u32::to_be_bytes(FDT_PROP));
+    dt_struct.extend(u32::to_be_bytes(value.len() as u32));
+    dt_struct.extend(u32::to_be_bytes(15));
+    dt_struct.extend(&value);
+    dt_struct.resize((dt_struct.len() + 3) & !3, 0);
+    for v in [2, 9] {
+        dt_struct.extend(u32::to_be_bytes(v));
+    }
+    let strings = b"#address-cells\0compatible\0";
+    let header_len = 40u32;
+    let mut r = Vec::new();
+    for v in [
+        FDT_MAGIC,
+        header_len + (dt_struct.len() + strings.len()) as u32,
+        header_len,
+        header_len + dt_struct.len() as u32,
+        header_len,
+        17,
+        16,
+        0,
+        strings.len() as u32,
+        dt_struct.len() as u32,
+    ] {
+        r.extend(v.to_be_bytes());
+    }
+    r.extend(dt_struct);
+    r.extend(strings);
+    r
+}
+
+#[test]
+fn test_root_compatible() {
+    let fdt = test_fdt(&[
+        "pine64,rockpro64-v2.1",
+        "pine64,rockpro64",
+        "rockchip,rk3399",
+    ]);
+    assert_eq!(
+        root_compatible(&fdt).unwrap(),
+        [
+            "pine64,rockpro64-v2.1",
+            "pine64,rockpro64",
+            "rockchip,rk3399"
+        ]
+    );
+    assert!(root_compatible(&fdt[..64]).is_err());
+    assert!(root_compatible(b"compatible").is_err());
+}
+
+#[test]
+fn test_resolve_dtb() {
+    let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    let path = |s: &str| DevicetreeSpec::from_str(s).unwrap();
+    let no_host = || -> Result<Vec<String>> { anyhow::bail!("No devicetree") };
+    let host = || {
+        Ok(vec![
+            "pine64,rockpro64-v2.1".into(),
+            "pine64,rockpro64".into(),
+            "rockchip,rk3399".into(),
+        ])
+    };
+    let board = "rockchip/rk3399-rockpro64.dtb";
+
+    // No kernel
+    td.create_dir_all(kernel::MODULES_DIR).unwrap();
+    assert!(resolve_dtb(&td, &path(board), None, no_host).is_err());
+    let kernel = "usr/lib/modules/6.2.9-300.fc38.aarch64";
+    td.create_dir_all(kernel).unwrap();
+    td.write(format!("{kernel}/vmlinuz"), "").unwrap();
+    // No dtb directory
+    assert!(resolve_dtb(&td, &path(board), None, no_host).is_err());
+    let dtb = format!("{kernel}/dtb");
+    for dir in ["rockchip", "allwinner", "broadcom"] {
+        td.create_dir_all(format!("{dtb}/{dir}")).unwrap();
+    }
+    for (name, compatible) in [
+        (
+            "rockchip/rk3399-evb.dtb",
+            &["rockchip,rk3399-evb", "rockchip,rk3399"][..],
+        ),
+        (board, &["pine64,rockpro64", "rockchip,rk3399"]),
+        (
+            "allwinner/sun50i-a64-pine64.dtb",
+            &["pine64,pine64", "allwinner,sun50i-a64"],
+        ),
+        (
+            "broadcom/bcm2711-rpi-4-b.dtb",
+            &["raspberrypi,4-model-b", "brcm,bcm2711"],
+        ),
+    ] {
+        td.write(format!("{dtb}/{name}"), test_fdt(compatible))
+            .unwrap();
+    }
+    // Not a blob, and skipped
+    td.write(format!("{dtb}/broadcom/README"), "").unwrap();
+
+    let expected = Utf8PathBuf::from(format!("{dtb}/{board}"));
+    assert_eq!(
+        resolve_dtb(&td, &path(board), None, no_host).unwrap(),
+        expected
+    );
+    assert!(resolve_dtb(&td, &path("rockchip/missing.dtb"), None, no_host).is_err());
+    assert!(resolve_dtb(&td, &path("rockchip"), None, no_host).is_err());
+    // The board's blob is preferred over the SoC's evaluation board
+    assert_eq!(
+        resolve_dtb(&td, &DevicetreeSpec::Auto, None, host).unwrap(),
+        expected
+    );
+    let soc_only = || Ok(vec!["rockchip,rk3399".into()]);
+    assert_eq!(
+        resolve_dtb(&td, &DevicetreeSpec::Auto, None, soc_only).unwrap(),
+        Utf8PathBuf::from(format!("{dtb}/rockchip/rk3399-evb.dtb"))
+    );
+    // A blob for which the host's entry is the most specific is preferred over one
+    // where it is a fallback
+    td.write(
+        format!("{dtb}/rockchip/rk3399-rockpro64-v2.dtb"),
+        test_fdt(&["pine64,rockpro64-v2", "pine64,rockpro64", "rockchip,rk3399"]),
+    )
+    .unwrap();
+    assert_eq!(
+        resolve_dtb(&td, &DevicetreeSpec::Auto, None, host).unwrap(),
+        expected
+    );
+    let unknown = || Ok(vec!["qemu,virt".into()]);
+    assert!(resolve_dtb(&td, &DevicetreeSpec::Auto, None, unknown).is_err());
+    assert!(resolve_dtb(&td, &DevicetreeSpec::Auto, None, no_host).is_err());
+
+    // Multiple kernels
+    td.create_dir_all("usr/lib/modules/6.3.0-1.fc38.aarch64")
+        .unwrap();
+    td.write("usr/lib/modules/6.3.0-1.fc38.aarch64/vmlinuz", "")
+        .unwrap();
+    assert!(resolve_dtb(&td, &path(board), None, no_host).is_err());
+    let selected = Some("6.2.9-300.fc38.aarch64");
+    assert_eq!(
+        resolve_dtb(&td, &path(board), selected, no_host).unwrap(),
+        expected
+    );
+    assert!(resolve_dtb(&td, &path(board), Some("6.3.0-1.fc38.aarch64"), no_host).is_err());
+
+    let boot = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    let installed = install_dtb(&td, &expected, &boot).unwrap();
+    assert_eq!(installed, "bootc-dtb/rk3399-rockpro64.dtb");
+    assert_eq!(boot.read(&installed).unwrap(), td.read(&expected).unwrap());
+
+    write_selection(&td, &expected).unwrap();
+    assert_eq!(
+        td.read_to_string(ETC_DEVICETREE).unwrap(),
+        "rockchip/rk3399-rockpro64.dtb\n"
+    );
+    assert!(write_selection(&td, Utf8Path::new(&dtb)).is_err());
+}
+
+#[test]
+fn test_devicetree_spec() {
+    assert_eq!(
+        DevicetreeSpec::from_str("auto").unwrap(),
+        DevicetreeSpec::Auto
+    );
+    let spec = DevicetreeSpec::from_str("rockchip/rk3399-rockpro64.dtb").unwrap();
+    assert_eq!(spec.to_string(), "rockchip/rk3399-rockpro64.dtb");
+    for invalid in ["", "/usr/lib/modules/6.2/dtb/a.dtb", "../a.dtb", "./a.dtb"] {
+        assert!(DevicetreeSpec::from_str(invalid).is_err(), "{invalid}");
+    }
+}
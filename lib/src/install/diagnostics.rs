@@ -0,0 +1,60 @@
+//! # Collecting warnings
+//!
+//! Non-fatal issues found during an installation are printed when they occur, and
+//! again together when the installation completes, so that they are not lost among
+//! the rest of the output.  They are also part of the
+//! [`InstallSummary`](super::summary::InstallSummary).
+
+use std::io::Write;
+use std::sync::Mutex;
+
+use anyhow::Result;
+
+/// Warnings collected during an installation.
+#[derive(Debug, Default)]
+pub(crate) struct Diagnostics {
+    warnings: Mutex<Vec<String>>,
+}
+
+impl Diagnostics {
+    /// Print and record a warning.
+    pub(crate) fn warn(&self, msg: impl Into<String>) {
+        let msg = msg.into();
+        eprintln!("warning: {msg}");
+        self.warnings.lock().unwrap().push(msg);
+    }
+
+    /// The warnings recorded so far, in order.
+    pub(crate) fn warnings(&self) -> Vec<String> {
+        self.warnings.lock().unwrap().clone()
+    }
+
+    /// Write all recorded warnings, if any.
+    pub(crate) fn write_summary(&self, mut w: impl Write) -> Result<()> {
+        let warnings = self.warnings.lock().unwrap();
+        if warnings.is_empty() {
+            return Ok(());
+        }
+        writeln!(w, "Warnings ({}):", warnings.len())?;
+        for msg in warnings.iter() {
+            writeln!(w, "  {msg}")?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_diagnostics() {
+    let d = Diagnostics::default();
+    let mut buf = Vec::new();
+    d.write_summary(&mut buf).unwrap();
+    assert!(buf.is_empty());
+    d.warn("first");
+    d.warn(format!("second {}", 2));
+    assert_eq!(d.warnings(), ["first", "second 2"]);
+    d.write_summary(&mut buf).unwrap();
+    assert_eq!(
+        String::from_utf8(buf).unwrap(),
+        "Warnings (2):\n  first\n  second 2\n"
+    );
+}
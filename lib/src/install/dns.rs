@@ -0,0 +1,277 @@
+//! # Static DNS configuration
+//!
+//! Without DHCP, the installed system has no nameserver on first boot, and so can't
+//! even reach the registry for updates.  With `--dns`, the nameservers and any
+//! `--dns-search` domains are written into the deployment: as a systemd-resolved
+//! drop-in if the image's `/etc/resolv.conf` is the link to the resolved stub, and as
+//! a plain `/etc/resolv.conf` otherwise.
+
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use fn_error_context::context;
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+
+/// The resolver configuration, relative to the deployment
+const RESOLV_CONF_PATH: &str = "etc/resolv.conf";
+/// Where systemd-resolved provides the resolver configuration, relative to the root
+const RESOLVED_RUNTIME_DIR: &str = "run/systemd/resolve";
+/// The files of systemd-resolved which `/etc/resolv.conf` may link to
+const RESOLVED_RESOLV_CONFS: &[&str] = &["stub-resolv.conf", "resolv.conf"];
+/// The path of our resolved.conf drop-in, relative to the deployment
+const RESOLVED_DROPIN_PATH: &str = "etc/systemd/resolved.conf.d/50-bootc-dns.conf";
+/// The maximum number of nameservers in resolv.conf used by glibc (`MAXNS`)
+const RESOLV_CONF_MAX_NAMESERVERS: usize = 3;
+
+/// A DNS search domain.
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeFromStr, SerializeDisplay)]
+pub(crate) struct SearchDomain(String);
+
+impl FromStr for SearchDomain {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let valid_label = |l: &str| {
+            (1..=63).contains(&l.len())
+                && l.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                && !l.starts_with('-')
+                && !l.ends_with('-')
+        };
+        if s.len() > 253 || !s.split('.').all(valid_label) {
+            anyhow::bail!("Invalid search domain {s:?}");
+        }
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl Display for SearchDomain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Whether the image uses systemd-resolved, i.e. its `/etc/resolv.conf` is a symbolic
+/// link to one provided by resolved.
+fn uses_resolved(deployment_root: &Utf8Path) -> Result<bool> {
+    let path = deployment_root.join(RESOLV_CONF_PATH);
+    match path.symlink_metadata() {
+        Ok(m) if m.file_type().is_symlink() => {}
+        Ok(_) => return Ok(false),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e).with_context(|| format!("Querying {path}")),
+    }
+    let target = path
+        .read_link_utf8()
+        .with_context(|| format!("Reading {path}"))?;
+    // Either absolute, or relative to /etc
+    let target = Utf8Path::new(
+        target
+            .as_str()
+            .trim_start_matches("../")
+            .trim_start_matches('/'),
+    );
+    let is_resolved = target.parent() == Some(Utf8Path::new(RESOLVED_RUNTIME_DIR))
+        && target
+            .file_name()
+            .map_or(false, |n| RESOLVED_RESOLV_CONFS.contains(&n));
+    Ok(is_resolved)
+}
+
+/// The contents of our resolved.conf drop-in.
+fn resolved_dropin_contents(nameservers: &[IpAddr], search: &[SearchDomain]) -> String {
+    let nameservers = nameservers
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    let mut r = format!(
+        "# Created by bootc install --dns\n[Resolve]\nDNS={}\n",
+        nameservers.join(" ")
+    );
+    if !search.is_empty() {
+        let search = search.iter().map(|d| d.0.as_str()).collect::<Vec<_>>();
+        r.push_str(&format!("Domains={}\n", search.join(" ")));
+    }
+    r
+}
+
+/// The contents of a resolv.conf(5), which supports only a limited number of nameservers.
+fn resolv_conf_contents(nameservers: &[IpAddr], search: &[SearchDomain]) -> Result<String> {
+    if nameservers.len() > RESOLV_CONF_MAX_NAMESERVERS {
+        anyhow::bail!(
+            "At most {RESOLV_CONF_MAX_NAMESERVERS} nameservers are supported without systemd-resolved"
+        );
+    }
+    let mut r = String::from("# Created by bootc install --dns\n");
+    if !search.is_empty() {
+        let search = search.iter().map(|d| d.0.as_str()).collect::<Vec<_>>();
+        r.push_str(&format!("search {}\n", search.join(" ")));
+    }
+    for ns in nameservers {
+        r.push_str(&format!("nameserver {ns}\n"));
+    }
+    Ok(r)
+}
+
+/// Write the DNS configuration into the deployment, returning the path written
+/// relative to the deployment root.
+#[context("Configuring DNS")]
+pub(crate) fn write_dns_config(
+    deployment_root: &Utf8Path,
+    nameservers: &[IpAddr],
+    search: &[SearchDomain],
+) -> Result<&'static str> {
+    let (relpath, contents) = if uses_resolved(deployment_root)? {
+        let contents = resolved_dropin_contents(nameservers, search);
+        (RESOLVED_DROPIN_PATH, contents)
+    } else {
+        let contents = resolv_conf_contents(nameservers, search)?;
+        (RESOLV_CONF_PATH, contents)
+    };
+    let path = deployment_root.join(relpath);
+    // SAFETY: The path has a parent
+    let dir = path.parent().unwrap();
+    std::fs::create_dir_all(dir).with_context(|| format!("Creating {dir}"))?;
+    // This may be a symbolic link, which must not be followed
+    if path.symlink_metadata().is_ok() {
+        std::fs::remove_file(&path).with_context(|| format!("Removing {path}"))?;
+    }
+    std::fs::write(&path, contents).with_context(|| format!("Writing {path}"))?;
+    Ok(relpath)
+}
+
+#[test]
+fn test_search_domain() {
+    for valid in ["example.com", "corp", "a-b.example.com", "123.example"] {
+        assert_eq!(SearchDomain::from_str(valid).unwrap().to_string(), valid);
+    }
+    let long_label = "a".repeat(64);
+    for invalid in [
+        "",
+        ".",
+        "example..com",
+        "example.com.",
+        "-corp.example.com",
+        "corp-.example.com",
+        "exa mple.com",
+        "example.com\nnameserver 10.0.0.1",
+        long_label.as_str(),
+    ] {
+        assert!(SearchDomain::from_str(invalid).is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn test_write_dns_config() {
+    let td = tempfile::tempdir().unwrap();
+    let root = Utf8Path::from_path(td.path()).unwrap();
+    std::fs::create_dir(root.join("etc")).unwrap();
+    let nameservers = ["192.0.2.53", "2001:db8::53"].map(|s| s.parse().unwrap());
+    let search = ["example.com", "corp.example.com"].map(|s| s.parse().unwrap());
+
+    // No resolv.conf in the image
+    assert!(!uses_resolved(root).unwrap());
+    assert_eq!(
+        write_dns_config(root, &nameservers, &search).unwrap(),
+        RESOLV_CONF_PATH
+    );
+    let expected = "# Created by bootc install --dns\n\
+                    search example.com corp.example.com\n\
+                    nameserver 192.0.2.53\n\
+                    nameserver 2001:db8::53\n";
+    assert_eq!(
+        std::fs::read_to_string(root.join(RESOLV_CONF_PATH)).unwrap(),
+        expected
+    );
+    // Replacing a plain file, or a link elsewhere
+    assert!(!uses_resolved(root).unwrap());
+    write_dns_config(root, &nameservers, &search).unwrap();
+    std::fs::remove_file(root.join(RESOLV_CONF_PATH)).unwrap();
+    std::os::unix::fs::symlink(
+        "../run/NetworkManager/resolv.conf",
+        root.join(RESOLV_CONF_PATH),
+    )
+    .unwrap();
+    assert!(!uses_resolved(root).unwrap());
+    write_dns_config(root, &nameservers[..1], &[]).unwrap();
+    let path = root.join(RESOLV_CONF_PATH);
+    assert!(!path.symlink_metadata().unwrap().file_type().is_symlink());
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "# Created by bootc install --dns\nnameserver 192.0.2.53\n"
+    );
+    let many = ["192.0.2.1", "192.0.2.2", "192.0.2.3", "192.0.2.4"].map(|s| s.parse().unwrap());
+    assert!(write_dns_config(root, &many, &[]).is_err());
+
+    // The image uses systemd-resolved
+    for target in [
+        "../run/systemd/resolve/stub-resolv.conf",
+        "/run/systemd/resolve/stub-resolv.conf",
+        "../run/systemd/resolve/resolv.conf",
+    ] {
+        std::fs::remove_file(&path).unwrap();
+        std::os::unix::fs::symlink(target, &path).unwrap();
+        assert!(uses_resolved(root).unwrap(), "{target}");
+    }
+    assert_eq!(
+        write_dns_config(root, &nameservers, &search).unwrap(),
+        RESOLVED_DROPIN_PATH
+    );
+    assert_eq!(
+        std::fs::read_to_string(root.join(RESOLVED_DROPIN_PATH)).unwrap(),
+        "# Created by bootc install --dns\n\
+         [Resolve]\n\
+         DNS=192.0.2.53 2001:db8::53\n\
+         Domains=example.com corp.example.com\n"
+    );
+    // The link is kept, and any number of nameservers is fine
+    assert!(path.symlink_metadata().unwrap().file_type().is_symlink());
+    write_dns_config(root, &many, &[]).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(root.join(RESOLVED_DROPIN_PATH)).unwrap(),
+        "# Created by bootc install --dns\n\
+         [Resolve]\n\
+         DNS=192.0.2.1 192.0.2.2 192.0.2.3 192.0.2.4\n"
+    );
+}
+
+#[test]
+fn test_dns_opts() {
+    use clap::Parser;
+    let o = super::InstallOpts::try_parse_from([
+        "install",
+        "--dns",
+        "192.0.2.53",
+        "--dns",
+        "2001:db8::53",
+        "--dns-search",
+        "example.com",
+        "/dev/vda",
+    ])
+    .unwrap();
+    assert_eq!(
+        o.config_opts.dns,
+        ["192.0.2.53", "2001:db8::53"].map(|s| s.parse::<IpAddr>().unwrap())
+    );
+    assert_eq!(o.config_opts.dns_search[0].to_string(), "example.com");
+    for args in [
+        &["install", "--dns", "ns1.example.com", "/dev/vda"][..],
+        &["install", "--dns", "192.0.2.256", "/dev/vda"],
+        &["install", "--dns-search", "example.com", "/dev/vda"],
+        &[
+            "install",
+            "--dns",
+            "192.0.2.53",
+            "--dns-search",
+            "example..com",
+            "/dev/vda",
+        ],
+    ] {
+        assert!(
+            super::InstallOpts::try_parse_from(args).is_err(),
+            "{args:?}"
+        );
+    }
+}
@@ -0,0 +1,154 @@
+//! # Checking the kernel's random number generator
+//!
+//! Filesystem UUIDs are generated via getrandom(2), which blocks until the kernel's
+//! random number generator is initialized; on a freshly booted minimal installer
+//! without a hardware RNG that can take a long time.  Before partitioning, the
+//! generator is probed without blocking so that a stall is explained by a warning,
+//! along with whether a hardware RNG is feeding the kernel.  The entropy estimate is
+//! only informational: since Linux 5.18 it is always 256 bits.
+//!
+//! LUKS keys are drawn from the same generator; for those a hardware RNG is preferred,
+//! so its absence is warned about even once the generator is initialized.
+
+use std::io::Read;
+
+use anyhow::{Context, Result};
+use cap_std::fs::Dir;
+use cap_std_ext::cap_std;
+use cap_std_ext::dirext::CapStdExtDirExt;
+
+/// The entropy estimate of the kernel in bits, relative to the root
+const ENTROPY_AVAIL: &str = "proc/sys/kernel/random/entropy_avail";
+/// The hardware RNG in use by the kernel, relative to the root
+const HWRNG_CURRENT: &str = "sys/class/misc/hw_random/rng_current";
+
+/// Read a file which may be missing, e.g. as /sys is not mounted.
+fn read_optional(root: &Dir, path: &str) -> Result<Option<String>> {
+    let mut f = if let Some(f) = root.open_optional(path)? {
+        f
+    } else {
+        return Ok(None);
+    };
+    let mut buf = String::new();
+    f.read_to_string(&mut buf)
+        .with_context(|| format!("Reading {path}"))?;
+    Ok(Some(buf.trim().to_string()))
+}
+
+/// Whether the kernel's random number generator is initialized, i.e. getrandom(2)
+/// would not block.
+pub(crate) fn rng_initialized() -> Result<bool> {
+    let mut buf = [0u8; 1];
+    // SAFETY: The buffer is valid for writes of its length
+    #[allow(unsafe_code)]
+    let r = unsafe { libc::getrandom(buf.as_mut_ptr().cast(), buf.len(), libc::GRND_NONBLOCK) };
+    if r >= 0 {
+        return Ok(true);
+    }
+    let e = std::io::Error::last_os_error();
+    if e.raw_os_error() == Some(libc::EAGAIN) {
+        Ok(false)
+    } else {
+        Err(e).context("Probing getrandom")
+    }
+}
+
+/// The warning for the provided generator state, entropy estimate in bits and hardware
+/// RNG, if any.
+fn entropy_warning(
+    initialized: bool,
+    entropy_avail: Option<u32>,
+    hwrng: Option<&str>,
+    luks: bool,
+) -> Option<String> {
+    if initialized {
+        if luks && hwrng.is_none() {
+            return Some(
+                "No hardware RNG is available; LUKS keys will be generated from the kernel's \
+                 random number generator alone, consider e.g. a TPM or virtio-rng"
+                    .to_string(),
+            );
+        }
+        return None;
+    }
+    let mut msg = "The kernel's random number generator is not yet initialized".to_string();
+    if let Some(bits) = entropy_avail {
+        msg.push_str(&format!(" ({bits} bits of entropy)"));
+    }
+    msg.push_str("; generating filesystem UUIDs may block until it is");
+    match hwrng {
+        Some(rng) => msg.push_str(&format!(" (being seeded from hardware RNG {rng})")),
+        None => msg.push_str("; no hardware RNG is available, consider e.g. virtio-rng"),
+    }
+    Some(msg)
+}
+
+/// Check the kernel's random number generator, with the provided state and via the
+/// provided host root; returns the warnings, e.g. if it is not yet initialized.
+pub(crate) fn check(root: &Dir, initialized: bool, luks: bool) -> Result<Vec<String>> {
+    let mut warnings = Vec::new();
+    let entropy_avail = read_optional(root, ENTROPY_AVAIL)?.and_then(|v| {
+        v.parse::<u32>()
+            .map_err(|e| warnings.push(format!("Parsing {ENTROPY_AVAIL}: {v}: {e}")))
+            .ok()
+    });
+    let hwrng = read_optional(root, HWRNG_CURRENT)?.filter(|v| !v.is_empty() && v != "none");
+    tracing::debug!(
+        "RNG initialized: {initialized}; entropy: {entropy_avail:?} bits; hardware RNG: {hwrng:?}"
+    );
+    warnings.extend(entropy_warning(
+        initialized,
+        entropy_avail,
+        hwrng.as_deref(),
+        luks,
+    ));
+    Ok(warnings)
+}
+
+#[test]
+fn test_entropy_warning() {
+    // The estimate doesn't matter once initialized
+    for bits in [None, Some(0), Some(256)] {
+        assert_eq!(entropy_warning(true, bits, None, false), None, "{bits:?}");
+    }
+    assert_eq!(entropy_warning(true, None, Some("tpm-rng-0"), true), None);
+    let w = entropy_warning(true, Some(256), None, true).unwrap();
+    assert!(w.contains("LUKS keys"), "{w}");
+    let w = entropy_warning(false, Some(127), None, false).unwrap();
+    assert!(w.contains("not yet initialized (127 bits"), "{w}");
+    assert!(w.contains("no hardware RNG"), "{w}");
+    let w = entropy_warning(false, None, Some("tpm-rng-0"), true).unwrap();
+    assert!(w.contains("initialized; generating"), "{w}");
+    assert!(w.contains("hardware RNG tpm-rng-0"), "{w}");
+}
+
+#[test]
+fn test_check_entropy() {
+    let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    // Nothing to check
+    assert!(check(&td, true, false).unwrap().is_empty());
+    assert!(check(&td, false, false).unwrap()[0].contains("no hardware RNG"));
+    td.create_dir_all("proc/sys/kernel/random").unwrap();
+    td.write(ENTROPY_AVAIL, "256\n").unwrap();
+    assert!(check(&td, true, false).unwrap().is_empty());
+    td.write(ENTROPY_AVAIL, "42\n").unwrap();
+    assert!(check(&td, true, false).unwrap().is_empty());
+    assert!(check(&td, false, false).unwrap()[0].contains("(42 bits"));
+    td.create_dir_all("sys/class/misc/hw_random").unwrap();
+    td.write(HWRNG_CURRENT, "none\n").unwrap();
+    assert!(check(&td, true, true).unwrap()[0].contains("No hardware RNG"));
+    td.write(HWRNG_CURRENT, "virtio_rng.0\n").unwrap();
+    assert!(check(&td, true, true).unwrap().is_empty());
+    assert!(check(&td, false, false).unwrap()[0].contains("hardware RNG virtio_rng.0"));
+    // A bogus estimate is only a warning
+    td.write(ENTROPY_AVAIL, "bogus\n").unwrap();
+    let w = check(&td, true, false).unwrap();
+    assert_eq!(w.len(), 1);
+    assert!(w[0].contains("Parsing"), "{}", w[0]);
+}
+
+#[test]
+fn test_rng_initialized() {
+    // Anything running these tests has long since initialized the generator
+    assert!(rng_initialized().unwrap());
+}
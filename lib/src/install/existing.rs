@@ -0,0 +1,269 @@
+//! # Checking the existing installation before wiping
+//!
+//! When reinstalling, `--require-existing-image` guards against wiping a disk which
+//! does not contain a previous installation of the expected image, according to the
+//! aleph file written by that installation.  Reading the previous installation is
+//! best-effort: anything which can't be read is treated as not being an installation.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use camino::Utf8Path;
+use cap_std::fs::Dir;
+use cap_std_ext::cap_std;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use serde::Deserialize;
+
+use super::diagnostics::Diagnostics;
+use super::BOOTC_ALEPH_PATH;
+
+/// The fields we need from the aleph of a previous installation; older versions
+/// may lack others.
+#[derive(Debug, Deserialize)]
+struct ExistingAleph {
+    image: String,
+}
+
+/// Match a shell-style glob, where `*` matches any (possibly empty) sequence of
+/// characters including `/`, and `?` any single character.
+pub(crate) fn glob_matches(pattern: &str, s: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let s = s.chars().collect::<Vec<_>>();
+    let (mut p, mut i) = (0, 0);
+    // The position of the last `*` in the pattern, and where in `s` it matched up to
+    let mut backtrack = None;
+    while i < s.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, i));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == s[i] => {
+                p += 1;
+                i += 1;
+            }
+            _ => match backtrack {
+                // Have the last `*` match one more character, and retry
+                Some((star, matched)) => {
+                    p = star + 1;
+                    i = matched + 1;
+                    backtrack = Some((star, i));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Find a path in an existing root, returning where it was found; for an ostree-based
+/// root, the first deployment with it is used.
+pub(crate) fn find_in_root(root: &Dir, path: &str) -> Result<Option<PathBuf>> {
+    if root.symlink_metadata_optional(path)?.is_some() {
+        return Ok(Some(path.into()));
+    }
+    let deploy = if let Some(d) = root.open_dir_optional("ostree/deploy")? {
+        d
+    } else {
+        return Ok(None);
+    };
+    let mut deployments = Vec::new();
+    for stateroot in deploy.entries()? {
+        let stateroot = stateroot?;
+        if !stateroot.file_type()?.is_dir() {
+            continue;
+        }
+        let name = stateroot.file_name();
+        let stateroot = deploy.open_dir(&name)?;
+        if let Some(d) = stateroot.open_dir_optional("deploy")? {
+            for e in d.entries()? {
+                let e = e?;
+                if e.file_type()?.is_dir() {
+                    deployments.push((name.clone(), e.file_name()));
+                }
+            }
+        }
+    }
+    deployments.sort();
+    for (stateroot, deployment) in deployments {
+        let path = Path::new("ostree/deploy")
+            .join(stateroot)
+            .join("deploy")
+            .join(deployment)
+            .join(path);
+        if root.symlink_metadata_optional(&path)?.is_some() {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+/// Read the image recorded by the installation on the provided root, if any.
+fn read_existing_image(root: &Dir) -> Result<Option<String>> {
+    let f = if let Some(f) = root.open_optional(BOOTC_ALEPH_PATH)? {
+        f
+    } else {
+        return Ok(None);
+    };
+    let aleph: ExistingAleph = serde_json::from_reader(std::io::BufReader::new(f))?;
+    Ok(Some(aleph.image))
+}
+
+/// Like [`read_existing_image`], but any error is only a warning.
+fn find_existing_image(root: &Dir, diagnostics: &Diagnostics) -> Option<String> {
+    read_existing_image(root)
+        .map_err(|e| diagnostics.warn(format!("Failed to read {BOOTC_ALEPH_PATH}: {e}")))
+        .ok()
+        .flatten()
+}
+
+/// Fail unless the image of the existing installation matches the pattern; with
+/// `force`, only warn.
+fn check_existing_image(
+    existing: Option<&str>,
+    pattern: &str,
+    force: bool,
+    diagnostics: &Diagnostics,
+) -> Result<()> {
+    let err = match existing {
+        Some(image) if glob_matches(pattern, image) => {
+            println!("Replacing existing installation of {image}");
+            return Ok(());
+        }
+        Some(image) => format!("Existing installation of {image} does not match {pattern}"),
+        None => "No existing installation found".to_string(),
+    };
+    if force {
+        diagnostics.warn(format!("{err}; continuing due to --force"));
+        Ok(())
+    } else {
+        anyhow::bail!("{err}; refusing to wipe (use --force to override)")
+    }
+}
+
+/// Check the existing installation on the provided target root filesystem.
+#[context("Checking existing installation")]
+pub(crate) fn check_root(
+    root: &Dir,
+    pattern: &str,
+    force: bool,
+    diagnostics: &Diagnostics,
+) -> Result<()> {
+    let existing = find_existing_image(root, diagnostics);
+    check_existing_image(existing.as_deref(), pattern, force, diagnostics)
+}
+
+/// Find the image of the existing installation on the provided device, by mounting
+/// each filesystem on it read-only.
+fn find_existing_image_on_device(
+    device: &Utf8Path,
+    mntdir: &Utf8Path,
+    diagnostics: &Diagnostics,
+) -> Result<Option<String>> {
+    let device = crate::blockdev::list_dev(device)?;
+    let candidates = match device.children.as_ref() {
+        Some(children) if !children.is_empty() => children.iter().map(|c| c.path()).collect(),
+        _ => vec![device.path()],
+    };
+    std::fs::create_dir_all(mntdir)?;
+    for dev in candidates {
+        if let Err(e) = crate::mount::mount_readonly(&dev, mntdir) {
+            tracing::debug!("{e:#}");
+            continue;
+        }
+        let image = Dir::open_ambient_dir(mntdir, cap_std::ambient_authority())
+            .map(|d| find_existing_image(&d, diagnostics));
+        crate::mount::unmount(mntdir, false)?;
+        if let Some(image) = image? {
+            return Ok(Some(image));
+        }
+    }
+    Ok(None)
+}
+
+/// Check the existing installation on the provided block device.
+#[context("Checking existing installation on {device}")]
+pub(crate) fn check_device(
+    device: &Utf8Path,
+    pattern: &str,
+    force: bool,
+    diagnostics: &Diagnostics,
+) -> Result<()> {
+    // Under the directory which is cleaned up if we're interrupted
+    let mntdir = super::lock::mounts_dir().join("existing");
+    let existing = find_existing_image_on_device(device, &mntdir, diagnostics)?;
+    std::fs::remove_dir(&mntdir)?;
+    check_existing_image(existing.as_deref(), pattern, force, diagnostics)
+}
+
+#[test]
+fn test_glob_matches() {
+    let image = "quay.io/example/os:latest@sha256:0123abcd";
+    for pattern in [
+        image,
+        "*",
+        "quay.io/example/os*",
+        "quay.io/example/*:latest@*",
+        "*/os:*",
+        "quay.io/example/o?:latest@sha256:*",
+        "quay.io/*/os:latest@sha256:0123abc?",
+        "**a*b*c*d",
+    ] {
+        assert!(glob_matches(pattern, image), "{pattern}");
+    }
+    for pattern in [
+        "",
+        "quay.io/example/os",
+        "quay.io/other/*",
+        "*/os:stable*",
+        "quay.io/example/os:latest@sha256:0123abcd?",
+        "?quay.io/*",
+    ] {
+        assert!(!glob_matches(pattern, image), "{pattern}");
+    }
+    assert!(glob_matches("", ""));
+    assert!(glob_matches("*", ""));
+    assert!(!glob_matches("?", ""));
+}
+
+#[test]
+fn test_check_existing_aleph() {
+    let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    let pattern = "quay.io/example/os*";
+    let d = Diagnostics::default();
+    let check_root = |td: &Dir, pattern: &str, force: bool| check_root(td, pattern, force, &d);
+    // No previous installation
+    assert!(check_root(&td, pattern, false).is_err());
+    check_root(&td, pattern, true).unwrap();
+    assert_eq!(
+        d.warnings(),
+        ["No existing installation found; continuing due to --force"]
+    );
+
+    // An aleph written by an older version
+    td.write(
+        BOOTC_ALEPH_PATH,
+        r#"{"image": "quay.io/example/os:latest@sha256:0123abcd", "kernel": "6.2.9-300.fc38.x86_64"}"#,
+    )
+    .unwrap();
+    check_root(&td, pattern, false).unwrap();
+    let e = check_root(&td, "quay.io/other/*", false).unwrap_err();
+    assert!(format!("{e:#}").contains("does not match quay.io/other/*"));
+    check_root(&td, "quay.io/other/*", true).unwrap();
+    assert_eq!(d.warnings().len(), 2);
+
+    // Unreadable alephs are treated as absent
+    for contents in ["", "{}", r#"{"image": 42}"#] {
+        td.write(BOOTC_ALEPH_PATH, contents).unwrap();
+        let d = Diagnostics::default();
+        assert!(find_existing_image(&td, &d).is_none());
+        assert!(
+            d.warnings()[0].contains("Failed to read"),
+            "{:?}",
+            d.warnings()
+        );
+        let e = check_root(&td, "*", false).unwrap_err();
+        assert!(format!("{e:#}").contains("No existing installation"));
+    }
+}
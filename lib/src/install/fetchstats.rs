@@ -0,0 +1,80 @@
+//! # Measuring the bytes fetched for the image
+//!
+//! With partial pulls of zstd:chunked layers, and layers reused from the host's
+//! container storage or an adopted sysroot, the bytes fetched for an installation can
+//! be far less than the size of the image's layers.  As the fetching is done by skopeo,
+//! which only enters the host's mount namespace, the bytes actually transferred are
+//! measured as the bytes received by the network interfaces of our network namespace
+//! meanwhile; this includes any unrelated traffic in the namespace at the same time.
+
+use anyhow::{Context, Result};
+use fn_error_context::context;
+
+/// The statistics of the network interfaces of our network namespace
+const PROC_NET_DEV: &str = "/proc/net/dev";
+/// The loopback interface, whose traffic is not fetched from anywhere
+const LOOPBACK: &str = "lo";
+
+/// The total bytes received by the interfaces other than loopback, from the contents
+/// of `/proc/net/dev`.
+fn parse_received_bytes(s: &str) -> Result<u64> {
+    let mut r = 0u64;
+    // The first two lines are headers
+    for line in s.lines().skip(2) {
+        let (name, stats) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Invalid line in {PROC_NET_DEV}: {line}"))?;
+        if name.trim() == LOOPBACK {
+            continue;
+        }
+        let received = stats
+            .split_whitespace()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Missing received bytes in {PROC_NET_DEV}: {line}"))?;
+        let received: u64 = received
+            .parse()
+            .with_context(|| format!("Parsing received bytes {received}"))?;
+        r = r.saturating_add(received);
+    }
+    Ok(r)
+}
+
+/// The total bytes received by the network interfaces of our network namespace.
+#[context("Querying network statistics")]
+fn received_bytes() -> Result<u64> {
+    let s = std::fs::read_to_string(PROC_NET_DEV)?;
+    parse_received_bytes(&s)
+}
+
+/// Measures the bytes received from when it was started.
+#[derive(Debug)]
+pub(crate) struct FetchCounter {
+    start: u64,
+}
+
+impl FetchCounter {
+    pub(crate) fn start() -> Result<Self> {
+        Ok(Self {
+            start: received_bytes()?,
+        })
+    }
+
+    /// The bytes received since the counter was started.
+    pub(crate) fn finish(self) -> Result<u64> {
+        // Counters are reset if an interface is removed meanwhile
+        Ok(received_bytes()?.saturating_sub(self.start))
+    }
+}
+
+#[test]
+fn test_parse_received_bytes() {
+    let s = "Inter-|   Receive                            |  Transmit\n \
+             face |bytes    packets errs drop fifo frame|bytes    packets\n    \
+             lo: 8123456   12345    0    0    0     0  8123456   12345\n  \
+             eth0: 812345678  601234    0   12    0     0 12345678   98765\n \
+             wlan0:    1000      10    0    0    0     0     2000      20\n";
+    assert_eq!(parse_received_bytes(s).unwrap(), 812346678);
+    let only_headers = s.lines().take(2).collect::<Vec<_>>().join("\n");
+    assert_eq!(parse_received_bytes(&only_headers).unwrap(), 0);
+    assert!(parse_received_bytes(&format!("{only_headers}\n  eth0: many")).is_err());
+}
@@ -0,0 +1,277 @@
+//! # Installing alongside existing partitions
+//!
+//! With `--use-free-space`, the existing GPT partition table of the target device is
+//! kept, e.g. for dual booting with Windows.  The /boot and root partitions are
+//! created in the largest contiguous free space, and the existing EFI system partition
+//! is reused.
+
+use anyhow::Result;
+
+use super::baseline::ESP_TYPECODE;
+use crate::blockdev::PartitionTable;
+
+/// Partitions are aligned to this many bytes, like the tools creating them do.
+const ALIGNMENT_BYTES: u64 = 1024 * 1024;
+/// The size required for the root filesystem if `--root-size` is not given.
+const MIN_ROOT_SIZE_MIB: u64 = 8 * 1024;
+/// The highest partition number in a GPT with the default number of entries.
+const MAX_PARTITION_NUMBER: u32 = 128;
+
+/// A range of sectors, both inclusive.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct Extent {
+    pub(crate) start: u64,
+    pub(crate) end: u64,
+}
+
+impl Extent {
+    /// The number of sectors.
+    fn len(&self) -> u64 {
+        self.end + 1 - self.start
+    }
+
+    /// The argument used to create a partition here with `sgdisk -n`.
+    pub(crate) fn to_sgdisk(self) -> String {
+        format!("{}:{}", self.start, self.end)
+    }
+}
+
+/// A partition to create.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct PlannedPartition {
+    pub(crate) number: u32,
+    pub(crate) extent: Extent,
+}
+
+/// Where to create the /boot and root partitions on the existing partition table.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct FreeSpacePlan {
+    /// The device node of the existing EFI system partition
+    pub(crate) esp: String,
+    pub(crate) esp_number: u32,
+    pub(crate) boot: PlannedPartition,
+    pub(crate) root: PlannedPartition,
+}
+
+/// Find the unpartitioned extents of the table which are at least one aligned unit
+/// in size, with their starts aligned.
+fn free_extents(table: &PartitionTable, sector_size: u64) -> Result<Vec<Extent>> {
+    let (first, last) = match (table.first_lba, table.last_lba) {
+        (Some(first), Some(last)) => (first, last),
+        _ => anyhow::bail!("The partition table does not describe its usable sectors"),
+    };
+    let align = (ALIGNMENT_BYTES / sector_size).max(1);
+    let mut used = table
+        .partitions
+        .iter()
+        .map(|p| (p.start, p.start + p.size))
+        .collect::<Vec<_>>();
+    used.sort_unstable();
+    let mut r = Vec::new();
+    let mut next_free = first;
+    for (start, end) in used
+        .into_iter()
+        .chain(std::iter::once((last + 1, last + 1)))
+    {
+        let aligned = (next_free + align - 1) / align * align;
+        if start >= aligned + align {
+            r.push(Extent {
+                start: aligned,
+                end: start - 1,
+            });
+        }
+        next_free = next_free.max(end);
+    }
+    Ok(r)
+}
+
+/// Plan creating the /boot and root partitions, of the provided sizes in MiB, in the
+/// largest free extent of the table.  Without a root size, the root partition uses
+/// the rest of the extent.
+pub(crate) fn plan(
+    table: &PartitionTable,
+    sector_size: u64,
+    boot_size: u64,
+    root_size: Option<u64>,
+) -> Result<FreeSpacePlan> {
+    if table.label != "gpt" {
+        anyhow::bail!(
+            "Installing into free space requires a GPT partition table, found {}",
+            table.label
+        );
+    }
+    let mut esps = table
+        .partitions
+        .iter()
+        .filter(|p| p.parttype.eq_ignore_ascii_case(ESP_TYPECODE));
+    let esp = match (esps.next(), esps.next()) {
+        (Some(esp), None) => esp,
+        (Some(a), Some(b)) => {
+            anyhow::bail!(
+                "Found multiple EFI system partitions ({} and {})",
+                a.node,
+                b.node
+            )
+        }
+        (None, _) => anyhow::bail!("No existing EFI system partition found"),
+    };
+
+    let mib = ALIGNMENT_BYTES / sector_size;
+    let boot_sectors = boot_size * mib;
+    let required = boot_sectors + root_size.unwrap_or(MIN_ROOT_SIZE_MIB) * mib;
+    let largest = free_extents(table, sector_size)?
+        .into_iter()
+        .max_by_key(|e| e.len());
+    let extent = match largest {
+        Some(e) if e.len() >= required => e,
+        largest => anyhow::bail!(
+            "Insufficient contiguous free space: {} MiB required, largest free extent is {} MiB",
+            required / mib,
+            largest.map_or(0, |e| e.len() / mib)
+        ),
+    };
+
+    let used = table
+        .partitions
+        .iter()
+        .map(|p| p.number())
+        .collect::<Result<Vec<_>>>()?;
+    let mut numbers = (1..=MAX_PARTITION_NUMBER).filter(|n| !used.contains(n));
+    let (boot_number, root_number) = match (numbers.next(), numbers.next()) {
+        (Some(boot), Some(root)) => (boot, root),
+        _ => anyhow::bail!("No free partition numbers"),
+    };
+
+    let boot = Extent {
+        start: extent.start,
+        end: extent.start + boot_sectors - 1,
+    };
+    let root_start = boot.end + 1;
+    let root = Extent {
+        start: root_start,
+        end: root_size.map_or(extent.end, |size| root_start + size * mib - 1),
+    };
+    Ok(FreeSpacePlan {
+        esp: esp.node.clone(),
+        esp_number: esp.number()?,
+        boot: PlannedPartition {
+            number: boot_number,
+            extent: boot,
+        },
+        root: PlannedPartition {
+            number: root_number,
+            extent: root,
+        },
+    })
+}
+
+#[test]
+fn test_plan_free_space() {
+    // A 256 GiB disk with Windows, with space freed up by shrinking its partition
+    let table = |partitions: serde_json::Value| -> PartitionTable {
+        serde_json::from_value(serde_json::json!({
+            "label": "gpt",
+            "first-lba": 34,
+            "last-lba": 536870878,
+            "partitions": partitions,
+        }))
+        .unwrap()
+    };
+    let windows = table(serde_json::json!([
+        {
+            "node": "/dev/nvme0n1p1", "start": 2048, "size": 204800,
+            "type": "C12A7328-F81F-11D2-BA4B-00A0C93EC93B", "name": "EFI system partition"
+        },
+        {
+            "node": "/dev/nvme0n1p2", "start": 206848, "size": 32768,
+            "type": "E3C9E316-0B5C-4DB8-817D-F92DF00215AE", "name": "Microsoft reserved partition"
+        },
+        {
+            "node": "/dev/nvme0n1p3", "start": 239616, "size": 209715200,
+            "type": "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7", "name": "Basic data partition"
+        },
+        {
+            "node": "/dev/nvme0n1p4", "start": 534773760, "size": 2095104,
+            "type": "DE94BBA4-06D1-4D40-A16A-BFD50179D6AC"
+        }
+    ]));
+    // The free space between the Windows and recovery partitions
+    let free = Extent {
+        start: 209954816,
+        end: 534773759,
+    };
+    assert_eq!(free_extents(&windows, 512).unwrap(), [free]);
+
+    let p = plan(&windows, 512, 510, None).unwrap();
+    assert_eq!(p.esp, "/dev/nvme0n1p1");
+    assert_eq!(p.esp_number, 1);
+    assert_eq!(
+        p.boot,
+        PlannedPartition {
+            number: 5,
+            extent: Extent {
+                start: free.start,
+                end: free.start + 510 * 2048 - 1
+            }
+        }
+    );
+    assert_eq!(p.root.number, 6);
+    assert_eq!(p.root.extent.start, free.start + 510 * 2048);
+    assert_eq!(p.root.extent.end, free.end);
+    assert_eq!(p.boot.extent.to_sgdisk(), "209954816:210999295");
+
+    let p = plan(&windows, 512, 510, Some(20 * 1024)).unwrap();
+    assert_eq!(p.root.extent.len(), 20 * 1024 * 2048);
+    // There are about 155 GiB free
+    let e = plan(&windows, 512, 510, Some(200 * 1024)).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "Insufficient contiguous free space: 205310 MiB required, largest free extent is 158603 MiB"
+    );
+
+    // Unaligned free space at the start, and the space at the end
+    let t = table(serde_json::json!([
+        {
+            "node": "/dev/sda2", "start": 4000, "size": 204800,
+            "type": "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
+        },
+        {
+            "node": "/dev/sda3", "start": 208800, "size": 268435456,
+            "type": "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7"
+        }
+    ]));
+    assert_eq!(
+        free_extents(&t, 512).unwrap(),
+        [Extent {
+            start: 268644352,
+            end: 536870878
+        }]
+    );
+    let p = plan(&t, 512, 510, None).unwrap();
+    assert_eq!((p.esp_number, p.boot.number, p.root.number), (2, 1, 4));
+    // 4Kn disks
+    let t4k: PartitionTable = serde_json::from_value(serde_json::json!({
+        "label": "gpt",
+        "first-lba": 6,
+        "last-lba": 67108858,
+        "partitions": [{
+            "node": "/dev/sda1", "start": 256, "size": 25600,
+            "type": "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
+        }]
+    }))
+    .unwrap();
+    assert_eq!(
+        free_extents(&t4k, 4096).unwrap(),
+        [Extent {
+            start: 25856,
+            end: 67108858
+        }]
+    );
+
+    let no_esp = table(serde_json::json!([]));
+    let e = plan(&no_esp, 512, 510, None).unwrap_err();
+    assert_eq!(e.to_string(), "No existing EFI system partition found");
+    let dos: PartitionTable =
+        serde_json::from_value(serde_json::json!({ "label": "dos", "partitions": [] })).unwrap();
+    assert!(plan(&dos, 512, 510, None).is_err());
+}
@@ -0,0 +1,274 @@
+//! # Filesystem feature compatibility
+//!
+//! Creating a filesystem with features the target kernel can't mount results in an
+//! unbootable system.  The default features are chosen by the `mkfs` shipped in the
+//! image itself, and so generally match its kernel; this checks the features
+//! explicitly requested via mkfs options against a table of known minimum kernel versions.
+
+use anyhow::{Context, Result};
+use cap_std::fs::Dir;
+use cap_std_ext::cap_std;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+
+use super::baseline::Filesystem;
+use super::diagnostics::Diagnostics;
+
+/// A kernel version, as (major, minor)
+pub(crate) type KernelVersion = (u32, u32);
+
+/// Filesystem features, and the kernel version which introduced support for them.
+const EXT4_FEATURES: &[(&str, KernelVersion)] = &[
+    ("inline_data", (3, 8)),
+    ("metadata_csum", (3, 18)),
+    ("encrypt", (4, 1)),
+    ("metadata_csum_seed", (4, 4)),
+    ("large_dir", (4, 13)),
+    ("ea_inode", (4, 13)),
+    ("casefold", (5, 2)),
+    ("verity", (5, 4)),
+    ("stable_inodes", (5, 5)),
+    ("fast_commit", (5, 10)),
+    ("orphan_file", (5, 15)),
+];
+const XFS_FEATURES: &[(&str, KernelVersion)] = &[
+    ("finobt", (3, 16)),
+    ("sparse", (4, 2)),
+    ("rmapbt", (4, 8)),
+    ("reflink", (4, 9)),
+    ("bigtime", (5, 10)),
+    ("inobtcount", (5, 10)),
+    ("nrext64", (5, 19)),
+];
+const BTRFS_FEATURES: &[(&str, KernelVersion)] = &[
+    ("extref", (3, 7)),
+    ("skinny-metadata", (3, 10)),
+    ("no-holes", (3, 14)),
+    ("free-space-tree", (4, 5)),
+    ("raid1c34", (5, 5)),
+    ("zoned", (5, 12)),
+    ("block-group-tree", (6, 1)),
+    ("raid-stripe-tree", (6, 7)),
+    ("squota", (6, 7)),
+];
+
+fn feature_table(fs: Filesystem) -> &'static [(&'static str, KernelVersion)] {
+    match fs {
+        Filesystem::Ext4 => EXT4_FEATURES,
+        Filesystem::Xfs => XFS_FEATURES,
+        Filesystem::Btrfs => BTRFS_FEATURES,
+    }
+}
+
+/// Extract the features enabled by the provided mkfs options.
+pub(crate) fn requested_features(fs: Filesystem, opts: &[String]) -> Vec<String> {
+    // The options whose values are a list of features (or for xfs, suboptions)
+    let list_opts: &[&str] = match fs {
+        Filesystem::Ext4 => &["-O"],
+        Filesystem::Btrfs => &["-O", "--features", "-R", "--runtime-features"],
+        Filesystem::Xfs => &["-m", "-i", "-n", "-r"],
+    };
+    let mut values = Vec::new();
+    let mut opts = opts.iter().map(|s| s.as_str());
+    while let Some(opt) = opts.next() {
+        if list_opts.contains(&opt) {
+            values.extend(opts.next());
+        } else if let Some(v) = list_opts.iter().find_map(|o| {
+            opt.strip_prefix(o)
+                .map(|v| v.strip_prefix('=').unwrap_or(v))
+                .filter(|v| !v.is_empty())
+        }) {
+            values.push(v);
+        }
+    }
+    values
+        .into_iter()
+        .flat_map(|v| v.split(','))
+        .filter_map(|v| match fs {
+            // Suboptions like bigtime=1; a bare suboption means enabled
+            Filesystem::Xfs => match v.split_once('=') {
+                Some((k, v)) if v != "0" => Some(k),
+                Some(_) => None,
+                None => Some(v),
+            },
+            // A leading ^ disables the feature
+            Filesystem::Ext4 | Filesystem::Btrfs => (!v.starts_with('^')).then_some(v),
+        })
+        .filter(|v| !v.is_empty())
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+/// Return the requested features (with their minimum kernel version) which are
+/// not supported by the provided kernel.  Unknown features are assumed supported.
+pub(crate) fn unsupported_features(
+    fs: Filesystem,
+    features: &[String],
+    kernel: KernelVersion,
+) -> Vec<(String, KernelVersion)> {
+    let table = feature_table(fs);
+    features
+        .iter()
+        .filter_map(|f| {
+            table
+                .iter()
+                .find(|(name, _)| name == f)
+                .filter(|(_, min)| kernel < *min)
+                .map(|(_, min)| (f.clone(), *min))
+        })
+        .collect()
+}
+
+/// Parse the major and minor version from a kernel release string such as `6.0.9-300.fc37.x86_64`.
+pub(crate) fn parse_kernel_version(release: &str) -> Option<KernelVersion> {
+    let mut parts = release.split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// Find the version of the kernel shipped in the provided root; if there are multiple,
+/// the oldest is returned.
+#[context("Finding kernel version")]
+pub(crate) fn find_kernel_version(root: &Dir) -> Result<Option<KernelVersion>> {
+    let modules = if let Some(d) = root.open_dir_optional("usr/lib/modules")? {
+        d
+    } else {
+        return Ok(None);
+    };
+    let mut r: Option<KernelVersion> = None;
+    for e in modules.entries()? {
+        let e = e?;
+        let name = e.file_name();
+        let name = if let Some(n) = name.to_str() {
+            n
+        } else {
+            continue;
+        };
+        if !modules.try_exists(format!("{name}/vmlinuz"))? {
+            continue;
+        }
+        if let Some(v) = parse_kernel_version(name) {
+            r = Some(r.map_or(v, |prev| prev.min(v)));
+        }
+    }
+    Ok(r)
+}
+
+/// Verify that the features requested via mkfs options are supported by the image's kernel.
+#[context("Checking filesystem features")]
+pub(crate) fn check_mkfs_opts(
+    fs: Filesystem,
+    opts: &[String],
+    diagnostics: &Diagnostics,
+) -> Result<()> {
+    let features = requested_features(fs, opts);
+    if features.is_empty() {
+        return Ok(());
+    }
+    let root = Dir::open_ambient_dir("/", cap_std::ambient_authority()).context("Opening /")?;
+    let kernel = if let Some(k) = find_kernel_version(&root)? {
+        k
+    } else {
+        diagnostics.warn("Failed to find kernel version; not checking filesystem features");
+        return Ok(());
+    };
+    let unsupported = unsupported_features(fs, &features, kernel);
+    if !unsupported.is_empty() {
+        let unsupported = unsupported
+            .iter()
+            .map(|(f, (major, minor))| format!("{f} (requires {major}.{minor})"))
+            .collect::<Vec<_>>();
+        anyhow::bail!(
+            "The kernel ({}.{}) does not support {fs} features: {}",
+            kernel.0,
+            kernel.1,
+            unsupported.join(", ")
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_requested_features() {
+    let opts = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        requested_features(
+            Filesystem::Ext4,
+            &opts(&[
+                "-O",
+                "orphan_file,^has_journal",
+                "-Ofast_commit",
+                "-E",
+                "foo"
+            ])
+        ),
+        ["orphan_file", "fast_commit"]
+    );
+    assert_eq!(
+        requested_features(
+            Filesystem::Xfs,
+            &opts(&["-m", "bigtime=1,reflink=0,crc=1", "-i", "nrext64", "-f"])
+        ),
+        ["bigtime", "crc", "nrext64"]
+    );
+    assert_eq!(
+        requested_features(
+            Filesystem::Btrfs,
+            &opts(&[
+                "--features=block-group-tree,^no-holes",
+                "-R",
+                "free-space-tree"
+            ])
+        ),
+        ["block-group-tree", "free-space-tree"]
+    );
+    assert!(requested_features(Filesystem::Ext4, &opts(&["-O"])).is_empty());
+    assert!(requested_features(Filesystem::Xfs, &[]).is_empty());
+}
+
+#[test]
+fn test_unsupported_features() {
+    let features = ["orphan_file", "metadata_csum", "unknown"].map(String::from);
+    assert_eq!(
+        unsupported_features(Filesystem::Ext4, &features, (5, 14)),
+        [("orphan_file".to_string(), (5, 15))]
+    );
+    assert!(unsupported_features(Filesystem::Ext4, &features, (5, 15)).is_empty());
+    assert!(unsupported_features(Filesystem::Ext4, &features, (6, 0)).is_empty());
+    assert_eq!(
+        unsupported_features(Filesystem::Ext4, &features, (3, 10)).len(),
+        2
+    );
+    let features = ["bigtime".to_string()];
+    assert_eq!(
+        unsupported_features(Filesystem::Xfs, &features, (4, 18)),
+        [("bigtime".to_string(), (5, 10))]
+    );
+    // Feature names are specific to the filesystem
+    assert!(unsupported_features(Filesystem::Btrfs, &features, (4, 18)).is_empty());
+}
+
+#[test]
+fn test_kernel_version() {
+    assert_eq!(parse_kernel_version("6.0.9-300.fc37.x86_64"), Some((6, 0)));
+    assert_eq!(parse_kernel_version("4.18.0-425.el8.x86_64"), Some((4, 18)));
+    assert_eq!(parse_kernel_version("5.15"), Some((5, 15)));
+    assert_eq!(parse_kernel_version("foo"), None);
+    assert_eq!(parse_kernel_version("6"), None);
+
+    let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    assert_eq!(find_kernel_version(&td).unwrap(), None);
+    for (v, vmlinuz) in [
+        ("6.0.9-300.fc37.x86_64", true),
+        ("5.19.1-200.fc36.x86_64", true),
+        ("4.18.0-425.el8.x86_64", false),
+    ] {
+        let d = format!("usr/lib/modules/{v}");
+        td.create_dir_all(&d).unwrap();
+        if vmlinuz {
+            td.write(format!("{d}/vmlinuz"), "").unwrap();
+        }
+    }
+    assert_eq!(find_kernel_version(&td).unwrap(), Some((5, 19)));
+}
@@ -0,0 +1,204 @@
+//! # Persistent journal
+//!
+//! With the default `Storage=auto`, systemd-journald only stores logs persistently
+//! if `/var/log/journal` exists.  Since `/var` is not populated from the image on
+//! ostree systems, but shared between the deployments of a stateroot, the directory
+//! is created in the stateroot's `/var` with the ownership and mode systemd's
+//! tmpfiles.d(5) configuration would give it.
+
+use std::fmt::Display;
+use std::os::unix::fs::PermissionsExt;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use fn_error_context::context;
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+
+/// The journal directory in the installed system
+pub(crate) const JOURNAL_DIR: &str = "/var/log/journal";
+/// The group owning the journal directory
+const JOURNAL_GROUP: &str = "systemd-journal";
+/// The mode of the journal directory; files created in it inherit its group
+const JOURNAL_DIR_MODE: u32 = 0o2755;
+/// The path of our journald.conf drop-in, relative to the deployment
+pub(crate) const DROPIN_PATH: &str = "etc/systemd/journald.conf.d/50-bootc-persistent.conf";
+
+/// A setting for the `[Journal]` section of journald.conf(5).
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeFromStr, SerializeDisplay)]
+pub(crate) struct JournaldSetting {
+    key: String,
+    value: String,
+}
+
+impl FromStr for JournaldSetting {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Invalid journald setting {s}: expected KEY=VALUE"))?;
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric()) {
+            anyhow::bail!("Invalid journald setting key {key:?}");
+        }
+        if value.contains('\n') {
+            anyhow::bail!("Invalid journald setting value for {key}");
+        }
+        Ok(Self {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+impl Display for JournaldSetting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.key, self.value)
+    }
+}
+
+/// Find the GID of a group in the deployment, which may be defined in `/etc/group` or
+/// (with nss-altfiles) `/usr/lib/group`.
+fn find_gid(deployment_root: &Utf8Path, name: &str) -> Result<Option<u32>> {
+    for path in ["etc/group", "usr/lib/group"] {
+        let path = deployment_root.join(path);
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("Reading {path}")),
+        };
+        let gid = contents.lines().find_map(|line| {
+            let mut fields = line.split(':');
+            (fields.next() == Some(name))
+                .then(|| fields.nth(1))
+                .flatten()
+        });
+        if let Some(gid) = gid {
+            let gid = gid
+                .parse()
+                .with_context(|| format!("Parsing GID of {name} in {path}"))?;
+            return Ok(Some(gid));
+        }
+    }
+    Ok(None)
+}
+
+/// Create the journal directory in the provided `/var`, owned by the journal group of
+/// the deployment if it has one.  Returns the path of the directory.
+#[context("Creating journal directory")]
+pub(crate) fn create_journal_dir(
+    var: &Utf8Path,
+    deployment_root: &Utf8Path,
+) -> Result<Utf8PathBuf> {
+    let dir = var.join(JOURNAL_DIR.trim_start_matches("/var/"));
+    std::fs::create_dir_all(&dir).with_context(|| format!("Creating {dir}"))?;
+    // Otherwise systemd-tmpfiles fixes the group at boot
+    if let Some(gid) = find_gid(deployment_root, JOURNAL_GROUP)? {
+        nix::unistd::chown(dir.as_std_path(), None, Some(gid.into()))
+            .with_context(|| format!("Setting ownership of {dir}"))?;
+    }
+    // This must come after chown, which clears the setgid bit
+    std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(JOURNAL_DIR_MODE))?;
+    Ok(dir)
+}
+
+/// The contents of our journald.conf drop-in.
+fn dropin_contents(settings: &[JournaldSetting]) -> String {
+    let mut r = String::from(
+        "# Created by bootc install --persistent-journal\n[Journal]\nStorage=persistent\n",
+    );
+    for setting in settings {
+        r.push_str(&format!("{setting}\n"));
+    }
+    r
+}
+
+/// Write our journald.conf drop-in into the deployment, returning its path.
+#[context("Writing journald.conf drop-in")]
+pub(crate) fn write_dropin(
+    deployment_root: &Utf8Path,
+    settings: &[JournaldSetting],
+) -> Result<Utf8PathBuf> {
+    let path = deployment_root.join(DROPIN_PATH);
+    // SAFETY: The path has a parent
+    let dir = path.parent().unwrap();
+    std::fs::create_dir_all(dir).with_context(|| format!("Creating {dir}"))?;
+    std::fs::write(&path, dropin_contents(settings)).with_context(|| format!("Writing {path}"))?;
+    Ok(path)
+}
+
+#[test]
+fn test_journald_setting() {
+    let s: JournaldSetting = "SystemMaxUse=500M".parse().unwrap();
+    assert_eq!(s.to_string(), "SystemMaxUse=500M");
+    let s: JournaldSetting = "ForwardToConsole=".parse().unwrap();
+    assert_eq!(s.value, "");
+    for invalid in [
+        "Storage",
+        "=yes",
+        "Max Use=1G",
+        "[Journal]=x",
+        "Compress=yes\n[Upload]",
+    ] {
+        assert!(JournaldSetting::from_str(invalid).is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn test_persistent_journal() {
+    use std::os::unix::fs::MetadataExt;
+    let td = tempfile::tempdir().unwrap();
+    let td = Utf8Path::from_path(td.path()).unwrap();
+    let var = td.join("ostree/deploy/default/var");
+    let deployment_root = td.join("ostree/deploy/default/deploy/abcd.0");
+    std::fs::create_dir_all(deployment_root.join("usr/lib")).unwrap();
+    std::fs::create_dir_all(&var).unwrap();
+
+    assert_eq!(find_gid(&deployment_root, JOURNAL_GROUP).unwrap(), None);
+    let dir = create_journal_dir(&var, &deployment_root).unwrap();
+    assert_eq!(dir, var.join("log/journal"));
+    let meta = dir.metadata().unwrap();
+    assert!(meta.is_dir());
+    assert_eq!(meta.mode() & 0o7777, JOURNAL_DIR_MODE);
+    // Existing directories are fine
+    create_journal_dir(&var, &deployment_root).unwrap();
+
+    let gid = nix::unistd::getgid().as_raw();
+    std::fs::write(
+        deployment_root.join("usr/lib/group"),
+        format!("root:x:0:\nsystemd-journal:x:{gid}:\n"),
+    )
+    .unwrap();
+    assert_eq!(
+        find_gid(&deployment_root, JOURNAL_GROUP).unwrap(),
+        Some(gid)
+    );
+    let meta = create_journal_dir(&var, &deployment_root)
+        .unwrap()
+        .metadata()
+        .unwrap();
+    assert_eq!((meta.gid(), meta.mode() & 0o7777), (gid, JOURNAL_DIR_MODE));
+    // /etc/group takes precedence
+    std::fs::create_dir(deployment_root.join("etc")).unwrap();
+    std::fs::write(
+        deployment_root.join("etc/group"),
+        "systemd-journal:x:190:\n",
+    )
+    .unwrap();
+    assert_eq!(
+        find_gid(&deployment_root, JOURNAL_GROUP).unwrap(),
+        Some(190)
+    );
+
+    let settings = ["SystemMaxUse=1G", "Compress=no"].map(|s| s.parse().unwrap());
+    let path = write_dropin(&deployment_root, &settings).unwrap();
+    assert_eq!(path, deployment_root.join(DROPIN_PATH));
+    assert_eq!(
+        std::fs::read_to_string(path).unwrap(),
+        "# Created by bootc install --persistent-journal\n\
+         [Journal]\n\
+         Storage=persistent\n\
+         SystemMaxUse=1G\n\
+         Compress=no\n"
+    );
+}
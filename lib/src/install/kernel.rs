@@ -0,0 +1,156 @@
+//! # Selecting a kernel
+//!
+//! Some images ship multiple kernels in `/usr/lib/modules`, e.g. a standard and a
+//! realtime one, of which ostree deploys an arbitrary one.  With `--kernel`, the
+//! selected kernel is looked up in the image before modifying the target, and after
+//! deploying, it and its initramfs are copied to `/boot` and referenced by the boot
+//! entries instead.  Updates boot the kernel ostree deploys again.
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std::fs::Dir;
+use cap_std_ext::cap_std;
+use fn_error_context::context;
+
+/// The kernel modules directory, relative to the deployment root
+pub(crate) const MODULES_DIR: &str = "usr/lib/modules";
+/// The directory the selected kernel is installed to, relative to `/boot`
+const BOOT_KERNEL_DIR: &str = "bootc-kernel";
+/// The kernel, relative to its modules directory
+const KERNEL_NAME: &str = "vmlinuz";
+/// The initramfs, relative to its modules directory
+const INITRAMFS_NAME: &str = "initramfs.img";
+
+/// List the versions of the kernels in the provided deployment, sorted.
+fn list_kernels(deployment: &Dir) -> Result<Vec<String>> {
+    let mut r = Vec::new();
+    for e in deployment.read_dir(MODULES_DIR)? {
+        let e = e?;
+        let name = e.file_name();
+        let name = name
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Invalid non-UTF8 filename: {name:?}"))?;
+        let kernel = Utf8Path::new(MODULES_DIR).join(name).join(KERNEL_NAME);
+        if deployment.try_exists(kernel)? {
+            r.push(name.to_string());
+        }
+    }
+    r.sort();
+    Ok(r)
+}
+
+/// Select the requested kernel version among the available ones; without a request,
+/// there must be a single kernel.
+fn select_kernel<'k>(available: &'k [String], requested: Option<&str>) -> Result<&'k str> {
+    let kernel = match (available, requested) {
+        ([], _) => anyhow::bail!("No kernel found in /{MODULES_DIR}"),
+        (_, Some(requested)) => available
+            .iter()
+            .find(|k| k.as_str() == requested)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Kernel {requested} not found in /{MODULES_DIR}; available: {}",
+                    available.join(", ")
+                )
+            })?,
+        ([k], None) => k,
+        (_, None) => anyhow::bail!(
+            "Multiple kernels found in /{MODULES_DIR}: {}",
+            available.join(", ")
+        ),
+    };
+    Ok(kernel.as_str())
+}
+
+/// Find the modules directory of the requested kernel version (or the single kernel)
+/// in the provided deployment, relative to it.
+#[context("Finding kernel")]
+pub(crate) fn find_kernel(deployment: &Dir, requested: Option<&str>) -> Result<Utf8PathBuf> {
+    let available = list_kernels(deployment)?;
+    let kernel = select_kernel(&available, requested)?;
+    Ok(Utf8Path::new(MODULES_DIR).join(kernel))
+}
+
+/// Copy the kernel and initramfs of the provided kernel version from the deployment
+/// to the provided `/boot`, returning their paths relative to `/boot`.
+#[context("Installing kernel {kver}")]
+pub(crate) fn install_kernel(
+    deployment: &Dir,
+    kver: &str,
+    bootfs: &Dir,
+) -> Result<(Utf8PathBuf, Utf8PathBuf)> {
+    let src = find_kernel(deployment, Some(kver))?;
+    let initramfs = src.join(INITRAMFS_NAME);
+    if !deployment.try_exists(&initramfs)? {
+        anyhow::bail!("No initramfs found for the kernel in /{src}");
+    }
+    let target = Utf8Path::new(BOOT_KERNEL_DIR).join(kver);
+    bootfs.create_dir_all(&target)?;
+    for name in [KERNEL_NAME, INITRAMFS_NAME] {
+        let target = target.join(name);
+        deployment
+            .copy(src.join(name), bootfs, &target)
+            .with_context(|| format!("Copying /{src}/{name} to {target}"))?;
+    }
+    Ok((target.join(KERNEL_NAME), target.join(INITRAMFS_NAME)))
+}
+
+#[test]
+fn test_select_kernel() {
+    let standard = "6.2.9-300.fc38.x86_64";
+    let rt = "6.2.9-300.rt14.fc38.x86_64";
+    let available = [standard, rt].map(ToOwned::to_owned);
+    assert_eq!(select_kernel(&available, Some(rt)).unwrap(), rt);
+    assert_eq!(select_kernel(&available, Some(standard)).unwrap(), standard);
+    let e = select_kernel(&available, Some("6.3.0-1.fc38.x86_64")).unwrap_err();
+    assert!(e
+        .to_string()
+        .contains(&format!("available: {standard}, {rt}")));
+    // Only exact versions are accepted
+    assert!(select_kernel(&available, Some("6.2.9")).is_err());
+    assert!(select_kernel(&available, None).is_err());
+    assert_eq!(select_kernel(&available[..1], None).unwrap(), standard);
+    assert!(select_kernel(&[], None).is_err());
+    assert!(select_kernel(&[], Some(standard)).is_err());
+}
+
+#[test]
+fn test_install_kernel() {
+    let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    let rt = "6.2.9-300.rt14.fc38.x86_64";
+    for kver in ["6.2.9-300.fc38.x86_64", rt] {
+        let dir = format!("{MODULES_DIR}/{kver}");
+        td.create_dir_all(&dir).unwrap();
+        td.write(format!("{dir}/{KERNEL_NAME}"), format!("kernel {kver}"))
+            .unwrap();
+    }
+    // Not a kernel
+    td.create_dir_all(format!("{MODULES_DIR}/extra")).unwrap();
+    assert_eq!(list_kernels(&td).unwrap(), ["6.2.9-300.fc38.x86_64", rt]);
+    assert!(find_kernel(&td, None).is_err());
+    assert_eq!(
+        find_kernel(&td, Some(rt)).unwrap(),
+        format!("{MODULES_DIR}/{rt}")
+    );
+
+    let boot = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    // No initramfs
+    assert!(install_kernel(&td, rt, &boot).is_err());
+    td.write(
+        format!("{MODULES_DIR}/{rt}/{INITRAMFS_NAME}"),
+        format!("initramfs {rt}"),
+    )
+    .unwrap();
+    let (kernel, initramfs) = install_kernel(&td, rt, &boot).unwrap();
+    assert_eq!(kernel, format!("bootc-kernel/{rt}/vmlinuz"));
+    assert_eq!(initramfs, format!("bootc-kernel/{rt}/initramfs.img"));
+    assert_eq!(
+        boot.read_to_string(&kernel).unwrap(),
+        format!("kernel {rt}")
+    );
+    assert_eq!(
+        boot.read_to_string(&initramfs).unwrap(),
+        format!("initramfs {rt}")
+    );
+    assert!(install_kernel(&td, "extra", &boot).is_err());
+}
@@ -0,0 +1,261 @@
+//! # Serializing concurrent installations
+//!
+//! Each installation takes a lock on its target (block device or root directory)
+//! under `/run/bootc/locks`, so a second installation to the same target fails
+//! early, and creates its mounts under its own `/run/bootc/<pid>/mounts`, including
+//! the [target deployment](target_deployment_dir), so that installations to different
+//! targets do not interfere.  State left behind by
+//! processes which no longer exist is cleaned up when taking a lock.
+
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use fn_error_context::context;
+use nix::errno::Errno;
+use nix::fcntl::FlockArg;
+
+use super::RUN_BOOTC;
+
+/// The directory of lock files, under [`RUN_BOOTC`]
+const LOCKS_DIR: &str = "locks";
+
+/// The directory holding the state of this process.
+pub(crate) fn process_dir() -> Utf8PathBuf {
+    Utf8Path::new(RUN_BOOTC).join(std::process::id().to_string())
+}
+
+/// The directory under which this process creates its mounts.
+pub(crate) fn mounts_dir() -> Utf8PathBuf {
+    process_dir().join("mounts")
+}
+
+/// Where the target deployment is bind mounted during the installation, in the
+/// provided [`process_dir`]; see [`super::targetdeploy`].
+pub(crate) fn target_deployment_dir(process_dir: &Utf8Path) -> Utf8PathBuf {
+    process_dir.join("mounts/target-deployment")
+}
+
+/// The file holding the final kernel arguments of the installation, in the provided
+/// [`process_dir`].
+pub(crate) fn kargs_file(process_dir: &Utf8Path) -> Utf8PathBuf {
+    process_dir.join("kargs")
+}
+
+/// The name of the lock file for a target, escaping it like `systemd-escape --path`.
+fn lock_file_name(target: &Utf8Path) -> String {
+    let mut r = String::new();
+    let target = target.as_str().trim_matches('/');
+    for (i, b) in target.bytes().enumerate() {
+        match b {
+            b'/' => r.push('-'),
+            b'.' if i == 0 => r.push_str("\\x2e"),
+            b if b.is_ascii_alphanumeric() || b == b'_' || b == b'.' => r.push(b as char),
+            b => r.push_str(&format!("\\x{b:02x}")),
+        }
+    }
+    if r.is_empty() {
+        r.push('-');
+    }
+    r.push_str(".lock");
+    r
+}
+
+/// Try to take the lock on an open lock file; returns false if it is held by another
+/// process.
+fn try_lock(f: &File) -> Result<bool> {
+    match nix::fcntl::flock(f.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        Ok(()) => Ok(true),
+        Err(Errno::EWOULDBLOCK) => Ok(false),
+        Err(e) => Err(e).context("Locking"),
+    }
+}
+
+/// An exclusive lock on an installation target, released when dropped.
+#[derive(Debug)]
+pub(crate) struct InstallLock {
+    path: Utf8PathBuf,
+    _file: File,
+}
+
+impl InstallLock {
+    /// Lock the provided target, with the lock file in `dir`.
+    fn acquire_in(dir: &Utf8Path, target: &Utf8Path) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(lock_file_name(target));
+        loop {
+            let mut f = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                // Keep the pid of any current holder, for the error below
+                .truncate(false)
+                .open(&path)
+                .with_context(|| format!("Opening {path}"))?;
+            if !try_lock(&f)? {
+                let pid = std::fs::read_to_string(&path).unwrap_or_default();
+                let pid = Some(pid.trim())
+                    .filter(|p| !p.is_empty())
+                    .unwrap_or("unknown");
+                anyhow::bail!("Another install to {target} is in progress (pid {pid})");
+            }
+            // The previous holder removes the file when done, possibly after we opened
+            // it; in that case our lock is on a file nobody else will see.
+            match path.metadata() {
+                Ok(m) if m.ino() == f.metadata()?.ino() => {}
+                _ => continue,
+            }
+            f.set_len(0)?;
+            writeln!(f, "{}", std::process::id())?;
+            return Ok(Self { path, _file: f });
+        }
+    }
+}
+
+impl Drop for InstallLock {
+    fn drop(&mut self) {
+        // While still holding the lock
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Remove the state of processes in `run_bootc` for which `is_alive` is false, after
+/// unmounting anything still mounted there, and lock files which are not held.
+fn clean_stale_in(
+    run_bootc: &Utf8Path,
+    mountinfo: &str,
+    is_alive: impl Fn(u32) -> bool,
+    unmount: impl FnMut(&Utf8Path) -> Result<()> + Copy,
+) -> Result<()> {
+    if !run_bootc.exists() {
+        return Ok(());
+    }
+    for e in run_bootc.read_dir_utf8()? {
+        let e = e?;
+        let pid = match e.file_name().parse::<u32>() {
+            Ok(pid) if !is_alive(pid) => pid,
+            _ => continue,
+        };
+        tracing::debug!("Removing state of exited process {pid}");
+        let mntdir = e.path().join("mounts");
+        if mntdir.exists() {
+            super::baseline::clean_mntdir(&mntdir, mountinfo, unmount)?;
+        }
+        std::fs::remove_dir_all(e.path())?;
+    }
+    let locks = run_bootc.join(LOCKS_DIR);
+    if !locks.exists() {
+        return Ok(());
+    }
+    for e in locks.read_dir_utf8()? {
+        let e = e?;
+        let f = File::open(e.path())?;
+        if try_lock(&f)? {
+            std::fs::remove_file(e.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Clean up after installations which exited, then lock the provided target.
+#[context("Locking {target}")]
+pub(crate) fn lock_target(target: &Utf8Path) -> Result<InstallLock> {
+    let run_bootc = Utf8Path::new(RUN_BOOTC);
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+    let is_alive = |pid: u32| {
+        let pid = nix::unistd::Pid::from_raw(pid as i32);
+        !matches!(nix::sys::signal::kill(pid, None), Err(Errno::ESRCH))
+    };
+    clean_stale_in(run_bootc, &mountinfo, is_alive, |p| {
+        crate::mount::unmount(p, false)
+    })
+    .context("Cleaning up stale state")?;
+    // Resolve e.g. /dev/disk/by-id symlinks, so all paths to a target use the same lock
+    let target = target
+        .canonicalize_utf8()
+        .unwrap_or_else(|_| target.to_owned());
+    InstallLock::acquire_in(&run_bootc.join(LOCKS_DIR), &target)
+}
+
+#[test]
+fn test_lock_file_name() {
+    let cases = [
+        ("/dev/vda", "dev-vda.lock"),
+        (
+            "/dev/disk/by-id/nvme-Samsung_SSD",
+            "dev-disk-by\\x2did-nvme\\x2dSamsung_SSD.lock",
+        ),
+        ("/mnt/target/", "mnt-target.lock"),
+        ("/srv/.hidden", "srv-.hidden.lock"),
+        ("/", "-.lock"),
+    ];
+    for (target, expected) in cases {
+        assert_eq!(lock_file_name(target.into()), expected);
+    }
+}
+
+#[test]
+fn test_install_lock() {
+    let td = tempfile::tempdir().unwrap();
+    let td = Utf8Path::from_path(td.path()).unwrap();
+    let dir = td.join("locks");
+    let target = Utf8Path::new("/dev/vda");
+    let lock = InstallLock::acquire_in(&dir, target).unwrap();
+    let path = dir.join("dev-vda.lock");
+    let pid = std::process::id();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), format!("{pid}\n"));
+    // Locks are per open file, so this conflicts even within one process
+    let e = InstallLock::acquire_in(&dir, target).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        format!("Another install to /dev/vda is in progress (pid {pid})")
+    );
+    let other = InstallLock::acquire_in(&dir, "/dev/vdb".into()).unwrap();
+    drop(lock);
+    assert!(!path.exists());
+    let lock = InstallLock::acquire_in(&dir, target).unwrap();
+    drop((lock, other));
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+}
+
+#[test]
+fn test_clean_stale() {
+    let td = tempfile::tempdir().unwrap();
+    let run_bootc = Utf8Path::from_path(td.path()).unwrap();
+    for d in ["123/mounts/rootfs/boot", "456/mounts/dev", "other"] {
+        std::fs::create_dir_all(run_bootc.join(d)).unwrap();
+    }
+    let locks = run_bootc.join(LOCKS_DIR);
+    let held = InstallLock::acquire_in(&locks, "/dev/vdb".into()).unwrap();
+    std::fs::write(locks.join("dev-vda.lock"), "123\n").unwrap();
+    let mountinfo = format!(
+        "22 1 253:0 / / rw shared:1 - xfs /dev/vda4 rw\n\
+         99 22 253:4 / {run_bootc}/123/mounts/rootfs rw shared:3 - xfs /dev/vdb4 rw\n\
+         100 22 253:4 / {run_bootc}/456/mounts/dev rw shared:3 - devtmpfs devtmpfs rw\n"
+    );
+    let unmounted = std::cell::RefCell::new(Vec::new());
+    clean_stale_in(
+        run_bootc,
+        &mountinfo,
+        |pid| pid == 456,
+        |p| {
+            unmounted.borrow_mut().push(p.to_string());
+            Ok(())
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        *unmounted.borrow(),
+        [format!("{run_bootc}/123/mounts/rootfs")]
+    );
+    assert!(!run_bootc.join("123").exists());
+    assert!(run_bootc.join("456/mounts/dev").exists());
+    assert!(run_bootc.join("other").exists());
+    // Only the lock which is not held is removed
+    assert!(!locks.join("dev-vda.lock").exists());
+    assert!(locks.join("dev-vdb.lock").exists());
+    drop(held);
+}
@@ -0,0 +1,136 @@
+//! # Root filesystem on LVM
+//!
+//! With `--lvm`, the root partition becomes an LVM physical volume in a new volume
+//! group, and the root filesystem is created on a logical volume in it.  The initramfs
+//! activates the logical volume as requested by the `rd.lvm.lv` kernel argument.
+//!
+//! The LVM commands are run with an empty devices file, so that all devices are
+//! considered rather than only those the host is configured to use; see lvmdevices(8).
+//! Volume group names must be unique among all visible devices, so the name is
+//! checked against those of the host first, and the volume group is deactivated once
+//! the installation is unmounted.
+
+use anyhow::Result;
+use camino::Utf8PathBuf;
+use fn_error_context::context;
+
+use crate::task::Task;
+
+/// The default name of the volume group
+pub(crate) const DEFAULT_VG_NAME: &str = "bootc";
+/// The default name of the root logical volume
+pub(crate) const DEFAULT_LV_NAME: &str = "root";
+/// Partition type GUID for LVM physical volumes
+pub(crate) const LVM_TYPECODE: &str = "E6D6D379-F507-44C2-A23C-238F2A3DF928";
+/// Arguments making LVM commands ignore the host's devices file
+const NO_DEVICESFILE: [&str; 2] = ["--devicesfile", ""];
+
+/// Check that the provided name is usable for a volume group or logical volume; see
+/// lvm(8).
+pub(crate) fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 127
+        && !name.starts_with('-')
+        && !matches!(name, "." | "..")
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '_' | '.' | '-'));
+    if !valid {
+        anyhow::bail!("Invalid LVM name {name:?}");
+    }
+    Ok(())
+}
+
+/// The device-mapper path of the logical volume; `-` in the names is doubled, as it
+/// separates the volume group from the logical volume.
+pub(crate) fn dm_path(vg: &str, lv: &str) -> Utf8PathBuf {
+    let escape = |s: &str| s.replace('-', "--");
+    format!("/dev/mapper/{}-{}", escape(vg), escape(lv)).into()
+}
+
+/// The kernel argument which makes the initramfs activate the logical volume.
+pub(crate) fn karg(vg: &str, lv: &str) -> String {
+    format!("rd.lvm.lv={vg}/{lv}")
+}
+
+/// Fail if the volume group `vg` is among the volume groups in the output of `vgs`.
+fn check_vg_free(vg: &str, vgs: &str) -> Result<()> {
+    if vgs.lines().map(str::trim).any(|v| v == vg) {
+        anyhow::bail!(
+            "A volume group named {vg} already exists; use --vg-name to choose another name"
+        );
+    }
+    Ok(())
+}
+
+/// Create a volume group on the physical volume `pv`, and a logical volume in it of the
+/// provided size, by default using all of the volume group.  Returns the device-mapper
+/// path of the logical volume.
+#[context("Creating logical volume {vg}/{lv}")]
+pub(crate) fn create_lv(
+    pv: &str,
+    vg: &str,
+    lv: &str,
+    size_mib: Option<u64>,
+) -> Result<Utf8PathBuf> {
+    let vgs = Task::new("Listing LVM volume groups", "vgs")
+        .args(NO_DEVICESFILE)
+        .args(["--noheadings", "-o", "vg_name"])
+        .quiet()
+        .read()?;
+    check_vg_free(vg, &vgs)?;
+    Task::new("Creating LVM physical volume", "pvcreate")
+        .args(NO_DEVICESFILE)
+        .args(["--yes", pv])
+        .quiet_output()
+        .run()?;
+    Task::new("Creating LVM volume group", "vgcreate")
+        .args(NO_DEVICESFILE)
+        .args([vg, pv])
+        .quiet_output()
+        .run()?;
+    let size = size_mib.map_or_else(
+        || ["-l".into(), "100%FREE".into()],
+        |v| ["-L".into(), format!("{v}m")],
+    );
+    Task::new("Creating LVM logical volume", "lvcreate")
+        .args(NO_DEVICESFILE)
+        .args(["--yes", "--wipesignatures", "y", "-n", lv])
+        .args(size)
+        .args([vg])
+        .quiet_output()
+        .run()?;
+    Ok(dm_path(vg, lv))
+}
+
+/// Deactivate the volume group `vg`, releasing the disk; its filesystems must be
+/// unmounted.
+#[context("Deactivating volume group {vg}")]
+pub(crate) fn deactivate_vg(vg: &str) -> Result<()> {
+    Task::new("Deactivating LVM volume group", "vgchange")
+        .args(NO_DEVICESFILE)
+        .args(["-an", vg])
+        .quiet_output()
+        .run()
+}
+
+#[test]
+fn test_lvm_names() {
+    for valid in ["bootc", "root", "vg_sys", "my-vg.1", "a+b"] {
+        validate_name(valid).unwrap();
+    }
+    for invalid in ["", "-vg", ".", "..", "a/b", "a b", &"x".repeat(128)] {
+        assert!(validate_name(invalid).is_err(), "{invalid}");
+    }
+    assert_eq!(dm_path("bootc", "root"), "/dev/mapper/bootc-root");
+    assert_eq!(dm_path("my-vg", "var-lib"), "/dev/mapper/my--vg-var--lib");
+    assert_eq!(karg("my-vg", "root"), "rd.lvm.lv=my-vg/root");
+}
+
+#[test]
+fn test_check_vg_free() {
+    check_vg_free("bootc", "").unwrap();
+    check_vg_free("bootc", "  fedora\n  bootc-old\n").unwrap();
+    let e = check_vg_free("bootc", "  fedora\n  bootc\n").unwrap_err();
+    assert!(e.to_string().contains("named bootc already exists"), "{e}");
+}
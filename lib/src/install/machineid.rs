@@ -0,0 +1,126 @@
+//! # Preserving the machine ID across reinstalls
+//!
+//! Monitoring and licensing are often keyed on `/etc/machine-id`, which a fresh
+//! installation generates anew on first boot.  Like the SSH host keys, the existing
+//! ID is read before the target root is wiped, and written into the new deployment's
+//! `/etc`.  Note that a system with a machine ID is not considered to be booted for
+//! the first time by systemd (see `ConditionFirstBoot=`).
+
+use std::io::Read;
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use cap_std::fs::Dir;
+use cap_std_ext::cap_std;
+use fn_error_context::context;
+
+/// The path of the machine ID, relative to the root
+const MACHINE_ID_PATH: &str = "etc/machine-id";
+/// The mode of the machine ID file, as created by systemd
+const MACHINE_ID_MODE: u32 = 0o444;
+
+/// Parse the contents of a machine-id(5) file; a missing ID (an empty file, or
+/// `uninitialized`) is not an error.
+fn parse_machine_id(contents: &str) -> Result<Option<String>> {
+    let id = contents.trim_end_matches('\n');
+    if id.is_empty() || id == "uninitialized" {
+        return Ok(None);
+    }
+    let valid = id.len() == 32 && id.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'));
+    if !valid || id.chars().all(|c| c == '0') {
+        anyhow::bail!("Invalid machine ID {id:?}");
+    }
+    Ok(Some(id.to_string()))
+}
+
+/// Read the machine ID from the provided root; for an ostree-based root, the first
+/// deployment with one is used.
+#[context("Reading machine ID")]
+pub(crate) fn stash_machine_id(root: &Dir) -> Result<Option<String>> {
+    let path = if let Some(p) = super::existing::find_in_root(root, MACHINE_ID_PATH)? {
+        p
+    } else {
+        return Ok(None);
+    };
+    let mut contents = String::new();
+    root.open(&path)?
+        .read_to_string(&mut contents)
+        .with_context(|| format!("Reading {}", path.display()))?;
+    parse_machine_id(&contents)
+}
+
+/// Write the machine ID into the `etc` of the provided deployment root, replacing any
+/// existing file.  The `label` callback is invoked for the file, along with its path
+/// in the booted system.
+#[context("Restoring machine ID")]
+pub(crate) fn restore_machine_id(
+    root: &Utf8Path,
+    id: &str,
+    mut label: impl FnMut(&Utf8Path, &Utf8Path) -> Result<()>,
+) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let path = root.join(MACHINE_ID_PATH);
+    // The file is read-only
+    if path.symlink_metadata().is_ok() {
+        std::fs::remove_file(&path).with_context(|| format!("Removing {path}"))?;
+    }
+    std::fs::write(&path, format!("{id}\n")).with_context(|| format!("Writing {path}"))?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(MACHINE_ID_MODE))
+        .with_context(|| format!("Setting permissions of {path}"))?;
+    label(&path, &Utf8Path::new("/").join(MACHINE_ID_PATH))
+}
+
+#[test]
+fn test_parse_machine_id() {
+    let id = "4a3e6c1f0d6e4b7c9f2d8a5b6c7d8e9f";
+    assert_eq!(parse_machine_id(&format!("{id}\n")).unwrap().unwrap(), id);
+    assert_eq!(parse_machine_id(id).unwrap().unwrap(), id);
+    for missing in ["", "\n", "uninitialized\n"] {
+        assert_eq!(parse_machine_id(missing).unwrap(), None);
+    }
+    for invalid in [
+        "4A3E6C1F0D6E4B7C9F2D8A5B6C7D8E9F",
+        "4a3e6c1f",
+        "00000000000000000000000000000000",
+        "4a3e6c1f-0d6e-4b7c-9f2d-8a5b6c7d8e9f",
+    ] {
+        assert!(parse_machine_id(invalid).is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn test_preserve_machine_id() {
+    use cap_std_ext::cap_tempfile;
+    use std::os::unix::fs::MetadataExt;
+    let id = "4a3e6c1f0d6e4b7c9f2d8a5b6c7d8e9f";
+
+    let td = cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    // No previous installation
+    assert_eq!(stash_machine_id(&td).unwrap(), None);
+    // An ostree deployment which has not been booted yet
+    let deployment = "ostree/deploy/default/deploy/0123abcd.0";
+    td.create_dir_all(format!("{deployment}/etc")).unwrap();
+    td.write(format!("{deployment}/{MACHINE_ID_PATH}"), "")
+        .unwrap();
+    assert_eq!(stash_machine_id(&td).unwrap(), None);
+    td.write(format!("{deployment}/{MACHINE_ID_PATH}"), format!("{id}\n"))
+        .unwrap();
+    let stashed = stash_machine_id(&td).unwrap().unwrap();
+    assert_eq!(stashed, id);
+
+    // Restore into a new deployment, replacing the empty file from the image
+    let target = tempfile::tempdir().unwrap();
+    let target = Utf8Path::from_path(target.path()).unwrap();
+    std::fs::create_dir(target.join("etc")).unwrap();
+    let path = target.join(MACHINE_ID_PATH);
+    std::fs::write(&path, "").unwrap();
+    let mut labeled = Vec::new();
+    restore_machine_id(target, &stashed, |_, as_path| {
+        labeled.push(as_path.to_string());
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(labeled, ["/etc/machine-id"]);
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), format!("{id}\n"));
+    assert_eq!(path.metadata().unwrap().mode() & 0o7777, MACHINE_ID_MODE);
+}
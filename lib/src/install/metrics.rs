@@ -0,0 +1,498 @@
+//! # Installation metrics
+//!
+//! Metrics describing an installation, written via `--metrics-file` in the OpenMetrics
+//! text format for e.g. the node_exporter textfile collector on a provisioning host.
+//! All metric names and labels are defined here; they are part of our interface, and
+//! any incompatible change to them must increment [`METRICS_VERSION`].
+
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use cap_std::fs::{Dir, Permissions};
+use cap_std_ext::cap_std;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+
+/// The version of the metric names and labels, exposed as a label of [`METRIC_INFO`].
+const METRICS_VERSION: u32 = 1;
+
+const METRIC_INFO: &str = "bootc_install_metrics_info";
+const METRIC_SUCCESS: &str = "bootc_install_success";
+const METRIC_IMAGE_INFO: &str = "bootc_install_image_info";
+const METRIC_DURATION: &str = "bootc_install_duration_seconds";
+const METRIC_PHASE_DURATION: &str = "bootc_install_phase_duration_seconds";
+const METRIC_IMAGE_SIZE: &str = "bootc_install_image_size_bytes";
+const METRIC_PULLED: &str = "bootc_install_pulled_bytes";
+const METRIC_WRITTEN: &str = "bootc_install_written_bytes";
+const METRIC_RETRIES: &str = "bootc_install_retries";
+
+/// A step of the installation which is timed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Phase {
+    /// Checking the environment and options
+    Prepare,
+    /// Partitioning and creating filesystems, after fetching the image with
+    /// `--pull-first` (`bootc install` only)
+    Partition,
+    /// Importing the image and creating the deployment
+    Deploy,
+    /// Configuring the deployment and installing the bootloader
+    Finish,
+    /// Unmounting the target filesystems
+    Unmount,
+}
+
+/// The most recently started phase, for reporting where a failure occurred.
+static CURRENT_PHASE: Mutex<Option<Phase>> = Mutex::new(None);
+
+/// The phase in which the installation currently is, if any.
+pub(crate) fn current_phase() -> Option<Phase> {
+    *CURRENT_PHASE.lock().unwrap()
+}
+
+impl Phase {
+    /// Record the start of this phase, returning the start time for [`InstallMetrics::phase`].
+    pub(crate) fn enter(self) -> Instant {
+        *CURRENT_PHASE.lock().unwrap() = Some(self);
+        Instant::now()
+    }
+
+    /// The value of the `phase` label.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Phase::Prepare => "prepare",
+            Phase::Partition => "partition",
+            Phase::Deploy => "deploy",
+            Phase::Finish => "finish",
+            Phase::Unmount => "unmount",
+        }
+    }
+}
+
+/// An operation which is retried, used as the value of the `operation` label.
+const RETRY_UDEV_SETTLE: &str = "udev-settle";
+const RETRY_PARTITION_REREAD: &str = "partition-reread";
+
+/// Metrics gathered over the course of an installation.
+#[derive(Debug)]
+pub(crate) struct InstallMetrics {
+    start: Instant,
+    /// Whether the installation succeeded
+    success: bool,
+    /// The duration of each completed phase, in order
+    phases: Vec<(Phase, Duration)>,
+    /// The total size of the image's layers, as listed in its manifest
+    pub(crate) image_size: u64,
+    /// The bytes fetched for the image, see [`super::fetchstats`]
+    pub(crate) fetched_bytes: u64,
+    /// The space used on the target filesystems after the installation
+    pub(crate) written_bytes: u64,
+    /// The manifest digest of the installed image, once known
+    pub(crate) digest: Option<String>,
+}
+
+/// Escape a label value.
+fn escape_label(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The space used on the filesystems of the provided paths, counting each filesystem
+/// once; this is an approximation of the bytes written by the installation.
+pub(crate) fn used_bytes(paths: &[&Utf8Path]) -> Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+    let mut seen = Vec::new();
+    let mut r = 0;
+    for path in paths {
+        let dev = path
+            .metadata()
+            .with_context(|| format!("Querying {path}"))?
+            .dev();
+        if seen.contains(&dev) {
+            continue;
+        }
+        seen.push(dev);
+        let st = nix::sys::statvfs::statvfs(path.as_std_path())
+            .with_context(|| format!("Querying filesystem of {path}"))?;
+        r += (st.blocks() - st.blocks_free()) as u64 * st.fragment_size() as u64;
+    }
+    Ok(r)
+}
+
+impl Default for InstallMetrics {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            success: false,
+            phases: Vec::new(),
+            image_size: 0,
+            fetched_bytes: 0,
+            written_bytes: 0,
+            digest: None,
+        }
+    }
+}
+
+impl InstallMetrics {
+    /// Record the completion of a phase which started at `start`.
+    pub(crate) fn phase(&mut self, phase: Phase, start: Instant) {
+        self.phases.push((phase, start.elapsed()));
+    }
+
+    fn write_gauge(w: &mut impl Write, name: &str, help: &str, unit: Option<&str>) -> Result<()> {
+        writeln!(w, "# TYPE {name} gauge")?;
+        if let Some(unit) = unit {
+            writeln!(w, "# UNIT {name} {unit}")?;
+        }
+        writeln!(w, "# HELP {name} {help}")?;
+        Ok(())
+    }
+
+    /// Write the metrics in the OpenMetrics text format, with the provided total
+    /// duration and retry counts.
+    fn render(&self, mut w: impl Write, duration: Duration, retries: &[(&str, u64)]) -> Result<()> {
+        let w = &mut w;
+        Self::write_gauge(w, METRIC_INFO, "Version of the bootc install metrics", None)?;
+        writeln!(w, "{METRIC_INFO}{{version=\"{METRICS_VERSION}\"}} 1")?;
+        Self::write_gauge(
+            w,
+            METRIC_SUCCESS,
+            "Whether the installation succeeded",
+            None,
+        )?;
+        writeln!(w, "{METRIC_SUCCESS} {}", u8::from(self.success))?;
+        Self::write_gauge(w, METRIC_IMAGE_INFO, "The installed image", None)?;
+        if let Some(digest) = self.digest.as_deref() {
+            let digest = escape_label(digest);
+            writeln!(w, "{METRIC_IMAGE_INFO}{{digest=\"{digest}\"}} 1")?;
+        }
+        Self::write_gauge(
+            w,
+            METRIC_DURATION,
+            "Duration of the installation",
+            Some("seconds"),
+        )?;
+        writeln!(w, "{METRIC_DURATION} {:.3}", duration.as_secs_f64())?;
+        Self::write_gauge(
+            w,
+            METRIC_PHASE_DURATION,
+            "Duration of each installation phase",
+            Some("seconds"),
+        )?;
+        for (phase, duration) in self.phases.iter() {
+            let phase = phase.name();
+            let secs = duration.as_secs_f64();
+            writeln!(w, "{METRIC_PHASE_DURATION}{{phase=\"{phase}\"}} {secs:.3}")?;
+        }
+        Self::write_gauge(
+            w,
+            METRIC_IMAGE_SIZE,
+            "Size of the layers of the installed image",
+            Some("bytes"),
+        )?;
+        writeln!(w, "{METRIC_IMAGE_SIZE} {}", self.image_size)?;
+        Self::write_gauge(
+            w,
+            METRIC_PULLED,
+            "Bytes received over the network while fetching the image",
+            Some("bytes"),
+        )?;
+        writeln!(w, "{METRIC_PULLED} {}", self.fetched_bytes)?;
+        Self::write_gauge(
+            w,
+            METRIC_WRITTEN,
+            "Space used on the target filesystems after the installation",
+            Some("bytes"),
+        )?;
+        writeln!(w, "{METRIC_WRITTEN} {}", self.written_bytes)?;
+        Self::write_gauge(w, METRIC_RETRIES, "Retries of transient failures", None)?;
+        for (operation, n) in retries {
+            writeln!(w, "{METRIC_RETRIES}{{operation=\"{operation}\"}} {n}")?;
+        }
+        writeln!(w, "# EOF")?;
+        Ok(())
+    }
+
+    /// Atomically write the metrics to the provided path, so that collectors never
+    /// see a partially written file.
+    #[context("Writing metrics to {path}")]
+    pub(crate) fn write_file(&self, path: &Utf8Path) -> Result<()> {
+        let retries = [
+            (
+                RETRY_UDEV_SETTLE,
+                crate::blockdev::UDEV_SETTLE_RETRIES.load(Ordering::Relaxed),
+            ),
+            (
+                RETRY_PARTITION_REREAD,
+                crate::blockdev::PARTITION_REREAD_RETRIES.load(Ordering::Relaxed),
+            ),
+        ];
+        let mut buf = Vec::new();
+        self.render(&mut buf, self.start.elapsed(), &retries)?;
+        let name = path
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("Invalid metrics file path"))?;
+        let parent = match path.parent() {
+            Some(p) if !p.as_str().is_empty() => p,
+            _ => Utf8Path::new("."),
+        };
+        let dir = Dir::open_ambient_dir(parent, cap_std::ambient_authority())
+            .with_context(|| format!("Opening {parent}"))?;
+        dir.atomic_write_with_perms(name, buf, Permissions::from_mode(0o644))?;
+        Ok(())
+    }
+
+    /// Write the metrics with the outcome of the installation to the provided path, if
+    /// any.  If the installation failed, its error is returned even if writing the
+    /// metrics fails too.
+    pub(crate) fn write_outcome<T>(&mut self, path: Option<&Utf8Path>, r: Result<T>) -> Result<T> {
+        let path = if let Some(path) = path {
+            path
+        } else {
+            return r;
+        };
+        self.success = r.is_ok();
+        match (self.write_file(path), r) {
+            (Err(e), Err(installation)) => {
+                eprintln!("warning: {e:#}");
+                Err(installation)
+            }
+            (written, r) => written.and(r),
+        }
+    }
+}
+
+#[test]
+fn test_render_metrics() {
+    let mut metrics = InstallMetrics {
+        success: true,
+        image_size: 812345678,
+        fetched_bytes: 203086419,
+        written_bytes: 2147483648,
+        digest: Some("sha256:5e0be47d".into()),
+        ..Default::default()
+    };
+    metrics.phases = vec![
+        (Phase::Prepare, Duration::from_millis(1520)),
+        (Phase::Partition, Duration::from_millis(3004)),
+        (Phase::Deploy, Duration::from_millis(61250)),
+        (Phase::Finish, Duration::from_millis(9876)),
+        (Phase::Unmount, Duration::from_millis(100)),
+    ];
+    let mut buf = Vec::new();
+    let retries = [(RETRY_UDEV_SETTLE, 2), (RETRY_PARTITION_REREAD, 0)];
+    metrics
+        .render(&mut buf, Duration::from_millis(75750), &retries)
+        .unwrap();
+    // Changes to this output must be reflected in METRICS_VERSION
+    let expected = r#"# TYPE bootc_install_metrics_info gauge
+# HELP bootc_install_metrics_info Version of the bootc install metrics
+bootc_install_metrics_info{version="1"} 1
+# TYPE bootc_install_success gauge
+# HELP bootc_install_success Whether the installation succeeded
+bootc_install_success 1
+# TYPE bootc_install_image_info gauge
+# HELP bootc_install_image_info The installed image
+bootc_install_image_info{digest="sha256:5e0be47d"} 1
+# TYPE bootc_install_duration_seconds gauge
+# UNIT bootc_install_duration_seconds seconds
+# HELP bootc_install_duration_seconds Duration of the installation
+bootc_install_duration_seconds 75.750
+# TYPE bootc_install_phase_duration_seconds gauge
+# UNIT bootc_install_phase_duration_seconds seconds
+# HELP bootc_install_phase_duration_seconds Duration of each installation phase
+bootc_install_phase_duration_seconds{phase="prepare"} 1.520
+bootc_install_phase_duration_seconds{phase="partition"} 3.004
+bootc_install_phase_duration_seconds{phase="deploy"} 61.250
+bootc_install_phase_duration_seconds{phase="finish"} 9.876
+bootc_install_phase_duration_seconds{phase="unmount"} 0.100
+# TYPE bootc_install_image_size_bytes gauge
+# UNIT bootc_install_image_size_bytes bytes
+# HELP bootc_install_image_size_bytes Size of the layers of the installed image
+bootc_install_image_size_bytes 812345678
+# TYPE bootc_install_pulled_bytes gauge
+# UNIT bootc_install_pulled_bytes bytes
+# HELP bootc_install_pulled_bytes Bytes received over the network while fetching the image
+bootc_install_pulled_bytes 203086419
+# TYPE bootc_install_written_bytes gauge
+# UNIT bootc_install_written_bytes bytes
+# HELP bootc_install_written_bytes Space used on the target filesystems after the installation
+bootc_install_written_bytes 2147483648
+# TYPE bootc_install_retries gauge
+# HELP bootc_install_retries Retries of transient failures
+bootc_install_retries{operation="udev-settle"} 2
+bootc_install_retries{operation="partition-reread"} 0
+# EOF
+"#;
+    let buf = String::from_utf8(buf).unwrap();
+    assert_eq!(buf, expected);
+    assert_eq!(parse_openmetrics(&buf).unwrap().len(), 15);
+}
+
+#[test]
+fn test_write_metrics_file() {
+    let td = tempfile::tempdir().unwrap();
+    let td: &Utf8Path = td.path().try_into().unwrap();
+    let path = td.join("bootc.prom");
+    std::fs::write(&path, "old").unwrap();
+    InstallMetrics::default().write_file(&path).unwrap();
+    let contents = std::fs::read_to_string(&path).unwrap();
+    assert!(contents.starts_with("# TYPE bootc_install_metrics_info gauge\n"));
+    assert!(contents.ends_with("# EOF\n"));
+    // The temporary file was renamed over the old one
+    assert_eq!(std::fs::read_dir(td).unwrap().count(), 1);
+    assert!(InstallMetrics::default()
+        .write_file(&td.join("missing/bootc.prom"))
+        .is_err());
+}
+
+/// Parse the OpenMetrics text format, as far as we use it, checking the rules of the
+/// specification which apply: every sample belongs to the preceding metric family,
+/// families are declared once, units are suffixes of their names, and the exposition
+/// ends with `# EOF`.  Returns the value of each sample, by name and labels.
+#[cfg(test)]
+fn parse_openmetrics(s: &str) -> Result<std::collections::BTreeMap<String, f64>> {
+    use std::collections::{BTreeMap, BTreeSet};
+    let valid_name = |n: &str| {
+        !n.is_empty()
+            && !n.starts_with(|c: char| c.is_ascii_digit())
+            && n.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+    };
+    let body = s
+        .strip_suffix("# EOF\n")
+        .ok_or_else(|| anyhow::anyhow!("Missing # EOF"))?;
+    let mut families = BTreeSet::new();
+    let mut family: Option<&str> = None;
+    let mut samples = BTreeMap::new();
+    for line in body.lines() {
+        if let Some(meta) = line.strip_prefix("# ") {
+            let mut parts = meta.splitn(3, ' ');
+            let (kind, name, value) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(k), Some(n), Some(v)) => (k, n, v),
+                _ => anyhow::bail!("Invalid metadata: {line}"),
+            };
+            match kind {
+                "TYPE" => {
+                    if !valid_name(name) || !families.insert(name) {
+                        anyhow::bail!("Invalid or duplicate family {name}");
+                    }
+                    if value != "gauge" {
+                        anyhow::bail!("Unexpected type {value}");
+                    }
+                    family = Some(name);
+                }
+                "UNIT" if family == Some(name) => {
+                    if !name.ends_with(&format!("_{value}")) {
+                        anyhow::bail!("{name} does not end with its unit {value}");
+                    }
+                }
+                "HELP" if family == Some(name) => {}
+                _ => anyhow::bail!("Unexpected metadata: {line}"),
+            }
+            continue;
+        }
+        let (series, value) = line
+            .rsplit_once(' ')
+            .ok_or_else(|| anyhow::anyhow!("Invalid sample: {line}"))?;
+        let name = match series.split_once('{') {
+            Some((name, labels)) => {
+                let labels = labels
+                    .strip_suffix('}')
+                    .ok_or_else(|| anyhow::anyhow!("Invalid labels: {line}"))?;
+                for label in labels.split(',') {
+                    let (k, v) = label
+                        .split_once('=')
+                        .ok_or_else(|| anyhow::anyhow!("Invalid label: {line}"))?;
+                    let quoted = v.len() >= 2 && v.starts_with('"') && v.ends_with('"');
+                    if !valid_name(k) || !quoted {
+                        anyhow::bail!("Invalid label: {line}");
+                    }
+                }
+                name
+            }
+            None => series,
+        };
+        if Some(name) != family {
+            anyhow::bail!("Sample {name} outside of its family");
+        }
+        let value = value.parse::<f64>()?;
+        if samples.insert(series.to_string(), value).is_some() {
+            anyhow::bail!("Duplicate sample {series}");
+        }
+    }
+    Ok(samples)
+}
+
+#[test]
+fn test_metrics_outcome() {
+    let td = tempfile::tempdir().unwrap();
+    let td: &Utf8Path = td.path().try_into().unwrap();
+    let path = td.join("bootc.prom");
+    let read = || parse_openmetrics(&std::fs::read_to_string(&path).unwrap()).unwrap();
+
+    let mut metrics = InstallMetrics::default();
+    metrics.phase(Phase::Prepare, Instant::now());
+    let r = metrics.write_outcome(Some(&path), Err::<(), _>(anyhow::anyhow!("Failed")));
+    assert_eq!(r.unwrap_err().to_string(), "Failed");
+    let samples = read();
+    assert_eq!(samples["bootc_install_success"], 0.0);
+    assert!(samples.contains_key("bootc_install_phase_duration_seconds{phase=\"prepare\"}"));
+    assert!(!samples.keys().any(|k| k.starts_with(METRIC_IMAGE_INFO)));
+
+    metrics.digest = Some("sha256:5e0be47d".into());
+    metrics.written_bytes = 4096;
+    metrics.phase(Phase::Deploy, Instant::now());
+    metrics.write_outcome(Some(&path), Ok(())).unwrap();
+    let samples = read();
+    for series in [
+        "bootc_install_metrics_info{version=\"1\"}",
+        "bootc_install_duration_seconds",
+        "bootc_install_phase_duration_seconds{phase=\"deploy\"}",
+        "bootc_install_image_size_bytes",
+        "bootc_install_pulled_bytes",
+        "bootc_install_retries{operation=\"udev-settle\"}",
+    ] {
+        assert!(samples.contains_key(series), "Missing {series}");
+    }
+    assert_eq!(samples["bootc_install_success"], 1.0);
+    assert_eq!(
+        samples["bootc_install_image_info{digest=\"sha256:5e0be47d\"}"],
+        1.0
+    );
+    assert_eq!(samples["bootc_install_written_bytes"], 4096.0);
+
+    // The installation's error takes precedence
+    let missing = td.join("missing/bootc.prom");
+    let r = metrics.write_outcome(Some(&missing), Err::<(), _>(anyhow::anyhow!("Failed")));
+    assert_eq!(r.unwrap_err().to_string(), "Failed");
+    assert!(metrics.write_outcome(Some(&missing), Ok(())).is_err());
+    metrics.write_outcome(None, Ok(())).unwrap();
+
+    // The validation itself
+    for invalid in [
+        "",
+        "bootc_install_success 1\n# EOF\n",
+        "# TYPE a gauge\n# UNIT a bytes\na 1\n# EOF\n",
+        "# TYPE a gauge\na{b=c} 1\n# EOF\n",
+        "# TYPE a gauge\na 1\na 2\n# EOF\n",
+        "# TYPE a gauge\n# TYPE b gauge\na 1\n# EOF\n",
+    ] {
+        assert!(parse_openmetrics(invalid).is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn test_used_bytes() {
+    let td = tempfile::tempdir().unwrap();
+    let td: &Utf8Path = td.path().try_into().unwrap();
+    std::fs::write(td.join("data"), vec![1u8; 1 << 20]).unwrap();
+    assert!(used_bytes(&[td]).unwrap() > 0);
+    assert!(used_bytes(&[&td.join("missing")]).is_err());
+}
@@ -0,0 +1,500 @@
+//! # Privileged operations
+//!
+//! The operations performed during installation which require root and real
+//! block devices, abstracted so that the install orchestration can be exercised
+//! by tests with a fake implementation.
+
+use std::future::Future;
+use std::pin::Pin;
+
+use anyhow::Result;
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std::fs::Dir;
+use cap_std_ext::cap_std;
+use ostree_ext::container as ostree_container;
+
+use crate::blockdev::Device;
+use crate::bootloader::BootloaderComponent;
+use crate::install::baseline::InstallBlockDeviceOpts;
+use crate::install::diagnostics::Diagnostics;
+use crate::install::{BootKargBy, Compression, EspMountpoint, InitialDeployment, RootSetup, State};
+use crate::task::Task;
+
+pub(crate) trait InstallOps {
+    /// Set the SELinux label of `target` to the policy default for `as_path`.
+    fn lsm_label(&self, target: &Utf8Path, as_path: &Utf8Path, recurse: bool) -> Result<()>;
+
+    /// Rebuild the SELinux policy of the deployment rooted at `root`.
+    fn rebuild_selinux_policy(&self, root: &Utf8Path) -> Result<()>;
+
+    /// Install the bootloader for the root filesystem to the provided device, and the
+    /// ESP mounted at `esp` in the root filesystem.
+    fn install_bootloader(
+        &self,
+        device: &Utf8Path,
+        rootfs: &Utf8Path,
+        esp: EspMountpoint,
+        boot_uuid: &str,
+    ) -> Result<Vec<BootloaderComponent>>;
+
+    /// Execute the user-provided commands inside the deployment.
+    fn run_in_target(&self, root: &Utf8Path, cmds: &[String]) -> Result<()>;
+
+    /// Run an interactive shell inside the deployment.
+    fn run_shell(&self, root: &Utf8Path) -> Result<()>;
+
+    /// Set the immutable bit on the root directory.
+    fn set_immutable(&self, root: &Dir) -> Result<()>;
+
+    /// Flush and remount the filesystem read-only.
+    fn finalize_filesystem(&self, fs: &Utf8Path) -> Result<()>;
+
+    /// Create and format a swapfile of the provided size.
+    fn create_swapfile(&self, path: &Utf8Path, size_mib: u64, nocow: bool) -> Result<()>;
+
+    /// Whether the provided `root=` kernel argument value names an existing device.
+    fn root_device_exists(&self, spec: &str) -> bool;
+
+    fn mount(&self, dev: &str, target: &Utf8Path) -> Result<()>;
+
+    fn unmount(&self, target: &Utf8Path) -> Result<()>;
+
+    fn bind_mount(&self, src: &Utf8Path, target: &Utf8Path) -> Result<()>;
+
+    fn unmount_recursive(&self, target: &Utf8Path) -> Result<()>;
+
+    /// Gather information about the filesystem mounted at the provided path.
+    fn inspect_filesystem(&self, path: &Utf8Path) -> Result<crate::mount::Filesystem>;
+
+    /// Query the provided block device, including its partitions.
+    fn list_dev(&self, dev: &Utf8Path) -> Result<Device>;
+
+    fn find_parent_devices(&self, dev: &str) -> Result<Vec<String>>;
+
+    /// Whether the provided block device is a multipath device.
+    fn is_multipath(&self, dev: &str) -> Result<bool>;
+
+    /// Whether the provided path is writable in the host mount namespace.
+    fn host_path_writable(&self, path: &Utf8Path) -> bool;
+
+    /// Copy the image `src` to `dest`, returning the reference to the copy.
+    fn copy_image(
+        &self,
+        src: &ostree_container::ImageReference,
+        dest: ostree_container::ImageReference,
+        concurrency: Option<u32>,
+        compression: Option<Compression>,
+        diagnostics: &Diagnostics,
+    ) -> Result<ostree_container::ImageReference>;
+
+    /// Partition the target block device and create the filesystems on it.
+    fn create_rootfs(
+        &self,
+        opts: InstallBlockDeviceOpts,
+        boot_karg_by: BootKargBy,
+        esp_mountpoint: EspMountpoint,
+        diagnostics: &Diagnostics,
+    ) -> Result<RootSetup>;
+
+    /// Create the initial ostree deployment of the source image in the root filesystem.
+    fn deploy<'a>(
+        &'a self,
+        state: &'a State,
+        root_setup: &'a RootSetup,
+    ) -> Pin<Box<dyn Future<Output = Result<InitialDeployment>> + 'a>>;
+}
+
+/// A filesystem mounted by the installer, which is unmounted when dropped, including if
+/// the installation fails.
+pub(crate) struct MountGuard<'a> {
+    ops: &'a dyn InstallOps,
+    path: Utf8PathBuf,
+    /// Whether submounts are unmounted too, for bind mounts
+    recursive: bool,
+    mounted: bool,
+}
+
+impl<'a> MountGuard<'a> {
+    /// Mount the device at `path`.
+    pub(crate) fn mount(ops: &'a dyn InstallOps, dev: &str, path: &Utf8Path) -> Result<Self> {
+        ops.mount(dev, path)?;
+        Ok(Self {
+            ops,
+            path: path.to_owned(),
+            recursive: false,
+            mounted: true,
+        })
+    }
+
+    /// Bind mount `src` at `path`; anything mounted below it meanwhile is unmounted
+    /// along with it.
+    pub(crate) fn bind(ops: &'a dyn InstallOps, src: &Utf8Path, path: &Utf8Path) -> Result<Self> {
+        ops.bind_mount(src, path)?;
+        Ok(Self {
+            ops,
+            path: path.to_owned(),
+            recursive: true,
+            mounted: true,
+        })
+    }
+
+    /// The mountpoint.
+    pub(crate) fn path(&self) -> &Utf8Path {
+        &self.path
+    }
+
+    fn do_unmount(&self) -> Result<()> {
+        if self.recursive {
+            self.ops.unmount_recursive(&self.path)
+        } else {
+            self.ops.unmount(&self.path)
+        }
+    }
+
+    /// Unmount the filesystem, reporting any error.
+    pub(crate) fn unmount(mut self) -> Result<()> {
+        self.mounted = false;
+        self.do_unmount()
+    }
+}
+
+impl Drop for MountGuard<'_> {
+    fn drop(&mut self) {
+        if !self.mounted {
+            return;
+        }
+        if let Err(e) = self.do_unmount() {
+            eprintln!("warning: Failed to unmount {}: {e:#}", self.path);
+        }
+    }
+}
+
+/// The real implementation, operating on the host.
+pub(crate) struct HostOps;
+
+impl InstallOps for HostOps {
+    fn lsm_label(&self, target: &Utf8Path, as_path: &Utf8Path, recurse: bool) -> Result<()> {
+        crate::lsm::lsm_label(target, as_path, recurse)
+    }
+
+    fn rebuild_selinux_policy(&self, root: &Utf8Path) -> Result<()> {
+        crate::install::sebool::rebuild_policy(root)
+    }
+
+    fn install_bootloader(
+        &self,
+        device: &Utf8Path,
+        rootfs: &Utf8Path,
+        esp: EspMountpoint,
+        boot_uuid: &str,
+    ) -> Result<Vec<BootloaderComponent>> {
+        crate::bootloader::install_via_bootupd(device, rootfs, esp, boot_uuid)
+    }
+
+    fn run_in_target(&self, root: &Utf8Path, cmds: &[String]) -> Result<()> {
+        super::run_in_target(root, cmds)
+    }
+
+    fn run_shell(&self, root: &Utf8Path) -> Result<()> {
+        super::run_shell(root)
+    }
+
+    fn set_immutable(&self, root: &Dir) -> Result<()> {
+        Task::new("Setting root immutable bit", "chattr")
+            .cwd(root)?
+            .args(["+i", "."])
+            .run()
+    }
+
+    fn finalize_filesystem(&self, fs: &Utf8Path) -> Result<()> {
+        super::finalize_filesystem(fs)
+    }
+
+    fn create_swapfile(&self, path: &Utf8Path, size_mib: u64, nocow: bool) -> Result<()> {
+        super::swap::create_swapfile(path, size_mib, nocow)
+    }
+
+    fn root_device_exists(&self, spec: &str) -> bool {
+        crate::bootloader::root_device_exists(spec)
+    }
+
+    fn mount(&self, dev: &str, target: &Utf8Path) -> Result<()> {
+        crate::mount::mount(dev, target)
+    }
+
+    fn unmount(&self, target: &Utf8Path) -> Result<()> {
+        crate::mount::unmount(target, false)
+    }
+
+    fn bind_mount(&self, src: &Utf8Path, target: &Utf8Path) -> Result<()> {
+        crate::mount::rbind(src, target)
+    }
+
+    fn unmount_recursive(&self, target: &Utf8Path) -> Result<()> {
+        crate::mount::unmount_recursive(target)
+    }
+
+    fn inspect_filesystem(&self, path: &Utf8Path) -> Result<crate::mount::Filesystem> {
+        crate::mount::inspect_filesystem(path)
+    }
+
+    fn list_dev(&self, dev: &Utf8Path) -> Result<Device> {
+        crate::blockdev::list_dev(dev)
+    }
+
+    fn find_parent_devices(&self, dev: &str) -> Result<Vec<String>> {
+        crate::blockdev::find_parent_devices(dev)
+    }
+
+    fn is_multipath(&self, dev: &str) -> Result<bool> {
+        crate::blockdev::is_multipath(dev)
+    }
+
+    fn host_path_writable(&self, path: &Utf8Path) -> bool {
+        crate::utils::host_path_writable(path.as_str())
+    }
+
+    fn copy_image(
+        &self,
+        src: &ostree_container::ImageReference,
+        dest: ostree_container::ImageReference,
+        concurrency: Option<u32>,
+        compression: Option<Compression>,
+        diagnostics: &Diagnostics,
+    ) -> Result<ostree_container::ImageReference> {
+        super::copy_to_oci(
+            "Copying image",
+            src,
+            dest,
+            concurrency,
+            compression,
+            diagnostics,
+        )
+    }
+
+    fn create_rootfs(
+        &self,
+        opts: InstallBlockDeviceOpts,
+        boot_karg_by: BootKargBy,
+        esp_mountpoint: EspMountpoint,
+        diagnostics: &Diagnostics,
+    ) -> Result<RootSetup> {
+        super::baseline::install_create_rootfs(opts, boot_karg_by, esp_mountpoint, diagnostics)
+    }
+
+    fn deploy<'a>(
+        &'a self,
+        state: &'a State,
+        root_setup: &'a RootSetup,
+    ) -> Pin<Box<dyn Future<Output = Result<InitialDeployment>> + 'a>> {
+        Box::pin(super::initialize_ostree_root_from_self(
+            self, state, root_setup,
+        ))
+    }
+}
+
+/// A fake implementation which records the operations performed, for tests.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct FakeOps {
+    pub(crate) calls: std::cell::RefCell<Vec<String>>,
+    /// The mounted filesystems, by mountpoint
+    pub(crate) filesystems: Vec<(Utf8PathBuf, crate::mount::Filesystem)>,
+    /// The result of [`InstallOps::deploy`], which fails if there is none
+    pub(crate) deployment: std::cell::RefCell<Option<InitialDeployment>>,
+    /// Emulate bind mounts onto empty directories with symlinks, so that files can be
+    /// accessed through them
+    pub(crate) emulate_bind_mounts: bool,
+}
+
+#[cfg(test)]
+impl FakeOps {
+    fn record(&self, call: String) {
+        self.calls.borrow_mut().push(call);
+    }
+}
+
+#[cfg(test)]
+impl InstallOps for FakeOps {
+    fn lsm_label(&self, target: &Utf8Path, as_path: &Utf8Path, _recurse: bool) -> Result<()> {
+        assert!(target.symlink_metadata().is_ok(), "{target}");
+        self.record(format!("label {as_path}"));
+        Ok(())
+    }
+
+    fn rebuild_selinux_policy(&self, _root: &Utf8Path) -> Result<()> {
+        self.record("rebuild-selinux-policy".to_string());
+        Ok(())
+    }
+
+    fn install_bootloader(
+        &self,
+        device: &Utf8Path,
+        rootfs: &Utf8Path,
+        esp: EspMountpoint,
+        boot_uuid: &str,
+    ) -> Result<Vec<BootloaderComponent>> {
+        std::fs::create_dir_all(rootfs.join("boot/grub2"))?;
+        self.record(format!(
+            "install-bootloader {device} {} {boot_uuid}",
+            esp.path()
+        ));
+        Ok(Vec::new())
+    }
+
+    fn run_in_target(&self, _root: &Utf8Path, cmds: &[String]) -> Result<()> {
+        for cmd in cmds {
+            self.record(format!("run-in-target {cmd}"));
+        }
+        Ok(())
+    }
+
+    fn run_shell(&self, root: &Utf8Path) -> Result<()> {
+        self.record(format!("run-shell {root}"));
+        Ok(())
+    }
+
+    fn set_immutable(&self, _root: &Dir) -> Result<()> {
+        self.record("set-immutable".into());
+        Ok(())
+    }
+
+    fn finalize_filesystem(&self, fs: &Utf8Path) -> Result<()> {
+        self.record(format!("finalize {fs}"));
+        Ok(())
+    }
+
+    fn create_swapfile(&self, path: &Utf8Path, size_mib: u64, nocow: bool) -> Result<()> {
+        std::fs::write(path, "")?;
+        self.record(format!("create-swapfile {size_mib} nocow={nocow}"));
+        Ok(())
+    }
+
+    fn root_device_exists(&self, spec: &str) -> bool {
+        self.record(format!("root-device-exists {spec}"));
+        true
+    }
+
+    fn mount(&self, dev: &str, target: &Utf8Path) -> Result<()> {
+        self.record(format!("mount {dev} {target}"));
+        Ok(())
+    }
+
+    fn unmount(&self, target: &Utf8Path) -> Result<()> {
+        self.record(format!("unmount {target}"));
+        Ok(())
+    }
+
+    fn bind_mount(&self, src: &Utf8Path, target: &Utf8Path) -> Result<()> {
+        self.record(format!("bind-mount {src} {target}"));
+        if self.emulate_bind_mounts {
+            std::fs::remove_dir(target)?;
+            std::os::unix::fs::symlink(src, target)?;
+        }
+        Ok(())
+    }
+
+    fn unmount_recursive(&self, target: &Utf8Path) -> Result<()> {
+        self.record(format!("unmount-recursive {target}"));
+        if self.emulate_bind_mounts {
+            std::fs::remove_file(target)?;
+            std::fs::create_dir(target)?;
+        }
+        Ok(())
+    }
+
+    fn inspect_filesystem(&self, path: &Utf8Path) -> Result<crate::mount::Filesystem> {
+        self.filesystems
+            .iter()
+            .find(|(p, _)| p == path)
+            .map(|(_, fs)| fs.clone())
+            .ok_or_else(|| anyhow::anyhow!("No filesystem at {path}"))
+    }
+
+    fn list_dev(&self, dev: &Utf8Path) -> Result<Device> {
+        anyhow::bail!("No device {dev}")
+    }
+
+    fn find_parent_devices(&self, _dev: &str) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    fn is_multipath(&self, _dev: &str) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn host_path_writable(&self, path: &Utf8Path) -> bool {
+        self.record(format!("host-path-writable {path}"));
+        true
+    }
+
+    fn copy_image(
+        &self,
+        src: &ostree_container::ImageReference,
+        dest: ostree_container::ImageReference,
+        _concurrency: Option<u32>,
+        _compression: Option<Compression>,
+        _diagnostics: &Diagnostics,
+    ) -> Result<ostree_container::ImageReference> {
+        self.record(format!("copy-image {src}"));
+        Ok(dest)
+    }
+
+    fn create_rootfs(
+        &self,
+        opts: InstallBlockDeviceOpts,
+        _boot_karg_by: BootKargBy,
+        _esp_mountpoint: EspMountpoint,
+        _diagnostics: &Diagnostics,
+    ) -> Result<RootSetup> {
+        self.record(format!("create-rootfs {}", opts.device));
+        anyhow::bail!("Cannot create filesystems on {}", opts.device)
+    }
+
+    fn deploy<'a>(
+        &'a self,
+        _state: &'a State,
+        _root_setup: &'a RootSetup,
+    ) -> Pin<Box<dyn Future<Output = Result<InitialDeployment>> + 'a>> {
+        self.record("deploy".into());
+        let r = self
+            .deployment
+            .borrow_mut()
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("No deployment"));
+        Box::pin(std::future::ready(r))
+    }
+}
+
+#[test]
+fn test_mount_guard() {
+    let ops = FakeOps::default();
+    let target = Utf8Path::new("/target/efi");
+    let mount = MountGuard::mount(&ops, "/dev/vda2", target).unwrap();
+    mount.unmount().unwrap();
+    // Unmounted when dropped, e.g. on errors
+    drop(MountGuard::mount(&ops, "/dev/vda3", target).unwrap());
+    assert_eq!(
+        *ops.calls.borrow(),
+        [
+            "mount /dev/vda2 /target/efi",
+            "unmount /target/efi",
+            "mount /dev/vda3 /target/efi",
+            "unmount /target/efi",
+        ]
+    );
+
+    // Bind mounts are unmounted along with their submounts
+    ops.calls.borrow_mut().clear();
+    let src = Utf8Path::new("/target/ostree/deploy/default/deploy/abcd.0");
+    let mount = MountGuard::bind(&ops, src, target).unwrap();
+    assert_eq!(mount.path(), target);
+    drop(mount);
+    assert_eq!(
+        *ops.calls.borrow(),
+        [
+            format!("bind-mount {src} {target}"),
+            format!("unmount-recursive {target}"),
+        ]
+    );
+}
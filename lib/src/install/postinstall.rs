@@ -0,0 +1,189 @@
+//! # Booting into the installed system
+//!
+//! With `--post-install`, the host is rebooted into the installed system once the
+//! installation is complete and its result has been written.  With `kexec`, the
+//! installed kernel is loaded before the target is unmounted, and systemd boots it
+//! directly after a clean shutdown, skipping the firmware; if it can't be loaded, the
+//! host is rebooted instead.  Neither is done when installing to a loopback device,
+//! as that is not what the host boots from.
+
+use std::io::{Read, Write};
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use cap_std::fs::Dir;
+use cap_std_ext::cap_std;
+use cap_std_ext::prelude::CapStdExtDirExt;
+use fn_error_context::context;
+use serde::{Deserialize, Serialize};
+
+use crate::task::Task;
+use crate::utils::run_in_host_mountns;
+
+/// What to do after a successful installation.
+#[derive(clap::ValueEnum, Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum PostInstall {
+    /// Nothing
+    #[default]
+    None,
+    /// Reboot the host
+    Reboot,
+    /// Boot the installed kernel directly via kexec, or reboot if that's not possible
+    Kexec,
+}
+
+impl PostInstall {
+    /// The name of the action, as used in the options.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            PostInstall::None => "none",
+            PostInstall::Reboot => "reboot",
+            PostInstall::Kexec => "kexec",
+        }
+    }
+}
+
+/// Whether the provided block device is a loopback device, i.e. the target is a disk
+/// image.
+pub(crate) fn is_loopback(dev: &Utf8Path) -> Result<bool> {
+    let dev = dev
+        .canonicalize_utf8()
+        .with_context(|| format!("Resolving {dev}"))?;
+    Ok(dev.file_name().map_or(false, |n| n.starts_with("loop")))
+}
+
+/// Check whether the running kernel can kexec, given the host root; returns the reason
+/// if it can't.
+fn kexec_unsupported(root: &Dir) -> Result<Option<&'static str>> {
+    if !root.try_exists("sys/kernel/kexec_loaded")? {
+        return Ok(Some("the kernel does not support kexec"));
+    }
+    if let Some(mut f) = root.open_optional("proc/sys/kernel/kexec_load_disabled")? {
+        let mut buf = String::new();
+        f.read_to_string(&mut buf)?;
+        if buf.trim() == "1" {
+            return Ok(Some(
+                "loading a kernel is disabled (kernel.kexec_load_disabled)",
+            ));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64"
+))]
+#[allow(unsafe_code)]
+fn kexec_file_load(
+    kernel: &std::fs::File,
+    initrd: Option<&std::fs::File>,
+    cmdline: &str,
+) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+    const KEXEC_FILE_NO_INITRAMFS: std::os::raw::c_ulong = 0x4;
+    let (initrd, flags) = match initrd {
+        Some(f) => (f.as_raw_fd(), 0),
+        None => (-1, KEXEC_FILE_NO_INITRAMFS),
+    };
+    let cmdline = std::ffi::CString::new(cmdline)?;
+    let cmdline = cmdline.as_bytes_with_nul();
+    // SAFETY: The file descriptors and command line are valid for the duration of the call
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_kexec_file_load,
+            kernel.as_raw_fd(),
+            initrd,
+            cmdline.len(),
+            cmdline.as_ptr(),
+            flags,
+        )
+    };
+    if r < 0 {
+        return Err(std::io::Error::last_os_error()).context("kexec_file_load");
+    }
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64"
+)))]
+fn kexec_file_load(_: &std::fs::File, _: Option<&std::fs::File>, _: &str) -> Result<()> {
+    anyhow::bail!("kexec is not supported on this architecture")
+}
+
+/// Load the kernel of the default boot entry in the provided `/boot`, with its kernel
+/// arguments, to be booted by the next kexec.
+#[context("Loading installed kernel for kexec")]
+pub(crate) fn load_kexec(bootfs: &Utf8Path) -> Result<()> {
+    let host = Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    if let Some(reason) = kexec_unsupported(&host)? {
+        anyhow::bail!("Cannot kexec: {reason}");
+    }
+    let bootfs = Dir::open_ambient_dir(bootfs, cap_std::ambient_authority())
+        .with_context(|| format!("Opening {bootfs}"))?;
+    let target = crate::bootloader::default_boot_target(&bootfs)?;
+    let open = |path: &str| -> Result<std::fs::File> {
+        let f = bootfs
+            .open(path)
+            .with_context(|| format!("Opening {path}"))?;
+        Ok(f.into_std())
+    };
+    let kernel = open(&target.kernel)?;
+    let initrd = target.initrd.as_deref().map(open).transpose()?;
+    kexec_file_load(&kernel, initrd.as_ref(), &target.options)?;
+    println!("Loaded {} for kexec", target.kernel);
+    Ok(())
+}
+
+/// Perform the post-install action; this must be the last thing done, as the host
+/// shuts down.
+pub(crate) fn run(action: PostInstall) -> Result<()> {
+    if action == PostInstall::None {
+        return Ok(());
+    }
+    // These are also the systemctl verbs
+    let verb = action.name();
+    // Make sure the result of the installation isn't lost
+    std::io::stdout().flush()?;
+    std::io::stderr().flush()?;
+    Task::new_cmd(
+        format!("Running systemctl {verb}"),
+        run_in_host_mountns("systemctl"),
+    )
+    .args([verb])
+    .run()
+}
+
+#[test]
+fn test_kexec_unsupported() {
+    let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    assert!(kexec_unsupported(&td).unwrap().is_some());
+    td.create_dir_all("sys/kernel").unwrap();
+    td.write("sys/kernel/kexec_loaded", "0\n").unwrap();
+    assert_eq!(kexec_unsupported(&td).unwrap(), None);
+    td.create_dir_all("proc/sys/kernel").unwrap();
+    td.write("proc/sys/kernel/kexec_load_disabled", "0\n")
+        .unwrap();
+    assert_eq!(kexec_unsupported(&td).unwrap(), None);
+    td.write("proc/sys/kernel/kexec_load_disabled", "1\n")
+        .unwrap();
+    assert!(kexec_unsupported(&td).unwrap().is_some());
+}
+
+#[test]
+fn test_is_loopback() {
+    let td = tempfile::tempdir().unwrap();
+    let td = Utf8Path::from_path(td.path()).unwrap();
+    std::fs::write(td.join("loop0"), "").unwrap();
+    std::fs::write(td.join("vda"), "").unwrap();
+    std::os::unix::fs::symlink(td.join("loop0"), td.join("by-id")).unwrap();
+    assert!(is_loopback(&td.join("loop0")).unwrap());
+    assert!(is_loopback(&td.join("by-id")).unwrap());
+    assert!(!is_loopback(&td.join("vda")).unwrap());
+    assert!(is_loopback(&td.join("missing")).is_err());
+}
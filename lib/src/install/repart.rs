@@ -0,0 +1,189 @@
+//! # systemd-repart definitions
+//!
+//! Describe the partitions created by the installer as repart.d(5) definition files,
+//! for use by systemd-repart configurations which must match them, e.g. for growing
+//! partitions on first boot or a factory reset.
+
+use anyhow::Result;
+use camino::Utf8Path;
+use cap_std::fs::Dir;
+use cap_std_ext::cap_std;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+
+use super::baseline::{ESP_TYPECODE, LINUX_TYPECODE, XBOOTLDR_TYPECODE};
+use crate::blockdev::PartitionTable;
+
+/// Partition type GUIDs which have an identifier in systemd-repart
+const REPART_TYPES: &[(&str, &str)] = &[
+    (ESP_TYPECODE, "esp"),
+    (XBOOTLDR_TYPECODE, "xbootldr"),
+    (LINUX_TYPECODE, "linux-generic"),
+];
+
+/// A partition, described as a repart.d definition.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct RepartDefinition {
+    number: u32,
+    /// The systemd-repart partition type identifier, or a type GUID
+    parttype: String,
+    label: Option<String>,
+    uuid: Option<String>,
+    size_min_bytes: u64,
+}
+
+impl RepartDefinition {
+    /// The file name; definitions are applied in the order of their file names, which
+    /// matches the order of the partitions.
+    fn file_name(&self) -> String {
+        let label = self
+            .label
+            .as_deref()
+            .filter(|l| {
+                !l.is_empty()
+                    && l.chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            })
+            .unwrap_or("partition");
+        format!("{:02}-{label}.conf", self.number)
+    }
+
+    fn to_conf(&self) -> String {
+        let mut r = String::from("[Partition]\n");
+        r.push_str(&format!("Type={}\n", self.parttype));
+        if let Some(label) = self.label.as_deref() {
+            r.push_str(&format!("Label={label}\n"));
+        }
+        if let Some(uuid) = self.uuid.as_deref() {
+            r.push_str(&format!("UUID={}\n", uuid.to_lowercase()));
+        }
+        r.push_str(&format!("SizeMinBytes={}\n", self.size_min_bytes));
+        r
+    }
+}
+
+/// Describe the provided GPT partition table, whose sizes are in sectors of
+/// `sector_size` bytes.
+pub(crate) fn definitions(
+    table: &PartitionTable,
+    sector_size: u64,
+) -> Result<Vec<RepartDefinition>> {
+    if table.label != "gpt" {
+        anyhow::bail!("Unsupported partition table type {}", table.label);
+    }
+    let mut r = table
+        .partitions
+        .iter()
+        .map(|p| {
+            let parttype = REPART_TYPES
+                .iter()
+                .find(|(guid, _)| guid.eq_ignore_ascii_case(&p.parttype))
+                .map_or_else(|| p.parttype.to_lowercase(), |(_, name)| name.to_string());
+            Ok(RepartDefinition {
+                number: p.number()?,
+                parttype,
+                label: p.name.clone().filter(|n| !n.is_empty()),
+                uuid: p.uuid.clone(),
+                size_min_bytes: p.size * sector_size,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    r.sort_by_key(|d| d.number);
+    Ok(r)
+}
+
+/// Write the definitions into the provided directory, which is created if necessary.
+#[context("Writing repart definitions to {dir}")]
+pub(crate) fn write_definitions(dir: &Utf8Path, definitions: &[RepartDefinition]) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let dir = Dir::open_ambient_dir(dir, cap_std::ambient_authority())?;
+    for def in definitions {
+        dir.atomic_write(def.file_name(), def.to_conf())?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_repart_definitions() {
+    let table: PartitionTable = serde_json::from_value(serde_json::json!({
+        "label": "gpt",
+        "partitions": [
+            {
+                "node": "/dev/vda1", "start": 2048, "size": 2048,
+                "type": "21686148-6449-6E6F-744E-656564454649",
+                "uuid": "6B1F2C3D-4E5F-4A6B-8C7D-9E0F1A2B3C4D", "name": "BIOS-BOOT"
+            },
+            {
+                "node": "/dev/vda2", "start": 4096, "size": 1048576,
+                "type": "C12A7328-F81F-11D2-BA4B-00A0C93EC93B",
+                "uuid": "0A1B2C3D-4E5F-4061-8293-A4B5C6D7E8F9", "name": "EFI-SYSTEM"
+            },
+            {
+                "node": "/dev/vda4", "start": 2101248, "size": 39841758,
+                "type": "0FC63DAF-8483-4772-8E79-3D69D8477DE4",
+                "uuid": "11223344-5566-4778-899A-ABBCCDDEEFF0", "name": "root"
+            },
+            {
+                "node": "/dev/vda3", "start": 1052672, "size": 1048576,
+                "type": "0FC63DAF-8483-4772-8E79-3D69D8477DE4",
+                "uuid": "AABBCCDD-EEFF-4011-9223-344556677889", "name": "boot"
+            },
+            {
+                "node": "/dev/vda5", "start": 41943006, "size": 32768,
+                "type": "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7",
+                "name": ""
+            }
+        ]
+    }))
+    .unwrap();
+    let defs = definitions(&table, 512).unwrap();
+    let files = defs
+        .iter()
+        .map(|d| (d.file_name(), d.to_conf()))
+        .collect::<Vec<_>>();
+    let expected = [
+        (
+            "01-BIOS-BOOT.conf",
+            "[Partition]\nType=21686148-6449-6e6f-744e-656564454649\nLabel=BIOS-BOOT\nUUID=6b1f2c3d-4e5f-4a6b-8c7d-9e0f1a2b3c4d\nSizeMinBytes=1048576\n",
+        ),
+        (
+            "02-EFI-SYSTEM.conf",
+            "[Partition]\nType=esp\nLabel=EFI-SYSTEM\nUUID=0a1b2c3d-4e5f-4061-8293-a4b5c6d7e8f9\nSizeMinBytes=536870912\n",
+        ),
+        (
+            "03-boot.conf",
+            "[Partition]\nType=linux-generic\nLabel=boot\nUUID=aabbccdd-eeff-4011-9223-344556677889\nSizeMinBytes=536870912\n",
+        ),
+        (
+            "04-root.conf",
+            "[Partition]\nType=linux-generic\nLabel=root\nUUID=11223344-5566-4778-899a-abbccddeeff0\nSizeMinBytes=20398980096\n",
+        ),
+        (
+            "05-partition.conf",
+            "[Partition]\nType=ebd0a0a2-b9e5-4433-87c0-68b6b72699c7\nSizeMinBytes=16777216\n",
+        ),
+    ];
+    assert_eq!(files.len(), expected.len());
+    for ((name, conf), (expected_name, expected_conf)) in files.iter().zip(expected) {
+        assert_eq!(name, expected_name);
+        assert_eq!(conf, expected_conf, "{name}");
+    }
+
+    // 4Kn disks
+    let defs = definitions(&table, 4096).unwrap();
+    assert_eq!(defs[0].size_min_bytes, 8 * 1024 * 1024);
+
+    let td = tempfile::tempdir().unwrap();
+    let dir = Utf8Path::from_path(td.path()).unwrap().join("repart.d");
+    write_definitions(&dir, &defs).unwrap();
+    let mut names = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, expected.map(|(name, _)| name));
+
+    let table: PartitionTable =
+        serde_json::from_value(serde_json::json!({ "label": "dos", "partitions": [] })).unwrap();
+    assert!(definitions(&table, 512).is_err());
+}
@@ -0,0 +1,207 @@
+//! # Machine readable error reports
+//!
+//! With `--format json`, all other output of the installation goes to standard error,
+//! and a single JSON object is written to standard output: the
+//! [`InstallSummary`] if it succeeds, and otherwise an error report.  The human
+//! readable error is still printed to standard error.  Fields may be added to the
+//! error report; any other change to it must increment [`ERROR_REPORT_VERSION`].
+
+use std::io::Write;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::summary::InstallSummary;
+use crate::task::TaskError;
+
+/// The version of the error report schema.
+const ERROR_REPORT_VERSION: u32 = 1;
+
+/// The exit code of bootc for all errors.
+const EXIT_CODE_FAILURE: i32 = 1;
+
+/// The format of the final result.
+#[derive(clap::ValueEnum, Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum OutputFormat {
+    /// Only human readable output
+    #[default]
+    Human,
+    /// Write a JSON error report to standard output on failure
+    Json,
+}
+
+/// The kind of an error.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ErrorClass {
+    /// An external command failed; see [`ErrorReport::command`]
+    Command,
+    /// Any other error
+    Other,
+}
+
+impl ErrorClass {
+    /// The exit code used for errors of this class.
+    fn exit_code(self) -> i32 {
+        match self {
+            ErrorClass::Command | ErrorClass::Other => EXIT_CODE_FAILURE,
+        }
+    }
+}
+
+/// An external command which failed.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct CommandFailure {
+    description: String,
+    command: String,
+    /// The exit code, unless the command was killed by a signal
+    exit_code: Option<i32>,
+    signal: Option<i32>,
+}
+
+/// A description of an error, serialized as JSON.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct ErrorReport {
+    version: u32,
+    class: ErrorClass,
+    exit_code: i32,
+    /// The outermost error message
+    message: String,
+    /// The messages of the error and each of its causes, outermost first
+    chain: Vec<String>,
+    /// The installation phase which failed, if it had started
+    phase: Option<&'static str>,
+    command: Option<CommandFailure>,
+}
+
+impl ErrorReport {
+    fn new(e: &anyhow::Error, phase: Option<super::metrics::Phase>) -> Self {
+        use std::os::unix::process::ExitStatusExt;
+        let command = e
+            .chain()
+            .find_map(|e| e.downcast_ref::<TaskError>())
+            .map(|t| CommandFailure {
+                description: t.description.clone(),
+                command: t.command.clone(),
+                exit_code: t.status.code(),
+                signal: t.status.signal(),
+            });
+        let class = if command.is_some() {
+            ErrorClass::Command
+        } else {
+            ErrorClass::Other
+        };
+        Self {
+            version: ERROR_REPORT_VERSION,
+            class,
+            exit_code: class.exit_code(),
+            message: e.to_string(),
+            chain: e.chain().map(|e| e.to_string()).collect(),
+            phase: phase.map(|p| p.name()),
+            command,
+        }
+    }
+}
+
+/// Run an installation, reporting its result in the provided format.  Errors are
+/// returned unchanged.
+pub(crate) async fn run_reporting(
+    format: OutputFormat,
+    f: impl std::future::Future<Output = Result<InstallSummary>>,
+) -> Result<InstallSummary> {
+    if format == OutputFormat::Human {
+        return f.await;
+    }
+    // A process re-executed while this is active gets the original stdout back, and
+    // sets up its own redirection as it also runs this.
+    let redirect = crate::utils::StdoutToStderr::new()?;
+    let r = f.await;
+    drop(redirect);
+    let mut stdout = std::io::stdout().lock();
+    match r.as_ref() {
+        Ok(summary) => serde_json::to_writer(&mut stdout, summary)?,
+        Err(e) => {
+            let report = ErrorReport::new(e, super::metrics::current_phase());
+            serde_json::to_writer(&mut stdout, &report)?
+        }
+    }
+    writeln!(stdout)?;
+    stdout.flush()?;
+    r
+}
+
+#[test]
+fn test_error_report() {
+    use std::os::unix::process::ExitStatusExt;
+    let status = std::process::ExitStatus::from_raw(1 << 8);
+    let e = anyhow::Error::new(TaskError {
+        description: "Creating filesystem".into(),
+        command: "mkfs.xfs -m uuid=0123 /dev/vda4".into(),
+        status,
+    })
+    .context("Initializing /boot")
+    .context("Creating rootfs");
+    let report = ErrorReport::new(&e, Some(super::metrics::Phase::Partition));
+    assert_eq!(
+        serde_json::to_value(&report).unwrap(),
+        serde_json::json!({
+            "version": 1,
+            "class": "command",
+            "exit-code": 1,
+            "message": "Creating rootfs",
+            "chain": [
+                "Creating rootfs",
+                "Initializing /boot",
+                format!("Task Creating filesystem failed: {status:?}")
+            ],
+            "phase": "partition",
+            "command": {
+                "description": "Creating filesystem",
+                "command": "mkfs.xfs -m uuid=0123 /dev/vda4",
+                "exit-code": 1,
+                "signal": null,
+            }
+        })
+    );
+
+    // Killed by SIGKILL
+    let e = anyhow::Error::new(TaskError {
+        description: "Running bootupctl to install bootloader".into(),
+        command: "bootupctl backend install".into(),
+        status: std::process::ExitStatus::from_raw(9),
+    });
+    let report = serde_json::to_value(ErrorReport::new(&e, None)).unwrap();
+    assert_eq!(report["command"]["exit-code"], serde_json::Value::Null);
+    assert_eq!(report["command"]["signal"], 9);
+    assert_eq!(report["phase"], serde_json::Value::Null);
+
+    let e = anyhow::anyhow!("No such OCI archive: /run/media/os.ociarchive")
+        .context("Preparing installation");
+    let report =
+        serde_json::to_value(ErrorReport::new(&e, Some(super::metrics::Phase::Prepare))).unwrap();
+    assert_eq!(report["class"], "other");
+    assert_eq!(report["chain"].as_array().unwrap().len(), 2);
+    assert_eq!(report["command"], serde_json::Value::Null);
+}
+
+#[test]
+fn test_output_format_opts() {
+    use clap::Parser;
+    let o =
+        super::InstallOpts::try_parse_from(["install", "--format", "json", "/dev/vda"]).unwrap();
+    assert_eq!(o.config_opts.format, OutputFormat::Json);
+    let o = super::InstallOpts::try_parse_from(["install", "--print-env", "/dev/vda"]).unwrap();
+    assert_eq!(o.config_opts.format, OutputFormat::Human);
+    // Both use standard output
+    let o = super::InstallOpts::try_parse_from([
+        "install",
+        "--format",
+        "json",
+        "--print-env",
+        "/dev/vda",
+    ]);
+    assert!(o.is_err());
+}
@@ -0,0 +1,200 @@
+//! # Software bill of materials
+//!
+//! Save the SBOM of the installed image into the target for supply-chain compliance.
+//! It is preferably fetched from the registry as an artifact attached to the image
+//! using the cosign tag scheme (`<repository>:sha256-<hex>.sbom`), since the image proxy
+//! does not support the referrers API; otherwise a file embedded in the image is used.
+
+use std::os::unix::fs::PermissionsExt;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std::fs::{Dir, Permissions};
+use cap_std_ext::cap_std;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::oci_spec::image::{Descriptor, ImageManifest};
+use tokio::io::AsyncReadExt;
+
+/// Where the SBOM is written, relative to the deployment root
+pub(crate) const SBOM_PATH: &str = "etc/bootc/sbom.json";
+/// Directories in the image which may contain an embedded SBOM
+const EMBEDDED_SBOM_DIRS: &[&str] = &["usr/share/sbom", "usr/share/buildinfo"];
+
+/// The reference to the SBOM attached to the provided image (in a registry) by cosign.
+pub(crate) fn sbom_tag_imgref(name: &str, digest: &str) -> String {
+    let repo = name.split_once('@').map_or(name, |(repo, _)| repo);
+    // Strip any tag, taking care not to confuse a registry port for one
+    let repo = match repo.rsplit_once(':') {
+        Some((repo, tag)) if !tag.contains('/') => repo,
+        _ => repo,
+    };
+    let tag = digest.replace(':', "-");
+    format!("docker://{repo}:{tag}.sbom")
+}
+
+/// Whether the media type is that of a JSON SBOM, as SPDX or CycloneDX.
+fn is_sbom_media_type(media_type: &str) -> bool {
+    (media_type.contains("spdx") || media_type.contains("cyclonedx")) && media_type.contains("json")
+}
+
+/// Find the SBOM in the provided artifact manifest.
+pub(crate) fn find_sbom_layer(manifest: &ImageManifest) -> Option<&Descriptor> {
+    manifest
+        .layers()
+        .iter()
+        .find(|l| is_sbom_media_type(&l.media_type().to_string()))
+}
+
+/// Fetch the SBOM attached to the provided image; returns `None` if there is none.
+#[context("Fetching SBOM")]
+pub(crate) async fn fetch_sbom_referrer(
+    config: ostree_ext::container::store::ImageProxyConfig,
+    name: &str,
+    digest: &str,
+) -> Result<Option<Vec<u8>>> {
+    let imgref = sbom_tag_imgref(name, digest);
+    let proxy = containers_image_proxy::ImageProxy::new_with_config(config).await?;
+    let img = match proxy.open_image(&imgref).await {
+        Ok(img) => img,
+        Err(e) => {
+            tracing::debug!("Opening {imgref}: {e}");
+            return Ok(None);
+        }
+    };
+    let (_, manifest) = proxy.fetch_manifest(&img).await?;
+    let layer = if let Some(layer) = find_sbom_layer(&manifest) {
+        layer
+    } else {
+        return Ok(None);
+    };
+    let size = u64::try_from(layer.size()).context("Invalid SBOM size")?;
+    let (mut blob, driver) = proxy.get_blob(&img, layer.digest(), size).await?;
+    let mut buf = Vec::new();
+    // The proxy closes the pipe once it has written the blob, so it can be read
+    // in full before waiting for the result
+    blob.read_to_end(&mut buf).await?;
+    drop(blob);
+    driver.await?;
+    proxy.close_image(&img).await?;
+    proxy.finalize().await?;
+    Ok(Some(buf))
+}
+
+/// Find an SBOM embedded in the provided root, returning its path and contents.
+#[context("Finding embedded SBOM")]
+pub(crate) fn find_embedded_sbom(root: &Dir) -> Result<Option<(Utf8PathBuf, Vec<u8>)>> {
+    for dir in EMBEDDED_SBOM_DIRS {
+        let d = if let Some(d) = root.open_dir_optional(dir)? {
+            d
+        } else {
+            continue;
+        };
+        let mut names = Vec::new();
+        for e in d.entries()? {
+            let e = e?;
+            if !e.file_type()?.is_file() {
+                continue;
+            }
+            if let Some(name) = e.file_name().to_str().filter(|n| n.ends_with(".json")) {
+                names.push(name.to_owned());
+            }
+        }
+        names.sort();
+        if let Some(name) = names.into_iter().next() {
+            let contents = d.read(&name).with_context(|| format!("Reading {name}"))?;
+            return Ok(Some((Utf8Path::new(dir).join(name), contents)));
+        }
+    }
+    Ok(None)
+}
+
+/// Validate and atomically write the SBOM into the provided deployment root.
+#[context("Writing SBOM")]
+pub(crate) fn write_sbom(root: &Dir, contents: &[u8]) -> Result<()> {
+    serde_json::from_slice::<serde_json::Value>(contents).context("Parsing SBOM")?;
+    // SAFETY: The path has a parent
+    root.create_dir_all(Utf8Path::new(SBOM_PATH).parent().unwrap())?;
+    root.atomic_write_with_perms(SBOM_PATH, contents, Permissions::from_mode(0o644))?;
+    Ok(())
+}
+
+#[test]
+fn test_sbom_tag_imgref() {
+    let digest = "sha256:0123abcd";
+    for name in [
+        "quay.io/example/os",
+        "quay.io/example/os:latest",
+        "quay.io/example/os@sha256:4567ef01",
+        "quay.io/example/os:latest@sha256:4567ef01",
+    ] {
+        assert_eq!(
+            sbom_tag_imgref(name, digest),
+            "docker://quay.io/example/os:sha256-0123abcd.sbom"
+        );
+    }
+    assert_eq!(
+        sbom_tag_imgref("localhost:5000/os", digest),
+        "docker://localhost:5000/os:sha256-0123abcd.sbom"
+    );
+}
+
+#[test]
+fn test_find_sbom_layer() {
+    let manifest = |media_type: &str| -> ImageManifest {
+        serde_json::from_value(serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+                "size": 2
+            },
+            "layers": [{
+                "mediaType": media_type,
+                "digest": "sha256:a8b2a3fe2d2bd6ea4e6a9d8a6f6c3c9d1ed9cb2e3f5ad1c2b4b5b5fbd0b34a7e",
+                "size": 1234
+            }]
+        }))
+        .unwrap()
+    };
+    for media_type in [
+        "text/spdx+json",
+        "application/spdx+json",
+        "application/vnd.cyclonedx+json",
+    ] {
+        let manifest = manifest(media_type);
+        let layer = find_sbom_layer(&manifest).unwrap();
+        assert_eq!(layer.size(), 1234);
+    }
+    for media_type in ["application/vnd.oci.image.layer.v1.tar+gzip", "text/spdx"] {
+        assert!(find_sbom_layer(&manifest(media_type)).is_none());
+    }
+}
+
+#[test]
+fn test_embedded_sbom() {
+    let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    assert!(find_embedded_sbom(&td).unwrap().is_none());
+    td.create_dir_all("usr/share/buildinfo").unwrap();
+    td.write(
+        "usr/share/buildinfo/sbom-os.json",
+        r#"{"spdxVersion": "SPDX-2.3"}"#,
+    )
+    .unwrap();
+    td.write("usr/share/buildinfo/content.txt", "").unwrap();
+    let (path, contents) = find_embedded_sbom(&td).unwrap().unwrap();
+    assert_eq!(path, "usr/share/buildinfo/sbom-os.json");
+
+    assert!(write_sbom(&td, b"not json").is_err());
+    assert!(!td.try_exists(SBOM_PATH).unwrap());
+    write_sbom(&td, &contents).unwrap();
+    assert_eq!(td.read(SBOM_PATH).unwrap(), contents);
+    // Only the SBOM itself should be left behind
+    let names = td
+        .read_dir("etc/bootc")
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["sbom.json"]);
+}
@@ -0,0 +1,303 @@
+//! # Presetting SELinux booleans
+//!
+//! Appliances often need SELinux booleans such as `httpd_can_network_connect` turned
+//! on.  With `--selinux-boolean`, they are written as local modifications into the
+//! deployment's SELinux policy store, and the policy is rebuilt, just like
+//! `setsebool -P`; so they are in effect from the first boot.
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std::fs::Dir;
+use cap_std_ext::cap_std;
+use fn_error_context::context;
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+
+use crate::task::Task;
+
+/// The libsemanage configuration, relative to the deployment
+const SEMANAGE_CONF: &str = "etc/selinux/semanage.conf";
+/// The default location of the policy stores, as in libsemanage
+const DEFAULT_STORE_ROOT: &str = "/var/lib/selinux";
+/// The default policy, as in libselinux
+const DEFAULT_POLICY: &str = "targeted";
+/// The local boolean modifications, relative to the policy store
+const BOOLEANS_LOCAL: &str = "active/booleans.local";
+/// The booleans of the loaded policy, in selinuxfs
+const SELINUXFS_BOOLEANS: &str = "/sys/fs/selinux/booleans";
+
+/// An SELinux boolean to set persistently.
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeFromStr, SerializeDisplay)]
+pub(crate) struct SelinuxBoolean {
+    name: String,
+    value: bool,
+}
+
+impl FromStr for SelinuxBoolean {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, value) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Invalid SELinux boolean {s}: expected NAME=on|off"))?;
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            anyhow::bail!("Invalid SELinux boolean name {name:?}");
+        }
+        let value = match value {
+            "on" | "1" | "true" => true,
+            "off" | "0" | "false" => false,
+            o => anyhow::bail!("Invalid value for SELinux boolean {name}: {o}"),
+        };
+        Ok(Self {
+            name: name.to_string(),
+            value,
+        })
+    }
+}
+
+impl Display for SelinuxBoolean {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = if self.value { "on" } else { "off" };
+        write!(f, "{}={value}", self.name)
+    }
+}
+
+/// Check that the provided booleans exist in the policy in the provided selinuxfs
+/// `booleans` directory.
+fn validate_in(booleans_dir: &Dir, booleans: &[SelinuxBoolean]) -> Result<()> {
+    let unknown = booleans
+        .iter()
+        .map(|b| b.name.as_str())
+        .filter(|name| !booleans_dir.exists(name))
+        .collect::<Vec<_>>();
+    if !unknown.is_empty() {
+        anyhow::bail!("Unknown SELinux booleans: {}", unknown.join(", "));
+    }
+    Ok(())
+}
+
+/// Check that the provided booleans exist in the loaded policy; this is skipped if
+/// SELinux is disabled on the host.
+#[context("Validating SELinux booleans")]
+pub(crate) fn validate(booleans: &[SelinuxBoolean]) -> Result<()> {
+    if Utf8Path::new(SELINUXFS_BOOLEANS)
+        .symlink_metadata()
+        .is_err()
+    {
+        tracing::debug!("No {SELINUXFS_BOOLEANS}; not validating SELinux booleans");
+        return Ok(());
+    }
+    let dir = Dir::open_ambient_dir(SELINUXFS_BOOLEANS, cap_std::ambient_authority())?;
+    validate_in(&dir, booleans)
+}
+
+/// The value of the provided key in a configuration of `key=value` lines, if any.
+fn config_value<'c>(contents: &'c str, key: &str) -> Option<&'c str> {
+    contents.lines().find_map(|line| {
+        let (k, v) = line.split_once('=')?;
+        (k.trim() == key).then(|| v.trim())
+    })
+}
+
+/// Find the policy store of the deployment, relative to it.
+fn policy_store(deployment_root: &Utf8Path) -> Result<Utf8PathBuf> {
+    let read = |path: &str| -> Result<Option<String>> {
+        let path = deployment_root.join(path);
+        match std::fs::read_to_string(&path) {
+            Ok(c) => Ok(Some(c)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Reading {path}")),
+        }
+    };
+    let config = read(crate::lsm::SELINUX_CONFIG)?.ok_or_else(|| {
+        anyhow::anyhow!(
+            "No /{}; does the image use SELinux?",
+            crate::lsm::SELINUX_CONFIG
+        )
+    })?;
+    let policy = config_value(&config, "SELINUXTYPE").unwrap_or(DEFAULT_POLICY);
+    let semanage_conf = read(SEMANAGE_CONF)?;
+    let store_root = semanage_conf
+        .as_deref()
+        .and_then(|c| config_value(c, "store-root"))
+        .unwrap_or(DEFAULT_STORE_ROOT);
+    // The deployment's /var is not the one of the installed system
+    if store_root.starts_with("/var/") {
+        anyhow::bail!(
+            "The SELinux policy store in {store_root} is not supported; it must be in /etc"
+        );
+    }
+    Ok(Utf8Path::new(store_root.trim_start_matches('/')).join(policy))
+}
+
+/// Merge the provided booleans into the contents of `booleans.local`, replacing any
+/// previous settings of them.
+fn booleans_local_contents(previous: &str, booleans: &[SelinuxBoolean]) -> String {
+    let mut r = String::new();
+    for line in previous.lines() {
+        let name = line.split_once('=').map(|(k, _)| k.trim());
+        if name.map_or(false, |n| booleans.iter().any(|b| b.name == n)) {
+            continue;
+        }
+        r.push_str(line);
+        r.push('\n');
+    }
+    for b in booleans {
+        r.push_str(&format!("{}={}\n", b.name, u8::from(b.value)));
+    }
+    r
+}
+
+/// Write the provided booleans into the policy store of the deployment, returning the
+/// policy store relative to the deployment root.  The policy must be rebuilt with
+/// [`rebuild_policy`] afterwards.
+#[context("Writing SELinux booleans")]
+pub(crate) fn write_booleans(
+    deployment_root: &Utf8Path,
+    booleans: &[SelinuxBoolean],
+) -> Result<Utf8PathBuf> {
+    let store = policy_store(deployment_root)?;
+    let path = deployment_root.join(&store).join(BOOLEANS_LOCAL);
+    let previous = match std::fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("Reading {path}")),
+    };
+    // SAFETY: The path has a parent
+    let dir = path.parent().unwrap();
+    if dir.symlink_metadata().is_err() {
+        anyhow::bail!("No SELinux policy store found in /{store}");
+    }
+    std::fs::write(&path, booleans_local_contents(&previous, booleans))
+        .with_context(|| format!("Writing {path}"))?;
+    Ok(store)
+}
+
+/// Rebuild the SELinux policy of the deployment, applying the local modifications.
+pub(crate) fn rebuild_policy(deployment_root: &Utf8Path) -> Result<()> {
+    let mut cmd = std::process::Command::new("chroot");
+    cmd.args([deployment_root.as_str(), "semodule", "-N", "-B"]);
+    Task::new_cmd("Rebuilding SELinux policy", cmd).run()
+}
+
+#[test]
+fn test_selinux_boolean() {
+    for (s, name, value) in [
+        (
+            "httpd_can_network_connect=on",
+            "httpd_can_network_connect",
+            true,
+        ),
+        ("virt_use_nfs=1", "virt_use_nfs", true),
+        (
+            "container_manage_cgroup=off",
+            "container_manage_cgroup",
+            false,
+        ),
+        (
+            "container_manage_cgroup=false",
+            "container_manage_cgroup",
+            false,
+        ),
+    ] {
+        let b = SelinuxBoolean::from_str(s).unwrap();
+        assert_eq!(b.name, name);
+        assert_eq!(b.value, value);
+    }
+    assert_eq!(
+        SelinuxBoolean::from_str("virt_use_nfs=1")
+            .unwrap()
+            .to_string(),
+        "virt_use_nfs=on"
+    );
+    for invalid in [
+        "",
+        "httpd_can_network_connect",
+        "=on",
+        "httpd_can_network_connect=yes",
+        "../foo=on",
+        "foo bar=on",
+    ] {
+        assert!(SelinuxBoolean::from_str(invalid).is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn test_validate_booleans() {
+    let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    td.write("httpd_can_network_connect", "0 0").unwrap();
+    td.write("virt_use_nfs", "0 0").unwrap();
+    let parse =
+        |s: &[&str]| -> Vec<SelinuxBoolean> { s.iter().map(|s| s.parse().unwrap()).collect() };
+    validate_in(
+        &td,
+        &parse(&["httpd_can_network_connect=on", "virt_use_nfs=off"]),
+    )
+    .unwrap();
+    let e = validate_in(
+        &td,
+        &parse(&["httpd_can_network_connect=on", "httpd_typo=on", "other=off"]),
+    )
+    .unwrap_err();
+    assert_eq!(e.to_string(), "Unknown SELinux booleans: httpd_typo, other");
+}
+
+#[test]
+fn test_write_booleans() {
+    let td = tempfile::tempdir().unwrap();
+    let root = Utf8Path::from_path(td.path()).unwrap();
+    let booleans = ["httpd_can_network_connect=on", "virt_use_nfs=off"]
+        .map(|s| s.parse::<SelinuxBoolean>().unwrap());
+    // Not an SELinux image
+    assert!(write_booleans(root, &booleans).is_err());
+    std::fs::create_dir_all(root.join("etc/selinux")).unwrap();
+    std::fs::write(
+        root.join(crate::lsm::SELINUX_CONFIG),
+        "SELINUX=enforcing\nSELINUXTYPE=targeted\n",
+    )
+    .unwrap();
+    // The default store is in /var
+    assert!(write_booleans(root, &booleans).is_err());
+    std::fs::write(
+        root.join(SEMANAGE_CONF),
+        "module-store = direct\nstore-root = /etc/selinux\n",
+    )
+    .unwrap();
+    // No policy store
+    assert!(write_booleans(root, &booleans).is_err());
+    std::fs::create_dir_all(root.join("etc/selinux/targeted/active")).unwrap();
+    let path = root.join("etc/selinux/targeted").join(BOOLEANS_LOCAL);
+    std::fs::write(
+        &path,
+        "# This file is auto-generated by libsemanage\nvirt_use_nfs=1\nssh_sysadm_login=1\n",
+    )
+    .unwrap();
+    assert_eq!(
+        write_booleans(root, &booleans).unwrap(),
+        "etc/selinux/targeted"
+    );
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "# This file is auto-generated by libsemanage\n\
+         ssh_sysadm_login=1\n\
+         httpd_can_network_connect=1\n\
+         virt_use_nfs=0\n"
+    );
+    // Without previous modifications, and with another policy
+    std::fs::write(
+        root.join(crate::lsm::SELINUX_CONFIG),
+        "SELINUX=enforcing\nSELINUXTYPE=mls\n",
+    )
+    .unwrap();
+    std::fs::create_dir_all(root.join("etc/selinux/mls/active")).unwrap();
+    assert_eq!(
+        write_booleans(root, &booleans[..1]).unwrap(),
+        "etc/selinux/mls"
+    );
+    assert_eq!(
+        std::fs::read_to_string(root.join("etc/selinux/mls").join(BOOLEANS_LOCAL)).unwrap(),
+        "httpd_can_network_connect=1\n"
+    );
+}
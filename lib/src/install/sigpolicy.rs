@@ -0,0 +1,234 @@
+//! # Container signature policy for updates
+//!
+//! The installed system verifies updates using the `/etc/containers/policy.json`
+//! shipped in the image, which commonly defaults to `insecureAcceptAnything`.
+//! This detects that case, and supports installing a stricter policy along with
+//! the sigstore configuration (see containers-registries.d(5)) it relies on.
+
+use std::collections::BTreeMap;
+use std::os::unix::prelude::PermissionsExt;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std::fs::{Dir, Permissions};
+use cap_std_ext::cap_std;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use serde::Deserialize;
+
+/// The signature policy, relative to the root
+pub(crate) const POLICY_PATH: &str = "etc/containers/policy.json";
+/// Directory for registry (including sigstore) configuration, relative to the root
+pub(crate) const REGISTRIES_D: &str = "etc/containers/registries.d";
+/// The requirement type which accepts any image
+const INSECURE_ACCEPT_ANYTHING: &str = "insecureAcceptAnything";
+
+/// A single policy requirement; we only need the type.
+#[derive(Debug, Deserialize)]
+struct Requirement {
+    #[serde(rename = "type")]
+    ty: String,
+}
+
+/// The subset of containers-policy.json(5) needed to find the requirements for an image.
+#[derive(Debug, Deserialize)]
+struct Policy {
+    default: Vec<Requirement>,
+    #[serde(default)]
+    transports: BTreeMap<String, BTreeMap<String, Vec<Requirement>>>,
+}
+
+/// Whether a `docker` transport scope applies to the provided image reference.
+fn scope_matches(scope: &str, image: &str) -> bool {
+    if let Some(domain) = scope.strip_prefix("*.") {
+        let host = image.split('/').next().unwrap_or_default();
+        return host.ends_with(&format!(".{domain}"));
+    }
+    image == scope
+        || image
+            .strip_prefix(scope)
+            .and_then(|r| r.chars().next())
+            .map_or(false, |c| matches!(c, '/' | ':' | '@'))
+}
+
+impl Policy {
+    /// Find the requirements applying to the provided `docker` transport image, using
+    /// the most specific matching scope.
+    fn requirements_for(&self, image: &str) -> &[Requirement] {
+        let scopes = self.transports.get("docker");
+        let matching = scopes
+            .into_iter()
+            .flatten()
+            .filter(|(scope, _)| !scope.is_empty() && scope_matches(scope, image));
+        // Explicit scopes are more specific than wildcards, and longer more specific than shorter
+        let best = matching.max_by_key(|(scope, _)| (!scope.starts_with("*."), scope.len()));
+        if let Some((_, reqs)) = best {
+            return reqs;
+        }
+        scopes
+            .and_then(|s| s.get(""))
+            .map(|r| r.as_slice())
+            .unwrap_or(&self.default)
+    }
+}
+
+/// Parse and validate a signature policy.
+fn parse_policy(contents: &str) -> Result<Policy> {
+    let policy: Policy = serde_json::from_str(contents).context("Parsing signature policy")?;
+    Ok(policy)
+}
+
+/// Whether the policy accepts any image from the provided `docker` transport reference,
+/// i.e. all of its requirements are `insecureAcceptAnything`.
+pub(crate) fn accepts_anything(contents: &str, image: &str) -> Result<bool> {
+    let policy = parse_policy(contents)?;
+    let reqs = policy.requirements_for(image);
+    Ok(!reqs.is_empty() && reqs.iter().all(|r| r.ty == INSECURE_ACCEPT_ANYTHING))
+}
+
+/// A validated signature policy and sigstore configuration to install.
+#[derive(Debug, Default)]
+pub(crate) struct InstallPolicy {
+    policy: Option<String>,
+    /// The file name and contents of the registries.d configuration
+    sigstore: Option<(String, String)>,
+}
+
+impl InstallPolicy {
+    /// Read and validate the provided files.
+    #[context("Reading signature policy")]
+    pub(crate) fn new(policy: Option<&Utf8Path>, sigstore: Option<&Utf8Path>) -> Result<Self> {
+        let policy = policy
+            .map(|path| -> Result<_> {
+                let contents =
+                    std::fs::read_to_string(path).with_context(|| format!("Reading {path}"))?;
+                parse_policy(&contents).with_context(|| format!("Validating {path}"))?;
+                Ok(contents)
+            })
+            .transpose()?;
+        let sigstore = sigstore
+            .map(|path| -> Result<_> {
+                let name = path
+                    .file_name()
+                    .filter(|n| n.ends_with(".yaml"))
+                    .ok_or_else(|| anyhow::anyhow!("Expected a .yaml file: {path}"))?;
+                let contents =
+                    std::fs::read_to_string(path).with_context(|| format!("Reading {path}"))?;
+                Ok((name.to_string(), contents))
+            })
+            .transpose()?;
+        Ok(Self { policy, sigstore })
+    }
+
+    /// Write the configuration into the provided deployment root, returning the
+    /// paths written.
+    #[context("Installing signature policy")]
+    pub(crate) fn write(&self, root: &Dir) -> Result<Vec<Utf8PathBuf>> {
+        let mut r = Vec::new();
+        let perms = || Permissions::from_mode(0o644);
+        if let Some(policy) = self.policy.as_deref() {
+            root.create_dir_all(Utf8Path::new(POLICY_PATH).parent().unwrap())?;
+            root.atomic_write_with_perms(POLICY_PATH, policy, perms())
+                .with_context(|| format!("Writing {POLICY_PATH}"))?;
+            r.push(POLICY_PATH.into());
+        }
+        if let Some((name, contents)) = self.sigstore.as_ref() {
+            root.create_dir_all(REGISTRIES_D)?;
+            let path = Utf8Path::new(REGISTRIES_D).join(name);
+            root.atomic_write_with_perms(&path, contents, perms())
+                .with_context(|| format!("Writing {path}"))?;
+            r.push(path);
+        }
+        Ok(r)
+    }
+}
+
+/// Check whether the policy in the provided root effectively accepts any image for
+/// the provided reference.  Returns `None` if there is no policy.
+pub(crate) fn deployment_accepts_anything(root: &Dir, image: &str) -> Result<Option<bool>> {
+    let contents = if let Some(mut f) = root.open_optional(POLICY_PATH)? {
+        let mut buf = String::new();
+        std::io::Read::read_to_string(&mut f, &mut buf)?;
+        buf
+    } else {
+        return Ok(None);
+    };
+    accepts_anything(&contents, image)
+        .with_context(|| format!("Checking {POLICY_PATH}"))
+        .map(Some)
+}
+
+#[test]
+fn test_accepts_anything() {
+    let image = "quay.io/example/os:latest";
+    // The common default
+    let insecure = r#"{"default": [{"type": "insecureAcceptAnything"}], "transports": {"docker-daemon": {"": [{"type": "insecureAcceptAnything"}]}}}"#;
+    assert!(accepts_anything(insecure, image).unwrap());
+    // Rejecting by default, with the image's repository signed
+    let signed = r#"{
+        "default": [{"type": "reject"}],
+        "transports": {
+            "docker": {
+                "quay.io/example": [{"type": "sigstoreSigned", "keyPath": "/etc/pki/example.pub"}],
+                "quay.io/example/unsigned": [{"type": "insecureAcceptAnything"}]
+            }
+        }
+    }"#;
+    assert!(!accepts_anything(signed, image).unwrap());
+    assert!(accepts_anything(signed, "quay.io/example/unsigned:latest").unwrap());
+    assert!(!accepts_anything(signed, "quay.io/examples/os:latest").unwrap());
+    // A transport-wide default and a wildcard scope
+    let wildcard = r#"{
+        "default": [{"type": "reject"}],
+        "transports": {
+            "docker": {
+                "": [{"type": "insecureAcceptAnything"}],
+                "*.example.com": [{"type": "signedBy", "keyType": "GPGKeys", "keyPath": "/etc/pki/key.gpg"}]
+            }
+        }
+    }"#;
+    assert!(accepts_anything(wildcard, image).unwrap());
+    assert!(!accepts_anything(wildcard, "registry.example.com/os:latest").unwrap());
+    assert!(accepts_anything(wildcard, "example.com/os:latest").unwrap());
+    // Mixed requirements must all be satisfied
+    let mixed = r#"{"default": [{"type": "insecureAcceptAnything"}, {"type": "sigstoreSigned"}]}"#;
+    assert!(!accepts_anything(mixed, image).unwrap());
+    // Invalid policies
+    assert!(accepts_anything("{}", image).is_err());
+    assert!(accepts_anything("not json", image).is_err());
+}
+
+#[test]
+fn test_install_policy() {
+    let td = tempfile::tempdir().unwrap();
+    let src = Utf8Path::from_path(td.path()).unwrap();
+    let policy = src.join("policy.json");
+    let sigstore = src.join("example.yaml");
+    std::fs::write(&policy, r#"{"default": [{"type": "reject"}]}"#).unwrap();
+    std::fs::write(
+        &sigstore,
+        "docker:\n  quay.io/example:\n    use-sigstore-attachments: true\n",
+    )
+    .unwrap();
+    assert!(InstallPolicy::new(Some(&sigstore), None).is_err());
+    assert!(InstallPolicy::new(None, Some(&policy)).is_err());
+
+    let root = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    assert_eq!(
+        deployment_accepts_anything(&root, "quay.io/example/os").unwrap(),
+        None
+    );
+    let written = InstallPolicy::new(Some(&policy), Some(&sigstore))
+        .unwrap()
+        .write(&root)
+        .unwrap();
+    assert_eq!(
+        written,
+        [POLICY_PATH, "etc/containers/registries.d/example.yaml"]
+    );
+    assert_eq!(
+        deployment_accepts_anything(&root, "quay.io/example/os").unwrap(),
+        Some(false)
+    );
+    assert!(InstallPolicy::default().write(&root).unwrap().is_empty());
+}
@@ -0,0 +1,202 @@
+//! # How the source image is read
+//!
+//! The source image is normally read directly from the host's container storage by
+//! skopeo.  When that storage is read-only, e.g. as podman runs the image from an
+//! additional image store, skopeo fails to lock it, which surfaces late as a generic
+//! error.  Instead, this is detected before installing, and the image is pulled by
+//! digest from the registry reference recorded in its `org.opencontainers.image.ref.name`
+//! annotation; without one, it is copied to an OCI directory first.  If container
+//! storage can't be queried, it is read directly, as before.
+
+use std::fmt::Display;
+
+use anyhow::Result;
+use camino::Utf8Path;
+use fn_error_context::context;
+
+use crate::podman::{Inspect, StoreInfo};
+
+/// How the source image is read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SourceStrategy {
+    /// Directly from container storage
+    ContainerStorage,
+    /// Pulled from the registry by digest, with the provided digested pull spec
+    Registry(String),
+    /// Copied to an OCI directory first, for the provided reason
+    OciCopy(String),
+}
+
+impl Display for SourceStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ContainerStorage => f.write_str("container storage"),
+            Self::Registry(spec) => write!(f, "registry ({spec})"),
+            Self::OciCopy(reason) => write!(f, "OCI copy ({reason})"),
+        }
+    }
+}
+
+/// Why the container storage holding the image is read-only, if it is.  `image_dir`
+/// is where the storage driver keeps the image, if known.
+fn readonly_reason(
+    info: &StoreInfo,
+    image_dir: Option<&str>,
+    graph_root_writable: bool,
+) -> Option<String> {
+    if let Some(image_dir) = image_dir.map(Utf8Path::new) {
+        let store = info
+            .additional_image_stores()
+            .into_iter()
+            .find(|s| image_dir.starts_with(s));
+        if let Some(store) = store {
+            return Some(format!(
+                "the image is in the additional image store {store}"
+            ));
+        }
+    }
+    if !graph_root_writable {
+        return Some(format!("{} is read-only", info.graph_root));
+    }
+    None
+}
+
+/// The annotation recording the reference the image was built or pulled as
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+/// Whether the image name refers to a registry, rather than e.g. a local build.
+fn is_registry_image(name: &str) -> bool {
+    match name.split_once('/') {
+        Some((host, _)) => (host.contains('.') || host.contains(':')) && host != "localhost",
+        None => false,
+    }
+}
+
+/// The registry reference recorded in the image's annotations, if any.
+fn registry_reference(inspect: &Inspect) -> Option<&str> {
+    inspect
+        .annotations
+        .as_ref()?
+        .get(REF_NAME_ANNOTATION)
+        .map(String::as_str)
+        .filter(|r| is_registry_image(r))
+}
+
+/// Select how to read the image with the provided registry reference and digest.
+fn select_strategy(
+    supports_containers_storage: bool,
+    readonly: Option<String>,
+    registry_reference: Option<&str>,
+    digest: &str,
+) -> SourceStrategy {
+    match (readonly, registry_reference) {
+        (None, _) if supports_containers_storage => SourceStrategy::ContainerStorage,
+        (None, _) => {
+            SourceStrategy::OciCopy("skopeo is too old to read from container storage".into())
+        }
+        (Some(_), Some(r)) => SourceStrategy::Registry(crate::utils::digested_pullspec(r, digest)),
+        (Some(reason), None) => {
+            SourceStrategy::OciCopy(format!("container storage is read-only: {reason}"))
+        }
+    }
+}
+
+/// Determine how to read the running image.
+#[context("Inspecting container storage")]
+pub(crate) fn source_strategy(inspect: &Inspect) -> Result<SourceStrategy> {
+    let readonly = match crate::podman::store_info() {
+        Ok(info) => {
+            let writable = crate::utils::host_path_writable(&info.graph_root);
+            readonly_reason(&info, inspect.graph_driver.upper_dir(), writable)
+        }
+        Err(e) => {
+            tracing::debug!("Assuming container storage is writable: {e:#}");
+            None
+        }
+    };
+    if let Some(reason) = readonly.as_deref() {
+        tracing::debug!("Container storage is read-only: {reason}");
+    }
+    let supports_containers_storage = super::skopeo_supports_containers_storage()?;
+    let r = select_strategy(
+        supports_containers_storage,
+        readonly,
+        registry_reference(inspect),
+        &inspect.digest,
+    );
+    tracing::debug!("Reading source image via {r}");
+    Ok(r)
+}
+
+#[test]
+fn test_select_strategy() {
+    let info: StoreInfo = serde_json::from_str(
+        r#"{
+            "graphOptions": {"overlay.additionalImageStores": ["/usr/lib/containers/storage"]},
+            "graphRoot": "/var/lib/containers/storage"
+        }"#,
+    )
+    .unwrap();
+    let primary = Some("/var/lib/containers/storage/overlay/a1b2/diff");
+    let additional = Some("/usr/lib/containers/storage/overlay/a1b2/diff");
+    assert_eq!(readonly_reason(&info, primary, true), None);
+    assert_eq!(readonly_reason(&info, None, true), None);
+    assert_eq!(
+        readonly_reason(&info, additional, true).unwrap(),
+        "the image is in the additional image store /usr/lib/containers/storage"
+    );
+    assert_eq!(
+        readonly_reason(&info, primary, false).unwrap(),
+        "/var/lib/containers/storage is read-only"
+    );
+    // Not a path prefix
+    let similar = Some("/usr/lib/containers/storage2/overlay/a1b2/diff");
+    assert_eq!(readonly_reason(&info, similar, true), None);
+
+    for (name, expected) in [
+        ("quay.io/example/os:latest", true),
+        ("registry.example.com:5000/os", true),
+        ("localhost:5000/os", true),
+        ("localhost/os:latest", false),
+        ("os:latest", false),
+        ("sha256:5e0b", false),
+    ] {
+        assert_eq!(is_registry_image(name), expected, "{name}");
+    }
+
+    let inspect = |annotations: serde_json::Value| -> Inspect {
+        serde_json::from_value(serde_json::json!({
+            "Digest": "sha256:5e0b",
+            "Annotations": annotations,
+        }))
+        .unwrap()
+    };
+    let registry = "quay.io/example/os:latest";
+    let i = inspect(serde_json::json!({ REF_NAME_ANNOTATION: registry }));
+    assert_eq!(registry_reference(&i), Some(registry));
+    let i = inspect(serde_json::json!({ REF_NAME_ANNOTATION: "localhost/os:latest" }));
+    assert_eq!(registry_reference(&i), None);
+    assert_eq!(registry_reference(&inspect(serde_json::json!({}))), None);
+    assert_eq!(registry_reference(&inspect(serde_json::Value::Null)), None);
+
+    let digest = "sha256:5e0b";
+    let readonly = || Some("/var/lib/containers/storage is read-only".to_string());
+    let select = |supported, readonly, r| select_strategy(supported, readonly, r, digest);
+    assert_eq!(
+        select(true, None, Some(registry)),
+        SourceStrategy::ContainerStorage
+    );
+    assert_eq!(select(true, None, None), SourceStrategy::ContainerStorage);
+    assert!(matches!(
+        select(false, None, Some(registry)),
+        SourceStrategy::OciCopy(r) if r.contains("skopeo is too old")
+    ));
+    assert_eq!(
+        select(true, readonly(), Some(registry)),
+        SourceStrategy::Registry("quay.io/example/os:latest@sha256:5e0b".into())
+    );
+    assert!(matches!(
+        select(true, readonly(), None),
+        SourceStrategy::OciCopy(r) if r.contains("read-only")
+    ));
+}
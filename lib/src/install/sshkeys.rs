@@ -0,0 +1,230 @@
+//! # Preserving SSH host keys across reinstalls
+//!
+//! Reinstalling a machine would otherwise generate new SSH host keys on first boot,
+//! which trips every client's `known_hosts`.  The existing keys are read into memory
+//! before the target root is wiped, and written into the new deployment's `/etc`.
+
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use cap_std::fs::Dir;
+use cap_std_ext::cap_std;
+use cap_std_ext::rustix::fs::MetadataExt;
+use fn_error_context::context;
+
+/// The directory holding SSH host keys, relative to the root
+const SSH_CONFIG_DIR: &str = "etc/ssh";
+/// The filename prefix of SSH host keys
+const SSH_HOST_KEY_PREFIX: &str = "ssh_host_";
+/// The root of the live host, as visible from our container
+const HOST_ROOT: &str = "/proc/1/root";
+
+/// Where to find the SSH host keys to preserve.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SshHostKeySource {
+    /// The existing contents of the target root filesystem
+    Root,
+    /// The live host system
+    Host,
+}
+
+impl FromStr for SshHostKeySource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "root" => Ok(Self::Root),
+            "host" => Ok(Self::Host),
+            o => anyhow::bail!("Invalid value {o}: expected root or host"),
+        }
+    }
+}
+
+/// An SSH host key file read from an existing system.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SshHostKey {
+    pub(crate) name: String,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    contents: Vec<u8>,
+}
+
+/// Read the SSH host keys from the provided root.  Private keys which are readable
+/// by group or other are rejected.
+#[context("Reading SSH host keys")]
+pub(crate) fn stash_ssh_host_keys(root: &Dir) -> Result<Vec<SshHostKey>> {
+    let dir = if let Some(path) = super::existing::find_in_root(root, SSH_CONFIG_DIR)? {
+        root.open_dir(path)?
+    } else {
+        return Ok(Vec::new());
+    };
+    let mut r = Vec::new();
+    for e in dir.entries()? {
+        let e = e?;
+        let name = e.file_name();
+        let name = if let Some(n) = name.to_str() {
+            n
+        } else {
+            continue;
+        };
+        if !(name.starts_with(SSH_HOST_KEY_PREFIX) && e.file_type()?.is_file()) {
+            continue;
+        }
+        let meta = e.metadata()?;
+        let mode = meta.mode() & 0o7777;
+        if !name.ends_with(".pub") && mode & 0o077 != 0 {
+            anyhow::bail!(
+                "Refusing to preserve {name} with mode {mode:04o}; it must not be accessible by group or other"
+            );
+        }
+        let mut contents = Vec::new();
+        e.open()?
+            .read_to_end(&mut contents)
+            .with_context(|| format!("Reading {name}"))?;
+        r.push(SshHostKey {
+            name: name.to_owned(),
+            mode,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            contents,
+        });
+    }
+    r.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(r)
+}
+
+/// Read the SSH host keys from the requested source.
+pub(crate) fn stash_from_source(source: SshHostKeySource, rootfs: &Dir) -> Result<Vec<SshHostKey>> {
+    match source {
+        SshHostKeySource::Root => stash_ssh_host_keys(rootfs),
+        SshHostKeySource::Host => {
+            let host = Dir::open_ambient_dir(HOST_ROOT, cap_std::ambient_authority())
+                .with_context(|| format!("Opening {HOST_ROOT}"))?;
+            stash_ssh_host_keys(&host)
+        }
+    }
+}
+
+/// Write the stashed SSH host keys into the `etc` of the provided deployment root,
+/// with their original mode and ownership.  The `label` callback is invoked for each
+/// key, along with its path in the booted system.
+#[context("Restoring SSH host keys")]
+pub(crate) fn restore_ssh_host_keys(
+    root: &Utf8Path,
+    keys: &[SshHostKey],
+    mut label: impl FnMut(&Utf8Path, &Utf8Path) -> Result<()>,
+) -> Result<()> {
+    let sshdir = root.join(SSH_CONFIG_DIR);
+    std::fs::create_dir_all(&sshdir).with_context(|| format!("Creating {sshdir}"))?;
+    for key in keys {
+        let path = sshdir.join(&key.name);
+        // Ensure we don't widen the permissions of an existing file while writing
+        if path.symlink_metadata().is_ok() {
+            std::fs::remove_file(&path).with_context(|| format!("Removing {path}"))?;
+        }
+        let mut f = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(key.mode)
+            .open(&path)
+            .with_context(|| format!("Creating {path}"))?;
+        f.write_all(&key.contents)?;
+        nix::unistd::fchown(f.as_raw_fd(), Some(key.uid.into()), Some(key.gid.into()))
+            .with_context(|| format!("Setting ownership of {path}"))?;
+        // The umask may have masked the requested mode
+        f.set_permissions(std::fs::Permissions::from_mode(key.mode))
+            .with_context(|| format!("Setting permissions of {path}"))?;
+        drop(f);
+        label(
+            &path,
+            &Utf8Path::new("/").join(SSH_CONFIG_DIR).join(&key.name),
+        )?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_ssh_host_key_source() {
+    for (s, v) in [
+        ("root", SshHostKeySource::Root),
+        ("host", SshHostKeySource::Host),
+    ] {
+        assert_eq!(SshHostKeySource::from_str(s).unwrap(), v);
+    }
+    assert!(SshHostKeySource::from_str("host:").is_err());
+}
+
+#[test]
+fn test_ssh_host_keys() {
+    use cap_std::fs::Permissions;
+    use cap_std_ext::cap_tempfile;
+
+    let td = cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    // Nothing to preserve
+    assert!(stash_ssh_host_keys(&td).unwrap().is_empty());
+
+    // Keys in an ostree deployment
+    let sshdir = "ostree/deploy/default/deploy/0123abcd.0/etc/ssh";
+    td.create_dir_all(sshdir).unwrap();
+    td.create_dir_all("ostree/deploy/default/deploy/4567ef01.0")
+        .unwrap();
+    let write = |name: &str, mode: u32| {
+        let path = format!("{sshdir}/{name}");
+        td.write(&path, name).unwrap();
+        td.set_permissions(
+            &path,
+            Permissions::from_std(std::fs::Permissions::from_mode(mode)),
+        )
+        .unwrap();
+    };
+    write("ssh_host_ed25519_key", 0o600);
+    write("ssh_host_ed25519_key.pub", 0o644);
+    write("sshd_config", 0o644);
+    let keys = stash_ssh_host_keys(&td).unwrap();
+    let names = keys.iter().map(|k| k.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["ssh_host_ed25519_key", "ssh_host_ed25519_key.pub"]);
+
+    // A plain /etc takes precedence
+    td.create_dir_all(SSH_CONFIG_DIR).unwrap();
+    assert!(stash_ssh_host_keys(&td).unwrap().is_empty());
+    td.remove_dir_all("etc").unwrap();
+
+    // Restore into a new root, replacing any existing key
+    let target = tempfile::tempdir().unwrap();
+    let target = Utf8Path::from_path(target.path()).unwrap();
+    std::fs::create_dir_all(target.join(SSH_CONFIG_DIR)).unwrap();
+    std::fs::write(target.join("etc/ssh/ssh_host_ed25519_key"), "generated").unwrap();
+    let mut labeled = Vec::new();
+    restore_ssh_host_keys(target, &keys, |path, as_path| {
+        assert!(path.symlink_metadata().is_ok());
+        labeled.push(as_path.to_string());
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(
+        labeled,
+        [
+            "/etc/ssh/ssh_host_ed25519_key",
+            "/etc/ssh/ssh_host_ed25519_key.pub"
+        ]
+    );
+    for (name, mode) in [
+        ("ssh_host_ed25519_key", 0o600),
+        ("ssh_host_ed25519_key.pub", 0o644),
+    ] {
+        let path = target.join(SSH_CONFIG_DIR).join(name);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), name);
+        assert_eq!(path.metadata().unwrap().permissions().mode() & 0o7777, mode);
+    }
+
+    // Private keys readable by others are rejected
+    write("ssh_host_rsa_key", 0o644);
+    assert!(stash_ssh_host_keys(&td).is_err());
+}
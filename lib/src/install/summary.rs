@@ -0,0 +1,249 @@
+//! # Describing the result of an installation
+//!
+//! The [`InstallSummary`] is the single description of what was installed where;
+//! any output formats for external consumers should be derived from it.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use anyhow::Result;
+use serde::Serialize;
+
+/// The current version of the Anaconda results format.
+const ANACONDA_RESULTS_VERSION: u32 = 1;
+
+/// Information about a completed installation.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct InstallSummary {
+    /// The block device the bootloader was installed to; unset with `--stateless`
+    pub(crate) bootloader_device: Option<String>,
+    /// The UUID of the root filesystem, if it is mounted by UUID
+    pub(crate) root_uuid: Option<String>,
+    /// The UUID of the /boot filesystem, if it is separate
+    pub(crate) boot_uuid: Option<String>,
+    /// The ostree stateroot
+    pub(crate) stateroot: String,
+    /// The manifest digest of the installed image
+    pub(crate) digest: String,
+    /// Bootloader components installed by bootupd
+    pub(crate) bootloader: Vec<crate::bootloader::BootloaderComponent>,
+    /// Non-default ostree repository configuration
+    pub(crate) ostree_config: BTreeMap<String, String>,
+    /// Additional partitions created via `--extra-partition`
+    pub(crate) extra_partitions: Vec<CreatedPartition>,
+    /// The total size of the image's layers, as listed in its manifest
+    pub(crate) layer_bytes: u64,
+    /// The bytes fetched for the image, including with `--pull-first`; this is less than
+    /// `layer_bytes` if layers were reused or fetched partially, and more if the image
+    /// was copied first
+    pub(crate) fetched_bytes: u64,
+    /// Warnings found during the installation
+    pub(crate) warnings: Vec<String>,
+    /// Warnings about the deployed image, which are also among `warnings`
+    pub(crate) deploy_warnings: Vec<super::deploycheck::DeployWarning>,
+    /// What is done after the installation; this differs from `--post-install` if
+    /// kexec is not possible
+    pub(crate) post_install: super::postinstall::PostInstall,
+}
+
+/// An additional partition created by the installer.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct CreatedPartition {
+    pub(crate) label: String,
+    /// The filesystem UUID (or FAT volume ID)
+    pub(crate) uuid: String,
+    pub(crate) fstype: String,
+    /// The mountpoint in the installed system
+    pub(crate) mountpoint: String,
+}
+
+impl CreatedPartition {
+    pub(crate) fn mount_spec(&self) -> super::MountSpec {
+        super::MountSpec {
+            fstype: self.fstype.clone(),
+            ..super::MountSpec::new_uuid_src(&self.uuid, &self.mountpoint)
+        }
+    }
+}
+
+impl InstallSummary {
+    /// Write the `KEY=value` format consumed by Anaconda's ostree payload.  The
+    /// keys are the uppercased names of the fields; unset fields are omitted.  Only
+    /// the number of warnings and the classes of the warnings about the deployed image are
+    /// included, as they are printed by the installer.
+    pub(crate) fn write_anaconda_results(&self, mut w: impl Write) -> Result<()> {
+        writeln!(w, "# Generated by bootc install")?;
+        writeln!(w, "VERSION={ANACONDA_RESULTS_VERSION}")?;
+        if let Some(device) = self.bootloader_device.as_deref() {
+            writeln!(w, "BOOTLOADER_DEVICE={device}")?;
+        }
+        if let Some(root_uuid) = self.root_uuid.as_deref() {
+            writeln!(w, "ROOT_UUID={root_uuid}")?;
+        }
+        if let Some(boot_uuid) = self.boot_uuid.as_deref() {
+            writeln!(w, "BOOT_UUID={boot_uuid}")?;
+        }
+        writeln!(w, "STATEROOT={}", self.stateroot)?;
+        writeln!(w, "DIGEST={}", self.digest)?;
+        let bootloader = self
+            .bootloader
+            .iter()
+            .map(|c| c.name.as_str())
+            .collect::<Vec<_>>();
+        writeln!(w, "BOOTLOADER={}", bootloader.join(","))?;
+        let ostree_config = self
+            .ostree_config
+            .iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect::<Vec<_>>();
+        writeln!(w, "OSTREE_CONFIG={}", ostree_config.join(","))?;
+        let extra_partitions = self
+            .extra_partitions
+            .iter()
+            .map(|p| format!("{}={}", p.label, p.uuid))
+            .collect::<Vec<_>>();
+        writeln!(w, "EXTRA_PARTITIONS={}", extra_partitions.join(","))?;
+        writeln!(w, "LAYER_BYTES={}", self.layer_bytes)?;
+        writeln!(w, "FETCHED_BYTES={}", self.fetched_bytes)?;
+        writeln!(w, "WARNINGS={}", self.warnings.len())?;
+        let deploy_warnings = self
+            .deploy_warnings
+            .iter()
+            .map(|d| d.class.to_string())
+            .collect::<Vec<_>>();
+        writeln!(w, "DEPLOY_WARNINGS={}", deploy_warnings.join(","))?;
+        writeln!(w, "POST_INSTALL={}", self.post_install.name())?;
+        Ok(())
+    }
+
+    /// Write `BOOTC_KEY=value` lines suitable for `eval` in a POSIX shell.
+    pub(crate) fn write_env(&self, mut w: impl Write) -> Result<()> {
+        let vars = [
+            ("DEVICE", self.bootloader_device.as_deref()),
+            ("ROOT_UUID", self.root_uuid.as_deref()),
+            ("BOOT_UUID", self.boot_uuid.as_deref()),
+            ("STATEROOT", Some(self.stateroot.as_str())),
+            ("DIGEST", Some(self.digest.as_str())),
+        ];
+        for (k, v) in vars {
+            if let Some(v) = v {
+                writeln!(w, "BOOTC_{k}={}", shell_quote(v))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Quote a value for a POSIX shell, if necessary.
+fn shell_quote(s: &str) -> Cow<'_, str> {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_-./:@%+=,".contains(c);
+    if !s.is_empty() && s.chars().all(safe) {
+        Cow::Borrowed(s)
+    } else {
+        Cow::Owned(format!("'{}'", s.replace('\'', "'\\''")))
+    }
+}
+
+#[test]
+fn test_anaconda_results() {
+    let mut summary = InstallSummary {
+        bootloader_device: Some("/dev/vda".into()),
+        root_uuid: Some("e4a8bcb9-9d93-44a4-9af7-6e2f4a1d8bd8".into()),
+        boot_uuid: Some("0e2d7a53-7d9c-4ef6-a66c-8cd0b0cb8a4c".into()),
+        stateroot: "default".into(),
+        digest: "sha256:5e0be47d0fdcb3f1c6ac2d6f03e0def6c7e9e5bae0e5c3b5e02cbb0e6e8b3fd1".into(),
+        bootloader: ["BIOS", "EFI"]
+            .map(|name| crate::bootloader::BootloaderComponent {
+                name: name.into(),
+                version: "1".into(),
+            })
+            .into(),
+        ostree_config: [("core.min-free-space-percent", "0")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+        extra_partitions: vec![CreatedPartition {
+            label: "oem".into(),
+            uuid: "7B77-95E7".into(),
+            fstype: "vfat".into(),
+            mountpoint: "/run/media/oem".into(),
+        }],
+        layer_bytes: 812345678,
+        fetched_bytes: 203086419,
+        warnings: vec![
+            "No SBOM found for the image".into(),
+            "1 of 2 layers are uncompressed; updates transfer them in full (uncompressed-layer)"
+                .into(),
+        ],
+        deploy_warnings: vec![super::deploycheck::DeployWarning {
+            class: super::deploycheck::WarningClass::UncompressedLayer,
+            message: "1 of 2 layers are uncompressed; updates transfer them in full".into(),
+        }],
+        post_install: super::postinstall::PostInstall::Kexec,
+    };
+    let mut buf = Vec::new();
+    summary.write_anaconda_results(&mut buf).unwrap();
+    let buf = String::from_utf8(buf).unwrap();
+    assert_eq!(
+        buf,
+        "# Generated by bootc install\n\
+         VERSION=1\n\
+         BOOTLOADER_DEVICE=/dev/vda\n\
+         ROOT_UUID=e4a8bcb9-9d93-44a4-9af7-6e2f4a1d8bd8\n\
+         BOOT_UUID=0e2d7a53-7d9c-4ef6-a66c-8cd0b0cb8a4c\n\
+         STATEROOT=default\n\
+         DIGEST=sha256:5e0be47d0fdcb3f1c6ac2d6f03e0def6c7e9e5bae0e5c3b5e02cbb0e6e8b3fd1\n\
+         BOOTLOADER=BIOS,EFI\n\
+         OSTREE_CONFIG=core.min-free-space-percent=0\n\
+         EXTRA_PARTITIONS=oem=7B77-95E7\n\
+         LAYER_BYTES=812345678\n\
+         FETCHED_BYTES=203086419\n\
+         WARNINGS=2\n\
+         DEPLOY_WARNINGS=uncompressed-layer\n\
+         POST_INSTALL=kexec\n"
+    );
+    // Every field of the JSON summary must also be in the Anaconda results
+    let fields = serde_json::to_value(&summary).unwrap();
+    for k in fields.as_object().unwrap().keys() {
+        let k = format!("\n{}=", k.replace('-', "_").to_ascii_uppercase());
+        assert!(buf.contains(&k), "Missing {k}");
+    }
+
+    summary.root_uuid = None;
+    let mut buf = Vec::new();
+    summary.write_anaconda_results(&mut buf).unwrap();
+    assert!(!String::from_utf8(buf).unwrap().contains("ROOT_UUID"));
+}
+
+#[test]
+fn test_env_output() {
+    let summary = InstallSummary {
+        bootloader_device: Some("/dev/disk/by-path/pci-0000:00:1f.2 ata-1".into()),
+        root_uuid: None,
+        boot_uuid: Some("0e2d7a53-7d9c-4ef6-a66c-8cd0b0cb8a4c".into()),
+        stateroot: "it's".into(),
+        digest: "sha256:5e0be47d".into(),
+        bootloader: Vec::new(),
+        ostree_config: BTreeMap::new(),
+        extra_partitions: Vec::new(),
+        layer_bytes: 0,
+        fetched_bytes: 0,
+        warnings: Vec::new(),
+        deploy_warnings: Vec::new(),
+        post_install: Default::default(),
+    };
+    let mut buf = Vec::new();
+    summary.write_env(&mut buf).unwrap();
+    assert_eq!(
+        String::from_utf8(buf).unwrap(),
+        "BOOTC_DEVICE='/dev/disk/by-path/pci-0000:00:1f.2 ata-1'\n\
+         BOOTC_BOOT_UUID=0e2d7a53-7d9c-4ef6-a66c-8cd0b0cb8a4c\n\
+         BOOTC_STATEROOT='it'\\''s'\n\
+         BOOTC_DIGEST=sha256:5e0be47d\n"
+    );
+    assert_eq!(shell_quote(""), "''");
+    assert_eq!(shell_quote("$(reboot)"), "'$(reboot)'");
+}
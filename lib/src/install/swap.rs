@@ -0,0 +1,129 @@
+//! # Swapfiles
+//!
+//! A swap partition is rarely worth it for a filesystem like btrfs, but swapfiles
+//! need to be created in a specific way: they must not be sparse, and on btrfs
+//! must be created empty with copy-on-write disabled before any data is written.
+//! Hibernation to a swapfile is not supported; no `resume=` karg is added.
+
+use std::fmt::Display;
+use std::os::unix::fs::OpenOptionsExt;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use fn_error_context::context;
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+
+use crate::task::Task;
+
+/// The path to the swapfile in the installed system
+pub(crate) const SWAPFILE_PATH: &str = "/var/swap/swapfile";
+/// The prefix for `--swap` values creating a swapfile
+const SWAPFILE_PREFIX: &str = "swapfile:";
+
+/// Swap to configure in the installed system.
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeFromStr, SerializeDisplay)]
+pub(crate) struct SwapSpec {
+    /// Size of the swapfile in MiB
+    pub(crate) size_mib: u64,
+}
+
+impl FromStr for SwapSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let size = s
+            .strip_prefix(SWAPFILE_PREFIX)
+            .ok_or_else(|| anyhow::anyhow!("Invalid swap {s}: expected swapfile:SIZE"))?;
+        let size_mib = crate::blockdev::parse_size_mib(size)
+            .with_context(|| format!("Parsing swapfile size {size}"))?;
+        if size_mib == 0 {
+            anyhow::bail!("Invalid swapfile size {size}");
+        }
+        Ok(Self { size_mib })
+    }
+}
+
+impl Display for SwapSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{SWAPFILE_PREFIX}{}M", self.size_mib)
+    }
+}
+
+impl SwapSpec {
+    /// The `/etc/fstab` entry activating the swapfile.
+    pub(crate) fn fstab_entry(&self) -> String {
+        format!("{SWAPFILE_PATH} none swap defaults 0 0")
+    }
+}
+
+/// Check that swapfiles are supported on the provided root filesystem type, returning
+/// whether copy-on-write must be disabled for the file.
+pub(crate) fn check_supported(fstype: &str) -> Result<bool> {
+    match fstype {
+        "btrfs" => Ok(true),
+        "ext4" | "xfs" => Ok(false),
+        o => anyhow::bail!("Swapfiles are not supported on the root filesystem type {o}"),
+    }
+}
+
+/// Create and format a swapfile at the provided path; on failure, the partially
+/// created file is removed.
+#[context("Creating swapfile")]
+pub(crate) fn create_swapfile(path: &Utf8Path, size_mib: u64, nocow: bool) -> Result<()> {
+    // The file must be empty for chattr +C to take effect
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Creating {path}"))?;
+    let r = format_swapfile(path, size_mib, nocow);
+    if r.is_err() {
+        if let Err(e) = std::fs::remove_file(path) {
+            eprintln!("warning: Failed to remove {path}: {e}");
+        }
+    }
+    r
+}
+
+fn format_swapfile(path: &Utf8Path, size_mib: u64, nocow: bool) -> Result<()> {
+    if nocow {
+        Task::new("Disabling copy-on-write for swapfile", "chattr")
+            .args(["+C", path.as_str()])
+            .run()?;
+    }
+    // Not truncate, as the kernel refuses to swap to a sparse file
+    let len = (size_mib * 1024 * 1024).to_string();
+    Task::new("Allocating swapfile", "fallocate")
+        .args(["-l", len.as_str(), path.as_str()])
+        .run()?;
+    Task::new("Formatting swapfile", "mkswap")
+        .args([path.as_str()])
+        .run()
+}
+
+#[test]
+fn test_swapspec() {
+    let spec = SwapSpec::from_str("swapfile:4G").unwrap();
+    assert_eq!(spec.size_mib, 4096);
+    assert_eq!(spec.to_string(), "swapfile:4096M");
+    assert_eq!(SwapSpec::from_str(&spec.to_string()).unwrap(), spec);
+    assert_eq!(
+        spec.fstab_entry(),
+        "/var/swap/swapfile none swap defaults 0 0"
+    );
+    for invalid in [
+        "4G",
+        "swapfile:",
+        "swapfile:0",
+        "swapfile:4X",
+        "partition:4G",
+    ] {
+        assert!(SwapSpec::from_str(invalid).is_err(), "{invalid}");
+    }
+    assert!(check_supported("btrfs").unwrap());
+    assert!(!check_supported("xfs").unwrap());
+    assert!(check_supported("vfat").is_err());
+    assert!(check_supported("auto").is_err());
+}
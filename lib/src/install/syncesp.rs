@@ -0,0 +1,183 @@
+//! # A second EFI system partition
+//!
+//! With `--sync-esp`, an EFI system partition is also created on a second disk, so that
+//! the system remains bootable if the first one fails.  bootupd only manages the ESP
+//! mounted in the root filesystem, so after installing the bootloader its contents are
+//! copied to the second ESP.  bootupd records each update in its state file in `/boot`,
+//! which a path unit in the target watches to copy the updates as well.
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std::fs::Dir;
+use cap_std_ext::cap_std;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+
+use super::EspMountpoint;
+
+/// The name of the units copying bootloader updates, without suffix
+const UNIT_NAME: &str = "bootc-sync-esp";
+/// Where the units are written, relative to the deployment root
+const UNIT_DIR: &str = "etc/systemd/system";
+/// The directory which enables the path unit, relative to [`UNIT_DIR`]
+const UNIT_INSTALL_DIR: &str = "multi-user.target.wants";
+
+/// Copy the contents of the ESP mounted at `src` to the one mounted at `dest`,
+/// returning the number of files copied.  FAT has no ownership, permissions or
+/// symbolic links, so only directories and file contents are copied.
+#[context("Copying {src} to {dest}")]
+pub(crate) fn copy_esp(src: &Utf8Path, dest: &Utf8Path) -> Result<u64> {
+    let mut n = 0;
+    for entry in src.read_dir_utf8()? {
+        let entry = entry?;
+        let path = entry.path();
+        let target = dest.join(entry.file_name());
+        let ty = entry.file_type()?;
+        if ty.is_dir() {
+            if target.symlink_metadata().is_err() {
+                std::fs::create_dir(&target).with_context(|| format!("Creating {target}"))?;
+            }
+            n += copy_esp(path, &target)?;
+        } else if ty.is_file() {
+            std::fs::copy(path, &target).with_context(|| format!("Copying {path}"))?;
+            n += 1;
+        } else {
+            anyhow::bail!("Unsupported file type of {path}");
+        }
+    }
+    Ok(n)
+}
+
+/// The unit which watches the bootupd state file for bootloader updates.
+fn path_unit_contents() -> String {
+    format!(
+        "# Generated by bootc install --sync-esp\n\
+         [Unit]\n\
+         Description=Watch for bootloader updates to copy to the second EFI system partition\n\
+         \n\
+         [Path]\n\
+         PathChanged=/{}\n\
+         \n\
+         [Install]\n\
+         WantedBy=multi-user.target\n",
+        crate::bootloader::BOOTUPD_STATE
+    )
+}
+
+/// The unit which copies the ESP mounted at `esp` to the one with the provided
+/// filesystem source (e.g. `UUID=...`).  The second ESP is only mounted in the unit's
+/// own mount namespace.
+fn service_unit_contents(esp: EspMountpoint, source: &str) -> String {
+    let what = crate::mount::source_device_path(source);
+    let esp = esp.path();
+    format!(
+        "# Generated by bootc install --sync-esp\n\
+         [Unit]\n\
+         Description=Copy bootloader updates to the second EFI system partition\n\
+         RequiresMountsFor={esp}\n\
+         \n\
+         [Service]\n\
+         Type=oneshot\n\
+         PrivateMounts=yes\n\
+         RuntimeDirectory={UNIT_NAME}\n\
+         ExecStart=/usr/bin/mount -t vfat -o umask=0077 {what} /run/{UNIT_NAME}\n\
+         ExecStart=/usr/bin/cp -rT {esp} /run/{UNIT_NAME}\n"
+    )
+}
+
+/// Write the units copying bootloader updates to the second ESP into the deployment,
+/// and enable them.  Returns the paths written, relative to the deployment.
+#[context("Writing units to copy bootloader updates")]
+pub(crate) fn write_units(
+    deployment: &Dir,
+    esp: EspMountpoint,
+    source: &str,
+) -> Result<Vec<Utf8PathBuf>> {
+    deployment.create_dir_all(UNIT_DIR)?;
+    let unitdir = deployment.open_dir(UNIT_DIR)?;
+    let path_unit = format!("{UNIT_NAME}.path");
+    let service_unit = format!("{UNIT_NAME}.service");
+    for (name, contents) in [
+        (&path_unit, path_unit_contents()),
+        (&service_unit, service_unit_contents(esp, source)),
+    ] {
+        unitdir
+            .atomic_write(name, contents)
+            .with_context(|| format!("Writing {name}"))?;
+    }
+    unitdir.create_dir_all(UNIT_INSTALL_DIR)?;
+    let link = Utf8Path::new(UNIT_INSTALL_DIR).join(&path_unit);
+    unitdir.remove_file_optional(&link)?;
+    unitdir
+        .symlink(format!("../{path_unit}"), &link)
+        .with_context(|| format!("Enabling {path_unit}"))?;
+    let unit_dir = Utf8Path::new(UNIT_DIR);
+    Ok([
+        Utf8Path::new(&path_unit),
+        Utf8Path::new(&service_unit),
+        Utf8Path::new(UNIT_INSTALL_DIR),
+        &link,
+    ]
+    .into_iter()
+    .map(|p| unit_dir.join(p))
+    .collect())
+}
+
+#[test]
+fn test_copy_esp() {
+    let td = tempfile::tempdir().unwrap();
+    let td = Utf8Path::from_path(td.path()).unwrap();
+    let (src, dest) = (td.join("src"), td.join("dest"));
+    std::fs::create_dir_all(src.join("EFI/fedora")).unwrap();
+    std::fs::create_dir_all(src.join("EFI/BOOT")).unwrap();
+    std::fs::write(src.join("EFI/fedora/grub.cfg"), "configfile\n").unwrap();
+    std::fs::write(src.join("EFI/BOOT/BOOTX64.EFI"), "shim").unwrap();
+    std::fs::create_dir_all(dest.join("EFI/BOOT")).unwrap();
+    std::fs::write(dest.join("EFI/BOOT/BOOTX64.EFI"), "old shim").unwrap();
+
+    assert_eq!(copy_esp(&src, &dest).unwrap(), 2);
+    assert_eq!(
+        std::fs::read_to_string(dest.join("EFI/fedora/grub.cfg")).unwrap(),
+        "configfile\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(dest.join("EFI/BOOT/BOOTX64.EFI")).unwrap(),
+        "shim"
+    );
+
+    std::os::unix::fs::symlink("BOOT", src.join("EFI/link")).unwrap();
+    assert!(copy_esp(&src, &dest).is_err());
+}
+
+#[test]
+fn test_write_units() {
+    let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+    let paths = write_units(&td, EspMountpoint::Efi, "UUID=ABCD-1234").unwrap();
+    assert_eq!(
+        paths,
+        [
+            "etc/systemd/system/bootc-sync-esp.path",
+            "etc/systemd/system/bootc-sync-esp.service",
+            "etc/systemd/system/multi-user.target.wants",
+            "etc/systemd/system/multi-user.target.wants/bootc-sync-esp.path",
+        ]
+    );
+    assert_eq!(
+        td.read_link(&paths[3]).unwrap().to_str().unwrap(),
+        "../bootc-sync-esp.path"
+    );
+    assert!(td
+        .read_to_string("etc/systemd/system/bootc-sync-esp.path")
+        .unwrap()
+        .contains("PathChanged=/boot/bootupd-state.json\n"));
+    let service = td
+        .read_to_string("etc/systemd/system/bootc-sync-esp.service")
+        .unwrap();
+    assert!(service.contains("RequiresMountsFor=/efi\n"));
+    assert!(service.contains(
+        "ExecStart=/usr/bin/mount -t vfat -o umask=0077 /dev/disk/by-uuid/ABCD-1234 /run/bootc-sync-esp\n\
+         ExecStart=/usr/bin/cp -rT /efi /run/bootc-sync-esp\n"
+    ));
+    // Rewriting is idempotent
+    write_units(&td, EspMountpoint::Efi, "UUID=ABCD-1234").unwrap();
+}
@@ -0,0 +1,96 @@
+//! # A stable path for the target deployment
+//!
+//! The root of the new deployment lives at a path in the ostree repository which
+//! depends on the stateroot and the commit.  While the installation is finished, it is
+//! instead bind mounted at [`lock::target_deployment_dir`], which is per invocation so
+//! that concurrent installations do not collide, and everything operating on the
+//! deployment from the host uses that path.  `--run-in-target` commands run inside the
+//! deployment, so for them [`TARGET_DEPLOYMENT_ENV`] is `/`; the inspection shell is
+//! given the path of the mount on the host instead.  The mount is a [`MountGuard`]
+//! made via [`InstallOps`]; it is removed when the guard is dropped, including if the
+//! installation fails, and left behind by a process which exited is removed by the
+//! next installation.
+//!
+//! [`lock::target_deployment_dir`]: super::lock::target_deployment_dir
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use fn_error_context::context;
+
+use super::ops::{InstallOps, MountGuard};
+
+/// The environment variable holding the path of the target deployment for commands
+pub(crate) const TARGET_DEPLOYMENT_ENV: &str = "BOOTC_TARGET_DEPLOYMENT";
+/// The path of the target deployment for commands run inside it
+pub(crate) const TARGET_DEPLOYMENT_IN_TARGET: &str = "/";
+
+/// The bind mount of the target deployment, which is unmounted when dropped.
+pub(crate) struct TargetDeployment<'a> {
+    mount: MountGuard<'a>,
+}
+
+impl<'a> TargetDeployment<'a> {
+    /// Bind mount the deployment root `src` at `path`, which must be empty if it exists.
+    #[context("Mounting target deployment at {path}")]
+    pub(crate) fn mount(ops: &'a dyn InstallOps, src: &Utf8Path, path: &Utf8Path) -> Result<Self> {
+        std::fs::create_dir_all(path).with_context(|| format!("Creating {path}"))?;
+        if path.read_dir_utf8()?.next().is_some() {
+            anyhow::bail!("{path} is not empty");
+        }
+        let mount = MountGuard::bind(ops, src, path)?;
+        Ok(Self { mount })
+    }
+
+    /// The path of the deployment root.
+    pub(crate) fn path(&self) -> &Utf8Path {
+        self.mount.path()
+    }
+
+    /// Unmount the deployment, reporting any error.
+    pub(crate) fn unmount(self) -> Result<()> {
+        self.mount.unmount()
+    }
+}
+
+#[test]
+fn test_target_deployment() {
+    let td = tempfile::tempdir().unwrap();
+    let td = Utf8Path::from_path(td.path()).unwrap();
+    let src = td.join("ostree/deploy/default/deploy/abcd.0");
+    let path = td.join("run/bootc/123/mounts/target-deployment");
+    let ops = super::ops::FakeOps::default();
+
+    // The mount exists while a command runs in the target, and is gone afterwards
+    let target = TargetDeployment::mount(&ops, &src, &path).unwrap();
+    assert_eq!(target.path(), path);
+    ops.run_in_target(target.path(), &["true".to_string()])
+        .unwrap();
+    target.unmount().unwrap();
+    assert_eq!(
+        *ops.calls.borrow(),
+        [
+            format!("bind-mount {src} {path}"),
+            "run-in-target true".to_string(),
+            format!("unmount-recursive {path}"),
+        ]
+    );
+
+    // Also if the installation fails
+    ops.calls.borrow_mut().clear();
+    let r = (|| -> Result<()> {
+        let target = TargetDeployment::mount(&ops, &src, &path)?;
+        ops.run_in_target(target.path(), &["false".to_string()])?;
+        anyhow::bail!("Installation failed")
+    })();
+    assert!(r.is_err());
+    assert_eq!(
+        ops.calls.borrow().last().unwrap(),
+        &format!("unmount-recursive {path}")
+    );
+
+    // Something is already mounted there
+    std::fs::write(path.join("etc"), "").unwrap();
+    ops.calls.borrow_mut().clear();
+    assert!(TargetDeployment::mount(&ops, &src, &path).is_err());
+    assert!(ops.calls.borrow().is_empty());
+}
@@ -19,6 +19,8 @@ const SELINUXFS: &str = "/sys/fs/selinux";
 /// The SELinux xattr
 #[cfg(feature = "install")]
 const SELINUX_XATTR: &[u8] = b"security.selinux\0";
+/// The SELinux configuration, relative to the root
+pub(crate) const SELINUX_CONFIG: &str = "etc/selinux/config";
 
 #[context("Querying selinux availability")]
 pub(crate) fn selinux_enabled() -> Result<bool> {
@@ -57,6 +59,7 @@ pub(crate) fn selinux_ensure_install() -> Result<()> {
     let mut cmd = Command::new(&tmpf);
     cmd.env(guardenv, tmpf);
     cmd.args(std::env::args_os().skip(1));
+    crate::utils::restore_stdout_for_reexec(&mut cmd)?;
     tracing::debug!("Re-executing");
     Err(anyhow::Error::msg(cmd.exec()).context("execve"))
 }
@@ -91,14 +94,19 @@ fn selinux_label_for_path(target: &str) -> Result<String> {
     Ok(label.to_string())
 }
 
-// Write filesystem labels (currently just for SELinux)
-#[context("Labeling {as_path}")]
-pub(crate) fn lsm_label(target: &Utf8Path, as_path: &Utf8Path, recurse: bool) -> Result<()> {
-    let label = selinux_label_for_path(as_path.as_str())?;
+/// How many subtrees to aim for per worker when labeling in parallel, to balance
+/// subtrees of differing sizes.
+const LABEL_SUBTREES_PER_JOB: usize = 4;
+/// How deep to look for subtrees to label in parallel.
+const LABEL_SPLIT_MAX_DEPTH: usize = 4;
+
+/// Set the label of the provided paths, recursively if `recurse` is set.
+fn chcon(label: &str, paths: &[Utf8PathBuf], recurse: bool) -> Result<()> {
     let st = Command::new("chcon")
         .arg("-h")
         .args(recurse.then_some("-R"))
-        .args(["-h", label.as_str(), target.as_str()])
+        .args(["-h", label])
+        .args(paths)
         .status()?;
     if !st.success() {
         anyhow::bail!("Failed to invoke chcon: {st:?}");
@@ -106,6 +114,123 @@ pub(crate) fn lsm_label(target: &Utf8Path, as_path: &Utf8Path, recurse: bool) ->
     Ok(())
 }
 
+/// Split the tree at `root` for labeling in parallel, returning the directories to
+/// label non-recursively, and the subtrees to label recursively.  Directories (but
+/// not symlinks to them) are expanded breadth-first until there are at least `want`
+/// subtrees.
+fn split_tree(root: &Utf8Path, want: usize) -> Result<(Vec<Utf8PathBuf>, Vec<Utf8PathBuf>)> {
+    let mut dirs = Vec::new();
+    let mut subtrees = vec![root.to_owned()];
+    for _ in 0..LABEL_SPLIT_MAX_DEPTH {
+        if subtrees.len() >= want {
+            break;
+        }
+        let mut next = Vec::new();
+        let mut expanded = false;
+        for path in subtrees {
+            if !path.symlink_metadata()?.is_dir() {
+                next.push(path);
+                continue;
+            }
+            let mut children = path
+                .read_dir_utf8()?
+                .map(|e| e.map(|e| e.into_path()))
+                .collect::<std::io::Result<Vec<_>>>()?;
+            children.sort();
+            next.extend(children);
+            dirs.push(path);
+            expanded = true;
+        }
+        subtrees = next;
+        if !expanded {
+            break;
+        }
+    }
+    Ok((dirs, subtrees))
+}
+
+/// Label the tree at `root` via `label`, which is invoked with the paths to label and
+/// whether to do so recursively, using up to `jobs` threads.
+fn label_tree(
+    root: &Utf8Path,
+    jobs: usize,
+    label: impl Fn(&[Utf8PathBuf], bool) -> Result<()> + Sync,
+) -> Result<()> {
+    if jobs <= 1 {
+        return label(&[root.to_owned()], true);
+    }
+    let (dirs, subtrees) = split_tree(root, jobs * LABEL_SUBTREES_PER_JOB)?;
+    if !dirs.is_empty() {
+        label(&dirs, false)?;
+    }
+    if subtrees.is_empty() {
+        return Ok(());
+    }
+    let chunk_size = (subtrees.len() + jobs - 1) / jobs;
+    let label = &label;
+    std::thread::scope(|s| {
+        let workers = subtrees
+            .chunks(chunk_size)
+            .map(|chunk| s.spawn(move || label(chunk, true)))
+            .collect::<Vec<_>>();
+        workers.into_iter().try_for_each(|w| {
+            w.join()
+                .map_err(|_| anyhow::anyhow!("Labeling thread panicked"))?
+        })
+    })
+}
+
+// Write filesystem labels (currently just for SELinux).  Trees are labeled in
+// parallel, as large images have many files.
+#[context("Labeling {as_path}")]
+pub(crate) fn lsm_label(target: &Utf8Path, as_path: &Utf8Path, recurse: bool) -> Result<()> {
+    let label = selinux_label_for_path(as_path.as_str())?;
+    if recurse {
+        let jobs = std::thread::available_parallelism().map_or(1, |n| n.get());
+        label_tree(target, jobs, |paths, recurse| chcon(&label, paths, recurse))
+    } else {
+        chcon(&label, &[target.to_owned()], false)
+    }
+}
+
+/// Set the `SELINUX=` mode in the contents of an SELinux configuration, replacing any
+/// previous one in place.
+fn selinux_config_with_mode(contents: &str, mode: &str) -> String {
+    let mut found = false;
+    let mut r = String::new();
+    for line in contents.lines() {
+        if line.trim_start().starts_with("SELINUX=") {
+            if !found {
+                r.push_str(&format!("SELINUX={mode}\n"));
+                found = true;
+            }
+            continue;
+        }
+        r.push_str(line);
+        r.push('\n');
+    }
+    if !found {
+        r.push_str(&format!("SELINUX={mode}\n"));
+    }
+    r
+}
+
+/// Set the SELinux mode (`enforcing`, `permissive` or `disabled`) in the configuration
+/// of the provided root, which must exist.
+#[context("Setting SELinux mode to {mode}")]
+pub(crate) fn set_selinux_config_mode(root: &Utf8Path, mode: &str) -> Result<()> {
+    let path = root.join(SELINUX_CONFIG);
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            anyhow::bail!("No /{SELINUX_CONFIG} found; does the image use SELinux?")
+        }
+        Err(e) => return Err(e).with_context(|| format!("Reading {path}")),
+    };
+    std::fs::write(&path, selinux_config_with_mode(&contents, mode))
+        .with_context(|| format!("Writing {path}"))
+}
+
 #[cfg(feature = "install")]
 pub(crate) fn xattrs_have_selinux(xattrs: &ostree::glib::Variant) -> bool {
     let v = xattrs.data_as_bytes();
@@ -119,3 +244,104 @@ pub(crate) fn xattrs_have_selinux(xattrs: &ostree::glib::Variant) -> bool {
     }
     false
 }
+
+#[test]
+fn test_label_tree() {
+    use std::sync::Mutex;
+
+    let td = tempfile::tempdir().unwrap();
+    let root = Utf8Path::from_path(td.path()).unwrap().join("ostree");
+    for i in 0..20 {
+        let d = root.join(format!("repo/objects/{i:02x}"));
+        std::fs::create_dir_all(&d).unwrap();
+        for j in 0..(i % 4) {
+            std::fs::write(d.join(format!("{j}.file")), "").unwrap();
+        }
+    }
+    std::fs::create_dir_all(root.join("repo/tmp/cache/deep/er")).unwrap();
+    std::fs::create_dir_all(root.join("deploy/default/deploy")).unwrap();
+    std::fs::write(root.join("repo/config"), "").unwrap();
+    std::os::unix::fs::symlink("repo/objects", root.join("objects-link")).unwrap();
+
+    // Record every path labeled, emulating `chcon -R -h`
+    fn walk(path: &Utf8Path, labeled: &mut Vec<Utf8PathBuf>) {
+        labeled.push(path.to_owned());
+        if path.symlink_metadata().unwrap().is_dir() {
+            for e in path.read_dir_utf8().unwrap() {
+                walk(e.unwrap().path(), labeled);
+            }
+        }
+    }
+    let labeled_with = |jobs: usize| {
+        let labeled = Mutex::new(Vec::new());
+        label_tree(&root, jobs, |paths, recurse| {
+            let mut labeled = labeled.lock().unwrap();
+            for path in paths {
+                if recurse {
+                    walk(path, &mut labeled);
+                } else {
+                    labeled.push(path.clone());
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+        let mut labeled = labeled.into_inner().unwrap();
+        labeled.sort();
+        labeled
+    };
+    let serial = labeled_with(1);
+    let mut all = Vec::new();
+    walk(&root, &mut all);
+    all.sort();
+    assert_eq!(serial, all);
+    for jobs in [2, 4, 64] {
+        let parallel = labeled_with(jobs);
+        // Each path is labeled exactly once
+        assert_eq!(parallel, serial, "{jobs}");
+    }
+
+    // Errors from any worker are propagated
+    let r = label_tree(&root, 4, |paths, recurse| {
+        if recurse && paths.iter().any(|p| p.ends_with("07")) {
+            anyhow::bail!("chcon failed")
+        }
+        Ok(())
+    });
+    assert!(r.is_err());
+}
+
+#[test]
+fn test_set_selinux_config_mode() {
+    let td = tempfile::tempdir().unwrap();
+    let root = Utf8Path::from_path(td.path()).unwrap();
+    assert!(set_selinux_config_mode(root, "permissive").is_err());
+    let path = root.join(SELINUX_CONFIG);
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let config = "# This file controls the state of SELinux on the system.
+# SELINUX= can take one of these three values:
+SELINUX=enforcing
+SELINUXTYPE=targeted
+";
+    std::fs::write(&path, config).unwrap();
+    for mode in ["permissive", "enforcing", "disabled"] {
+        set_selinux_config_mode(root, mode).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            config.replace("SELINUX=enforcing", &format!("SELINUX={mode}"))
+        );
+    }
+    // Without a mode, and with repeated ones
+    std::fs::write(&path, "SELINUXTYPE=targeted\n").unwrap();
+    set_selinux_config_mode(root, "permissive").unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "SELINUXTYPE=targeted\nSELINUX=permissive\n"
+    );
+    std::fs::write(&path, "SELINUX=enforcing\nSELINUX=disabled\n").unwrap();
+    set_selinux_config_mode(root, "permissive").unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "SELINUX=permissive\n"
+    );
+}
@@ -1,19 +1,22 @@
 //! Helpers for interacting with mountpoints
 
-use std::process::Command;
+use std::process::{Command, Stdio};
 
 use anyhow::{anyhow, Context, Result};
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
 use fn_error_context::context;
 use serde::Deserialize;
 
 use crate::task::Task;
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub(crate) struct Filesystem {
     pub(crate) source: String,
+    pub(crate) fstype: String,
     pub(crate) uuid: Option<String>,
+    #[serde(default)]
+    pub(crate) label: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -21,6 +24,18 @@ pub(crate) struct Findmnt {
     pub(crate) filesystems: Vec<Filesystem>,
 }
 
+/// Convert a mount source such as `UUID=...` or `LABEL=...` into the corresponding
+/// device path; other values are returned unchanged.
+pub(crate) fn source_device_path(spec: &str) -> String {
+    match spec.split_once('=') {
+        Some(("UUID", v)) => format!("/dev/disk/by-uuid/{v}"),
+        Some(("LABEL", v)) => format!("/dev/disk/by-label/{v}"),
+        Some(("PARTUUID", v)) => format!("/dev/disk/by-partuuid/{v}"),
+        Some(("PARTLABEL", v)) => format!("/dev/disk/by-partlabel/{v}"),
+        _ => spec.to_string(),
+    }
+}
+
 #[context("Inspecting filesystem {path}")]
 pub(crate) fn inspect_filesystem(path: &Utf8Path) -> Result<Filesystem> {
     tracing::debug!("Inspecting {path}");
@@ -47,3 +62,113 @@ pub(crate) fn mount(dev: &str, target: &Utf8Path) -> Result<()> {
         [dev, target.as_str()],
     )
 }
+
+/// Recursively bind mount `src` to the target path.
+pub(crate) fn rbind(src: &Utf8Path, target: &Utf8Path) -> Result<()> {
+    Task::new_and_run(
+        format!("Mounting {target}"),
+        "mount",
+        ["--rbind", src.as_str(), target.as_str()],
+    )
+}
+
+/// Mount options for mounting read-only without replaying the filesystem journal, which
+/// would write to the device.  They are filesystem specific, so each is tried in order.
+const READONLY_NORECOVERY_OPTIONS: &[&str] = &["ro,norecovery", "ro,rescue=nologreplay"];
+
+/// Mount a device read-only to the target path, such that nothing is written to it;
+/// only filesystems supporting skipping journal replay, such as ext4, xfs and btrfs,
+/// can be mounted.
+#[context("Mounting {dev} read-only")]
+pub(crate) fn mount_readonly(dev: &str, target: &Utf8Path) -> Result<()> {
+    for opts in READONLY_NORECOVERY_OPTIONS {
+        tracing::debug!("Mounting {dev} with {opts}");
+        let st = Command::new("mount")
+            .args(["-o", opts, dev, target.as_str()])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?;
+        if st.success() {
+            return Ok(());
+        }
+    }
+    anyhow::bail!("Unsupported or invalid filesystem")
+}
+
+/// Unmount the target path.  A lazy unmount detaches the filesystem even if it is busy.
+pub(crate) fn unmount(target: &Utf8Path, lazy: bool) -> Result<()> {
+    Task::new(format!("Unmounting {target}"), "umount")
+        .args(lazy.then_some("--lazy"))
+        .args([target.as_str()])
+        .run()
+}
+
+/// Unmount the target path and all mounts beneath it.
+pub(crate) fn unmount_recursive(target: &Utf8Path) -> Result<()> {
+    Task::new_and_run(
+        format!("Unmounting {target}"),
+        "umount",
+        ["-R", target.as_str()],
+    )
+}
+
+/// Undo the octal escaping (e.g. `\040` for a space) used in `/proc/self/mountinfo`.
+fn unescape_mountinfo(s: &str) -> String {
+    let mut r = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('\\') {
+        r.push_str(&rest[..i]);
+        let escaped = rest.get(i + 1..i + 4);
+        match escaped.and_then(|v| u8::from_str_radix(v, 8).ok()) {
+            Some(c) => {
+                r.push(c as char);
+                rest = &rest[i + 4..];
+            }
+            None => {
+                r.push('\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    r.push_str(rest);
+    r
+}
+
+/// Find the mountpoints in the provided `/proc/self/mountinfo` contents which are
+/// underneath `dir` (including `dir` itself), in the order they should be unmounted.
+pub(crate) fn mounts_under(mountinfo: &str, dir: &Utf8Path) -> Vec<Utf8PathBuf> {
+    let mut r = mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(|mountpoint| Utf8PathBuf::from(unescape_mountinfo(mountpoint)))
+        .filter(|mountpoint| mountpoint.starts_with(dir))
+        .collect::<Vec<_>>();
+    // Later mounts may be stacked on top of or inside earlier ones
+    r.reverse();
+    r
+}
+
+#[test]
+fn test_mounts_under() {
+    let mountinfo = "\
+22 1 253:0 / / rw,relatime shared:1 - xfs /dev/vda4 rw
+98 22 0:5 / /run/bootc/mounts/dev rw,nosuid shared:2 - devtmpfs devtmpfs rw
+99 22 253:4 / /run/bootc/mounts/rootfs rw,relatime shared:3 - xfs /dev/vdb4 rw
+100 99 253:3 / /run/bootc/mounts/rootfs/boot rw,relatime shared:4 - ext4 /dev/vdb3 rw
+101 22 0:40 / /run/bootc/mounts-other rw shared:5 - tmpfs tmpfs rw
+102 22 0:41 / /run/bootc/mounts/with\\040space rw shared:6 - tmpfs tmpfs rw
+";
+    assert_eq!(
+        mounts_under(mountinfo, "/run/bootc/mounts".into()),
+        [
+            "/run/bootc/mounts/with space",
+            "/run/bootc/mounts/rootfs/boot",
+            "/run/bootc/mounts/rootfs",
+            "/run/bootc/mounts/dev"
+        ]
+        .map(Utf8PathBuf::from)
+    );
+    assert!(mounts_under(mountinfo, "/var".into()).is_empty());
+    assert_eq!(unescape_mountinfo("a\\134b\\x"), "a\\b\\x");
+}
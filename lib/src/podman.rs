@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use anyhow::{anyhow, Result};
 use serde::Deserialize;
 
@@ -7,10 +9,77 @@ use crate::utils::run_in_host_mountns;
 #[serde(rename_all = "PascalCase")]
 pub(crate) struct Inspect {
     pub(crate) digest: String,
+    #[serde(default)]
+    pub(crate) architecture: String,
+    #[serde(default)]
+    pub(crate) graph_driver: GraphDriver,
+    #[serde(default)]
+    pub(crate) annotations: Option<BTreeMap<String, String>>,
+}
+
+/// Where the storage driver keeps an image
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct GraphDriver {
+    #[serde(default)]
+    data: BTreeMap<String, serde_json::Value>,
+}
+
+impl GraphDriver {
+    /// The directory of the topmost layer of the image, if known.
+    pub(crate) fn upper_dir(&self) -> Option<&str> {
+        self.data.get("UpperDir").and_then(|v| v.as_str())
+    }
+}
+
+#[derive(Deserialize)]
+struct Info {
+    store: StoreInfo,
+}
+
+/// The configuration of container storage
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct StoreInfo {
+    pub(crate) graph_root: String,
+    #[serde(default)]
+    graph_options: BTreeMap<String, serde_json::Value>,
+}
+
+impl StoreInfo {
+    /// The additional image stores, which are always read-only.
+    pub(crate) fn additional_image_stores(&self) -> Vec<&str> {
+        self.graph_options
+            .iter()
+            // e.g. overlay.additionalImageStores
+            .filter(|(k, _)| k.to_ascii_lowercase().ends_with("additionalimagestores"))
+            .filter_map(|(_, v)| v.as_array())
+            .flatten()
+            .filter_map(|v| v.as_str())
+            .collect()
+    }
+}
+
+/// Query the configuration of container storage
+pub(crate) fn store_info() -> Result<StoreInfo> {
+    let o = run_in_host_mountns("podman")
+        .args(["info", "--format", "json"])
+        .output()?;
+    let st = o.status;
+    if !st.success() {
+        anyhow::bail!("Failed to execute podman info: {st:?}");
+    }
+    let info: Info = serde_json::from_slice(&o.stdout)?;
+    Ok(info.store)
 }
 
 /// Given an image ID, return its manifest digest
 pub(crate) fn imageid_to_digest(imgid: &str) -> Result<String> {
+    inspect(imgid).map(|i| i.digest)
+}
+
+/// Inspect the image with the provided ID
+pub(crate) fn inspect(imgid: &str) -> Result<Inspect> {
     let o = run_in_host_mountns("podman")
         .args(["inspect", imgid])
         .output()?;
@@ -23,5 +92,111 @@ pub(crate) fn imageid_to_digest(imgid: &str) -> Result<String> {
         .into_iter()
         .next()
         .ok_or_else(|| anyhow!("No images returned for inspect"))?;
-    Ok(i.digest)
+    Ok(i)
+}
+
+/// Given an image reference in container storage, return its image ID
+pub(crate) fn image_id(imgref: &str) -> Result<String> {
+    let o = run_in_host_mountns("podman")
+        .args(["image", "inspect", "--format", "{{.Id}}", imgref])
+        .output()?;
+    let st = o.status;
+    if !st.success() {
+        anyhow::bail!("Failed to execute podman image inspect: {st:?}");
+    }
+    let id = String::from_utf8(o.stdout)?.trim().to_string();
+    if id.is_empty() {
+        anyhow::bail!("No image found for {imgref}");
+    }
+    Ok(id)
+}
+
+/// The `podman ps` format used to find the image of a container
+const PS_FORMAT: &str = "{{.ID}} {{.ImageID}} {{.Image}}";
+
+/// Parse `podman ps` output in [`PS_FORMAT`], returning the image ID and image
+/// of the container with the provided ID.
+fn parse_ps(output: &str, id: &str) -> Option<(String, String)> {
+    output.lines().find_map(|line| {
+        let mut parts = line.split_whitespace();
+        let (cid, imageid, image) = (parts.next()?, parts.next()?, parts.next()?);
+        (cid == id).then(|| (imageid.to_string(), image.to_string()))
+    })
+}
+
+/// Find the image ID and image of the container with the provided ID.
+pub(crate) fn container_image(id: &str) -> Result<Option<(String, String)>> {
+    let o = run_in_host_mountns("podman")
+        .args(["ps", "--no-trunc", "--format", PS_FORMAT])
+        .output()?;
+    let st = o.status;
+    if !st.success() {
+        anyhow::bail!("Failed to execute podman ps: {st:?}");
+    }
+    Ok(parse_ps(&String::from_utf8(o.stdout)?, id))
+}
+
+#[test]
+fn test_parse_ps() {
+    let id = "5d6b2ef7a1a4c24a1d9d3f2a3dc0e0e5cdb76cb5bb69c7c7d1ac63eb4b5f1e53";
+    let output = format!(
+        "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef 89abcdef quay.io/example/other:latest\n\
+         {id} a1b2c3d4 quay.io/example/os:latest\n"
+    );
+    assert_eq!(
+        parse_ps(&output, id).unwrap(),
+        (
+            "a1b2c3d4".to_string(),
+            "quay.io/example/os:latest".to_string()
+        )
+    );
+    assert_eq!(parse_ps(&output, "5d6b2ef7a1a4"), None);
+    assert_eq!(parse_ps("", id), None);
+}
+
+#[test]
+fn test_store_info() {
+    let info = r#"{
+        "host": {"arch": "amd64"},
+        "store": {
+            "graphDriverName": "overlay",
+            "graphOptions": {
+                "overlay.additionalImageStores": ["/usr/lib/containers/storage", "/mnt/images"],
+                "overlay.mountopt": "nodev,metacopy=on"
+            },
+            "graphRoot": "/var/lib/containers/storage"
+        }
+    }"#;
+    let info: Info = serde_json::from_str(info).unwrap();
+    assert_eq!(info.store.graph_root, "/var/lib/containers/storage");
+    assert_eq!(
+        info.store.additional_image_stores(),
+        ["/usr/lib/containers/storage", "/mnt/images"]
+    );
+    let info = r#"{"store": {"graphOptions": {}, "graphRoot": "/var/lib/containers/storage"}}"#;
+    let info: Info = serde_json::from_str(info).unwrap();
+    assert!(info.store.additional_image_stores().is_empty());
+
+    let inspect = r#"[{
+        "Digest": "sha256:5e0b",
+        "GraphDriver": {
+            "Name": "overlay",
+            "Data": {"UpperDir": "/usr/lib/containers/storage/overlay/a1b2/diff"}
+        }
+    }]"#;
+    let inspect: Vec<Inspect> = serde_json::from_str(inspect).unwrap();
+    assert_eq!(
+        inspect[0].graph_driver.upper_dir(),
+        Some("/usr/lib/containers/storage/overlay/a1b2/diff")
+    );
+    assert_eq!(inspect[0].annotations, None);
+    let inspect = r#"[{
+        "Digest": "sha256:5e0b",
+        "Annotations": {"org.opencontainers.image.ref.name": "quay.io/example/os:latest"}
+    }]"#;
+    let inspect: Vec<Inspect> = serde_json::from_str(inspect).unwrap();
+    assert_eq!(
+        inspect[0].annotations.as_ref().unwrap()["org.opencontainers.image.ref.name"],
+        "quay.io/example/os:latest"
+    );
 }
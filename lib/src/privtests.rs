@@ -160,6 +160,54 @@ fn test_install_filesystem(image: &str, blockdev: &Utf8Path) -> Result<()> {
     Ok(())
 }
 
+/// Install from an image which podman runs from a read-only additional image store,
+/// which must not be read from container storage directly.
+#[context("Container tests")]
+fn test_install_filesystem_readonly_storage(image: &str, blockdev: &Utf8Path) -> Result<()> {
+    let sh = Shell::new()?;
+
+    let mountpoint_dir = prep_test_install_filesystem(blockdev)?;
+    let mountpoint: &Utf8Path = mountpoint_dir.path().try_into().unwrap();
+
+    // Copy the image into a separate store, and bind mount it read-only
+    let td = tempfile::tempdir_in("/var/tmp")?;
+    let td: &Utf8Path = td.path().try_into()?;
+    let store = td.join("store");
+    let dest = format!("containers-storage:[overlay@{store}+{td}/store-run]{image}");
+    cmd!(sh, "skopeo copy containers-storage:{image} {dest}").run()?;
+    cmd!(sh, "mount --bind {store} {store}").run()?;
+    cmd!(sh, "mount -o remount,bind,ro {store} {store}").run()?;
+    // With an empty primary store, so the image is only in the read-only one
+    let conf = td.join("storage.conf");
+    std::fs::write(
+        &conf,
+        format!(
+            "[storage]\ndriver = \"overlay\"\ngraphroot = \"{td}/graphroot\"\n\
+             runroot = \"{td}/runroot\"\n\
+             [storage.options]\nadditionalimagestores = [\"{store}\"]\n"
+        ),
+    )?;
+    let _g = sh.push_env("CONTAINERS_STORAGE_CONF", &conf);
+    let _g2 = sh.push_env("RUST_LOG", "bootc=debug");
+
+    // The network is needed to pull the image from its registry instead
+    let o = cmd!(sh, "podman run --rm --privileged --pid=host --env=RUST_LOG --env=CONTAINERS_STORAGE_CONF -v /usr/bin/bootc:/usr/bin/bootc -v {mountpoint}:/target-root {image} bootc install-to-filesystem /target-root").output()?;
+    let stderr = String::from_utf8_lossy(&o.stderr);
+    assert!(o.status.success(), "{stderr}");
+    assert!(
+        stderr.contains(&format!(
+            "the image is in the additional image store {store}"
+        )),
+        "{stderr}"
+    );
+    assert!(stderr.contains("Reading source image via registry"));
+
+    cmd!(sh, "umount -R {mountpoint}").run()?;
+    cmd!(sh, "umount {store}").run()?;
+
+    Ok(())
+}
+
 pub(crate) async fn run(opts: TestingOpts) -> Result<()> {
     match opts {
         TestingOpts::RunPrivilegedIntegration {} => {
@@ -177,5 +225,12 @@ pub(crate) async fn run(opts: TestingOpts) -> Result<()> {
             crate::cli::ensure_self_unshared_mount_namespace().await?;
             tokio::task::spawn_blocking(move || test_install_filesystem(&image, &blockdev)).await?
         }
+        TestingOpts::TestInstallFilesystemReadonlyStorage { image, blockdev } => {
+            crate::cli::ensure_self_unshared_mount_namespace().await?;
+            tokio::task::spawn_blocking(move || {
+                test_install_filesystem_readonly_storage(&image, &blockdev)
+            })
+            .await?
+        }
     }
 }
@@ -23,6 +23,7 @@ pub(crate) fn reexec_with_guardenv(k: &str, prefix_args: &[&str]) -> Result<()>
     };
     cmd.env(k, "1");
     cmd.args(std::env::args_os().skip(1));
+    crate::utils::restore_stdout_for_reexec(&mut cmd)?;
     tracing::debug!("Re-executing current process for {k}");
     Err(cmd.exec().into())
 }
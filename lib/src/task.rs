@@ -1,7 +1,7 @@
 use std::{
     ffi::OsStr,
     io::Seek,
-    process::{Command, Stdio},
+    process::{Command, ExitStatus, Stdio},
 };
 
 use anyhow::{Context, Result};
@@ -9,6 +9,38 @@ use cap_std::fs::Dir;
 use cap_std_ext::cap_std;
 use cap_std_ext::prelude::CapStdExtCommandExt;
 
+/// A command run by a [`Task`] which did not exit successfully.
+#[derive(Debug)]
+pub(crate) struct TaskError {
+    pub(crate) description: String,
+    /// The command line, with arguments separated by spaces
+    pub(crate) command: String,
+    pub(crate) status: ExitStatus,
+}
+
+impl TaskError {
+    fn new(description: String, cmd: &Command, status: ExitStatus) -> Self {
+        let command = std::iter::once(cmd.get_program())
+            .chain(cmd.get_args())
+            .map(|a| a.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ");
+        Self {
+            description,
+            command,
+            status,
+        }
+    }
+}
+
+impl std::fmt::Display for TaskError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Task {} failed: {:?}", self.description, self.status)
+    }
+}
+
+impl std::error::Error for TaskError {}
+
 pub(crate) struct Task {
     description: String,
     quiet: bool,
@@ -77,7 +109,7 @@ impl Task {
                 let mut stderr = std::io::stderr().lock();
                 std::io::copy(&mut output, &mut stderr)?;
             }
-            anyhow::bail!("Task {description} failed: {st:?}");
+            return Err(TaskError::new(description, &cmd, st).into());
         }
         Ok(())
     }
@@ -99,7 +131,7 @@ impl Task {
             .with_context(|| format!("Executing {description} failed"))?;
         let st = o.status;
         if !st.success() {
-            anyhow::bail!("Task {description} failed: {st:?}");
+            return Err(TaskError::new(description, &cmd, st).into());
         }
         Ok(String::from_utf8(o.stdout)?)
     }
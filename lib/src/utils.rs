@@ -1,5 +1,8 @@
 use std::fmt::Display;
+use std::io::Write;
+use std::os::unix::io::{AsFd, AsRawFd, OwnedFd};
 use std::process::Command;
+use std::sync::Mutex;
 
 use anyhow::{Context, Result};
 use ostree::glib;
@@ -56,15 +59,100 @@ pub(crate) fn run_in_host_mountns(cmd: &str) -> Command {
     c
 }
 
+/// Check whether a path exists in the host mount namespace
+pub(crate) fn host_path_exists(path: &str) -> bool {
+    run_in_host_mountns("test")
+        .args(["-e", path])
+        .status()
+        .map_or(false, |st| st.success())
+}
+
+/// Check whether a path is writable in the host mount namespace; this is false on a
+/// read-only filesystem, even for root.
+pub(crate) fn host_path_writable(path: &str) -> bool {
+    run_in_host_mountns("test")
+        .args(["-w", path])
+        .status()
+        .map_or(false, |st| st.success())
+}
+
+/// The standard output we had before the outermost [`StdoutToStderr`] redirected it.
+static ORIGINAL_STDOUT: Mutex<Option<OwnedFd>> = Mutex::new(None);
+
+/// While this is alive, anything written to our standard output (including by child
+/// processes) goes to standard error instead.
+#[derive(Debug)]
+pub(crate) struct StdoutToStderr {
+    saved: OwnedFd,
+    outermost: bool,
+}
+
+impl StdoutToStderr {
+    pub(crate) fn new() -> Result<Self> {
+        std::io::stdout().flush()?;
+        let saved = std::io::stdout().as_fd().try_clone_to_owned()?;
+        let mut original = ORIGINAL_STDOUT.lock().unwrap();
+        let outermost = original.is_none();
+        if outermost {
+            *original = Some(saved.try_clone()?);
+        }
+        nix::unistd::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO)?;
+        Ok(Self { saved, outermost })
+    }
+}
+
+impl Drop for StdoutToStderr {
+    fn drop(&mut self) {
+        let _ = std::io::stdout().flush();
+        let _ = nix::unistd::dup2(self.saved.as_raw_fd(), libc::STDOUT_FILENO);
+        if self.outermost {
+            *ORIGINAL_STDOUT.lock().unwrap() = None;
+        }
+    }
+}
+
+/// When our standard output is redirected by a [`StdoutToStderr`], give the original
+/// one to a command re-executing ourselves, which sets up its own redirection.
+pub(crate) fn restore_stdout_for_reexec(cmd: &mut Command) -> Result<()> {
+    if let Some(fd) = ORIGINAL_STDOUT.lock().unwrap().as_ref() {
+        cmd.stdout(fd.try_clone()?);
+    }
+    Ok(())
+}
+
 /// Given a possibly tagged image like quay.io/foo/bar:latest and a digest 0ab32..., return
 /// the digested form quay.io/foo/bar:latest@sha256:0ab32...
 /// If the image already has a digest, it will be replaced.
-#[allow(dead_code)]
 pub(crate) fn digested_pullspec(image: &str, digest: &str) -> String {
     let image = image.rsplit_once('@').map(|v| v.0).unwrap_or(image);
     format!("{image}@{digest}")
 }
 
+/// Given an image like quay.io/foo/bar:latest, possibly with a registry port or a digest,
+/// return it with the provided tag instead, e.g. quay.io/foo/bar:stable.  Any digest is
+/// removed.
+pub(crate) fn tagged_pullspec(image: &str, tag: &str) -> Result<String> {
+    let valid_tag = tag.len() <= 128
+        && tag.chars().enumerate().all(|(i, c)| {
+            c.is_ascii_alphanumeric() || c == '_' || (i > 0 && (c == '.' || c == '-'))
+        });
+    if tag.is_empty() || !valid_tag {
+        anyhow::bail!("Invalid tag {tag}");
+    }
+    let image = image.rsplit_once('@').map(|v| v.0).unwrap_or(image);
+    // A port is followed by a '/', a tag is not
+    let name_start = image.rfind('/').map(|i| i + 1).unwrap_or_default();
+    let repo = match image[name_start..].rfind(':') {
+        Some(i) => &image[..name_start + i],
+        None => image,
+    };
+    let valid_name = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || "._-".contains(c);
+    if repo.len() == name_start || !repo[name_start..].chars().all(valid_name) {
+        anyhow::bail!("Invalid image {image}");
+    }
+    Ok(format!("{repo}:{tag}"))
+}
+
 #[test]
 fn test_digested_pullspec() {
     let digest = "ebe3bdccc041864e5a485f1e755e242535c3b83d110c0357fe57f110b73b143e";
@@ -81,3 +169,50 @@ fn test_digested_pullspec() {
         format!("quay.io/example/foo@{digest}")
     );
 }
+
+#[test]
+fn test_tagged_pullspec() {
+    let digest = "sha256:ebe3bdccc041864e5a485f1e755e242535c3b83d110c0357fe57f110b73b143e";
+    let cases = [
+        ("quay.io/example/os:latest", "quay.io/example/os:stable"),
+        ("quay.io/example/os", "quay.io/example/os:stable"),
+        (
+            "registry.example.com:5000/os:latest",
+            "registry.example.com:5000/os:stable",
+        ),
+        (
+            "registry.example.com:5000/os",
+            "registry.example.com:5000/os:stable",
+        ),
+        ("localhost:5000/org/os:1.2", "localhost:5000/org/os:stable"),
+        ("fedora:39", "fedora:stable"),
+        ("fedora", "fedora:stable"),
+    ];
+    for (image, expected) in cases {
+        assert_eq!(tagged_pullspec(image, "stable").unwrap(), expected);
+        let digested = format!("{image}@{digest}");
+        assert_eq!(tagged_pullspec(&digested, "stable").unwrap(), expected);
+    }
+    assert_eq!(
+        tagged_pullspec("quay.io/example/os@sha256:0123", "candidate_2.x-1").unwrap(),
+        "quay.io/example/os:candidate_2.x-1"
+    );
+    for tag in [
+        "",
+        ".stable",
+        "-rc",
+        "a/b",
+        "stable@sha256",
+        &"x".repeat(129),
+    ] {
+        assert!(tagged_pullspec("quay.io/example/os", tag).is_err(), "{tag}");
+    }
+    for image in [
+        "quay.io/",
+        "quay.io/Example/OS:latest",
+        ":latest",
+        "quay.io:5000/",
+    ] {
+        assert!(tagged_pullspec(image, "stable").is_err(), "{image}");
+    }
+}